@@ -1,36 +1,53 @@
-use std::{
-    ffi::{CStr, CString},
-    ptr,
-};
+use std::{borrow::Cow, collections::HashSet, ffi::CStr, ptr, sync::Mutex};
 
+use lazy_static::lazy_static;
 use log::error;
 use log_derive::{logfn, logfn_inputs};
 use winapi::{
     shared::{
-        minwindef::{DWORD, HINSTANCE, HKEY, LPDWORD, LPVOID, PFILETIME, PHKEY},
-        winerror::{ERROR_FILE_NOT_FOUND, ERROR_INVALID_HANDLE, ERROR_KEY_HAS_CHILDREN, ERROR_MORE_DATA, ERROR_NO_MORE_ITEMS, ERROR_PATH_NOT_FOUND, ERROR_SUCCESS, HRESULT},
+        minwindef::{DWORD, HINSTANCE, HKEY, LPDWORD, LPVOID, MAX_PATH, PFILETIME, PHKEY},
+        winerror::{
+            ERROR_ACCESS_DENIED, ERROR_CHILD_MUST_BE_VOLATILE, ERROR_FILE_NOT_FOUND, ERROR_INVALID_HANDLE, ERROR_KEY_HAS_CHILDREN, ERROR_MORE_DATA, ERROR_NO_MORE_ITEMS, ERROR_PATH_NOT_FOUND,
+            ERROR_SUCCESS, HRESULT,
+        },
     },
     um::{
-        winnt::{KEY_ALL_ACCESS, LPSTR, REG_CREATED_NEW_KEY, REG_OPENED_EXISTING_KEY, REG_OPTION_NON_VOLATILE, REG_SZ},
+        winnt::{KEY_ALL_ACCESS, LPSTR, REG_EXPAND_SZ, REG_NONE, REG_OPENED_EXISTING_KEY, REG_OPTION_NON_VOLATILE, REG_OPTION_VOLATILE, REG_SZ},
         winreg::{
-            RegCloseKey, RegCreateKeyExA, RegDeleteKeyExA, RegDeleteValueA, RegEnumKeyExA, RegEnumValueA, RegGetValueA, RegOpenKeyA, RegSetValueExA, HKEY_CLASSES_ROOT, HKEY_LOCAL_MACHINE, HKEY_USERS,
-            RRF_RT_ANY,
+            RegCloseKey, RegCreateKeyExA, RegDeleteKeyExA, RegDeleteValueA, RegEnumKeyExA, RegEnumValueA, RegGetValueA, RegOpenKeyA, RegSetValueExA, HKEY_CLASSES_ROOT, HKEY_CURRENT_USER,
+            HKEY_LOCAL_MACHINE, HKEY_USERS, RRF_RT_ANY,
         },
     },
 };
 use xfslib::*;
 
+/// Moves the XFS configuration roots below another key, e.g. `HKCU\Software\XFS_TEST`, so tests and sandboxes
+/// work on their own copy of the configuration without admin rights.
+const REGISTRY_ROOT_ENV: &str = "XFS_REGISTRY_ROOT";
+
+lazy_static! {
+    // holds the keys issued by WFMOpenKey and WFMCreateKey that have not been closed yet
+    static ref KEYS: Mutex<HashSet<usize>> = Mutex::new(HashSet::new());
+}
+
 #[allow(non_snake_case)]
 #[no_mangle]
 #[logfn(TRACE)]
 #[logfn_inputs(TRACE)]
 pub unsafe extern "stdcall" fn WFMCloseKey(hKey: HKEY) -> HRESULT {
-    match RegCloseKey(hKey) as u32 {
-        ERROR_SUCCESS => WFS_SUCCESS,
-        ERROR_FILE_NOT_FOUND => xfs_reject!(WFS_ERR_CFG_INVALID_HKEY),
-        ERROR_INVALID_HANDLE => xfs_reject!(WFS_ERR_CFG_INVALID_HKEY),
-        _ => xfs_reject!(WFS_ERR_INTERNAL_ERROR),
-    }
+    catch_panic(|| {
+        // closing a handle twice could close an unrelated key that reused the value, so only issued keys are closed
+        if !xfs_unwrap!(KEYS.lock()).remove(&(hKey as usize)) {
+            xfs_reject!(WFS_ERR_CFG_INVALID_HKEY);
+        }
+
+        match RegCloseKey(hKey) as u32 {
+            ERROR_SUCCESS => WFS_SUCCESS,
+            ERROR_FILE_NOT_FOUND => xfs_reject!(WFS_ERR_CFG_INVALID_HKEY),
+            ERROR_INVALID_HANDLE => xfs_reject!(WFS_ERR_CFG_INVALID_HKEY),
+            _ => xfs_reject!(WFS_ERR_INTERNAL_ERROR),
+        }
+    })
 }
 
 #[allow(non_snake_case)]
@@ -38,44 +55,49 @@ pub unsafe extern "stdcall" fn WFMCloseKey(hKey: HKEY) -> HRESULT {
 #[logfn(TRACE)]
 #[logfn_inputs(TRACE)]
 pub unsafe extern "stdcall" fn WFMCreateKey(hKey: HKEY, lpszSubKey: LPSTR, phkResult: PHKEY, lpdwDisposition: LPDWORD) -> HRESULT {
-    if lpszSubKey.is_null() || lpdwDisposition.is_null() {
+    catch_panic(|| create_key(hKey, lpszSubKey, phkResult, lpdwDisposition, REG_OPTION_NON_VOLATILE))
+}
+
+/// WFMCreateKey for a volatile key, which lives in memory only and is gone after a reboot.
+///
+/// This is a manager extension, not part of the XFS API. Meant for transient session state, a key that already
+/// exists is opened as it is, volatile or not. Subkeys of a volatile key must be volatile as well, creating any
+/// other below it fails with WFS_ERR_CFG_INVALID_SUBKEY.
+#[allow(non_snake_case)]
+#[no_mangle]
+#[logfn(TRACE)]
+#[logfn_inputs(TRACE)]
+pub unsafe extern "stdcall" fn WFMCreateVolatileKey(hKey: HKEY, lpszSubKey: LPSTR, phkResult: PHKEY, lpdwDisposition: LPDWORD) -> HRESULT {
+    catch_panic(|| create_key(hKey, lpszSubKey, phkResult, lpdwDisposition, REG_OPTION_VOLATILE))
+}
+
+/// Shared part of WFMCreateKey and WFMCreateVolatileKey, `options` are the `REG_OPTION_*` the key is created with.
+unsafe fn create_key(key: HKEY, sub_key: LPSTR, result_key: PHKEY, disposition: LPDWORD, options: DWORD) -> HRESULT {
+    if sub_key.is_null() || disposition.is_null() {
         xfs_reject!(WFS_ERR_INVALID_POINTER);
     }
 
-    let dw_disposition: LPDWORD = 0 as LPDWORD;
+    let (h_key, prefix) = resolve_root(key);
+    let sub_key = CStr::from_ptr(sub_key);
+    xfs_unwrap!(sub_key.to_str());
 
-    let (sub_key, h_key) = match hKey {
-        WFS_CFG_HKEY_XFS_ROOT => ("WOSA/XFS_ROOT\\", HKEY_CLASSES_ROOT),
-        WFS_CFG_HKEY_MACHINE_XFS_ROOT => ("SOFTWARE\\XFS\\", HKEY_LOCAL_MACHINE),
-        WFS_CFG_HKEY_USER_DEFAULT_XFS_ROOT => (".DEFAULT\\XFS\\", HKEY_USERS),
-        _ => ("", hKey),
-    };
+    let mut reg_disposition: DWORD = 0;
+    let result = with_path(&prefix, sub_key, |path| {
+        RegCreateKeyExA(h_key, path.as_ptr(), 0, ptr::null_mut(), options, KEY_ALL_ACCESS, ptr::null_mut(), result_key, &mut reg_disposition)
+    });
 
-    let sub_key = format!("{}{}", sub_key, xfs_unwrap!(CStr::from_ptr(lpszSubKey).to_str()));
-    let sub_key_cstring = xfs_unwrap!(CString::new(sub_key));
-
-    match RegCreateKeyExA(
-        h_key,
-        sub_key_cstring.as_ptr(),
-        0,
-        ptr::null_mut(),
-        REG_OPTION_NON_VOLATILE,
-        KEY_ALL_ACCESS,
-        ptr::null_mut(),
-        phkResult,
-        dw_disposition,
-    ) as u32
-    {
+    match result as u32 {
         ERROR_SUCCESS => {
-            lpdwDisposition.write(match *dw_disposition {
-                REG_CREATED_NEW_KEY => WFS_CFG_CREATED_NEW_KEY,
+            issue_key(*result_key);
+            disposition.write(match reg_disposition {
                 REG_OPENED_EXISTING_KEY => WFS_CFG_OPENED_EXISTING_KEY,
-                _ => 0,
+                _ => WFS_CFG_CREATED_NEW_KEY,
             });
             WFS_SUCCESS
         }
         ERROR_FILE_NOT_FOUND => xfs_reject!(WFS_ERR_CFG_INVALID_HKEY),
-        ERROR_PATH_NOT_FOUND => xfs_reject!(WFS_ERR_CFG_INVALID_SUBKEY),
+        ERROR_PATH_NOT_FOUND | ERROR_CHILD_MUST_BE_VOLATILE => xfs_reject!(WFS_ERR_CFG_INVALID_SUBKEY),
+        ERROR_ACCESS_DENIED => access_denied(),
         _ => xfs_reject!(WFS_ERR_INTERNAL_ERROR),
     }
 }
@@ -85,17 +107,20 @@ pub unsafe extern "stdcall" fn WFMCreateKey(hKey: HKEY, lpszSubKey: LPSTR, phkRe
 #[logfn(TRACE)]
 #[logfn_inputs(TRACE)]
 pub unsafe extern "stdcall" fn WFMDeleteKey(hKey: HKEY, lpszSubKey: LPSTR) -> HRESULT {
-    if lpszSubKey.is_null() {
-        return WFS_ERR_INVALID_POINTER;
-    }
+    catch_panic(|| {
+        if lpszSubKey.is_null() {
+            return WFS_ERR_INVALID_POINTER;
+        }
 
-    match RegDeleteKeyExA(hKey, lpszSubKey, 0, 0) as u32 {
-        ERROR_SUCCESS => WFS_SUCCESS,
-        ERROR_FILE_NOT_FOUND => xfs_reject!(WFS_ERR_CFG_INVALID_HKEY),
-        ERROR_KEY_HAS_CHILDREN => xfs_reject!(WFS_ERR_CFG_KEY_NOT_EMPTY),
-        ERROR_PATH_NOT_FOUND => xfs_reject!(WFS_ERR_CFG_INVALID_SUBKEY),
-        _ => xfs_reject!(WFS_ERR_INTERNAL_ERROR),
-    }
+        match RegDeleteKeyExA(hKey, lpszSubKey, 0, 0) as u32 {
+            ERROR_SUCCESS => WFS_SUCCESS,
+            ERROR_FILE_NOT_FOUND => xfs_reject!(WFS_ERR_CFG_INVALID_HKEY),
+            ERROR_KEY_HAS_CHILDREN => xfs_reject!(WFS_ERR_CFG_KEY_NOT_EMPTY),
+            ERROR_PATH_NOT_FOUND => xfs_reject!(WFS_ERR_CFG_INVALID_SUBKEY),
+            ERROR_ACCESS_DENIED => access_denied(),
+            _ => xfs_reject!(WFS_ERR_INTERNAL_ERROR),
+        }
+    })
 }
 
 #[allow(non_snake_case)]
@@ -103,16 +128,19 @@ pub unsafe extern "stdcall" fn WFMDeleteKey(hKey: HKEY, lpszSubKey: LPSTR) -> HR
 #[logfn(TRACE)]
 #[logfn_inputs(TRACE)]
 pub unsafe extern "stdcall" fn WFMDeleteValue(hKey: HKEY, lpszValue: LPSTR) -> HRESULT {
-    if lpszValue.is_null() {
-        xfs_reject!(WFS_ERR_INVALID_POINTER);
-    }
+    catch_panic(|| {
+        if lpszValue.is_null() {
+            xfs_reject!(WFS_ERR_INVALID_POINTER);
+        }
 
-    match RegDeleteValueA(hKey, lpszValue) as u32 {
-        ERROR_SUCCESS => WFS_SUCCESS,
-        ERROR_FILE_NOT_FOUND => xfs_reject!(WFS_ERR_CFG_INVALID_HKEY),
-        ERROR_PATH_NOT_FOUND => xfs_reject!(WFS_ERR_CFG_INVALID_VALUE),
-        _ => xfs_reject!(WFS_ERR_INTERNAL_ERROR),
-    }
+        match RegDeleteValueA(hKey, lpszValue) as u32 {
+            ERROR_SUCCESS => WFS_SUCCESS,
+            ERROR_FILE_NOT_FOUND => xfs_reject!(WFS_ERR_CFG_INVALID_HKEY),
+            ERROR_PATH_NOT_FOUND => xfs_reject!(WFS_ERR_CFG_INVALID_VALUE),
+            ERROR_ACCESS_DENIED => access_denied(),
+            _ => xfs_reject!(WFS_ERR_INTERNAL_ERROR),
+        }
+    })
 }
 
 #[allow(non_snake_case)]
@@ -120,17 +148,19 @@ pub unsafe extern "stdcall" fn WFMDeleteValue(hKey: HKEY, lpszValue: LPSTR) -> H
 #[logfn(TRACE)]
 #[logfn_inputs(TRACE)]
 pub unsafe extern "stdcall" fn WFMEnumKey(hKey: HKEY, iSubKey: DWORD, lpszName: LPSTR, lpcchName: LPDWORD, lpftLastWrite: PFILETIME) -> HRESULT {
-    if lpszName.is_null() || lpcchName.is_null() {
-        xfs_reject!(WFS_ERR_INVALID_POINTER);
-    }
+    catch_panic(|| {
+        if lpszName.is_null() || lpcchName.is_null() {
+            xfs_reject!(WFS_ERR_INVALID_POINTER);
+        }
 
-    match RegEnumKeyExA(hKey, iSubKey, lpszName, lpcchName, ptr::null_mut(), ptr::null_mut(), ptr::null_mut(), lpftLastWrite) as u32 {
-        ERROR_SUCCESS => WFS_SUCCESS,
-        ERROR_FILE_NOT_FOUND => xfs_reject!(WFS_ERR_CFG_INVALID_HKEY),
-        ERROR_MORE_DATA => xfs_reject!(WFS_ERR_CFG_NAME_TOO_LONG),
-        ERROR_NO_MORE_ITEMS => xfs_reject!(WFS_ERR_CFG_NO_MORE_ITEMS),
-        _ => xfs_reject!(WFS_ERR_INTERNAL_ERROR),
-    }
+        match RegEnumKeyExA(hKey, iSubKey, lpszName, lpcchName, ptr::null_mut(), ptr::null_mut(), ptr::null_mut(), lpftLastWrite) as u32 {
+            ERROR_SUCCESS => WFS_SUCCESS,
+            ERROR_FILE_NOT_FOUND => xfs_reject!(WFS_ERR_CFG_INVALID_HKEY),
+            ERROR_MORE_DATA => xfs_reject!(WFS_ERR_CFG_NAME_TOO_LONG),
+            ERROR_NO_MORE_ITEMS => xfs_reject!(WFS_ERR_CFG_NO_MORE_ITEMS),
+            _ => xfs_reject!(WFS_ERR_INTERNAL_ERROR),
+        }
+    })
 }
 
 #[allow(non_snake_case)]
@@ -138,89 +168,232 @@ pub unsafe extern "stdcall" fn WFMEnumKey(hKey: HKEY, iSubKey: DWORD, lpszName:
 #[logfn(TRACE)]
 #[logfn_inputs(TRACE)]
 pub unsafe extern "stdcall" fn WFMEnumValue(hKey: HKEY, iValue: DWORD, lpszValue: LPSTR, lpcchValue: LPDWORD, lpszData: LPSTR, lpcchData: LPDWORD) -> HRESULT {
-    if lpszValue.is_null() || lpcchValue.is_null() || lpszData.is_null() || lpcchData.is_null() {
-        xfs_reject!(WFS_ERR_INVALID_POINTER);
-    }
-
-    let result = match RegEnumValueA(hKey, iValue, lpszValue, lpcchValue, ptr::null_mut(), ptr::null_mut(), lpszData as *mut _, lpcchData) as u32 {
-        ERROR_SUCCESS => WFS_SUCCESS,
-        ERROR_FILE_NOT_FOUND => xfs_reject!(WFS_ERR_CFG_INVALID_HKEY),
-        ERROR_PATH_NOT_FOUND => xfs_reject!(WFS_ERR_CFG_INVALID_SUBKEY),
-        ERROR_MORE_DATA => xfs_reject!(WFS_ERR_CFG_VALUE_TOO_LONG),
-        ERROR_NO_MORE_ITEMS => xfs_reject!(WFS_ERR_CFG_NO_MORE_ITEMS),
-        _ => xfs_reject!(WFS_ERR_INTERNAL_ERROR),
-    };
+    catch_panic(|| {
+        let result = enum_value(hKey, iValue, lpszValue, lpcchValue, ptr::null_mut(), lpszData, lpcchData);
+        if result == WFS_ERR_INVALID_POINTER {
+            return result;
+        }
 
-    // Diebold xfs simply decreases by 1 even if there was an error
-    *lpcchData = *lpcchData - 1;
+        // Diebold xfs simply decreases by 1 even if there was an error
+        *lpcchData = *lpcchData - 1;
 
-    result
+        result
+    })
 }
 
+/// WFMEnumValue that also reports the `REG_*` type of the value in `lpdwType`, may be null.
+///
+/// This is a manager extension, not part of the XFS API. Unlike WFMEnumValue the data length only drops the
+/// terminating null for string values, the length of any other type is returned as the registry reports it.
 #[allow(non_snake_case)]
 #[no_mangle]
 #[logfn(TRACE)]
 #[logfn_inputs(TRACE)]
-pub unsafe extern "stdcall" fn WFMOpenKey(hKey: HKEY, lpszSubKey: LPSTR, phkResult: PHKEY) -> HRESULT {
-    if lpszSubKey.is_null() {
-        xfs_reject!(WFS_ERR_INVALID_POINTER);
-    }
-    let (sub_key, h_key) = match hKey {
-        WFS_CFG_HKEY_XFS_ROOT => ("WOSA/XFS_ROOT\\", HKEY_CLASSES_ROOT),
-        WFS_CFG_HKEY_MACHINE_XFS_ROOT => ("SOFTWARE\\XFS\\", HKEY_LOCAL_MACHINE),
-        WFS_CFG_HKEY_USER_DEFAULT_XFS_ROOT => (".DEFAULT\\XFS\\", HKEY_USERS),
-        _ => ("", hKey),
-    };
+pub unsafe extern "stdcall" fn WFMEnumValueEx(hKey: HKEY, iValue: DWORD, lpszValue: LPSTR, lpcchValue: LPDWORD, lpdwType: LPDWORD, lpszData: LPSTR, lpcchData: LPDWORD) -> HRESULT {
+    catch_panic(|| {
+        let mut value_type: DWORD = REG_NONE;
+        let result = enum_value(hKey, iValue, lpszValue, lpcchValue, &mut value_type, lpszData, lpcchData);
+        if result != WFS_SUCCESS {
+            return result;
+        }
 
-    let sub_key = format!("{}{}", sub_key, xfs_unwrap!(CStr::from_ptr(lpszSubKey).to_str()));
-    let sub_key_cstring = xfs_unwrap!(CString::new(sub_key));
+        if matches!(value_type, REG_SZ | REG_EXPAND_SZ) && *lpcchData > 0 {
+            *lpcchData -= 1;
+        }
+        if !lpdwType.is_null() {
+            *lpdwType = value_type;
+        }
 
-    match RegOpenKeyA(h_key, sub_key_cstring.as_ptr(), phkResult) as DWORD {
-        ERROR_SUCCESS => WFS_SUCCESS,
-        ERROR_FILE_NOT_FOUND => xfs_reject!(WFS_ERR_CFG_INVALID_HKEY),
-        ERROR_PATH_NOT_FOUND => xfs_reject!(WFS_ERR_CFG_INVALID_SUBKEY),
-        _ => xfs_reject!(WFS_ERR_INTERNAL_ERROR),
-    }
+        result
+    })
 }
 
 #[allow(non_snake_case)]
 #[no_mangle]
 #[logfn(TRACE)]
 #[logfn_inputs(TRACE)]
-pub unsafe extern "stdcall" fn WFMQueryValue(hKey: HKEY, lpszValueName: LPSTR, lpszData: LPSTR, lpcchData: LPDWORD) -> HRESULT {
-    if lpszValueName.is_null() || lpcchData.is_null() || ((*lpcchData > 0) && lpszData.is_null()) {
-        xfs_reject!(WFS_ERR_INVALID_POINTER);
-    }
+pub unsafe extern "stdcall" fn WFMOpenKey(hKey: HKEY, lpszSubKey: LPSTR, phkResult: PHKEY) -> HRESULT {
+    catch_panic(|| {
+        if lpszSubKey.is_null() {
+            xfs_reject!(WFS_ERR_INVALID_POINTER);
+        }
+        let (h_key, prefix) = resolve_root(hKey);
+        let sub_key = CStr::from_ptr(lpszSubKey);
+        xfs_unwrap!(sub_key.to_str());
+
+        match with_path(&prefix, sub_key, |path| RegOpenKeyA(h_key, path.as_ptr(), phkResult)) as DWORD {
+            ERROR_SUCCESS => {
+                issue_key(*phkResult);
+                WFS_SUCCESS
+            }
+            ERROR_FILE_NOT_FOUND => xfs_reject!(WFS_ERR_CFG_INVALID_HKEY),
+            ERROR_PATH_NOT_FOUND => xfs_reject!(WFS_ERR_CFG_INVALID_SUBKEY),
+            _ => xfs_reject!(WFS_ERR_INTERNAL_ERROR),
+        }
+    })
+}
 
-    let result = match RegGetValueA(hKey, std::ptr::null_mut(), lpszValueName, RRF_RT_ANY, std::ptr::null_mut(), lpszData as *mut _, lpcchData) as u32 {
-        ERROR_SUCCESS => WFS_SUCCESS,
-        ERROR_FILE_NOT_FOUND => WFS_ERR_CFG_INVALID_NAME,
-        ERROR_PATH_NOT_FOUND => WFS_ERR_CFG_INVALID_HKEY,
-        ERROR_MORE_DATA => WFS_ERR_CFG_VALUE_TOO_LONG,
-        _ => WFS_ERR_INTERNAL_ERROR,
-    };
+/// Reads a value of the key into `lpszData`.
+///
+/// `lpcchData` holds the size of the buffer in bytes, which must leave room for the terminating null. On return it
+/// holds the length of the value without the terminating null, so a value written by WFMSetValue reads back with
+/// the length it was written with.
+#[allow(non_snake_case)]
+#[no_mangle]
+#[logfn(TRACE)]
+#[logfn_inputs(TRACE)]
+pub unsafe extern "stdcall" fn WFMQueryValue(hKey: HKEY, lpszValueName: LPSTR, lpszData: LPSTR, lpcchData: LPDWORD) -> HRESULT {
+    catch_panic(|| {
+        if lpszValueName.is_null() || lpcchData.is_null() || ((*lpcchData > 0) && lpszData.is_null()) {
+            xfs_reject!(WFS_ERR_INVALID_POINTER);
+        }
 
-    // Diebold xfs simply decreases by 1 even if there was an error
-    *lpcchData = *lpcchData - 1;
-    result
+        let result = match RegGetValueA(hKey, std::ptr::null_mut(), lpszValueName, RRF_RT_ANY, std::ptr::null_mut(), lpszData as *mut _, lpcchData) as u32 {
+            ERROR_SUCCESS => WFS_SUCCESS,
+            ERROR_FILE_NOT_FOUND => WFS_ERR_CFG_INVALID_NAME,
+            ERROR_PATH_NOT_FOUND => WFS_ERR_CFG_INVALID_HKEY,
+            ERROR_MORE_DATA => WFS_ERR_CFG_VALUE_TOO_LONG,
+            _ => WFS_ERR_INTERNAL_ERROR,
+        };
+
+        // Diebold xfs simply decreases by 1 even if there was an error
+        *lpcchData = *lpcchData - 1;
+        result
+    })
 }
 
+/// Writes `lpszData` as a REG_SZ value of the key.
+///
+/// `cchData` is the length of the string and may or may not count the terminating null, or the size of a buffer
+/// padded with nulls after it. The value is stored with exactly one terminating null either way.
 #[allow(non_snake_case)]
 #[no_mangle]
 #[logfn(TRACE)]
 #[logfn_inputs(TRACE)]
 pub unsafe extern "stdcall" fn WFMSetValue(hKey: HKEY, lpszValueName: LPSTR, lpszData: LPSTR, cchData: DWORD) -> HRESULT {
-    if lpszValueName.is_null() || lpszData.is_null() {
+    catch_panic(|| {
+        if lpszValueName.is_null() || lpszData.is_null() {
+            xfs_reject!(WFS_ERR_INVALID_POINTER);
+        }
+
+        // cchData bytes are read, the string may be followed by nulls up to there, as in a fixed-size buffer passed
+        // with its size. Anything else after the first null (embedded null, stale data) is rejected. A missing
+        // terminator is appended as REG_SZ requires one.
+        let buffer = std::slice::from_raw_parts(lpszData as *const u8, cchData as usize);
+        let mut data = match buffer.iter().position(|&c| c == 0) {
+            Some(end) if buffer[end..].iter().any(|&c| c != 0) => xfs_reject!(WFS_ERR_INVALID_DATA),
+            Some(end) => buffer[..end].to_vec(),
+            None => buffer.to_vec(),
+        };
+        data.push(0);
+
+        match RegSetValueExA(hKey, lpszValueName, 0, REG_SZ, data.as_ptr(), data.len() as DWORD) as u32 {
+            ERROR_SUCCESS => WFS_SUCCESS,
+            ERROR_FILE_NOT_FOUND => xfs_reject!(WFS_ERR_CFG_INVALID_HKEY),
+            ERROR_ACCESS_DENIED => access_denied(),
+            _ => xfs_reject!(WFS_ERR_INTERNAL_ERROR),
+        }
+    })
+}
+
+/// Shared part of WFMEnumValue and WFMEnumValueEx, the data length is left as RegEnumValueA reports it.
+unsafe fn enum_value(h_key: HKEY, index: DWORD, name: LPSTR, name_len: LPDWORD, value_type: LPDWORD, data: LPSTR, data_len: LPDWORD) -> HRESULT {
+    if name.is_null() || name_len.is_null() || data.is_null() || data_len.is_null() {
         xfs_reject!(WFS_ERR_INVALID_POINTER);
     }
 
-    match RegSetValueExA(hKey, lpszValueName, 0, REG_SZ, lpszData as *mut _, cchData) as u32 {
+    match RegEnumValueA(h_key, index, name, name_len, ptr::null_mut(), value_type, data as *mut _, data_len) as u32 {
         ERROR_SUCCESS => WFS_SUCCESS,
         ERROR_FILE_NOT_FOUND => xfs_reject!(WFS_ERR_CFG_INVALID_HKEY),
+        ERROR_PATH_NOT_FOUND => xfs_reject!(WFS_ERR_CFG_INVALID_SUBKEY),
+        ERROR_MORE_DATA => xfs_reject!(WFS_ERR_CFG_VALUE_TOO_LONG),
+        ERROR_NO_MORE_ITEMS => xfs_reject!(WFS_ERR_CFG_NO_MORE_ITEMS),
         _ => xfs_reject!(WFS_ERR_INTERNAL_ERROR),
     }
 }
 
+/// Rejects a registry write the caller lacks the rights for, typically HKLM\SOFTWARE\XFS without admin rights.
+/// XFS has no error code for it, so it is reported as a key that is not valid for the operation.
+fn access_denied() -> HRESULT {
+    error!("Insufficient privileges for XFS registry");
+    WFS_ERR_CFG_INVALID_HKEY
+}
+
+/// Records a key handed out to the application so WFMCloseKey accepts it exactly once.
+fn issue_key(h_key: HKEY) {
+    match KEYS.lock() {
+        Ok(mut keys) => {
+            keys.insert(h_key as usize);
+        }
+        Err(error) => error!("{error}"),
+    }
+}
+
+/// Maps the XFS configuration roots to the registry key and the path prefix below it, other keys are used as is.
+/// With XFS_REGISTRY_ROOT set, the roots keep their paths but move below the key it names.
+fn resolve_root(h_key: HKEY) -> (HKEY, Cow<'static, [u8]>) {
+    let (root, prefix): (HKEY, &'static [u8]) = match h_key {
+        WFS_CFG_HKEY_XFS_ROOT => (HKEY_CLASSES_ROOT, b"WOSA/XFS_ROOT\\"),
+        WFS_CFG_HKEY_MACHINE_XFS_ROOT => (HKEY_LOCAL_MACHINE, b"SOFTWARE\\XFS\\"),
+        WFS_CFG_HKEY_USER_DEFAULT_XFS_ROOT => (HKEY_USERS, b".DEFAULT\\XFS\\"),
+        _ => return (h_key, Cow::Borrowed(b"")),
+    };
+
+    match registry_root() {
+        Some((base, path)) if path.is_empty() => (base, Cow::Borrowed(prefix)),
+        Some((base, path)) => (base, Cow::Owned([path.as_bytes(), b"\\", prefix].concat())),
+        None => (root, Cow::Borrowed(prefix)),
+    }
+}
+
+/// Reads the XFS_REGISTRY_ROOT override, an invalid one is logged and ignored.
+fn registry_root() -> Option<(HKEY, String)> {
+    let value = std::env::var(REGISTRY_ROOT_ENV).ok()?;
+    let root = parse_registry_root(&value);
+    if root.is_none() {
+        error!("Invalid {REGISTRY_ROOT_ENV} {value:?}, using the default roots");
+    }
+    root
+}
+
+/// Splits a path like `HKCU\Software\XFS_TEST` into the predefined key and the path below it.
+fn parse_registry_root(value: &str) -> Option<(HKEY, String)> {
+    let (hive, path) = value.split_once('\\').unwrap_or((value, ""));
+    let hive = match hive.to_ascii_uppercase().as_str() {
+        "HKCU" | "HKEY_CURRENT_USER" => HKEY_CURRENT_USER,
+        "HKLM" | "HKEY_LOCAL_MACHINE" => HKEY_LOCAL_MACHINE,
+        "HKU" | "HKEY_USERS" => HKEY_USERS,
+        "HKCR" | "HKEY_CLASSES_ROOT" => HKEY_CLASSES_ROOT,
+        _ => return None,
+    };
+    if path.contains('\0') {
+        return None;
+    }
+    Some((hive, path.trim_matches('\\').to_owned()))
+}
+
+/// Joins the prefix and the sub key into a null terminated path and passes it to `f`.
+/// Paths shorter than MAX_PATH are built on the stack, only longer ones allocate.
+fn with_path<R>(prefix: &[u8], sub_key: &CStr, f: impl FnOnce(&CStr) -> R) -> R {
+    let sub_key = sub_key.to_bytes();
+    let len = prefix.len() + sub_key.len();
+
+    let mut stack = [0u8; MAX_PATH];
+    let mut heap = Vec::new();
+    let buffer = if len < MAX_PATH {
+        &mut stack[..=len]
+    } else {
+        heap.resize(len + 1, 0);
+        &mut heap[..]
+    };
+    buffer[..prefix.len()].copy_from_slice(prefix);
+    buffer[prefix.len()..len].copy_from_slice(sub_key);
+    buffer[len] = 0;
+
+    // SAFETY: the prefixes and the CStr contain no null, so the only one is the terminator written above
+    f(unsafe { CStr::from_bytes_with_nul_unchecked(buffer) })
+}
+
 #[allow(non_snake_case)]
 #[no_mangle]
 pub extern "stdcall" fn DllMain(hinst_dll: HINSTANCE, fdw_reason: DWORD, _: LPVOID) -> bool {
@@ -231,7 +404,35 @@ pub extern "stdcall" fn DllMain(hinst_dll: HINSTANCE, fdw_reason: DWORD, _: LPVO
 #[cfg(test)]
 mod tests {
     use super::*;
-    use winapi::shared::minwindef::MAX_PATH;
+    use std::ffi::CString;
+
+    #[test]
+    fn test_with_path() {
+        let sub_keys = ["", "LOGICAL_SERVICES\\cwd", &"x".repeat(MAX_PATH), &"y".repeat(MAX_PATH * 2)];
+        let roots = [WFS_CFG_HKEY_XFS_ROOT, WFS_CFG_HKEY_MACHINE_XFS_ROOT, WFS_CFG_HKEY_USER_DEFAULT_XFS_ROOT, HKEY_LOCAL_MACHINE];
+
+        for root in roots {
+            let (_, prefix) = resolve_root(root);
+            for sub_key in sub_keys {
+                let expected = format!("{}{}", std::str::from_utf8(&prefix).unwrap(), sub_key);
+                let sub_key = CString::new(sub_key).unwrap();
+                with_path(&prefix, &sub_key, |path| assert_eq!(path.to_str().unwrap(), expected));
+            }
+        }
+        assert_eq!(resolve_root(WFS_CFG_HKEY_USER_DEFAULT_XFS_ROOT), (HKEY_USERS, Cow::Borrowed(&b".DEFAULT\\XFS\\"[..])));
+    }
+
+    #[test]
+    fn test_parse_registry_root() {
+        assert_eq!(parse_registry_root("HKCU\\Software\\XFS_TEST\\"), Some((HKEY_CURRENT_USER, "Software\\XFS_TEST".to_string())));
+        assert_eq!(
+            parse_registry_root("hkey_local_machine\\SOFTWARE\\Sandbox"),
+            Some((HKEY_LOCAL_MACHINE, "SOFTWARE\\Sandbox".to_string()))
+        );
+        assert_eq!(parse_registry_root("HKU"), Some((HKEY_USERS, String::new())));
+        assert_eq!(parse_registry_root("HKCC\\Software"), None);
+        assert_eq!(parse_registry_root(""), None);
+    }
 
     #[test]
     fn test_open_key() {
@@ -244,6 +445,23 @@ mod tests {
         assert_eq!(result, WFS_SUCCESS);
     }
 
+    #[test]
+    fn test_close_key_twice() {
+        let mut key: HKEY = ptr::null_mut();
+        let path = CString::new("LOGICAL_SERVICES\\cwd").unwrap();
+        let result = unsafe { WFMOpenKey(WFS_CFG_HKEY_USER_DEFAULT_XFS_ROOT, path.as_ptr() as *mut i8, &mut key) };
+        assert_eq!(result, WFS_SUCCESS);
+
+        assert_eq!(unsafe { WFMCloseKey(key) }, WFS_SUCCESS);
+        assert_eq!(unsafe { WFMCloseKey(key) }, WFS_ERR_CFG_INVALID_HKEY);
+    }
+
+    #[test]
+    fn test_close_key_never_opened() {
+        assert_eq!(unsafe { WFMCloseKey(0x1234 as HKEY) }, WFS_ERR_CFG_INVALID_HKEY);
+        assert_eq!(unsafe { WFMCloseKey(WFS_CFG_HKEY_XFS_ROOT) }, WFS_ERR_CFG_INVALID_HKEY);
+    }
+
     #[test]
     fn test_open_key_fail() {
         let mut key: HKEY = ptr::null_mut();
@@ -292,6 +510,55 @@ mod tests {
         assert_eq!(result, WFS_SUCCESS);
     }
 
+    fn set_and_query(data: &[u8], cch_data: DWORD) -> (HRESULT, Vec<u8>) {
+        let mut key: HKEY = ptr::null_mut();
+        let path = CString::new("LOGICAL_SERVICES\\cwd").unwrap();
+        let name = CString::new("test_set_value").unwrap();
+        let mut buffer = [0u8; MAX_PATH];
+        let len = &mut (MAX_PATH as u32);
+
+        unsafe {
+            assert_eq!(WFMOpenKey(WFS_CFG_HKEY_USER_DEFAULT_XFS_ROOT, path.as_ptr() as *mut i8, &mut key), WFS_SUCCESS);
+            let result = WFMSetValue(key, name.as_ptr() as *mut _, data.as_ptr() as *mut _, cch_data);
+            if result == WFS_SUCCESS {
+                assert_eq!(WFMQueryValue(key, name.as_ptr() as *mut _, buffer.as_mut_ptr() as *mut _, len), WFS_SUCCESS);
+                assert_eq!(WFMDeleteValue(key, name.as_ptr() as *mut _), WFS_SUCCESS);
+            }
+            assert_eq!(WFMCloseKey(key), WFS_SUCCESS);
+            (result, buffer[..*len as usize].to_vec())
+        }
+    }
+
+    #[test]
+    fn test_set_value() {
+        assert_eq!(set_and_query(b"value\0", 6), (WFS_SUCCESS, b"value".to_vec()));
+        assert_eq!(set_and_query(b"value", 5), (WFS_SUCCESS, b"value".to_vec()));
+    }
+
+    #[test]
+    fn test_set_value_empty() {
+        assert_eq!(set_and_query(b"\0", 0), (WFS_SUCCESS, b"".to_vec()));
+    }
+
+    #[test]
+    fn test_set_value_padded() {
+        // a fixed-size buffer passed with its size
+        assert_eq!(set_and_query(b"value\0\0\0\0\0", 10), (WFS_SUCCESS, b"value".to_vec()));
+        assert_eq!(set_and_query(&[0; 8], 8), (WFS_SUCCESS, b"".to_vec()));
+    }
+
+    #[test]
+    fn test_set_value_embedded_null() {
+        assert_eq!(set_and_query(b"val\0ue", 6).0, WFS_ERR_INVALID_DATA);
+        assert_eq!(set_and_query(b"value\0\0\0x\0", 10).0, WFS_ERR_INVALID_DATA);
+    }
+
+    #[test]
+    fn test_set_value_stale_data() {
+        // a reused buffer still holding the tail of a longer string
+        assert_eq!(set_and_query(b"value\0der\0", 10).0, WFS_ERR_INVALID_DATA);
+    }
+
     // #[test]
     // fn test_create_delete() {
     //     let mut key: HKEY = ptr::null_mut();
@@ -0,0 +1,252 @@
+//! Runs the configuration API against a private copy of the XFS roots below HKEY_CURRENT_USER.
+//!
+//! XFS_REGISTRY_ROOT is process wide, so this lives in its own test binary rather than next to the unit tests
+//! that use the real roots. It needs no admin rights, only `xfs_conf.dll` on the DLL search path.
+#![cfg(windows)]
+
+use std::{ffi::CString, ptr};
+
+use libloading::{Library, Symbol};
+use winapi::{
+    shared::{
+        minwindef::{DWORD, HKEY, LPDWORD, MAX_PATH, PHKEY},
+        sddl::{ConvertStringSecurityDescriptorToSecurityDescriptorA, SDDL_REVISION_1},
+        winerror::{ERROR_SUCCESS, HRESULT},
+    },
+    um::{
+        winbase::LocalFree,
+        winnt::{DACL_SECURITY_INFORMATION, LPSTR, PSECURITY_DESCRIPTOR, REG_DWORD, REG_SZ, WRITE_DAC},
+        winreg::{RegCloseKey, RegOpenKeyExA, RegSetKeySecurity, HKEY_CURRENT_USER},
+    },
+};
+use xfslib::{
+    sandbox::{Sandbox, LOGICAL_SERVICES},
+    *,
+};
+
+/// Creates the registry sandbox with the `sandbox` logical service mapped to `sandbox.dll`.
+fn sandbox() -> Sandbox {
+    let sandbox = Sandbox::new("registry_root");
+    sandbox.map_service("sandbox", "sandbox_provider", Some("sandbox.dll"));
+    sandbox
+}
+
+/// Denies everyone writing values and creating subkeys below a sandbox key, and lifts that again on drop.
+struct WriteLock(String);
+
+impl WriteLock {
+    fn new(path: &str) -> Self {
+        // KEY_SET_VALUE | KEY_CREATE_SUB_KEY
+        set_security(path, "D:(D;;0x6;;;WD)(A;;KA;;;WD)");
+        WriteLock(path.to_string())
+    }
+}
+
+impl Drop for WriteLock {
+    fn drop(&mut self) {
+        set_security(&self.0, "D:(A;;KA;;;WD)");
+    }
+}
+
+fn set_security(path: &str, sddl: &str) {
+    let path = CString::new(path).unwrap();
+    let sddl = CString::new(sddl).unwrap();
+    let mut key: HKEY = ptr::null_mut();
+    let mut descriptor: PSECURITY_DESCRIPTOR = ptr::null_mut();
+
+    unsafe {
+        assert_ne!(
+            ConvertStringSecurityDescriptorToSecurityDescriptorA(sddl.as_ptr(), SDDL_REVISION_1 as DWORD, &mut descriptor, ptr::null_mut()),
+            0
+        );
+        assert_eq!(RegOpenKeyExA(HKEY_CURRENT_USER, path.as_ptr(), 0, WRITE_DAC, &mut key) as u32, ERROR_SUCCESS);
+        assert_eq!(RegSetKeySecurity(key, DACL_SECURITY_INFORMATION, descriptor) as u32, ERROR_SUCCESS);
+        RegCloseKey(key);
+        LocalFree(descriptor);
+    }
+}
+
+#[test]
+fn test_open_query_below_registry_root() {
+    let _sandbox = sandbox();
+
+    unsafe {
+        let lib = Library::new("xfs_conf.dll").unwrap();
+        let open_key: Symbol<unsafe extern "stdcall" fn(HKEY, LPSTR, PHKEY) -> HRESULT> = lib.get(b"WFMOpenKey").unwrap();
+        let query_value: Symbol<unsafe extern "stdcall" fn(HKEY, LPSTR, LPSTR, LPDWORD) -> HRESULT> = lib.get(b"WFMQueryValue").unwrap();
+        let close_key: Symbol<unsafe extern "stdcall" fn(HKEY) -> HRESULT> = lib.get(b"WFMCloseKey").unwrap();
+
+        let query = |root: HKEY, path: &str, name: &str| {
+            let path = CString::new(path).unwrap();
+            let name = CString::new(name).unwrap();
+            let mut key: HKEY = ptr::null_mut();
+            assert_eq!(open_key(root, path.as_ptr() as LPSTR, &mut key), WFS_SUCCESS);
+
+            let mut value = [0u8; MAX_PATH];
+            let mut len = MAX_PATH as DWORD;
+            assert_eq!(query_value(key, name.as_ptr() as LPSTR, value.as_mut_ptr() as LPSTR, &mut len), WFS_SUCCESS);
+            assert_eq!(close_key(key), WFS_SUCCESS);
+            String::from_utf8(value[..len as usize].to_vec()).unwrap()
+        };
+
+        assert_eq!(query(WFS_CFG_HKEY_USER_DEFAULT_XFS_ROOT, "LOGICAL_SERVICES\\sandbox", "provider"), "sandbox_provider");
+        assert_eq!(query(WFS_CFG_HKEY_MACHINE_XFS_ROOT, "SERVICE_PROVIDERS\\sandbox_provider", "dllname"), "sandbox.dll");
+
+        // the real roots are not consulted while the override is set
+        let path = CString::new("LOGICAL_SERVICES\\cwd").unwrap();
+        let mut key: HKEY = ptr::null_mut();
+        assert_eq!(open_key(WFS_CFG_HKEY_USER_DEFAULT_XFS_ROOT, path.as_ptr() as LPSTR, &mut key), WFS_ERR_CFG_INVALID_HKEY);
+    }
+}
+
+#[test]
+fn test_denied_write() {
+    let sandbox = sandbox();
+    let _lock = WriteLock::new(&sandbox.path(&format!("{LOGICAL_SERVICES}\\sandbox")));
+
+    unsafe {
+        let lib = Library::new("xfs_conf.dll").unwrap();
+        let open_key: Symbol<unsafe extern "stdcall" fn(HKEY, LPSTR, PHKEY) -> HRESULT> = lib.get(b"WFMOpenKey").unwrap();
+        let create_key: Symbol<unsafe extern "stdcall" fn(HKEY, LPSTR, PHKEY, LPDWORD) -> HRESULT> = lib.get(b"WFMCreateKey").unwrap();
+        let set_value: Symbol<unsafe extern "stdcall" fn(HKEY, LPSTR, LPSTR, DWORD) -> HRESULT> = lib.get(b"WFMSetValue").unwrap();
+        let delete_value: Symbol<unsafe extern "stdcall" fn(HKEY, LPSTR) -> HRESULT> = lib.get(b"WFMDeleteValue").unwrap();
+        let close_key: Symbol<unsafe extern "stdcall" fn(HKEY) -> HRESULT> = lib.get(b"WFMCloseKey").unwrap();
+
+        // a permissions problem is reported as such, not as an internal error
+        let child = CString::new("LOGICAL_SERVICES\\sandbox\\child").unwrap();
+        let mut key: HKEY = ptr::null_mut();
+        let mut disposition: DWORD = 0;
+        assert_eq!(
+            create_key(WFS_CFG_HKEY_USER_DEFAULT_XFS_ROOT, child.as_ptr() as LPSTR, &mut key, &mut disposition),
+            WFS_ERR_CFG_INVALID_HKEY
+        );
+
+        let path = CString::new("LOGICAL_SERVICES\\sandbox").unwrap();
+        let name = CString::new("provider").unwrap();
+        let data = CString::new("other_provider").unwrap();
+        assert_eq!(open_key(WFS_CFG_HKEY_USER_DEFAULT_XFS_ROOT, path.as_ptr() as LPSTR, &mut key), WFS_SUCCESS);
+        assert_eq!(set_value(key, name.as_ptr() as LPSTR, data.as_ptr() as LPSTR, data.as_bytes().len() as DWORD), WFS_ERR_CFG_INVALID_HKEY);
+        assert_eq!(delete_value(key, name.as_ptr() as LPSTR), WFS_ERR_CFG_INVALID_HKEY);
+        assert_eq!(close_key(key), WFS_SUCCESS);
+    }
+}
+
+#[test]
+fn test_enum_value_types() {
+    let sandbox = sandbox();
+    sandbox.set_raw_value(&format!("{LOGICAL_SERVICES}\\sandbox"), "timeout", REG_DWORD, &30u32.to_le_bytes());
+
+    unsafe {
+        let lib = Library::new("xfs_conf.dll").unwrap();
+        let open_key: Symbol<unsafe extern "stdcall" fn(HKEY, LPSTR, PHKEY) -> HRESULT> = lib.get(b"WFMOpenKey").unwrap();
+        let enum_value_ex: Symbol<unsafe extern "stdcall" fn(HKEY, DWORD, LPSTR, LPDWORD, LPDWORD, LPSTR, LPDWORD) -> HRESULT> = lib.get(b"WFMEnumValueEx").unwrap();
+        let close_key: Symbol<unsafe extern "stdcall" fn(HKEY) -> HRESULT> = lib.get(b"WFMCloseKey").unwrap();
+
+        let path = CString::new("LOGICAL_SERVICES\\sandbox").unwrap();
+        let mut key: HKEY = ptr::null_mut();
+        assert_eq!(open_key(WFS_CFG_HKEY_USER_DEFAULT_XFS_ROOT, path.as_ptr() as LPSTR, &mut key), WFS_SUCCESS);
+
+        let mut values = Vec::new();
+        for index in 0.. {
+            let mut name = [0u8; MAX_PATH];
+            let mut name_len = MAX_PATH as DWORD;
+            let mut data = [0u8; MAX_PATH];
+            let mut data_len = MAX_PATH as DWORD;
+            let mut value_type: DWORD = 0;
+            match enum_value_ex(key, index, name.as_mut_ptr() as LPSTR, &mut name_len, &mut value_type, data.as_mut_ptr() as LPSTR, &mut data_len) {
+                WFS_SUCCESS => values.push((String::from_utf8(name[..name_len as usize].to_vec()).unwrap(), value_type, data[..data_len as usize].to_vec())),
+                WFS_ERR_CFG_NO_MORE_ITEMS => break,
+                error => panic!("WFMEnumValueEx failed: {error}"),
+            }
+        }
+        values.sort();
+        assert_eq!(
+            values,
+            vec![
+                ("provider".to_string(), REG_SZ, b"sandbox_provider".to_vec()),
+                ("timeout".to_string(), REG_DWORD, 30u32.to_le_bytes().to_vec()),
+            ]
+        );
+
+        assert_eq!(close_key(key), WFS_SUCCESS);
+    }
+}
+
+#[test]
+fn test_set_query_round_trip() {
+    let _sandbox = sandbox();
+
+    unsafe {
+        let lib = Library::new("xfs_conf.dll").unwrap();
+        let open_key: Symbol<unsafe extern "stdcall" fn(HKEY, LPSTR, PHKEY) -> HRESULT> = lib.get(b"WFMOpenKey").unwrap();
+        let set_value: Symbol<unsafe extern "stdcall" fn(HKEY, LPSTR, LPSTR, DWORD) -> HRESULT> = lib.get(b"WFMSetValue").unwrap();
+        let query_value: Symbol<unsafe extern "stdcall" fn(HKEY, LPSTR, LPSTR, LPDWORD) -> HRESULT> = lib.get(b"WFMQueryValue").unwrap();
+        let close_key: Symbol<unsafe extern "stdcall" fn(HKEY) -> HRESULT> = lib.get(b"WFMCloseKey").unwrap();
+
+        let path = CString::new("LOGICAL_SERVICES\\sandbox").unwrap();
+        let name = CString::new("round_trip").unwrap();
+        let mut key: HKEY = ptr::null_mut();
+        assert_eq!(open_key(WFS_CFG_HKEY_USER_DEFAULT_XFS_ROOT, path.as_ptr() as LPSTR, &mut key), WFS_SUCCESS);
+
+        // the length read back is the string length whether or not the terminating null was counted on write
+        for (data, cch) in [(&b"xfs_mock\0"[..], 8), (b"xfs_mock\0", 9), (b"\0", 0), (b"\0", 1)] {
+            let expected = &data[..data.len() - 1];
+            assert_eq!(set_value(key, name.as_ptr() as LPSTR, data.as_ptr() as LPSTR, cch), WFS_SUCCESS);
+
+            let mut value = [0xFFu8; MAX_PATH];
+            let mut len = MAX_PATH as DWORD;
+            assert_eq!(query_value(key, name.as_ptr() as LPSTR, value.as_mut_ptr() as LPSTR, &mut len), WFS_SUCCESS);
+            assert_eq!(len as usize, expected.len());
+            assert_eq!(&value[..=expected.len()], data);
+        }
+
+        // the buffer must have room for the terminating null
+        assert_eq!(set_value(key, name.as_ptr() as LPSTR, b"xfs_mock\0".as_ptr() as LPSTR, 8), WFS_SUCCESS);
+        let mut value = [0u8; 8];
+        let mut len = 8;
+        assert_eq!(query_value(key, name.as_ptr() as LPSTR, value.as_mut_ptr() as LPSTR, &mut len), WFS_ERR_CFG_VALUE_TOO_LONG);
+
+        assert_eq!(close_key(key), WFS_SUCCESS);
+    }
+}
+
+#[test]
+fn test_create_volatile_key() {
+    let _sandbox = sandbox();
+
+    unsafe {
+        let lib = Library::new("xfs_conf.dll").unwrap();
+        let open_key: Symbol<unsafe extern "stdcall" fn(HKEY, LPSTR, PHKEY) -> HRESULT> = lib.get(b"WFMOpenKey").unwrap();
+        let create_key: Symbol<unsafe extern "stdcall" fn(HKEY, LPSTR, PHKEY, LPDWORD) -> HRESULT> = lib.get(b"WFMCreateKey").unwrap();
+        let create_volatile_key: Symbol<unsafe extern "stdcall" fn(HKEY, LPSTR, PHKEY, LPDWORD) -> HRESULT> = lib.get(b"WFMCreateVolatileKey").unwrap();
+        let delete_key: Symbol<unsafe extern "stdcall" fn(HKEY, LPSTR) -> HRESULT> = lib.get(b"WFMDeleteKey").unwrap();
+        let close_key: Symbol<unsafe extern "stdcall" fn(HKEY) -> HRESULT> = lib.get(b"WFMCloseKey").unwrap();
+
+        let path = CString::new("LOGICAL_SERVICES\\sandbox").unwrap();
+        let mut parent: HKEY = ptr::null_mut();
+        assert_eq!(open_key(WFS_CFG_HKEY_USER_DEFAULT_XFS_ROOT, path.as_ptr() as LPSTR, &mut parent), WFS_SUCCESS);
+
+        let create = |create_key: &Symbol<unsafe extern "stdcall" fn(HKEY, LPSTR, PHKEY, LPDWORD) -> HRESULT>, name: &str| -> Result<DWORD, HRESULT> {
+            let name = CString::new(name).unwrap();
+            let mut key: HKEY = ptr::null_mut();
+            let mut disposition: DWORD = DWORD::MAX;
+            create_key(parent, name.as_ptr() as LPSTR, &mut key, &mut disposition).ok()?;
+            assert_eq!(close_key(key), WFS_SUCCESS);
+            Ok(disposition)
+        };
+
+        assert_eq!(create(&create_volatile_key, "volatile"), Ok(WFS_CFG_CREATED_NEW_KEY));
+        assert_eq!(create(&create_volatile_key, "volatile"), Ok(WFS_CFG_OPENED_EXISTING_KEY));
+        assert_eq!(create(&create_key, "volatile"), Ok(WFS_CFG_OPENED_EXISTING_KEY));
+
+        // only volatile keys go below a volatile one
+        assert_eq!(create(&create_key, "volatile\\persistent"), Err(WFS_ERR_CFG_INVALID_SUBKEY));
+        assert_eq!(create(&create_volatile_key, "volatile\\volatile"), Ok(WFS_CFG_CREATED_NEW_KEY));
+
+        for name in ["volatile\\volatile", "volatile"] {
+            let name = CString::new(name).unwrap();
+            assert_eq!(delete_key(parent, name.as_ptr() as LPSTR), WFS_SUCCESS);
+        }
+        assert_eq!(close_key(parent), WFS_SUCCESS);
+    }
+}
@@ -302,7 +302,7 @@ pub extern "stdcall" fn DllMain(_hinst_dll: HINSTANCE, fdw_reason: DWORD, _: LPV
             .build(Root::builder().appender("logfile").build(LevelFilter::Trace))
             .unwrap();
 
-        log4rs::init_config(config).unwrap();
+        init_logger(config);
         trace!("XFS CONF DLL INIT");
     }
     true
@@ -0,0 +1,455 @@
+//! Conformance checks of a logical service, for vendors certifying their service provider.
+//!
+//! The suite drives the service through the manager the way an application would: start up with version
+//! negotiation, opens with the boundary versions the service reports, WFSGetInfo of the categories its class makes
+//! mandatory, lock ownership across two sessions, cancel of a request in flight and a clean close. Every check keeps
+//! the HRESULT of each call it made, [`to_json`] renders the report for tooling.
+
+use std::{
+    ffi::CString,
+    fmt::Write,
+    mem, ptr, thread,
+    time::{Duration, Instant},
+};
+
+use libloading::Library;
+use winapi::{
+    shared::{
+        minwindef::{DWORD, LPVOID},
+        windef::HWND,
+        winerror::HRESULT,
+    },
+    um::winnt::LPSTR,
+};
+use xfslib::*;
+
+/// Interval in which the cancel check looks for the completion.
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// Versions of the XFS API and the services the suite asks for.
+const REQUESTED_VERSIONS: VersionRange = VersionRange::new_explicit(Version::new_explicit(3, 0), Version::new_explicit(3, 30));
+
+/// Versions no manager supports, to see the version negotiation fail.
+const UNSUPPORTED_VERSIONS: VersionRange = VersionRange::new_explicit(Version::new_explicit(99, 0), Version::new_explicit(99, 99));
+
+type WfsStartUp = unsafe extern "stdcall" fn(DWORD, LPWFSVERSION) -> HRESULT;
+type WfsCleanUp = unsafe extern "stdcall" fn() -> HRESULT;
+type WfsOpen = unsafe extern "stdcall" fn(LPSTR, HAPP, LPSTR, DWORD, DWORD, DWORD, LPWFSVERSION, LPWFSVERSION, LPHSERVICE) -> HRESULT;
+type WfsClose = unsafe extern "stdcall" fn(HSERVICE) -> HRESULT;
+type WfsGetInfo = unsafe extern "stdcall" fn(HSERVICE, DWORD, LPVOID, DWORD, *mut LPWFSRESULT) -> HRESULT;
+type WfsLock = unsafe extern "stdcall" fn(HSERVICE, DWORD, *mut LPWFSRESULT) -> HRESULT;
+type WfsUnlock = unsafe extern "stdcall" fn(HSERVICE) -> HRESULT;
+type WfsAsyncExecute = unsafe extern "stdcall" fn(HSERVICE, DWORD, LPVOID, DWORD, HWND, LPREQUESTID) -> HRESULT;
+type WfsCancelAsyncRequest = unsafe extern "stdcall" fn(HSERVICE, REQUESTID) -> HRESULT;
+type WfsFreeResult = unsafe extern "stdcall" fn(LPWFSRESULT) -> HRESULT;
+
+/// Logical service the suite is run against and what it needs to know about its class.
+#[derive(Clone, Debug)]
+pub struct Target {
+    /// Logical service to check.
+    pub logical_name: String,
+    /// Application id passed to WFSOpen.
+    pub app_id: String,
+    /// Categories WFSGetInfo must answer, typically the status and capabilities categories of the service class.
+    pub categories: Vec<DWORD>,
+    /// Command the service keeps in flight long enough to be cancelled, the cancel check is skipped without one.
+    pub cancel_command: Option<DWORD>,
+    /// Time each call gets, the cancelled request included.
+    pub timeout: Duration,
+}
+
+impl Target {
+    pub fn new(logical_name: &str) -> Self {
+        Target {
+            logical_name: logical_name.to_string(),
+            app_id: "xfs_conformance".to_string(),
+            categories: Vec::new(),
+            cancel_command: None,
+            timeout: Duration::from_secs(10),
+        }
+    }
+
+    fn timeout_ms(&self) -> DWORD {
+        self.timeout.as_millis().clamp(1, DWORD::MAX as u128) as DWORD
+    }
+}
+
+/// Outcome of a check.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Status {
+    Passed,
+    Failed,
+    /// The target lacks what the check needs, or an earlier check it depends on failed.
+    Skipped,
+}
+
+impl Status {
+    fn name(self) -> &'static str {
+        match self {
+            Status::Passed => "passed",
+            Status::Failed => "failed",
+            Status::Skipped => "skipped",
+        }
+    }
+}
+
+/// One call a check made and what it returned.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Call {
+    pub function: String,
+    pub h_result: HRESULT,
+}
+
+/// Outcome of one check with the calls it made, `detail` tells why it did not pass.
+#[derive(Clone, Debug)]
+pub struct Check {
+    pub name: &'static str,
+    pub status: Status,
+    pub calls: Vec<Call>,
+    pub detail: String,
+}
+
+impl Check {
+    fn new(name: &'static str) -> Self {
+        Check {
+            name,
+            status: Status::Passed,
+            calls: Vec::new(),
+            detail: String::new(),
+        }
+    }
+
+    fn skipped(name: &'static str, detail: &str) -> Self {
+        Check {
+            status: Status::Skipped,
+            detail: detail.to_string(),
+            ..Check::new(name)
+        }
+    }
+
+    /// Records a call, failing the check unless it returned `expected`.
+    fn expect(&mut self, function: impl Into<String>, h_result: HRESULT, expected: HRESULT) -> bool {
+        let function = function.into();
+        let passed = h_result == expected;
+        if !passed {
+            self.fail(format!("{function} returned {h_result}, expected {expected}"));
+        }
+        self.calls.push(Call { function, h_result });
+        passed
+    }
+
+    /// Fails the check, the first failure is the one reported.
+    fn fail(&mut self, detail: String) {
+        if self.status != Status::Failed {
+            self.status = Status::Failed;
+            self.detail = detail;
+        }
+    }
+}
+
+/// Manager functions the suite calls.
+#[derive(Clone, Copy)]
+struct Api {
+    start_up: WfsStartUp,
+    clean_up: WfsCleanUp,
+    open: WfsOpen,
+    close: WfsClose,
+    get_info: WfsGetInfo,
+    lock: WfsLock,
+    unlock: WfsUnlock,
+    async_execute: WfsAsyncExecute,
+    cancel: WfsCancelAsyncRequest,
+    free_result: WfsFreeResult,
+}
+
+impl Api {
+    /// Looks up the functions, the manager must stay loaded as long as they are used.
+    unsafe fn load(manager: &Library) -> Result<Self, libloading::Error> {
+        Ok(Api {
+            start_up: *manager.get(b"WFSStartUp")?,
+            clean_up: *manager.get(b"WFSCleanUp")?,
+            open: *manager.get(b"WFSOpen")?,
+            close: *manager.get(b"WFSClose")?,
+            get_info: *manager.get(b"WFSGetInfo")?,
+            lock: *manager.get(b"WFSLock")?,
+            unlock: *manager.get(b"WFSUnlock")?,
+            async_execute: *manager.get(b"WFSAsyncExecute")?,
+            cancel: *manager.get(b"WFSCancelAsyncRequest")?,
+            free_result: *manager.get(b"WFSFreeResult")?,
+        })
+    }
+
+    /// Opens the target asking for the service versions `versions`, recording the call in the check. Returns the
+    /// service and the versions it reported if the open succeeded.
+    unsafe fn open(&self, target: &Target, versions: VersionRange, check: &mut Check) -> Option<(Session, WFSVERSION)> {
+        let logical_name = CString::new(target.logical_name.as_str()).unwrap_or_default();
+        let app_id = CString::new(target.app_id.as_str()).unwrap_or_default();
+        let mut srvc_version = mem::zeroed::<WFSVERSION>();
+        let mut spi_version = mem::zeroed::<WFSVERSION>();
+        let mut service: HSERVICE = 0;
+        let h_result = (self.open)(
+            logical_name.as_ptr() as LPSTR,
+            ptr::null_mut(),
+            app_id.as_ptr() as LPSTR,
+            0,
+            target.timeout_ms(),
+            versions.value(),
+            &mut srvc_version,
+            &mut spi_version,
+            &mut service,
+        );
+        let function = format!("WFSOpen {} to {}", version_name(versions.start), version_name(versions.end));
+        check.expect(function, h_result, WFS_SUCCESS).then_some((Session { api: *self, service }, srvc_version))
+    }
+}
+
+/// Open service, closed on drop unless the check closed it itself.
+struct Session {
+    api: Api,
+    service: HSERVICE,
+}
+
+impl Session {
+    /// Closes the service, recording the call in the check.
+    fn close(mut self, check: &mut Check) -> bool {
+        let service = mem::replace(&mut self.service, 0);
+        // SAFETY: the manager stays loaded while the suite runs
+        check.expect("WFSClose", unsafe { (self.api.close)(service) }, WFS_SUCCESS)
+    }
+}
+
+impl Drop for Session {
+    fn drop(&mut self) {
+        if self.service != 0 {
+            // SAFETY: the manager stays loaded while the suite runs
+            unsafe { (self.api.close)(self.service) };
+        }
+    }
+}
+
+/// Runs the suite against the target through the manager library, normally `msxfs.dll`.
+pub fn run(manager: &Library, target: &Target) -> Result<Vec<Check>, libloading::Error> {
+    // SAFETY: the manager stays loaded until the function returns
+    let api = unsafe { Api::load(manager)? };
+    let started = unsafe { start_up(&api) };
+    if started.status != Status::Passed {
+        let skipped = ["open_versions", "get_info", "lock_ownership", "cancel", "clean_close"].map(|name| Check::skipped(name, "WFSStartUp failed"));
+        return Ok([started].into_iter().chain(skipped).collect());
+    }
+    // SAFETY: as above, the checks close every service they open
+    unsafe {
+        Ok(vec![
+            started,
+            open_versions(&api, target),
+            get_info(&api, target),
+            lock_ownership(&api, target),
+            cancel(&api, target),
+            clean_close(&api, target),
+        ])
+    }
+}
+
+/// WFSStartUp rejects versions it does not support and negotiates one within the requested range.
+unsafe fn start_up(api: &Api) -> Check {
+    let mut check = Check::new("start_up");
+    let mut version = mem::zeroed::<WFSVERSION>();
+    check.expect("WFSStartUp 99.00 to 99.99", (api.start_up)(UNSUPPORTED_VERSIONS.value(), &mut version), WFS_ERR_API_VER_TOO_HIGH);
+    if !check.expect("WFSStartUp 3.00 to 3.30", (api.start_up)(REQUESTED_VERSIONS.value(), &mut version), WFS_SUCCESS) {
+        return check;
+    }
+    let negotiated = Version::new(version.w_version);
+    if negotiated < REQUESTED_VERSIONS.start || negotiated > REQUESTED_VERSIONS.end {
+        check.fail(format!("WFSStartUp negotiated version {} outside of the requested range", version_name(negotiated)));
+    }
+    check
+}
+
+/// The service opens with the lowest and the highest service version it reports to support.
+unsafe fn open_versions(api: &Api, target: &Target) -> Check {
+    let mut check = Check::new("open_versions");
+    let Some((session, srvc_version)) = api.open(target, REQUESTED_VERSIONS, &mut check) else {
+        return check;
+    };
+    session.close(&mut check);
+
+    let (low, high) = (Version::new(srvc_version.w_low_version), Version::new(srvc_version.w_high_version));
+    if low > high {
+        check.fail(format!("The service reported the empty version range {} to {}", version_name(low), version_name(high)));
+        return check;
+    }
+    for bound in [low, high] {
+        if let Some((session, _)) = api.open(target, VersionRange::new_explicit(bound, bound), &mut check) {
+            session.close(&mut check);
+        }
+    }
+    check
+}
+
+/// WFSGetInfo answers every mandatory category.
+unsafe fn get_info(api: &Api, target: &Target) -> Check {
+    if target.categories.is_empty() {
+        return Check::skipped("get_info", "No categories given");
+    }
+    let mut check = Check::new("get_info");
+    let Some((session, _)) = api.open(target, REQUESTED_VERSIONS, &mut check) else {
+        return check;
+    };
+    for &category in &target.categories {
+        let mut result: LPWFSRESULT = ptr::null_mut();
+        let h_result = (api.get_info)(session.service, category, ptr::null_mut(), target.timeout_ms(), &mut result);
+        check.expect(format!("WFSGetInfo {category}"), h_result, WFS_SUCCESS);
+        if !result.is_null() {
+            (api.free_result)(result);
+        }
+    }
+    session.close(&mut check);
+    check
+}
+
+/// The lock belongs to the session that took it: it unlocks and locks again, and closing it releases the lock for
+/// the next session.
+unsafe fn lock_ownership(api: &Api, target: &Target) -> Check {
+    let mut check = Check::new("lock_ownership");
+    let Some((owner, _)) = api.open(target, REQUESTED_VERSIONS, &mut check) else {
+        return check;
+    };
+    let Some((other, _)) = api.open(target, REQUESTED_VERSIONS, &mut check) else {
+        return check;
+    };
+    let timeout = target.timeout_ms();
+    check.expect("WFSLock", (api.lock)(owner.service, timeout, ptr::null_mut()), WFS_SUCCESS);
+    check.expect("WFSUnlock", (api.unlock)(owner.service), WFS_SUCCESS);
+    check.expect("WFSLock", (api.lock)(owner.service, timeout, ptr::null_mut()), WFS_SUCCESS);
+    owner.close(&mut check);
+    check.expect("WFSLock after the owner closed", (api.lock)(other.service, timeout, ptr::null_mut()), WFS_SUCCESS);
+    check.expect("WFSUnlock", (api.unlock)(other.service), WFS_SUCCESS);
+    other.close(&mut check);
+    check
+}
+
+/// A request in flight completes with WFS_ERR_CANCELED once it is cancelled.
+unsafe fn cancel(api: &Api, target: &Target) -> Check {
+    let Some(command) = target.cancel_command else {
+        return Check::skipped("cancel", "No cancel command given");
+    };
+    let mut check = Check::new("cancel");
+    let Some((session, _)) = api.open(target, REQUESTED_VERSIONS, &mut check) else {
+        return check;
+    };
+    let window = SyncWindow::new(WFS_EXECUTE_COMPLETE);
+    let mut request_id = 0;
+    let h_result = (api.async_execute)(session.service, command, ptr::null_mut(), WFS_INDEFINITE_WAIT, window.handle(), &mut request_id);
+    if !check.expect(format!("WFSAsyncExecute {command}"), h_result, WFS_SUCCESS) {
+        return check;
+    }
+    check.expect("WFSCancelAsyncRequest", (api.cancel)(session.service, request_id), WFS_SUCCESS);
+
+    let deadline = Instant::now() + target.timeout;
+    let result = loop {
+        match window.try_receive() {
+            Ok(Some(result)) => break result as LPWFSRESULT,
+            Ok(None) if Instant::now() < deadline => thread::sleep(POLL_INTERVAL),
+            _ => {
+                check.fail(format!("The cancelled request did not complete within {:?}", target.timeout));
+                return check;
+            }
+        }
+    };
+    check.expect("WFS_EXECUTE_COMPLETE", ptr::addr_of!((*result).hResult).read_unaligned(), WFS_ERR_CANCELED);
+    (api.free_result)(result);
+    session.close(&mut check);
+    check
+}
+
+/// A closed service is gone for good and the manager cleans up once every service is closed.
+unsafe fn clean_close(api: &Api, target: &Target) -> Check {
+    let mut check = Check::new("clean_close");
+    if let Some((session, _)) = api.open(target, REQUESTED_VERSIONS, &mut check) {
+        let service = session.service;
+        session.close(&mut check);
+        check.expect("WFSClose of the closed service", (api.close)(service), WFS_ERR_INVALID_HSERVICE);
+    }
+    check.expect("WFSCleanUp", (api.clean_up)(), WFS_SUCCESS);
+    check
+}
+
+fn version_name(version: Version) -> String {
+    format!("{}.{:02}", version.major, version.minor)
+}
+
+/// Whether no check failed, skipped ones do not count.
+pub fn passed(checks: &[Check]) -> bool {
+    checks.iter().all(|check| check.status != Status::Failed)
+}
+
+/// Renders the report as one JSON object.
+pub fn to_json(target: &Target, checks: &[Check]) -> String {
+    let mut json = String::new();
+    let _ = write!(json, "{{\"service\":{},\"passed\":{},\"checks\":[", json_string(&target.logical_name), passed(checks));
+    for (index, check) in checks.iter().enumerate() {
+        if index > 0 {
+            json.push(',');
+        }
+        let _ = write!(
+            json,
+            "{{\"name\":{},\"status\":{},\"detail\":{},\"calls\":[",
+            json_string(check.name),
+            json_string(check.status.name()),
+            json_string(&check.detail)
+        );
+        for (index, call) in check.calls.iter().enumerate() {
+            if index > 0 {
+                json.push(',');
+            }
+            let _ = write!(json, "{{\"function\":{},\"hresult\":{}}}", json_string(&call.function), call.h_result);
+        }
+        json.push_str("]}");
+    }
+    json.push_str("]}");
+    json
+}
+
+fn json_string(value: &str) -> String {
+    let mut json = String::with_capacity(value.len() + 2);
+    json.push('"');
+    for c in value.chars() {
+        match c {
+            '"' => json.push_str("\\\""),
+            '\\' => json.push_str("\\\\"),
+            '\n' => json.push_str("\\n"),
+            c if c.is_control() => {
+                let _ = write!(json, "\\u{:04x}", c as u32);
+            }
+            c => json.push(c),
+        }
+    }
+    json.push('"');
+    json
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_json_report() {
+        let target = Target::new("CDM\\\"30\"");
+        let mut failed = Check::new("lock_ownership");
+        failed.expect("WFSLock", WFS_SUCCESS, WFS_SUCCESS);
+        failed.expect("WFSUnlock", WFS_ERR_INTERNAL_ERROR, WFS_SUCCESS);
+        failed.expect("WFSClose", WFS_ERR_TIMEOUT, WFS_SUCCESS);
+        let checks = [Check::skipped("cancel", "No cancel command given\n"), failed];
+
+        assert!(!passed(&checks));
+        assert!(passed(&checks[..1]));
+        assert_eq!(
+            to_json(&target, &checks),
+            concat!(
+                r#"{"service":"CDM\\\"30\"","passed":false,"checks":["#,
+                r#"{"name":"cancel","status":"skipped","detail":"No cancel command given\n","calls":[]},"#,
+                r#"{"name":"lock_ownership","status":"failed","detail":"WFSUnlock returned -15, expected 0","calls":["#,
+                r#"{"function":"WFSLock","hresult":0},{"function":"WFSUnlock","hresult":-15},{"function":"WFSClose","hresult":-48}]}]}"#
+            )
+        );
+    }
+}
@@ -0,0 +1,59 @@
+//! Runs the conformance suite against a logical service and prints the report as JSON.
+//!
+//! Started as `xfs_conformance <logical service> [options]`, see [`USAGE`]. Exits with a failure when a check failed,
+//! so the suite can gate a build.
+
+use std::{env, process::ExitCode, time::Duration};
+
+use libloading::Library;
+use xfs_conformance::Target;
+
+const USAGE: &str = "usage: xfs_conformance <logical service> [--app-id <id>] [--category <code>]... [--cancel-command <code>] [--timeout <ms>] [--manager <dll>]";
+
+fn main() -> ExitCode {
+    let args: Vec<String> = env::args().skip(1).collect();
+    let (target, manager) = match parse(&args) {
+        Ok(parsed) => parsed,
+        Err(error) => {
+            eprintln!("xfs_conformance: {error}\n{USAGE}");
+            return ExitCode::FAILURE;
+        }
+    };
+    // SAFETY: the manager is the library under test, loaded like any application would
+    let checks = match unsafe { Library::new(&manager) }.and_then(|library| xfs_conformance::run(&library, &target)) {
+        Ok(checks) => checks,
+        Err(error) => {
+            eprintln!("xfs_conformance: {error}");
+            return ExitCode::FAILURE;
+        }
+    };
+    println!("{}", xfs_conformance::to_json(&target, &checks));
+    if xfs_conformance::passed(&checks) {
+        ExitCode::SUCCESS
+    } else {
+        ExitCode::FAILURE
+    }
+}
+
+/// Reads the target and the manager library from the command line.
+fn parse(args: &[String]) -> Result<(Target, String), String> {
+    let mut args = args.iter();
+    let mut target = match args.next() {
+        Some(logical_name) if !logical_name.starts_with("--") => Target::new(logical_name),
+        _ => return Err("no logical service given".to_string()),
+    };
+    let mut manager = "msxfs.dll".to_string();
+    while let Some(option) = args.next() {
+        let value = args.next().ok_or_else(|| format!("{option} needs a value"))?;
+        let number = || value.parse::<u32>().map_err(|error| format!("invalid {option} {value:?}: {error}"));
+        match option.as_str() {
+            "--app-id" => target.app_id = value.clone(),
+            "--category" => target.categories.push(number()?),
+            "--cancel-command" => target.cancel_command = Some(number()?),
+            "--timeout" => target.timeout = Duration::from_millis(number()? as u64),
+            "--manager" => manager = value.clone(),
+            _ => return Err(format!("unknown option {option}")),
+        }
+    }
+    Ok((target, manager))
+}
@@ -0,0 +1,34 @@
+//! Runs the conformance suite against the mock provider (`xfs_mock.dll`), which must pass every check.
+//!
+//! The test writes its own logical service into a registry sandbox below HKEY_CURRENT_USER, so it needs no admin
+//! rights, only `msxfs.dll`, `xfs_conf.dll`, `xfs_supp.dll` and `xfs_mock.dll` on the DLL search path.
+#![cfg(windows)]
+
+use libloading::Library;
+use winapi::shared::minwindef::DWORD;
+use xfs_conformance::{Status, Target};
+use xfslib::sandbox::Sandbox;
+
+/// Command the mock provider accepts but never completes, mirrors `xfs_mock::HANG_COMMAND`.
+const HANG_COMMAND: DWORD = 999;
+
+#[test]
+fn test_mock_conforms() {
+    let sandbox = Sandbox::new("conformance_mock");
+    sandbox.map_service("xfs_conformance_mock", "xfs_conformance_mock", Some("xfs_mock.dll"));
+    let manager = unsafe { Library::new("msxfs.dll").unwrap() };
+
+    let mut target = Target::new("xfs_conformance_mock");
+    // the mock answers any category and ignores cancels, which the manager completes for it
+    target.categories = vec![301, 302];
+    target.cancel_command = Some(HANG_COMMAND);
+    let checks = xfs_conformance::run(&manager, &target).unwrap();
+
+    let names: Vec<_> = checks.iter().map(|check| check.name).collect();
+    assert_eq!(names, ["start_up", "open_versions", "get_info", "lock_ownership", "cancel", "clean_close"]);
+    for check in &checks {
+        assert_eq!(check.status, Status::Passed, "{}: {}", check.name, check.detail);
+        assert!(!check.calls.is_empty(), "{} made no calls", check.name);
+    }
+    assert!(xfs_conformance::to_json(&target, &checks).starts_with(r#"{"service":"xfs_conformance_mock","passed":true,"checks":[{"name":"start_up","status":"passed""#));
+}
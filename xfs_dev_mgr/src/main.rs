@@ -1,11 +1,31 @@
-use std::{ffi::CString, ptr};
+use std::{
+    ffi::CString,
+    io::{self, Write},
+    ptr,
+};
 
 use winapi::{
     shared::minwindef::{DWORD, HKEY},
     um::winreg::{RegOpenKeyA, HKEY_LOCAL_MACHINE},
 };
+use xfslib::{registry::RegKey, *};
+
+use conf::*;
+
+mod conf;
 
 fn main() {
+    if std::env::args().nth(1).as_deref() == Some("--dump") {
+        let mut out = io::stdout().lock();
+        for (root, label) in [
+            (WFS_CFG_HKEY_MACHINE_XFS_ROOT, "HKEY_LOCAL_MACHINE\\SOFTWARE\\XFS"),
+            (WFS_CFG_HKEY_USER_DEFAULT_XFS_ROOT, "HKEY_USERS\\.DEFAULT\\XFS"),
+        ] {
+            dump(&mut out, root, label).unwrap();
+        }
+        return;
+    }
+
     let h_key: HKEY = HKEY_LOCAL_MACHINE;
 
     let os_string = CString::new("SOFTWARE\\XFS\\SERVICE_PROVIDERS\\serviceprovider").unwrap();
@@ -16,3 +36,60 @@ fn main() {
 
     println!("{}", result);
 }
+
+/// Writes an indented dump of all keys and values below one of the XFS configuration roots.
+fn dump(out: &mut impl Write, root: HKEY, label: &str) -> io::Result<()> {
+    match RegKey::open(&CONFIG_API, root, "") {
+        Ok(key) => {
+            writeln!(out, "[{label}]")?;
+            dump_key(out, &key, 1)
+        }
+        Err(result) => writeln!(out, "[{label}] <{result}>"),
+    }
+}
+
+fn dump_key(out: &mut impl Write, key: &RegKey, depth: usize) -> io::Result<()> {
+    let indent = "  ".repeat(depth);
+
+    match key.enum_values() {
+        Ok(values) => {
+            for (name, data) in values {
+                writeln!(out, "{indent}{name} = {data}")?;
+            }
+        }
+        Err(result) => return writeln!(out, "{indent}<values: {result}>"),
+    }
+
+    let names = match key.enum_keys() {
+        Ok(names) => names,
+        Err(result) => return writeln!(out, "{indent}<keys: {result}>"),
+    };
+    for name in names {
+        writeln!(out, "{indent}[{name}]")?;
+        match RegKey::open(&CONFIG_API, key.handle(), &name) {
+            Ok(sub_key) => dump_key(out, &sub_key, depth + 1)?,
+            Err(result) => writeln!(out, "{indent}  <{result}>")?,
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use xfslib::sandbox::Sandbox;
+
+    use super::*;
+
+    #[test]
+    fn test_dump() {
+        let sandbox = Sandbox::new("dev_mgr_dump");
+        sandbox.map_service("dumped", "dumped_provider", Some("dumped.dll"));
+
+        let mut out = Vec::new();
+        dump(&mut out, WFS_CFG_HKEY_USER_DEFAULT_XFS_ROOT, "USER_DEFAULT").unwrap();
+        let out = String::from_utf8(out).unwrap();
+
+        assert_eq!(out, "[USER_DEFAULT]\n  [LOGICAL_SERVICES]\n    [dumped]\n      provider = dumped_provider\n");
+    }
+}
@@ -0,0 +1,220 @@
+//! Surrogate process hosting one service provider for `xfs_isolate.dll`.
+//!
+//! Started as `xfs_host <pipe> <provider dll>`, it opens the pipes named by [`PipeNames`], loads the provider and
+//! calls it for every request frame. Each call runs on a thread of its own, which waits for the completion on a window
+//! of its own and sends it back, so a provider taking its time with one request holds up neither the others nor a
+//! cancel. The host exits once the requests pipe is closed, or together with the provider when it crashes.
+
+use std::{
+    env,
+    ffi::CString,
+    io, mem,
+    process::{self, ExitCode},
+    ptr,
+    sync::{Arc, Mutex},
+    thread,
+    time::Duration,
+};
+
+use libloading::Library;
+use winapi::{
+    shared::{
+        minwindef::{DWORD, LPVOID},
+        windef::HWND,
+        winerror::HRESULT,
+    },
+    um::winnt::LPSTR,
+};
+use xfslib::{
+    ipc::{self, Pipe, PipeNames, Reply, Request},
+    *,
+};
+
+/// Interval in which a call checks its window for the completion.
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+type WfpOpen = unsafe extern "stdcall" fn(HSERVICE, LPSTR, HAPP, LPSTR, DWORD, DWORD, HWND, REQUESTID, HPROVIDER, DWORD, LPWFSVERSION, DWORD, LPWFSVERSION) -> HRESULT;
+type WfpClose = unsafe extern "stdcall" fn(HSERVICE, HWND, REQUESTID) -> HRESULT;
+type WfpExecute = unsafe extern "stdcall" fn(HSERVICE, DWORD, LPVOID, DWORD, HWND, REQUESTID) -> HRESULT;
+type WfpLock = unsafe extern "stdcall" fn(HSERVICE, DWORD, HWND, REQUESTID) -> HRESULT;
+type WfpUnlock = unsafe extern "stdcall" fn(HSERVICE, HWND, REQUESTID) -> HRESULT;
+type WfpCancelAsyncRequest = unsafe extern "stdcall" fn(HSERVICE, REQUESTID) -> HRESULT;
+type WfmFreeBuffer = unsafe extern "stdcall" fn(LPVOID) -> HRESULT;
+
+/// Entry points of the hosted provider, plus the manager function freeing its results.
+#[derive(Clone, Copy)]
+struct Provider {
+    open: WfpOpen,
+    close: WfpClose,
+    execute: WfpExecute,
+    get_info: WfpExecute,
+    lock: WfpLock,
+    unlock: WfpUnlock,
+    cancel: WfpCancelAsyncRequest,
+    free_buffer: WfmFreeBuffer,
+}
+
+impl Provider {
+    /// Looks up the entry points, both libraries must stay loaded as long as the provider is used.
+    unsafe fn load(provider: &Library, manager: &Library) -> Result<Self, libloading::Error> {
+        Ok(Provider {
+            open: *provider.get(b"WFPOpen")?,
+            close: *provider.get(b"WFPClose")?,
+            execute: *provider.get(b"WFPExecute")?,
+            get_info: *provider.get(b"WFPGetInfo")?,
+            lock: *provider.get(b"WFPLock")?,
+            unlock: *provider.get(b"WFPUnlock")?,
+            cancel: *provider.get(b"WFPCancelAsyncRequest")?,
+            free_buffer: *manager.get(b"WFMFreeBuffer")?,
+        })
+    }
+}
+
+fn main() -> ExitCode {
+    let args: Vec<String> = env::args().collect();
+    let [_, pipe, provider] = &args[..] else {
+        eprintln!("usage: xfs_host <pipe> <provider dll>");
+        return ExitCode::FAILURE;
+    };
+    match run(pipe, provider) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(error) => {
+            eprintln!("xfs_host: {error}");
+            ExitCode::FAILURE
+        }
+    }
+}
+
+/// Serves the requests of the pipe until it is closed.
+fn run(pipe: &str, path: &str) -> Result<(), Box<dyn std::error::Error>> {
+    let names = PipeNames::new(pipe);
+    let mut requests = Pipe::open(&names.requests, true)?;
+    let replies = Arc::new(Mutex::new(Pipe::open(&names.replies, false)?));
+
+    // SAFETY: the provider is trusted as far as it would be inside the application, this process is there for when it is not
+    let library = unsafe { Library::new(path)? };
+    // SAFETY: the manager is already loaded by the provider, which links against it
+    let manager = unsafe { Library::new("msxfs.dll")? };
+    // SAFETY: both libraries stay loaded until the function returns
+    let provider = unsafe { Provider::load(&library, &manager)? };
+
+    let mut service: HSERVICE = 0;
+    loop {
+        let request = match ipc::receive::<Request>(&mut requests) {
+            Ok(request) => request,
+            Err(error) if error.kind() == io::ErrorKind::UnexpectedEof => return Ok(()),
+            Err(error) => return Err(error.into()),
+        };
+        match request {
+            Request::Open {
+                service: open_service,
+                request_id,
+                logical_name,
+                app_id,
+                trace_level,
+                timeout,
+                spi_versions,
+                srvc_versions,
+            } => {
+                service = open_service;
+                call(&replies, provider, request_id, WFS_OPEN_COMPLETE, move |window| unsafe {
+                    let logical_name = CString::new(logical_name).unwrap_or_default();
+                    let app_id = CString::new(app_id).unwrap_or_default();
+                    let mut spi_version = mem::zeroed::<WFSVERSION>();
+                    let mut srvc_version = mem::zeroed::<WFSVERSION>();
+                    let h_result = (provider.open)(
+                        service,
+                        logical_name.as_ptr() as LPSTR,
+                        ptr::null_mut(),
+                        app_id.as_ptr() as LPSTR,
+                        trace_level,
+                        timeout,
+                        window,
+                        request_id,
+                        ptr::null_mut(),
+                        spi_versions,
+                        &mut spi_version,
+                        srvc_versions,
+                        &mut srvc_version,
+                    );
+                    Reply::Opened {
+                        request_id,
+                        h_result,
+                        spi_version: [spi_version.w_version, spi_version.w_low_version, spi_version.w_high_version],
+                        srvc_version: [srvc_version.w_version, srvc_version.w_low_version, srvc_version.w_high_version],
+                    }
+                });
+            }
+            Request::Close { request_id } => call(&replies, provider, request_id, WFS_CLOSE_COMPLETE, move |window| unsafe {
+                returned(request_id, (provider.close)(service, window, request_id))
+            }),
+            Request::Execute { request_id, command, timeout } => call(&replies, provider, request_id, WFS_EXECUTE_COMPLETE, move |window| unsafe {
+                returned(request_id, (provider.execute)(service, command, ptr::null_mut(), timeout, window, request_id))
+            }),
+            Request::GetInfo { request_id, category, timeout } => call(&replies, provider, request_id, WFS_GETINFO_COMPLETE, move |window| unsafe {
+                returned(request_id, (provider.get_info)(service, category, ptr::null_mut(), timeout, window, request_id))
+            }),
+            Request::Lock { request_id, timeout } => call(&replies, provider, request_id, WFS_LOCK_COMPLETE, move |window| unsafe {
+                returned(request_id, (provider.lock)(service, timeout, window, request_id))
+            }),
+            Request::Unlock { request_id } => call(&replies, provider, request_id, WFS_UNLOCK_COMPLETE, move |window| unsafe {
+                returned(request_id, (provider.unlock)(service, window, request_id))
+            }),
+            Request::Cancel { request_id } => {
+                // SAFETY: the provider stays loaded until the function returns
+                unsafe { (provider.cancel)(service, request_id) };
+            }
+        }
+    }
+}
+
+fn returned(request_id: REQUESTID, h_result: HRESULT) -> Reply {
+    Reply::Returned { request_id, h_result }
+}
+
+/// Makes one SPI call on a thread of its own, sending back its return value and, if the call was accepted, the
+/// status of its completion. The result the provider posted is freed here, it never leaves the process.
+fn call(replies: &Arc<Mutex<Pipe>>, provider: Provider, request_id: REQUESTID, message: u32, spi_fn: impl FnOnce(HWND) -> Reply + Send + 'static) {
+    let replies = replies.clone();
+    thread::spawn(move || {
+        let window = SyncWindow::new(message);
+        let reply = spi_fn(window.handle());
+        let accepted = reply.h_result() == WFS_SUCCESS;
+        send(&replies, &reply);
+        if !accepted {
+            return;
+        }
+
+        let result = loop {
+            match window.try_receive() {
+                Ok(Some(result)) => break result as LPWFSRESULT,
+                Ok(None) => thread::sleep(POLL_INTERVAL),
+                Err(_) => return,
+            }
+        };
+        // SAFETY: the provider posted a WFSRESULT allocated on the XFS heap, which is ours to free
+        let (command, h_result) = unsafe {
+            let completion = (ptr::addr_of!((*result).u.dwCommandCode).read_unaligned(), ptr::addr_of!((*result).hResult).read_unaligned());
+            (provider.free_buffer)(result as LPVOID);
+            completion
+        };
+        send(
+            &replies,
+            &Reply::Completed {
+                request_id,
+                message,
+                command,
+                h_result,
+            },
+        );
+    });
+}
+
+/// Sends a reply, a host whose replies pipe broke has nobody left to serve and exits.
+fn send(replies: &Mutex<Pipe>, reply: &Reply) {
+    let mut replies = replies.lock().unwrap_or_else(|error| error.into_inner());
+    if let Err(error) = ipc::send(&mut *replies, reply) {
+        eprintln!("xfs_host: {error}");
+        process::exit(1);
+    }
+}
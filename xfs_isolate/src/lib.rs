@@ -0,0 +1,485 @@
+//! Stand-in provider that runs the real one in a surrogate process.
+//!
+//! The manager loads this library instead of the provider DLL for providers configured with `isolated = 1`. WFPOpen
+//! starts an `xfs_host.exe` for the service, which loads the DLL named by the provider's `dllname` value, and every
+//! SPI call of the service is then forwarded to it as an [`ipc`] frame. The completions come back as frames and are
+//! posted to the manager's window on results allocated here, on the manager's heap. When the surrogate dies, every
+//! pending request of the service fails with WFS_ERR_HARDWARE_ERROR, as does every later call but WFPClose, so a
+//! crashing provider costs the application one service instead of the whole process. A provider that does not return
+//! within the timeout of the call fails it with WFS_ERR_TIMEOUT, or after [`RETURN_TIMEOUT`] with
+//! WFS_ERR_HARDWARE_ERROR for calls without one, so a hung surrogate cannot block the application either.
+//!
+//! Only the status of a request crosses the process boundary so far: command data and query details are rejected
+//! with WFS_ERR_UNSUPP_DATA and completions carry no result buffer, as both need the class specific layout of the
+//! structures. For the same reason WFPRegister and WFPDeregister answer WFS_ERR_UNSUPP_COMMAND.
+
+use std::{
+    collections::HashMap,
+    ffi::CStr,
+    io, mem,
+    process::{self, Child, Command},
+    ptr,
+    sync::{
+        atomic::{AtomicBool, AtomicU32, Ordering},
+        mpsc::{self, RecvTimeoutError, Sender},
+        Arc, Mutex, MutexGuard, PoisonError,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use lazy_static::lazy_static;
+use libloading::Symbol;
+use winapi::{
+    shared::{
+        minwindef::{DWORD, HKEY, LPARAM, LPDWORD, LPVOID, PFILETIME, PHKEY, ULONG},
+        windef::HWND,
+        winerror::HRESULT,
+    },
+    um::{sysinfoapi::GetSystemTime, winnt::LPSTR, winuser::PostMessageA},
+};
+use xfslib::{
+    ipc::{self, Pipe, PipeNames, Reply, Request},
+    registry::{ConfigApi, RegKey},
+    *,
+};
+
+/// Surrogate executable, looked up like any other program.
+const HOST_EXE: &str = "xfs_host.exe";
+
+/// Time the surrogate gets to open its pipes.
+const HOST_START_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Time a call without timeout waits for the value the provider returns.
+const RETURN_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Interval in which a starting surrogate is checked on.
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// Number of surrogates started so far, part of the pipe names as service handles are reused.
+static HOSTS_STARTED: AtomicU32 = AtomicU32::new(0);
+
+lazy_static! {
+    static ref XFS_LIB: libloading::Library = unsafe { libloading::Library::new("msxfs.dll").unwrap() };
+    static ref WFM_ALLOCATE_BUFFER: Symbol<'static, unsafe extern "stdcall" fn(ULONG, ULONG, *mut LPVOID) -> HRESULT> = unsafe { XFS_LIB.get(b"WFMAllocateBuffer").unwrap() };
+    static ref WFM_FREE_BUFFER: Symbol<'static, unsafe extern "stdcall" fn(LPVOID) -> HRESULT> = unsafe { XFS_LIB.get(b"WFMFreeBuffer").unwrap() };
+
+    static ref CONF_LIB: libloading::Library = unsafe { libloading::Library::new("xfs_conf.dll").unwrap() };
+    static ref CONFIG_API: ConfigApi = unsafe {
+        ConfigApi {
+            open_key: *CONF_LIB.get::<unsafe extern "stdcall" fn(HKEY, LPSTR, PHKEY) -> HRESULT>(b"WFMOpenKey").unwrap(),
+            create_key: *CONF_LIB.get::<unsafe extern "stdcall" fn(HKEY, LPSTR, PHKEY, LPDWORD) -> HRESULT>(b"WFMCreateKey").unwrap(),
+            create_volatile_key: *CONF_LIB.get::<unsafe extern "stdcall" fn(HKEY, LPSTR, PHKEY, LPDWORD) -> HRESULT>(b"WFMCreateVolatileKey").unwrap(),
+            close_key: *CONF_LIB.get::<unsafe extern "stdcall" fn(HKEY) -> HRESULT>(b"WFMCloseKey").unwrap(),
+            query_value: *CONF_LIB.get::<unsafe extern "stdcall" fn(HKEY, LPSTR, LPSTR, LPDWORD) -> HRESULT>(b"WFMQueryValue").unwrap(),
+            enum_key: *CONF_LIB.get::<unsafe extern "stdcall" fn(HKEY, DWORD, LPSTR, LPDWORD, PFILETIME) -> HRESULT>(b"WFMEnumKey").unwrap(),
+            enum_value: *CONF_LIB.get::<unsafe extern "stdcall" fn(HKEY, DWORD, LPSTR, LPDWORD, LPSTR, LPDWORD) -> HRESULT>(b"WFMEnumValue").unwrap(),
+            set_value: *CONF_LIB.get::<unsafe extern "stdcall" fn(HKEY, LPSTR, LPSTR, DWORD) -> HRESULT>(b"WFMSetValue").unwrap(),
+        }
+    };
+
+    // holds the surrogate of every open service
+    static ref HOSTS: Mutex<HashMap<HSERVICE, Arc<Host>>> = Mutex::new(HashMap::new());
+}
+
+/// Request forwarded to a surrogate and not completed yet.
+struct Pending {
+    window: usize,
+    message: u32,
+    command: DWORD,
+    // receives the return value of the SPI function, taken once it arrived
+    returned: Option<Sender<Reply>>,
+}
+
+/// Surrogate process of one service.
+struct Host {
+    service: HSERVICE,
+    child: Mutex<Child>,
+    // None once the service is released, which makes the surrogate exit
+    requests: Mutex<Option<Pipe>>,
+    pending: Mutex<HashMap<REQUESTID, Pending>>,
+    dead: AtomicBool,
+}
+
+impl Host {
+    /// Starts the surrogate for the provider DLL and waits for it to connect. The manager calls WFPOpen without holding
+    /// its services lock, so a slow start delays no other service.
+    fn spawn(service: HSERVICE, dll_name: &str) -> io::Result<Arc<Host>> {
+        let base = format!("xfsrs.{}.{}", process::id(), HOSTS_STARTED.fetch_add(1, Ordering::SeqCst));
+        let names = PipeNames::new(&base);
+        let requests = Pipe::create(&names.requests, false)?;
+        let replies = Pipe::create(&names.replies, true)?;
+        let mut child = Command::new(HOST_EXE).arg(&base).arg(dll_name).spawn()?;
+        let (requests, replies) = match accept(requests, replies, &names, &mut child) {
+            Ok(pipes) => pipes,
+            Err(error) => {
+                let _ = child.kill();
+                let _ = child.wait();
+                return Err(error);
+            }
+        };
+
+        let host = Arc::new(Host {
+            service,
+            child: Mutex::new(child),
+            requests: Mutex::new(Some(requests)),
+            pending: Mutex::new(HashMap::new()),
+            dead: AtomicBool::new(false),
+        });
+        let reader = host.clone();
+        thread::spawn(move || reader.read_replies(replies));
+        Ok(host)
+    }
+
+    /// Forwards an SPI call and waits for the value the provider returned. The request is remembered until its
+    /// completion arrives, a surrogate that is gone or dies meanwhile fails the call with WFS_ERR_HARDWARE_ERROR.
+    ///
+    /// The wait is bounded by `timeout` in ms, after which the call fails with WFS_ERR_TIMEOUT, or by
+    /// [`RETURN_TIMEOUT`] for WFS_INDEFINITE_WAIT, after which it fails with WFS_ERR_HARDWARE_ERROR. The request is
+    /// then cancelled and its completion no longer posted.
+    fn call(&self, request: &Request, request_id: REQUESTID, window: HWND, message: u32, command: DWORD, timeout: DWORD) -> Reply {
+        let failed = |h_result| Reply::Returned { request_id, h_result };
+        let (sender, receiver) = mpsc::channel();
+        lock(&self.pending).insert(
+            request_id,
+            Pending {
+                window: window as usize,
+                message,
+                command,
+                returned: Some(sender),
+            },
+        );
+        // Checked after the insert, the reader marks the host dead before it fails the pending requests
+        if self.dead.load(Ordering::SeqCst) || self.send(request).is_err() {
+            lock(&self.pending).remove(&request_id);
+            return failed(WFS_ERR_HARDWARE_ERROR);
+        }
+
+        let (wait, expired) = match timeout {
+            WFS_INDEFINITE_WAIT => (RETURN_TIMEOUT, WFS_ERR_HARDWARE_ERROR),
+            timeout => (Duration::from_millis(timeout.into()), WFS_ERR_TIMEOUT),
+        };
+        match receiver.recv_timeout(wait) {
+            Ok(reply) => reply,
+            Err(RecvTimeoutError::Disconnected) => failed(WFS_ERR_HARDWARE_ERROR),
+            Err(RecvTimeoutError::Timeout) => {
+                // The reader hands over the reply under the lock, so it either arrived by now or never will
+                let mut pending = lock(&self.pending);
+                if let Ok(reply) = receiver.try_recv() {
+                    return reply;
+                }
+                pending.remove(&request_id);
+                drop(pending);
+                let _ = self.send(&Request::Cancel { request_id });
+                failed(expired)
+            }
+        }
+    }
+
+    fn send(&self, request: &Request) -> io::Result<()> {
+        match lock(&self.requests).as_mut() {
+            Some(requests) => ipc::send(requests, request),
+            None => Err(io::ErrorKind::BrokenPipe.into()),
+        }
+    }
+
+    /// Hands the replies of the surrogate to the waiting calls and posts the completions, until the pipe closes.
+    fn read_replies(self: Arc<Self>, mut replies: Pipe) {
+        while let Ok(reply) = ipc::receive::<Reply>(&mut replies) {
+            let request_id = reply.request_id();
+            let mut pending = lock(&self.pending);
+            match reply {
+                Reply::Completed { message, command, h_result, .. } => {
+                    let Some(request) = pending.remove(&request_id) else {
+                        continue;
+                    };
+                    drop(pending);
+                    // SAFETY: the window was passed along with the request
+                    unsafe { complete(&request, self.service, request_id, command, h_result) };
+                    if message == WFS_CLOSE_COMPLETE || (message == WFS_OPEN_COMPLETE && h_result != WFS_SUCCESS) {
+                        release(&self);
+                    }
+                }
+                reply => {
+                    let Some(request) = pending.get_mut(&request_id) else {
+                        continue;
+                    };
+                    let failed = reply.h_result() != WFS_SUCCESS;
+                    if let Some(returned) = request.returned.take() {
+                        let _ = returned.send(reply);
+                    }
+                    if failed {
+                        pending.remove(&request_id);
+                    }
+                }
+            }
+        }
+
+        self.dead.store(true, Ordering::SeqCst);
+        let pending: Vec<_> = lock(&self.pending).drain().collect();
+        for (request_id, mut request) in pending {
+            match request.returned.take() {
+                Some(returned) => {
+                    let _ = returned.send(Reply::Returned {
+                        request_id,
+                        h_result: WFS_ERR_HARDWARE_ERROR,
+                    });
+                }
+                // A close has nothing left to close
+                None if request.message == WFS_CLOSE_COMPLETE => unsafe { complete(&request, self.service, request_id, 0, WFS_SUCCESS) },
+                None => unsafe { complete(&request, self.service, request_id, request.command, WFS_ERR_HARDWARE_ERROR) },
+            }
+        }
+        let _ = lock(&self.child).wait();
+    }
+}
+
+/// Waits for the surrogate to open both pipes, giving up when it exits first or takes longer than
+/// [`HOST_START_TIMEOUT`].
+fn accept(requests: Pipe, replies: Pipe, names: &PipeNames, child: &mut Child) -> io::Result<(Pipe, Pipe)> {
+    let acceptor = thread::spawn(move || -> io::Result<(Pipe, Pipe)> {
+        requests.accept()?;
+        replies.accept()?;
+        Ok((requests, replies))
+    });
+    let deadline = Instant::now() + HOST_START_TIMEOUT;
+    while !acceptor.is_finished() {
+        if child.try_wait()?.is_some() || Instant::now() >= deadline {
+            // Connect in its place, so the pending ConnectNamedPipe returns
+            let _requests = Pipe::open(&names.requests, true);
+            let _replies = Pipe::open(&names.replies, false);
+            let _ = acceptor.join();
+            return Err(io::Error::new(io::ErrorKind::TimedOut, format!("{HOST_EXE} did not connect")));
+        }
+        thread::sleep(POLL_INTERVAL);
+    }
+    acceptor.join().map_err(|_| io::Error::new(io::ErrorKind::Other, "pipe acceptor panicked"))?
+}
+
+/// Forgets the surrogate of a service that is closed, closing its requests pipe makes it exit.
+fn release(host: &Arc<Host>) {
+    let mut hosts = lock(&HOSTS);
+    if hosts.get(&host.service).is_some_and(|current| Arc::ptr_eq(current, host)) {
+        hosts.remove(&host.service);
+    }
+    drop(hosts);
+    lock(&host.requests).take();
+}
+
+fn host(service: HSERVICE) -> Option<Arc<Host>> {
+    lock(&HOSTS).get(&service).cloned()
+}
+
+/// Locks a mutex even if a thread panicked holding it, none of the state it guards is left half updated.
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// Looks up the provider DLL the logical service is configured with.
+fn provider_dll(logical_name: &str) -> Result<String, HRESULT> {
+    let provider = RegKey::open(&CONFIG_API, WFS_CFG_HKEY_USER_DEFAULT_XFS_ROOT, &format!("LOGICAL_SERVICES\\{logical_name}"))?.query_value("provider")?;
+    RegKey::open(&CONFIG_API, WFS_CFG_HKEY_MACHINE_XFS_ROOT, &format!("SERVICE_PROVIDERS\\{provider}"))?.query_value("dllname")
+}
+
+/// Allocates a WFSRESULT without buffer on the XFS heap, where WFSFreeResult expects it, and posts it to the window
+/// of the request. A result the window does not take is freed again.
+unsafe fn complete(request: &Pending, service: HSERVICE, request_id: REQUESTID, command: DWORD, h_result: HRESULT) {
+    let mut result: LPVOID = ptr::null_mut();
+    if (WFM_ALLOCATE_BUFFER)(mem::size_of::<WFSRESULT>() as ULONG, WFS_MEM_ZEROINIT, &mut result) != WFS_SUCCESS {
+        return;
+    }
+
+    let mut timestamp = mem::zeroed();
+    GetSystemTime(&mut timestamp);
+
+    (result as LPWFSRESULT).write_unaligned(WFSRESULT {
+        RequestID: request_id,
+        hService: service,
+        tsTimestamp: timestamp,
+        hResult: h_result,
+        u: U { dwCommandCode: command },
+        lpBuffer: ptr::null_mut(),
+    });
+    if PostMessageA(request.window as HWND, request.message, 0, result as LPARAM) == 0 {
+        (WFM_FREE_BUFFER)(result);
+    }
+}
+
+/// Forwards a call to the surrogate of the service, returning what the provider returned within `timeout`.
+fn forward(service: HSERVICE, request: Request, request_id: REQUESTID, window: HWND, message: u32, command: DWORD, timeout: DWORD) -> HRESULT {
+    match host(service) {
+        Some(host) => host.call(&request, request_id, window, message, command, timeout).h_result(),
+        None => WFS_ERR_INVALID_HSERVICE,
+    }
+}
+
+#[allow(non_snake_case)]
+#[no_mangle]
+pub extern "stdcall" fn WFPCancelAsyncRequest(hService: HSERVICE, RequestID: REQUESTID) -> HRESULT {
+    catch_panic(|| match host(hService) {
+        // A surrogate that is gone already failed every request
+        Some(host) => {
+            let _ = host.send(&Request::Cancel { request_id: RequestID });
+            WFS_SUCCESS
+        }
+        None => WFS_ERR_INVALID_HSERVICE,
+    })
+}
+
+#[allow(non_snake_case)]
+#[no_mangle]
+pub extern "stdcall" fn WFPClose(hService: HSERVICE, hWnd: HWND, ReqID: REQUESTID) -> HRESULT {
+    catch_panic(|| {
+        let Some(host) = host(hService) else {
+            return WFS_ERR_INVALID_HSERVICE;
+        };
+        if host.dead.load(Ordering::SeqCst) {
+            release(&host);
+            let request = Pending {
+                window: hWnd as usize,
+                message: WFS_CLOSE_COMPLETE,
+                command: 0,
+                returned: None,
+            };
+            unsafe { complete(&request, hService, ReqID, 0, WFS_SUCCESS) };
+            return WFS_SUCCESS;
+        }
+        host.call(&Request::Close { request_id: ReqID }, ReqID, hWnd, WFS_CLOSE_COMPLETE, 0, WFS_INDEFINITE_WAIT).h_result()
+    })
+}
+
+#[allow(non_snake_case)]
+#[no_mangle]
+pub extern "stdcall" fn WFPDeregister(_hService: HSERVICE, _dwEventClass: DWORD, _hWndReg: HWND, _hWnd: HWND, _ReqID: REQUESTID) -> HRESULT {
+    WFS_ERR_UNSUPP_COMMAND
+}
+
+#[allow(non_snake_case)]
+#[no_mangle]
+pub extern "stdcall" fn WFPExecute(hService: HSERVICE, dwCommand: DWORD, lpCmdData: LPVOID, dwTimeOut: DWORD, hWnd: HWND, ReqID: REQUESTID) -> HRESULT {
+    catch_panic(|| {
+        if !lpCmdData.is_null() {
+            return WFS_ERR_UNSUPP_DATA;
+        }
+        let request = Request::Execute {
+            request_id: ReqID,
+            command: dwCommand,
+            timeout: dwTimeOut,
+        };
+        forward(hService, request, ReqID, hWnd, WFS_EXECUTE_COMPLETE, dwCommand, dwTimeOut)
+    })
+}
+
+#[allow(non_snake_case)]
+#[no_mangle]
+pub extern "stdcall" fn WFPGetInfo(hService: HSERVICE, dwCategory: DWORD, lpQueryDetails: LPVOID, dwTimeOut: DWORD, hWnd: HWND, ReqID: REQUESTID) -> HRESULT {
+    catch_panic(|| {
+        if !lpQueryDetails.is_null() {
+            return WFS_ERR_UNSUPP_DATA;
+        }
+        let request = Request::GetInfo {
+            request_id: ReqID,
+            category: dwCategory,
+            timeout: dwTimeOut,
+        };
+        forward(hService, request, ReqID, hWnd, WFS_GETINFO_COMPLETE, dwCategory, dwTimeOut)
+    })
+}
+
+#[allow(non_snake_case)]
+#[no_mangle]
+pub extern "stdcall" fn WFPLock(hService: HSERVICE, dwTimeOut: DWORD, hWnd: HWND, ReqID: REQUESTID) -> HRESULT {
+    catch_panic(|| {
+        let request = Request::Lock {
+            request_id: ReqID,
+            timeout: dwTimeOut,
+        };
+        forward(hService, request, ReqID, hWnd, WFS_LOCK_COMPLETE, 0, dwTimeOut)
+    })
+}
+
+#[allow(non_snake_case)]
+#[no_mangle]
+pub extern "stdcall" fn WFPOpen(
+    hService: HSERVICE,
+    lpszLogicalName: LPSTR,
+    _hApp: HAPP,
+    lpszAppID: LPSTR,
+    dwTraceLevel: DWORD,
+    dwTimeOut: DWORD,
+    hWnd: HWND,
+    ReqID: REQUESTID,
+    _hProvider: HPROVIDER,
+    dwSPIVersionsRequired: DWORD,
+    lpSPIVersion: LPWFSVERSION,
+    dwSrvcVersionsRequired: DWORD,
+    lpSrvcVersion: LPWFSVERSION,
+) -> HRESULT {
+    catch_panic(|| {
+        let logical_name = unsafe { CStr::from_ptr(lpszLogicalName) };
+        let Ok(dll_name) = provider_dll(&logical_name.to_string_lossy()) else {
+            return WFS_ERR_INVALID_SERVPROV;
+        };
+        let Ok(host) = Host::spawn(hService, &dll_name) else {
+            return WFS_ERR_INVALID_SERVPROV;
+        };
+        lock(&HOSTS).insert(hService, host.clone());
+
+        let request = Request::Open {
+            service: hService,
+            request_id: ReqID,
+            logical_name: logical_name.to_bytes().to_vec(),
+            app_id: if lpszAppID.is_null() {
+                Vec::new()
+            } else {
+                unsafe { CStr::from_ptr(lpszAppID) }.to_bytes().to_vec()
+            },
+            trace_level: dwTraceLevel,
+            timeout: dwTimeOut,
+            spi_versions: dwSPIVersionsRequired,
+            srvc_versions: dwSrvcVersionsRequired,
+        };
+        let reply = host.call(&request, ReqID, hWnd, WFS_OPEN_COMPLETE, 0, dwTimeOut);
+        if let Reply::Opened { spi_version, srvc_version, .. } = reply {
+            let version = |[version, low, high]: [u16; 3]| WFSVERSION {
+                w_version: version,
+                w_low_version: low,
+                w_high_version: high,
+                sz_description: [0; WFSDDESCRIPTION_LEN + 1],
+                sz_system_status: [0; WFSDSYSSTATUS_LEN + 1],
+            };
+            unsafe {
+                lpSPIVersion.write_unaligned(version(spi_version));
+                lpSrvcVersion.write_unaligned(version(srvc_version));
+            }
+        }
+        if reply.h_result() != WFS_SUCCESS {
+            release(&host);
+        }
+        reply.h_result()
+    })
+}
+
+#[allow(non_snake_case)]
+#[no_mangle]
+pub extern "stdcall" fn WFPRegister(_hService: HSERVICE, _dwEventClass: DWORD, _hWndReg: HWND, _hWnd: HWND, _ReqID: REQUESTID) -> HRESULT {
+    WFS_ERR_UNSUPP_COMMAND
+}
+
+#[allow(non_snake_case)]
+#[no_mangle]
+pub extern "stdcall" fn WFPSetTraceLevel(_hService: HSERVICE, _dwTraceLevel: DWORD) -> HRESULT {
+    WFS_SUCCESS
+}
+
+#[allow(non_snake_case)]
+#[no_mangle]
+pub extern "stdcall" fn WFPUnloadService() -> HRESULT {
+    WFS_SUCCESS
+}
+
+#[allow(non_snake_case)]
+#[no_mangle]
+pub extern "stdcall" fn WFPUnlock(hService: HSERVICE, hWnd: HWND, ReqID: REQUESTID) -> HRESULT {
+    catch_panic(|| forward(hService, Request::Unlock { request_id: ReqID }, ReqID, hWnd, WFS_UNLOCK_COMPLETE, 0, WFS_INDEFINITE_WAIT))
+}
@@ -0,0 +1,153 @@
+//! Names of the WFSExecute command codes the manager knows about.
+//!
+//! Command codes are the service class offset plus a number within the class, e.g. CDM_SERVICE_OFFSET (300) + 2
+//! is WFS_CMD_CDM_DISPENSE. The table only enriches the trace and is not authoritative: a command missing from it
+//! is still forwarded, unless XFS_STRICT_COMMANDS is set and the code does not even belong to a known service class.
+//! Entries are added to [`CLASSES`] and [`COMMANDS`] as needed.
+
+use std::fmt;
+
+use winapi::shared::minwindef::DWORD;
+
+/// When set, WFSExecute rejects command codes outside of every known service class with WFS_ERR_UNSUPP_COMMAND.
+pub const STRICT_COMMANDS_ENV: &str = "XFS_STRICT_COMMANDS";
+
+/// Distance between the command codes of two service classes.
+const CLASS_SIZE: DWORD = 100;
+
+/// Service class offsets and their names.
+const CLASSES: &[(DWORD, &str)] = &[
+    (100, "PTR"),
+    (200, "IDC"),
+    (300, "CDM"),
+    (400, "PIN"),
+    (500, "CHK"),
+    (600, "DEP"),
+    (700, "TTU"),
+    (800, "SIU"),
+    (900, "VDM"),
+    (1000, "CAM"),
+    (1100, "ALM"),
+    (1200, "CEU"),
+    (1300, "CIM"),
+    (1400, "CRD"),
+    (1500, "BCR"),
+    (1600, "IPM"),
+];
+
+/// Known command codes and their names without the WFS_CMD_ prefix.
+const COMMANDS: &[(DWORD, &str)] = &[
+    (101, "PTR_CONTROL_MEDIA"),
+    (102, "PTR_PRINT_FORM"),
+    (103, "PTR_READ_FORM"),
+    (104, "PTR_RAW_DATA"),
+    (105, "PTR_MEDIA_EXTENTS"),
+    (106, "PTR_RESET_COUNT"),
+    (107, "PTR_READ_IMAGE"),
+    (108, "PTR_RESET"),
+    (109, "PTR_RETRACT_MEDIA"),
+    (201, "IDC_READ_TRACK"),
+    (202, "IDC_WRITE_TRACK"),
+    (203, "IDC_EJECT_CARD"),
+    (204, "IDC_RETAIN_CARD"),
+    (205, "IDC_RESET_COUNT"),
+    (206, "IDC_SETKEY"),
+    (207, "IDC_READ_RAW_DATA"),
+    (208, "IDC_WRITE_RAW_DATA"),
+    (209, "IDC_CHIP_IO"),
+    (210, "IDC_RESET"),
+    (301, "CDM_DENOMINATE"),
+    (302, "CDM_DISPENSE"),
+    (303, "CDM_PRESENT"),
+    (304, "CDM_REJECT"),
+    (305, "CDM_RETRACT"),
+    (323, "CDM_COUNT"),
+    (401, "PIN_CRYPT"),
+    (403, "PIN_IMPORT_KEY"),
+    (407, "PIN_GET_PIN"),
+    (408, "PIN_GET_PINBLOCK"),
+    (409, "PIN_GET_DATA"),
+    (410, "PIN_INITIALIZATION"),
+    (801, "SIU_ENABLE_EVENTS"),
+    (802, "SIU_SET_PORTS"),
+    (803, "SIU_SET_DOOR"),
+    (804, "SIU_SET_INDICATOR"),
+    (805, "SIU_SET_AUXILIARY"),
+    (806, "SIU_SET_GUIDLIGHT"),
+    (807, "SIU_RESET"),
+];
+
+/// WFSExecute command code, displayed by name where it is known.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Command(pub DWORD);
+
+impl Command {
+    /// Name of the service class the code belongs to.
+    fn class(self) -> Option<&'static str> {
+        // The offsets themselves are not commands
+        if self.0 % CLASS_SIZE == 0 {
+            return None;
+        }
+        let offset = self.0 - self.0 % CLASS_SIZE;
+        CLASSES.iter().find(|(class, _)| *class == offset).map(|(_, name)| *name)
+    }
+
+    /// Whether the code is rejected in strict mode, only codes outside of every known class are.
+    pub fn is_invalid(self) -> bool {
+        self.class().is_none()
+    }
+}
+
+impl fmt::Display for Command {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if let Some((_, name)) = COMMANDS.iter().find(|(command, _)| *command == self.0) {
+            return write!(f, "{name}");
+        }
+        match self.class() {
+            Some(class) => write!(f, "{class} command {}", self.0),
+            None => write!(f, "command {}", self.0),
+        }
+    }
+}
+
+/// Whether strict mode is enabled, see [`STRICT_COMMANDS_ENV`].
+pub fn strict() -> bool {
+    std::env::var_os(STRICT_COMMANDS_ENV).is_some()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_command_names() {
+        assert_eq!(Command(302).to_string(), "CDM_DISPENSE");
+        assert_eq!(Command(201).to_string(), "IDC_READ_TRACK");
+        assert_eq!(Command(399).to_string(), "CDM command 399");
+        assert_eq!(Command(12345).to_string(), "command 12345");
+    }
+
+    #[test]
+    fn test_command_codes() {
+        // entries beyond the first of every class, as defined by its XFS header
+        assert_eq!(Command(109).to_string(), "PTR_RETRACT_MEDIA");
+        assert_eq!(Command(209).to_string(), "IDC_CHIP_IO");
+        assert_eq!(Command(303).to_string(), "CDM_PRESENT");
+        assert_eq!(Command(304).to_string(), "CDM_REJECT");
+        assert_eq!(Command(305).to_string(), "CDM_RETRACT");
+        assert_eq!(Command(306).to_string(), "CDM command 306");
+        assert_eq!(Command(323).to_string(), "CDM_COUNT");
+        assert_eq!(Command(408).to_string(), "PIN_GET_PINBLOCK");
+        assert_eq!(Command(806).to_string(), "SIU_SET_GUIDLIGHT");
+    }
+
+    #[test]
+    fn test_invalid_commands() {
+        assert!(!Command(302).is_invalid());
+        assert!(!Command(399).is_invalid());
+        assert!(Command(0).is_invalid());
+        assert!(Command(300).is_invalid());
+        assert!(Command(12345).is_invalid());
+        assert!(Command(DWORD::MAX).is_invalid());
+    }
+}
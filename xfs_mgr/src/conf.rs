@@ -1,3 +1,5 @@
+//! Configuration functions of `xfs_conf.dll`, which the manager re-exports and reads its own settings through.
+
 use lazy_static::lazy_static;
 use libloading::Symbol;
 use winapi::shared::{
@@ -5,11 +7,13 @@ use winapi::shared::{
     ntdef::LPSTR,
     winerror::HRESULT,
 };
+use xfslib::registry::ConfigApi;
 
 lazy_static! {
     static ref XFS_LIB: libloading::Library = unsafe { libloading::Library::new("xfs_conf.dll").unwrap() };
     pub static ref WFM_CLOSE_KEY: Symbol<'static, unsafe extern "stdcall" fn(HKEY) -> HRESULT> = unsafe { XFS_LIB.get(b"WFMCloseKey").unwrap() };
     pub static ref WFM_CREATE_KEY: Symbol<'static, unsafe extern "stdcall" fn(HKEY, LPSTR, PHKEY, LPDWORD) -> HRESULT> = unsafe { XFS_LIB.get(b"WFMCreateKey").unwrap() };
+    pub static ref WFM_CREATE_VOLATILE_KEY: Symbol<'static, unsafe extern "stdcall" fn(HKEY, LPSTR, PHKEY, LPDWORD) -> HRESULT> = unsafe { XFS_LIB.get(b"WFMCreateVolatileKey").unwrap() };
     pub static ref WFM_DELETE_KEY: Symbol<'static, unsafe extern "stdcall" fn(HKEY, LPSTR) -> HRESULT> = unsafe { XFS_LIB.get(b"WFMDeleteKey").unwrap() };
     pub static ref WFM_DELETE_VALUE: Symbol<'static, unsafe extern "stdcall" fn(HKEY, LPSTR) -> HRESULT> = unsafe { XFS_LIB.get(b"WFMDeleteValue").unwrap() };
     pub static ref WFM_ENUM_KEY: Symbol<'static, unsafe extern "stdcall" fn(HKEY, DWORD, LPSTR, LPDWORD, PFILETIME) -> HRESULT> = unsafe { XFS_LIB.get(b"WFMEnumKey").unwrap() };
@@ -17,4 +21,14 @@ lazy_static! {
     pub static ref WFM_OPEN_KEY: Symbol<'static, unsafe extern "stdcall" fn(HKEY, LPSTR, PHKEY) -> HRESULT> = unsafe { XFS_LIB.get(b"WFMOpenKey").unwrap() };
     pub static ref WFM_QUERY_VALUE: Symbol<'static, unsafe extern "stdcall" fn(HKEY, LPSTR, LPSTR, LPDWORD) -> HRESULT> = unsafe { XFS_LIB.get(b"WFMQueryValue").unwrap() };
     pub static ref WFM_SET_VALUE: Symbol<'static, unsafe extern "stdcall" fn(HKEY, LPSTR, LPSTR, DWORD) -> HRESULT> = unsafe { XFS_LIB.get(b"WFMSetValue").unwrap() };
+    pub static ref CONFIG_API: ConfigApi = ConfigApi {
+        open_key: *WFM_OPEN_KEY,
+        create_key: *WFM_CREATE_KEY,
+        create_volatile_key: *WFM_CREATE_VOLATILE_KEY,
+        close_key: *WFM_CLOSE_KEY,
+        query_value: *WFM_QUERY_VALUE,
+        enum_key: *WFM_ENUM_KEY,
+        enum_value: *WFM_ENUM_VALUE,
+        set_value: *WFM_SET_VALUE,
+    };
 }
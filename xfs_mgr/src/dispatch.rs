@@ -0,0 +1,78 @@
+//! First come, first served serialization of the calls into a provider that is not reentrant.
+//!
+//! A plain mutex hands a released lock to whichever waiter gets scheduled first, so with many application threads
+//! calling into one exclusive provider some of them may keep losing. Here every caller draws a ticket on arrival and
+//! the calls are let in in ticket order.
+
+use std::sync::{Condvar, Mutex, MutexGuard, PoisonError};
+
+/// Tickets drawn and the one being served.
+#[derive(Default)]
+struct Tickets {
+    next: u64,
+    serving: u64,
+}
+
+/// Ticket lock serializing the calls into one service.
+#[derive(Default)]
+pub struct Dispatch {
+    tickets: Mutex<Tickets>,
+    turn: Condvar,
+}
+
+/// Exclusive access to the service, the next caller in line is let in when it is dropped.
+pub struct Turn<'a>(&'a Dispatch);
+
+impl Dispatch {
+    /// Waits until every caller that arrived earlier is done.
+    pub fn enter(&self) -> Turn<'_> {
+        let mut tickets = self.lock();
+        let ticket = tickets.next;
+        tickets.next += 1;
+        while tickets.serving != ticket {
+            tickets = self.turn.wait(tickets).unwrap_or_else(PoisonError::into_inner);
+        }
+        Turn(self)
+    }
+
+    // A panic during an earlier call must not lock the service out for good
+    fn lock(&self) -> MutexGuard<'_, Tickets> {
+        self.tickets.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+impl Drop for Turn<'_> {
+    fn drop(&mut self) {
+        self.0.lock().serving += 1;
+        self.0.turn.notify_all();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{thread, time::Duration};
+
+    use super::*;
+
+    #[test]
+    fn test_arrival_order() {
+        let dispatch = Dispatch::default();
+        let entered = Mutex::new(Vec::new());
+        thread::scope(|scope| {
+            let first = dispatch.enter();
+            for caller in 0..8 {
+                let (dispatch, entered) = (&dispatch, &entered);
+                scope.spawn(move || {
+                    let _turn = dispatch.enter();
+                    entered.lock().unwrap().push(caller);
+                });
+                // each caller has drawn its ticket before the next one arrives
+                while dispatch.lock().next != caller + 2 {
+                    thread::sleep(Duration::from_millis(1));
+                }
+            }
+            drop(first);
+        });
+        assert_eq!(*entered.lock().unwrap(), (0..8).collect::<Vec<_>>());
+    }
+}
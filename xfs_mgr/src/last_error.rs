@@ -0,0 +1,126 @@
+//! Last error each application thread ran into.
+//!
+//! A bare HRESULT says little once the call that returned it is gone from the logs, so the exported functions record
+//! which of them failed, on which service and when. Only the function the application called is recorded: calls
+//! nested in it, from a blocking hook or from a provider calling back into the manager, leave the record alone.
+//! So do the support functions providers call, even on an application thread, e.g. from a window procedure the
+//! application's message loop dispatches. A successful call clears it.
+
+use std::{
+    cell::{Cell, RefCell},
+    mem,
+};
+
+use winapi::{
+    shared::winerror::HRESULT,
+    um::{minwinbase::SYSTEMTIME, sysinfoapi::GetSystemTime},
+};
+use xfslib::*;
+
+thread_local! {
+    // holds the last failed call of the thread, None once a call succeeded
+    static LAST_ERROR: RefCell<Option<LastError>> = const { RefCell::new(None) };
+
+    // holds the number of exported functions the thread is in
+    static DEPTH: Cell<u32> = const { Cell::new(0) };
+}
+
+/// Support functions service providers call, which are never recorded.
+const PROVIDER_FUNCTIONS: &[&str] = &[
+    "WFMAllocateBuffer",
+    "WFMAllocateMore",
+    "WFMAllocateTaggedBuffer",
+    "WFMFreeBuffer",
+    "WFMGetBufferLength",
+    "WFMGetTraceLevel",
+    "WFMKillTimer",
+    "WFMOutputTraceData",
+    "WFMReleaseDLL",
+    "WFMSetTimer",
+];
+
+/// Failed call of an application thread.
+#[derive(Clone, Copy)]
+pub struct LastError {
+    pub h_result: HRESULT,
+    /// Exported function the application called.
+    pub operation: &'static str,
+    /// Service the call was made on, 0 for none.
+    pub service: HSERVICE,
+    pub timestamp: SYSTEMTIME,
+}
+
+/// Runs an exported function under [`catch_panic`] and records its result as the last error of the thread.
+pub fn track(operation: &'static str, service: HSERVICE, body: impl FnOnce() -> HRESULT) -> HRESULT {
+    let outermost = DEPTH.with(|depth| {
+        depth.set(depth.get() + 1);
+        depth.get() == 1
+    });
+    let h_result = catch_panic(body);
+    DEPTH.with(|depth| depth.set(depth.get() - 1));
+
+    if outermost && !PROVIDER_FUNCTIONS.contains(&operation) {
+        let last_error = (h_result != WFS_SUCCESS).then(|| {
+            // SAFETY: the timestamp is a local
+            let timestamp = unsafe {
+                let mut timestamp = mem::zeroed();
+                GetSystemTime(&mut timestamp);
+                timestamp
+            };
+            LastError {
+                h_result,
+                operation,
+                service,
+                timestamp,
+            }
+        });
+        LAST_ERROR.with(|last| last.replace(last_error));
+    }
+    h_result
+}
+
+/// Gets the last failed call of the current thread, None if its last call succeeded or it made none.
+/// Inside an exported function, this is the call before it.
+pub fn last_error() -> Option<LastError> {
+    LAST_ERROR.with(|last| *last.borrow())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_track() {
+        assert!(last_error().is_none());
+
+        assert_eq!(track("WFSLock", 3, || WFS_ERR_INVALID_HSERVICE), WFS_ERR_INVALID_HSERVICE);
+        let error = last_error().unwrap();
+        assert_eq!((error.h_result, error.operation, error.service), (WFS_ERR_INVALID_HSERVICE, "WFSLock", 3));
+        assert_ne!(error.timestamp.wYear, 0);
+
+        // nested calls are not the application's, the outer one is recorded
+        let nested = track("WFSExecute", 3, || {
+            assert_eq!(track("WFMAllocateBuffer", 0, || WFS_SUCCESS), WFS_SUCCESS);
+            // the record still holds the call before
+            assert_eq!(last_error().unwrap().operation, "WFSLock");
+            track("WFSAsyncExecute", 3, || WFS_ERR_LOCKED)
+        });
+        assert_eq!(nested, WFS_ERR_LOCKED);
+        assert_eq!(last_error().unwrap().operation, "WFSExecute");
+
+        // support functions a provider calls on the application thread leave the record alone
+        assert_eq!(track("WFMAllocateBuffer", 0, || WFS_ERR_OUT_OF_MEMORY), WFS_ERR_OUT_OF_MEMORY);
+        assert_eq!(track("WFMKillTimer", 0, || WFS_SUCCESS), WFS_SUCCESS);
+        assert_eq!(last_error().unwrap().operation, "WFSExecute");
+
+        // panics are caught and recorded like any other error
+        assert_eq!(track("WFSClose", 3, || panic!("provider blew up")), WFS_ERR_INTERNAL_ERROR);
+        assert_eq!(last_error().unwrap().h_result, WFS_ERR_INTERNAL_ERROR);
+
+        // other threads keep their own record
+        std::thread::spawn(|| assert!(last_error().is_none())).join().unwrap();
+
+        assert_eq!(track("WFSClose", 3, || WFS_SUCCESS), WFS_SUCCESS);
+        assert!(last_error().is_none());
+    }
+}
@@ -1,34 +1,49 @@
 use std::{
-    collections::HashMap,
-    ffi::{CStr, CString},
+    cell::Cell,
+    collections::{HashMap, HashSet},
+    ffi::CStr,
+    fmt,
+    io::Read,
     mem, ptr,
     sync::{
-        atomic::{AtomicBool, AtomicPtr, Ordering},
-        Mutex,
+        atomic::{AtomicBool, AtomicPtr, AtomicU16, AtomicUsize, Ordering},
+        Arc, Mutex, Weak,
     },
+    thread,
+    time::{Duration, Instant},
 };
 
 use lazy_static::lazy_static;
-use log::{error, trace};
+use log::{error, trace, warn};
 use log_derive::{logfn, logfn_inputs};
 use winapi::{
     shared::{
-        minwindef::{DWORD, HINSTANCE, HKEY, LPDWORD, LPVOID, LPWORD, MAX_PATH, ULONG, WORD},
+        minwindef::{DWORD, HINSTANCE, LPDWORD, LPVOID, LPWORD, ULONG, WORD},
         windef::HWND,
         winerror::HRESULT,
     },
     um::{
         processthreadsapi::GetCurrentThreadId,
         winnt::LPSTR,
-        winuser::{DispatchMessageW, GetMessageW, TranslateMessage},
+        winuser::{DispatchMessageW, PeekMessageW, TranslateMessage, PM_REMOVE},
     },
 };
 
 use conf::*;
+use dispatch::Dispatch;
+use provider_cache::{ProviderCache, Resolved};
+use registrations::{Change, Registrations};
 use supp::*;
-use xfslib::*;
+use xfslib::{registry::RegKey, *};
 
+mod commands;
 mod conf;
+mod dispatch;
+mod last_error;
+mod manager;
+mod provider_cache;
+mod registrations;
+mod relay;
 mod spi;
 mod supp;
 
@@ -37,18 +52,78 @@ lazy_static! {
     static ref SERVICES: Mutex<Vec<Option<Service>>> = Mutex::new((0..8192).map(|_| None).collect());
 
     // holds app handles
-    static ref APP_HANDLES: Mutex<[bool; 8192]> = Mutex::new([false; 8192]);
+    static ref APP_HANDLES: Mutex<[AppHandle; 8192]> = Mutex::new([AppHandle::default(); 8192]);
 
     // indicates whether WFSStartup has been called
     static ref STARTED: AtomicBool = AtomicBool::new(false);
 
-    // holds blocked threads and unblock flag
-    static ref BLOCKED_THREADS: Mutex<HashMap<DWORD, bool>> = Mutex::new(HashMap::new());
+    // indicates whether WFSCleanUp is in progress
+    static ref CLEANING_UP: AtomicBool = AtomicBool::new(false);
+
+    // held by WFSStartUp and the tear down of WFSCleanUp, so a start up never completes against half cleared state
+    static ref LIFECYCLE: Mutex<()> = Mutex::new(());
+
+    // holds the generation of the next hProvider token
+    static ref PROVIDER_GENERATION: AtomicU16 = AtomicU16::new(0);
+
+    // holds blocked threads and the blocking call each of them is in
+    static ref BLOCKED_THREADS: Mutex<HashMap<DWORD, BlockingCall>> = Mutex::new(HashMap::new());
+
+    // holds the number of synchronous calls holding a window, from before their request is issued to the end of the wait
+    static ref BLOCKING_CALLS: AtomicUsize = AtomicUsize::new(0);
 
     // holds application defined blocking hook
     static ref BLOCKING_HOOK: AtomicPtr<XFSBLOCKINGHOOK> = AtomicPtr::new(ptr::null_mut());
+
+    // holds loaded provider libraries by lower case path, shared by all services opened on them
+    static ref PROVIDERS: Mutex<HashMap<String, Weak<libloading::Library>>> = Mutex::new(HashMap::new());
+
+    // holds the services still open when their application handle was destroyed or at WFSCleanUp, since WFSStartUp
+    static ref LEAKED: Mutex<Vec<HSERVICE>> = Mutex::new(Vec::new());
+
+    // holds the provider DLL each logical service resolved to
+    static ref PROVIDER_CACHE: Mutex<ProviderCache<'static>> = Mutex::new(ProviderCache::new(&CONFIG_API, provider_cache::ttl()));
 }
 
+/// When set, WFPUnloadService is called on providers that failed to close.
+const UNLOAD_ON_CLOSE_ERROR_ENV: &str = "XFS_UNLOAD_ON_CLOSE_ERROR";
+
+/// Trace level bits that are forced on for every service, decimal or 0x prefixed hex.
+/// Lets operators raise tracing during an incident regardless of what the applications request.
+const TRACE_LEVEL_FLOOR_ENV: &str = "XFS_TRACE_LEVEL_FLOOR";
+
+/// Maximum number of synchronous calls waiting at the same time, unlimited when unset or 0.
+/// Every waiting thread holds a window of its own and pumps its messages, so an application spawning threads without
+/// bound would otherwise run the process out of window handles. Calls beyond the limit fail with
+/// WFS_ERR_INTERNAL_ERROR rather than WFS_ERR_OUT_OF_MEMORY, which WFSGetInfo takes for the provider's and retries.
+const MAX_BLOCKING_CALLS_ENV: &str = "XFS_MAX_BLOCKING_CALLS";
+
+/// Time a released service is kept loaded waiting for completions of requests still in flight.
+const DRAIN_GRACE_PERIOD: Duration = Duration::from_secs(5);
+
+/// Number of times WFSGetInfo is retried while the provider reports WFS_ERR_DEV_NOT_READY, 0 when unset.
+/// Providers often report not ready for a while after the open as the hardware initializes.
+const NOT_READY_RETRIES_ENV: &str = "XFS_NOT_READY_RETRIES";
+
+/// Delay before the first retry of a request the provider was not ready for, doubled with every retry
+/// up to [`NOT_READY_MAX_BACKOFF`].
+const NOT_READY_BACKOFF: Duration = Duration::from_millis(50);
+
+/// Longest delay between the retries of a request the provider was not ready for.
+const NOT_READY_MAX_BACKOFF: Duration = Duration::from_secs(2);
+
+/// Number of times WFSGetInfo is reissued after the provider failed to allocate the result.
+/// The SPI has no way to pass a size hint, but the buffers of the failed attempt are freed before the retry,
+/// which is often what the provider was short of. Applications with providers that take a hint in lpQueryDetails
+/// use [`xfslib::conv::query_with_size_hint`].
+const OUT_OF_MEMORY_RETRIES: u32 = 1;
+
+/// Provider loaded in place of the DLL of providers configured with `isolated = 1`, see [`is_isolated`].
+const ISOLATED_PROVIDER: &str = "xfs_isolate.dll";
+
+/// Versions of the API, and of the SPI passed on to the providers, the manager supports.
+const SUPPORTED_VERSIONS: VersionRange = VersionRange::new_explicit(Version::new_explicit(2, 0), Version::new_explicit(3, 30));
+
 /// Asserts that the WFSStartup function has been called.
 macro_rules! assert_started {
     () => {
@@ -58,41 +133,49 @@ macro_rules! assert_started {
     };
 }
 
-/// Asserts that the current thread id does not have a blocking call in progress.
-macro_rules! assert_unblocked {
-    () => {{
-        let thread_id = unsafe { GetCurrentThreadId() };
+/// Rejects the call with WFS_ERR_INVALID_POINTER if any of the out-parameters the manager writes is not writable.
+macro_rules! assert_writable {
+    ($($ptr:expr),+) => {
+        if $(!is_writable($ptr))||+ {
+            xfs_reject!(WFS_ERR_INVALID_POINTER);
+        }
+    };
+}
 
-        if xfs_unwrap!(BLOCKED_THREADS.lock()).contains_key(&thread_id) {
-            xfs_reject!(WFS_ERR_OP_IN_PROGRESS);
+/// Unwraps the lookup of a provider export. A provider that does not export the function does not support
+/// the operation, so this rejects with WFS_ERR_UNSUPP_COMMAND rather than WFS_ERR_INTERNAL_ERROR.
+macro_rules! spi_unwrap {
+    ($l:expr) => {
+        match $l {
+            Ok(symbol) => symbol,
+            Err(error) => {
+                error!("{:?}", error);
+                return WFS_ERR_UNSUPP_COMMAND;
+            }
         }
-    }};
+    };
 }
 
-/// Asserts that the current thread id does not have a blocking call in progress and sets the blocking status to true.
-macro_rules! block_thread {
+/// Asserts that the current thread id does not have a blocking call in progress.
+/// The rejection is logged with the blocking call, which usually points straight at the nested call.
+macro_rules! assert_unblocked {
     () => {{
         let thread_id = unsafe { GetCurrentThreadId() };
-        let mut blocked_threads = xfs_unwrap!(BLOCKED_THREADS.lock());
 
-        if blocked_threads.contains_key(&thread_id) {
+        if let Some(call) = xfs_unwrap!(BLOCKED_THREADS.lock()).get(&thread_id) {
+            warn!("Thread {thread_id} is blocked in {call}, rejecting the nested call");
             xfs_reject!(WFS_ERR_OP_IN_PROGRESS);
-        } else {
-            blocked_threads.insert(thread_id, false);
         }
     }};
 }
 
-/// Gets a service by handle and increments the request id
-macro_rules! get_service_req {
-    ($hService:expr, $services:expr) => {{
+/// Gets a service by handle if `accepts` the state it is in
+macro_rules! get_service {
+    ($hService:expr, $services:expr, $accepts:expr) => {{
         match $hService {
             0 => xfs_reject!(WFS_ERR_INVALID_HSERVICE),
-            _ => match $services.get_mut($hService as usize - 1).and_then(|service| service.as_mut()) {
-                Some(service) => {
-                    service.request_id += 1;
-                    service
-                }
+            _ => match $services.get_mut($hService as usize - 1).and_then(|service| service.as_mut()).filter(|service| $accepts(service)) {
+                Some(service) => service,
                 None => xfs_reject!(WFS_ERR_INVALID_HSERVICE),
             },
         }
@@ -102,8 +185,194 @@ macro_rules! get_service_req {
 struct Service {
     service_id: HSERVICE,
     request_id: u32,
-    library: libloading::Library,
-    trace_level: DWORD,
+    // logical service name the service was opened with
+    logical_name: String,
+    // application handle the service was opened under, 0 for none
+    app: usize,
+    library: Arc<libloading::Library>,
+    trace_level: TraceLevel,
+    // released by the application, kept alive until the provider's late completions arrive
+    draining: bool,
+    // open requested, becomes usable once the provider completes the open successfully
+    opening: bool,
+    // close requested, usable again only if the close is cancelled
+    closing: bool,
+    // lock granted by the provider and not released since, other services of the logical service cannot execute
+    locked: bool,
+    // event registrations the provider accepted, a redundant WFSRegister or WFSDeregister is answered from these
+    registrations: Registrations,
+    // serializes the calls into providers that are not reentrant, None for reentrant ones
+    dispatch: Option<Arc<Dispatch>>,
+    // provider completes WFPRegister and WFPDeregister synchronously, WFSRegister and WFSDeregister do not wait
+    sync_register: bool,
+    // shortest timeout in milliseconds passed to the provider, 0 for none
+    timeout_floor: DWORD,
+    // hProvider token passed to WFPOpen, WFMReleaseDLL only accepts this exact value
+    provider: usize,
+}
+
+impl Service {
+    /// Whether the service accepts new requests.
+    fn is_active(&self) -> bool {
+        !self.draining && !self.opening && !self.closing
+    }
+
+    /// Whether requests of the service may be cancelled. The open and the close are requests as well, so only a
+    /// released service is out of reach.
+    fn is_cancellable(&self) -> bool {
+        !self.draining
+    }
+
+    /// Issues the hProvider token for a service opened in the slot: a fresh generation in the high word,
+    /// index + 1 in the low word. A token of an earlier service in the same slot never matches.
+    fn provider_token(index: usize) -> usize {
+        let generation = PROVIDER_GENERATION.fetch_add(1, Ordering::SeqCst);
+        ((generation as usize) << 16) | (index + 1)
+    }
+
+    /// Gets the slot index an hProvider token was issued for.
+    fn provider_index(h_provider: HPROVIDER) -> Option<usize> {
+        (h_provider as usize & 0xFFFF).checked_sub(1)
+    }
+}
+
+/// Blocking call a thread is waiting in.
+struct BlockingCall {
+    operation: &'static str,
+    service: Option<HSERVICE>,
+    // set by WFSCancelBlockingCall, the wait ends with WFS_ERR_CANCELED
+    canceled: bool,
+}
+
+impl BlockingCall {
+    /// Gets the synchronous function that waits for the completion message.
+    fn operation(message: u32) -> &'static str {
+        match message {
+            WFS_OPEN_COMPLETE => "WFSOpen",
+            WFS_CLOSE_COMPLETE => "WFSClose",
+            WFS_LOCK_COMPLETE => "WFSLock",
+            WFS_UNLOCK_COMPLETE => "WFSUnlock",
+            WFS_REGISTER_COMPLETE => "WFSRegister",
+            WFS_DEREGISTER_COMPLETE => "WFSDeregister",
+            WFS_GETINFO_COMPLETE => "WFSGetInfo",
+            WFS_EXECUTE_COMPLETE => "WFSExecute",
+            _ => "unknown call",
+        }
+    }
+}
+
+impl fmt::Display for BlockingCall {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.service {
+            Some(service) => write!(f, "{} on service {service}", self.operation),
+            None => write!(f, "{}", self.operation),
+        }
+    }
+}
+
+/// Registers the current thread as blocked in a call for as long as it lives.
+struct Blocking(DWORD);
+
+impl Blocking {
+    fn new(call: BlockingCall) -> Result<Self, HRESULT> {
+        let thread_id = unsafe { GetCurrentThreadId() };
+        let mut blocked_threads = BLOCKED_THREADS.lock().map_err(|error| {
+            error!("{:?}", error);
+            WFS_ERR_INTERNAL_ERROR
+        })?;
+        if let Some(blocking) = blocked_threads.get(&thread_id) {
+            warn!("Thread {thread_id} is blocked in {blocking}, rejecting {call}");
+            return Err(WFS_ERR_OP_IN_PROGRESS);
+        }
+        blocked_threads.insert(thread_id, call);
+        Ok(Blocking(thread_id))
+    }
+
+    fn canceled(&self) -> bool {
+        match BLOCKED_THREADS.lock() {
+            Ok(blocked_threads) => blocked_threads.get(&self.0).is_some_and(|call| call.canceled),
+            Err(error) => {
+                error!("{:?}", error);
+                false
+            }
+        }
+    }
+}
+
+impl Drop for Blocking {
+    fn drop(&mut self) {
+        match BLOCKED_THREADS.lock() {
+            Ok(mut blocked_threads) => {
+                blocked_threads.remove(&self.0);
+            }
+            Err(error) => error!("{:?}", error),
+        }
+    }
+}
+
+/// Counts a synchronous call against [`MAX_BLOCKING_CALLS_ENV`] for as long as it lives.
+struct BlockingSlot;
+
+impl BlockingSlot {
+    fn acquire(message: u32) -> Result<Self, HRESULT> {
+        let limit = max_blocking_calls();
+        let admitted = BLOCKING_CALLS.fetch_update(Ordering::SeqCst, Ordering::SeqCst, |calls| (limit == 0 || calls < limit).then_some(calls + 1));
+        if admitted.is_err() {
+            warn!("{limit} synchronous calls are waiting already, rejecting {}", BlockingCall::operation(message));
+            return Err(WFS_ERR_INTERNAL_ERROR);
+        }
+        Ok(BlockingSlot)
+    }
+}
+
+impl Drop for BlockingSlot {
+    fn drop(&mut self) {
+        BLOCKING_CALLS.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Reads the limit of synchronous calls waiting at the same time, see [`MAX_BLOCKING_CALLS_ENV`].
+fn max_blocking_calls() -> usize {
+    match std::env::var(MAX_BLOCKING_CALLS_ENV) {
+        Ok(limit) => limit.trim().parse().unwrap_or_else(|error| {
+            error!("Invalid {MAX_BLOCKING_CALLS_ENV} {limit:?}: {error}");
+            0
+        }),
+        Err(_) => 0,
+    }
+}
+
+/// Application handle slot. The generation is bumped whenever the slot is released,
+/// so a handle from before the release never matches the handle handed out after it.
+#[derive(Clone, Copy, Default)]
+struct AppHandle {
+    active: bool,
+    generation: u16,
+}
+
+impl AppHandle {
+    /// Encodes the slot as an HAPP: generation in the high word, index + 1 in the low word.
+    fn to_happ(self, index: usize) -> HAPP {
+        (((self.generation as usize) << 16) | (index + 1)) as HAPP
+    }
+
+    /// Splits an HAPP into slot index and generation.
+    fn from_happ(h_app: HAPP) -> Option<(usize, u16)> {
+        let value = h_app as usize;
+        let index = (value & 0xFFFF).checked_sub(1)?;
+        Some((index, (value >> 16) as u16))
+    }
+
+    /// Returns the slot index of an HAPP that was created and not destroyed since.
+    fn find(handles: &[AppHandle], h_app: HAPP) -> Option<usize> {
+        let (index, generation) = Self::from_happ(h_app)?;
+        handles.get(index).filter(|h| h.active && h.generation == generation).map(|_| index)
+    }
+
+    fn release(&mut self) {
+        self.active = false;
+        self.generation = self.generation.wrapping_add(1);
+    }
 }
 
 #[allow(non_snake_case)]
@@ -111,21 +380,25 @@ struct Service {
 #[logfn(TRACE)]
 #[logfn_inputs(TRACE)]
 pub extern "stdcall" fn WFSCancelAsyncRequest(hService: HSERVICE, RequestID: REQUESTID) -> HRESULT {
-    assert_started!();
-    // assert_unblocked!();
-
-    if hService == 0 {
-        xfs_reject!(WFS_ERR_INVALID_HSERVICE);
-    }
-
-    let services = xfs_unwrap!(SERVICES.lock());
-    let service = match services.get(hService as usize - 1).and_then(|service| service.as_ref()) {
-        Some(service) => service,
-        None => xfs_reject!(WFS_ERR_INVALID_HSERVICE),
-    };
-    let cancel = unsafe { xfs_unwrap!(service.library.get::<spi::WfpCancelAsyncRequest>(b"WFPCancelAsyncRequest")) };
-
-    cancel(hService, RequestID)
+    last_error::track("WFSCancelAsyncRequest", hService, || {
+        assert_started!();
+        assert_unblocked!();
+
+        with_provider::<spi::WfpCancelAsyncRequest>(
+            hService,
+            b"WFPCancelAsyncRequest",
+            Service::is_cancellable,
+            |_| {},
+            |cancel| {
+                let result = cancel(hService, RequestID);
+                if result == WFS_SUCCESS {
+                    // Not every provider posts the cancel completion, the relay posts it on their behalf
+                    relay::cancel(hService, RequestID);
+                }
+                result
+            },
+        )
+    })
 }
 
 #[allow(non_snake_case)]
@@ -133,34 +406,87 @@ pub extern "stdcall" fn WFSCancelAsyncRequest(hService: HSERVICE, RequestID: REQ
 #[logfn(TRACE)]
 #[logfn_inputs(TRACE)]
 pub extern "stdcall" fn WFSCancelBlockingCall(dwThreadID: DWORD) -> HRESULT {
-    assert_started!();
-
-    let thread_id = match dwThreadID {
-        0 => unsafe { GetCurrentThreadId() },
-        _ => dwThreadID,
-    };
+    last_error::track("WFSCancelBlockingCall", 0, || {
+        assert_started!();
 
-    let mut blocks = xfs_unwrap!(BLOCKED_THREADS.lock());
+        let thread_id = match dwThreadID {
+            0 => unsafe { GetCurrentThreadId() },
+            _ => dwThreadID,
+        };
 
-    if blocks.contains_key(&thread_id) {
-        blocks.insert(thread_id, true);
-    }
+        if let Some(call) = xfs_unwrap!(BLOCKED_THREADS.lock()).get_mut(&thread_id) {
+            call.canceled = true;
+        }
 
-    WFS_SUCCESS
+        WFS_SUCCESS
+    })
 }
 
-// TODO: Close service providers
+/// Closes the services the application left open and resets the manager.
+///
+/// A provider may call back into the manager from WFPClose. The services lock is never held while a provider
+/// runs, and a WFSCleanUp nested in such a callback is rejected with WFS_ERR_OP_IN_PROGRESS.
+///
+/// Clean up is rejected with WFS_ERR_OP_IN_PROGRESS as well while another thread is blocked in a synchronous call,
+/// tearing down the services would pull them from under that thread. The application cancels the call with
+/// WFSCancelBlockingCall and cleans up once the thread has returned.
+///
+/// Without a WFSStartUp to balance, clean up fails with WFS_ERR_NOT_STARTED, so a second WFSCleanUp finds nothing to
+/// tear down.
+///
+/// The support DLL is cleaned up after the services are closed and before the manager considers itself shut down, so
+/// none of its timers fires into the application afterwards. `xfs_supp.dll` and `xfs_conf.dll` themselves stay loaded
+/// as long as the manager is, completions straggling in after clean up are still freed through them.
 #[allow(non_snake_case)]
 #[no_mangle]
 #[logfn(TRACE)]
 #[logfn_inputs(TRACE)]
 pub extern "stdcall" fn WFSCleanUp() -> HRESULT {
-    // assert_unblocked!();
+    last_error::track("WFSCleanUp", 0, || {
+        assert_started!();
+        assert_unblocked!();
+        // The calling thread is not blocked, so any blocked thread is another one
+        if let Some((thread_id, call)) = xfs_unwrap!(BLOCKED_THREADS.lock()).iter().next() {
+            warn!("Thread {thread_id} is blocked in {call}, rejecting WFSCleanUp");
+            xfs_reject!(WFS_ERR_OP_IN_PROGRESS);
+        }
+        if CLEANING_UP.swap(true, Ordering::SeqCst) {
+            xfs_reject!(WFS_ERR_OP_IN_PROGRESS);
+        }
+        let result = clean_up();
+        CLEANING_UP.store(false, Ordering::SeqCst);
+        // the application may unload the manager or exit right after
+        flush_logs();
+        result
+    })
+}
+
+fn clean_up() -> HRESULT {
+    let open: Vec<HSERVICE> = xfs_unwrap!(SERVICES.lock()).iter().flatten().filter(|s| s.is_active()).map(|s| s.service_id).collect();
+    record_leaks(open.clone(), "WFSCleanUp was called");
+    for service_id in open {
+        let result = WFSClose(service_id);
+        if result != WFS_SUCCESS {
+            warn!("Failed to close service {service_id} on clean up: {result}");
+        }
+    }
+
+    // A WFSStartUp racing the tear down waits for it, so nothing it clears belongs to the next start up
+    let _lifecycle = xfs_unwrap!(LIFECYCLE.lock());
+    // SAFETY: the support DLL stays loaded for the lifetime of the manager
+    let result = unsafe { (XFS_SUPP_CLEANUP)() };
+    if result != WFS_SUCCESS {
+        warn!("Failed to clean up the support DLL: {result}");
+    }
     STARTED.store(false, Ordering::SeqCst);
     BLOCKING_HOOK.store(ptr::null_mut(), Ordering::SeqCst);
     xfs_unwrap!(BLOCKED_THREADS.lock()).clear();
-    *xfs_unwrap!(APP_HANDLES.lock()) = [false; 8192];
-    xfs_unwrap!(SERVICES.lock()).iter_mut().filter_map(|s| s.take()).for_each(drop);
+    relay::clear();
+    xfs_unwrap!(APP_HANDLES.lock()).iter_mut().filter(|h| h.active).for_each(AppHandle::release);
+
+    // Unloading a provider runs its detach code, which must not find the services lock held
+    let services: Vec<Service> = xfs_unwrap!(SERVICES.lock()).iter_mut().filter_map(|s| s.take()).collect();
+    drop(services);
     WFS_SUCCESS
 }
 
@@ -169,9 +495,11 @@ pub extern "stdcall" fn WFSCleanUp() -> HRESULT {
 #[logfn(TRACE)]
 #[logfn_inputs(TRACE)]
 pub extern "stdcall" fn WFSClose(hService: HSERVICE) -> HRESULT {
-    assert_started!();
-    // block_thread!();
-    call_async(WFS_CLOSE_COMPLETE, |hwnd, reqid| WFSAsyncClose(hService, hwnd, reqid), &mut ptr::null_mut())
+    last_error::track("WFSClose", hService, || {
+        assert_started!();
+        assert_unblocked!();
+        call_async(WFS_CLOSE_COMPLETE, Some(hService), |hwnd, reqid| WFSAsyncClose(hService, hwnd, reqid), ptr::null_mut())
+    })
 }
 
 #[allow(non_snake_case)]
@@ -179,18 +507,132 @@ pub extern "stdcall" fn WFSClose(hService: HSERVICE) -> HRESULT {
 #[logfn(TRACE)]
 #[logfn_inputs(TRACE)]
 pub extern "stdcall" fn WFSAsyncClose(hService: HSERVICE, hWnd: HWND, lpRequestID: LPREQUESTID) -> HRESULT {
-    assert_started!();
-    // assert_unblocked!();
+    last_error::track("WFSAsyncClose", hService, || {
+        assert_started!();
+        assert_writable!(lpRequestID);
+        assert_unblocked!();
+
+        let mut called = false;
+        let result = with_service::<spi::WfpClose>(hService, lpRequestID, b"WFPClose", |wfp_close, request_id| {
+            called = true;
+            // Requests racing the close must not reach a provider that is tearing the service down
+            set_closing(hService, true);
+            // Completions of requests still in flight would otherwise arrive after the slot is gone
+            cancel_in_flight(hService);
+            relay::forward(hService, request_id, hWnd, WFS_CLOSE_COMPLETE, 0, |hwnd| wfp_close(hService, hwnd, request_id))
+        });
+        // No completion follows a close the provider rejected, so it is settled here
+        if called && result != WFS_SUCCESS {
+            close_completed(hService, result);
+        }
+
+        result
+    })
+}
+
+/// Validates the service handle, hands out the next request id through `lp_request_id` and resolves the provider
+/// export `symbol`, then calls `call` with the export and the request id.
+///
+/// The services lock is released before the provider is called, so the provider may call back into the manager.
+/// The library is kept loaded for the duration of the call even if the service is released meanwhile.
+fn with_service<T>(h_service: HSERVICE, lp_request_id: LPREQUESTID, symbol: &[u8], call: impl FnOnce(&T, REQUESTID) -> HRESULT) -> HRESULT {
+    let request_id = Cell::new(0);
+    let next_request = |service: &mut Service| {
+        service.request_id += 1;
+        request_id.set(service.request_id);
+        // SAFETY: the callers check that the request id pointer is writable
+        unsafe { lp_request_id.write(service.request_id) };
+    };
+    with_provider::<T>(h_service, symbol, Service::is_active, next_request, |function| call(function, request_id.get()))
+}
+
+/// Like [`with_service`] for the provider functions that take no request id. The service must be in a state
+/// `accepts`, `update` is applied to it under the services lock before the provider is called.
+fn with_provider<T>(h_service: HSERVICE, symbol: &[u8], accepts: fn(&Service) -> bool, update: impl FnOnce(&mut Service), call: impl FnOnce(&T) -> HRESULT) -> HRESULT {
+    let (library, dispatch) = {
+        let mut services = xfs_unwrap!(SERVICES.lock());
+        let service = get_service!(h_service, services, accepts);
+        update(service);
+        (Arc::clone(&service.library), service.dispatch.clone())
+    };
+
+    // SAFETY: the export is called through the SPI signature of the symbol it was resolved by
+    let function = unsafe { spi_unwrap!(library.get::<T>(symbol)) };
+    let _turn = dispatch.as_ref().map(|dispatch| dispatch.enter());
+    call(&function)
+}
+
+/// Cancels the requests of the service the provider has not completed yet, from within the turn of the close.
+///
+/// The provider's WFPCancelAsyncRequest is called for each of them, and the relay completes those the provider has
+/// not completed within relay::CANCEL_GRACE_PERIOD with WFS_ERR_CANCELED, so no completion is left to arrive after
+/// the slot is released. The close is passed on meanwhile.
+fn cancel_in_flight(service_id: HSERVICE) {
+    let requests = relay::pending_requests(service_id);
+    if requests.is_empty() {
+        return;
+    }
+
+    let library = match SERVICES.lock() {
+        Ok(services) => match services.get((service_id as usize).wrapping_sub(1)).and_then(|service| service.as_ref()) {
+            Some(service) => Arc::clone(&service.library),
+            None => return,
+        },
+        Err(error) => {
+            error!("{:?}", error);
+            return;
+        }
+    };
+    // SAFETY: the export is called through the SPI signature of the symbol it was resolved by
+    let cancel = match unsafe { library.get::<spi::WfpCancelAsyncRequest>(b"WFPCancelAsyncRequest") } {
+        Ok(cancel) => Some(cancel),
+        Err(error) => {
+            error!("{:?}", error);
+            None
+        }
+    };
+    for request_id in requests {
+        if let Some(cancel) = &cancel {
+            trace!("WFPCancelAsyncRequest {request_id} of service {service_id}: {}", cancel(service_id, request_id));
+        }
+        relay::cancel(service_id, request_id);
+    }
+}
+
+/// Frees the manager side of a service whose provider failed to close.
+///
+/// The provider is not going to call WFMReleaseDLL in this case, so the slot is released here.
+/// If XFS_UNLOAD_ON_CLOSE_ERROR is set, WFPUnloadService is called to force the provider cleanup.
+fn release_failed_close(service_id: HSERVICE, result: HRESULT) {
+    error!("Provider failed to close service {service_id}: {result}");
 
-    let mut services = xfs_unwrap!(SERVICES.lock());
-    let service = get_service_req!(hService, services);
+    if service_id == 0 {
+        return;
+    }
 
-    let wfp_close = unsafe {
-        lpRequestID.write(service.request_id as u32);
-        xfs_unwrap!(service.library.get::<spi::WfpClose>(b"WFPClose"))
+    let service = match SERVICES.lock() {
+        Ok(mut services) => retire(&mut services, service_id as usize - 1),
+        Err(error) => {
+            error!("{:?}", error);
+            return;
+        }
     };
 
-    wfp_close(hService, hWnd, unsafe { *lpRequestID })
+    release_proxies();
+
+    // The provider may be unloaded here, which must not happen under the services lock
+    if let Some(service) = service {
+        // Other services may still use the same provider
+        if std::env::var_os(UNLOAD_ON_CLOSE_ERROR_ENV).is_some() && Arc::strong_count(&service.library) == 1 {
+            trace!("WFPUnloadService: {}", unload_service(&service.library));
+        }
+    }
+}
+
+/// Calls WFPUnloadService of the provider.
+fn unload_service(library: &libloading::Library) -> HRESULT {
+    let unload = unsafe { spi_unwrap!(library.get::<spi::WFPUnloadService>(b"WFPUnloadService")) };
+    unload()
 }
 
 /// Requests a new, unique application handle value.
@@ -203,32 +645,33 @@ pub extern "stdcall" fn WFSAsyncClose(hService: HSERVICE, hWnd: HWND, lpRequestI
 ///
 /// # Note:
 /// As per section Section 4.5, neither service nor application handles may be shared among two or more applications.
-/// This algorithm makes use of the uniqueness of system pointers.
-/// It takes the base pointer of array in the process memory and adds offset to it,
-/// thus making it unique for each application process and/or thread.
+/// The handle combines the slot index with a per slot generation counter,
+/// so a slot reused after WFSDestroyAppHandle never yields the value of a destroyed handle.
 #[allow(non_snake_case)]
 #[no_mangle]
 #[logfn(TRACE)]
 #[logfn_inputs(TRACE)]
 pub extern "stdcall" fn WFSCreateAppHandle(lphApp: LPHAPP) -> HRESULT {
-    assert_started!();
-    // assert_unblocked!();
+    last_error::track("WFSCreateAppHandle", 0, || {
+        assert_started!();
+        assert_writable!(lphApp);
+        assert_unblocked!();
 
-    let mut handles = xfs_unwrap!(APP_HANDLES.lock());
+        let mut handles = xfs_unwrap!(APP_HANDLES.lock());
 
-    let free = match handles.iter().position(|h| !h) {
-        Some(index) => index,
-        None => xfs_reject!(WFS_ERR_INTERNAL_ERROR),
-    };
+        let free = match handles.iter().position(|h| !h.active) {
+            Some(index) => index,
+            None => xfs_reject!(WFS_ERR_INTERNAL_ERROR),
+        };
 
-    handles[free] = true;
-    let ptr = (&*handles) as *const _ as usize + free;
+        handles[free].active = true;
 
-    unsafe {
-        lphApp.write(ptr as HAPP);
-    }
+        unsafe {
+            lphApp.write(handles[free].to_happ(free));
+        }
 
-    WFS_SUCCESS
+        WFS_SUCCESS
+    })
 }
 
 #[allow(non_snake_case)]
@@ -236,13 +679,13 @@ pub extern "stdcall" fn WFSCreateAppHandle(lphApp: LPHAPP) -> HRESULT {
 #[logfn(TRACE)]
 #[logfn_inputs(TRACE)]
 pub extern "stdcall" fn WFSDeregister(hService: HSERVICE, dwEventClass: DWORD, hWndReg: HWND) -> HRESULT {
-    assert_started!();
-    // block_thread!();
-    call_async(
-        WFS_DEREGISTER_COMPLETE,
-        |hwnd, request_id| WFSAsyncDeregister(hService, dwEventClass, hWndReg, hwnd, request_id),
-        &mut ptr::null_mut(),
-    )
+    last_error::track("WFSDeregister", hService, || {
+        assert_started!();
+        assert_unblocked!();
+        call_async_or_sync(WFS_DEREGISTER_COMPLETE, hService, |hwnd, request_id| {
+            WFSAsyncDeregister(hService, dwEventClass, hWndReg, hwnd, request_id)
+        })
+    })
 }
 
 #[allow(non_snake_case)]
@@ -250,18 +693,33 @@ pub extern "stdcall" fn WFSDeregister(hService: HSERVICE, dwEventClass: DWORD, h
 #[logfn(TRACE)]
 #[logfn_inputs(TRACE)]
 pub extern "stdcall" fn WFSAsyncDeregister(hService: HSERVICE, dwEventClass: DWORD, hWndReg: HWND, hWnd: HWND, lpRequestID: LPREQUESTID) -> HRESULT {
-    assert_started!();
-    // assert_unblocked!();
-
-    let mut services = xfs_unwrap!(SERVICES.lock());
-    let service = get_service_req!(hService, services);
-
-    let wfp_deregister = unsafe {
-        lpRequestID.write(service.request_id as u32);
-        xfs_unwrap!(service.library.get::<spi::WFPDeregister>(b"WFPDeregister"))
-    };
-
-    wfp_deregister(hService, dwEventClass, hWndReg, hWnd, unsafe { *lpRequestID })
+    last_error::track("WFSAsyncDeregister", hService, || {
+        assert_started!();
+        assert_writable!(lpRequestID);
+        assert_unblocked!();
+
+        with_service::<spi::WFPDeregister>(hService, lpRequestID, b"WFPDeregister", |wfp_deregister, request_id| {
+            let change = Change::Deregister {
+                window: hWndReg as usize,
+                classes: dwEventClass,
+            };
+            match request_registration(hService, request_id, change) {
+                Ok(true) => {}
+                Ok(false) => {
+                    trace!("Window {hWndReg:?} is not registered for event classes {dwEventClass:#x} of service {hService}");
+                    return complete_locally(hService, request_id, hWnd, WFS_DEREGISTER_COMPLETE);
+                }
+                Err(error) => return error,
+            }
+            let result = relay::forward(hService, request_id, hWnd, WFS_DEREGISTER_COMPLETE, 0, |hwnd| {
+                wfp_deregister(hService, dwEventClass, relay::proxy(hWndReg), hwnd, request_id)
+            });
+            if result != WFS_SUCCESS {
+                registration_completed(hService, request_id, result);
+            }
+            result
+        })
+    })
 }
 
 /// Makes the specified application handle invalid.
@@ -274,23 +732,27 @@ pub extern "stdcall" fn WFSAsyncDeregister(hService: HSERVICE, dwEventClass: DWO
 #[logfn(TRACE)]
 #[logfn_inputs(TRACE)]
 pub extern "stdcall" fn WFSDestroyAppHandle(hApp: HAPP) -> HRESULT {
-    assert_started!();
-    // assert_unblocked!();
-
-    if hApp.is_null() {
-        return WFS_ERR_INVALID_APP_HANDLE;
-    }
+    last_error::track("WFSDestroyAppHandle", 0, || {
+        assert_started!();
+        assert_unblocked!();
 
-    let mut handles = xfs_unwrap!(APP_HANDLES.lock());
-    let ptr = (&*handles) as *const _ as usize;
-    let index = hApp as usize - ptr;
+        let mut handles = xfs_unwrap!(APP_HANDLES.lock());
 
-    match handles.get_mut(index) {
-        Some(h) => *h = false,
-        None => xfs_reject!(WFS_ERR_INVALID_APP_HANDLE),
-    }
-
-    WFS_SUCCESS
+        match AppHandle::find(&handles[..], hApp) {
+            Some(index) => handles[index].release(),
+            None => xfs_reject!(WFS_ERR_INVALID_APP_HANDLE),
+        }
+        drop(handles);
+
+        let open = xfs_unwrap!(SERVICES.lock())
+            .iter()
+            .flatten()
+            .filter(|s| s.is_active() && s.app == hApp as usize)
+            .map(|s| s.service_id)
+            .collect();
+        record_leaks(open, &format!("application handle {hApp:?} was destroyed"));
+        WFS_SUCCESS
+    })
 }
 
 #[allow(non_snake_case)]
@@ -298,13 +760,17 @@ pub extern "stdcall" fn WFSDestroyAppHandle(hApp: HAPP) -> HRESULT {
 #[logfn(TRACE)]
 #[logfn_inputs(TRACE)]
 pub extern "stdcall" fn WFSExecute(hService: HSERVICE, dwCommandd: DWORD, lpCmdData: LPVOID, dwTimeOut: DWORD, lppResult: *mut LPWFSRESULT) -> HRESULT {
-    assert_started!();
-    // block_thread!();
-    call_async(
-        WFS_EXECUTE_COMPLETE,
-        |hwnd, request_id| WFSAsyncExecute(hService, dwCommandd, lpCmdData, dwTimeOut, hwnd, request_id),
-        lppResult,
-    )
+    last_error::track("WFSExecute", hService, || {
+        assert_started!();
+        assert_writable!(lppResult);
+        assert_unblocked!();
+        call_async(
+            WFS_EXECUTE_COMPLETE,
+            Some(hService),
+            |hwnd, request_id| WFSAsyncExecute(hService, dwCommandd, lpCmdData, dwTimeOut, hwnd, request_id),
+            lppResult,
+        )
+    })
 }
 
 #[allow(non_snake_case)]
@@ -312,18 +778,37 @@ pub extern "stdcall" fn WFSExecute(hService: HSERVICE, dwCommandd: DWORD, lpCmdD
 #[logfn(TRACE)]
 #[logfn_inputs(TRACE)]
 pub extern "stdcall" fn WFSAsyncExecute(hService: HSERVICE, dwCommand: DWORD, lpCmdData: LPVOID, dwTimeOut: DWORD, hWnd: HWND, lpRequestID: LPREQUESTID) -> HRESULT {
-    assert_started!();
-    // assert_unblocked!();
-
-    let mut services = xfs_unwrap!(SERVICES.lock());
-    let service = get_service_req!(hService, services);
-
-    let wfp_execute = unsafe {
-        lpRequestID.write(service.request_id as u32);
-        xfs_unwrap!(service.library.get::<spi::WFPExecute>(b"WFPExecute"))
-    };
-
-    wfp_execute(hService, dwCommand, lpCmdData, dwTimeOut, hWnd, unsafe { *lpRequestID })
+    last_error::track("WFSAsyncExecute", hService, || {
+        assert_started!();
+        assert_writable!(lpRequestID);
+        assert_unblocked!();
+
+        // Checked before the request is issued, so the provider never sees it and no completion is posted
+        if dwCommand == 0 {
+            xfs_reject!(WFS_ERR_INVALID_COMMAND);
+        }
+        let command = commands::Command(dwCommand);
+        let result = check_strict(command, commands::strict());
+        if result != WFS_SUCCESS {
+            return result;
+        }
+        match lock_owner(hService) {
+            Ok(Some(owner)) => {
+                warn!("Rejecting {command} on service {hService}, service {owner} holds the lock on the device");
+                return WFS_ERR_LOCKED;
+            }
+            Ok(None) => {}
+            Err(error) => return error,
+        }
+        trace!("WFSExecute {command} on service {hService}, lpCmdData {}", traced_data(lpCmdData));
+        let timeout = floor_timeout(hService, dwTimeOut);
+
+        with_service::<spi::WFPExecute>(hService, lpRequestID, b"WFPExecute", |wfp_execute, request_id| {
+            relay::forward(hService, request_id, hWnd, WFS_EXECUTE_COMPLETE, dwCommand, |hwnd| {
+                wfp_execute(hService, dwCommand, lpCmdData, timeout, hwnd, request_id)
+            })
+        })
+    })
 }
 
 #[allow(non_snake_case)]
@@ -331,9 +816,27 @@ pub extern "stdcall" fn WFSAsyncExecute(hService: HSERVICE, dwCommand: DWORD, lp
 #[logfn(TRACE)]
 #[logfn_inputs(TRACE)]
 pub extern "stdcall" fn WFSFreeResult(lpResult: LPWFSRESULT) -> HRESULT {
-    assert_started!();
-    // assert_unblocked!();
-    unsafe { WFMFreeBuffer(lpResult as *mut _) }
+    last_error::track("WFSFreeResult", 0, || {
+        assert_started!();
+        assert_unblocked!();
+        unsafe { WFMFreeBuffer(lpResult as *mut _) }
+    })
+}
+
+/// Frees a result like WFSFreeResult and reports the number of buffers the provider attached to it with
+/// WFMAllocateMore, all of which are freed along with it. Meant for leak auditing tools.
+///
+/// This is a manager extension, not part of the XFS API.
+#[allow(non_snake_case)]
+#[no_mangle]
+#[logfn(TRACE)]
+#[logfn_inputs(TRACE)]
+pub extern "stdcall" fn WFSFreeResultDeep(lpResult: LPWFSRESULT, lpdwChildren: LPDWORD) -> HRESULT {
+    last_error::track("WFSFreeResultDeep", 0, || {
+        assert_started!();
+        assert_unblocked!();
+        unsafe { (WFM_FREE_BUFFER_DEEP)(lpResult as LPVOID, lpdwChildren) }
+    })
 }
 
 #[allow(non_snake_case)]
@@ -341,32 +844,143 @@ pub extern "stdcall" fn WFSFreeResult(lpResult: LPWFSRESULT) -> HRESULT {
 #[logfn(TRACE)]
 #[logfn_inputs(TRACE)]
 pub extern "stdcall" fn WFSGetInfo(hService: HSERVICE, dwCategory: DWORD, lpQueryDetails: LPVOID, dwTimeOut: DWORD, lppResult: *mut LPWFSRESULT) -> HRESULT {
-    assert_started!();
-    // block_thread!();
-    call_async(
-        WFS_GETINFO_COMPLETE,
-        |hwnd, request_id| WFSAsyncGetInfo(hService, dwCategory, lpQueryDetails, dwTimeOut, hwnd, request_id),
-        lppResult,
-    )
+    last_error::track("WFSGetInfo", hService, || {
+        assert_started!();
+        assert_writable!(lppResult);
+        assert_unblocked!();
+
+        // GetInfo does not change the device, so it is safe to ask again while the device is coming up,
+        // or when the provider could not allocate the result. All attempts share the one timeout.
+        let deadline = deadline(dwTimeOut);
+        let retries = not_ready_retries();
+        let mut backoff = NOT_READY_BACKOFF;
+        let mut attempt = 0;
+        let mut reallocations = 0;
+        let free_previous = || {
+            let previous = unsafe { *lppResult };
+            if !previous.is_null() {
+                unsafe {
+                    WFMFreeBuffer(previous as LPVOID);
+                    lppResult.write(ptr::null_mut());
+                }
+            }
+        };
+        loop {
+            unsafe { lppResult.write(ptr::null_mut()) };
+            let result = call_async(
+                WFS_GETINFO_COMPLETE,
+                Some(hService),
+                |hwnd, request_id| WFSAsyncGetInfo(hService, dwCategory, lpQueryDetails, timeout_left(deadline), hwnd, request_id),
+                lppResult,
+            );
+            if result == WFS_ERR_OUT_OF_MEMORY && reallocations < OUT_OF_MEMORY_RETRIES {
+                warn!("Service {hService} could not allocate category {dwCategory}, retrying");
+                free_previous();
+                reallocations += 1;
+                continue;
+            }
+            if result != WFS_ERR_DEV_NOT_READY || attempt >= retries {
+                return result;
+            }
+            let delay = match deadline {
+                Some(deadline) => backoff.min(deadline.saturating_duration_since(Instant::now())),
+                None => backoff,
+            };
+            if delay.is_zero() {
+                trace!("Service {hService} not ready and no time left to retry category {dwCategory}");
+                return result;
+            }
+
+            trace!("Service {hService} not ready, retrying category {dwCategory} in {delay:?}");
+            free_previous();
+            let waited = block_for(WFS_GETINFO_COMPLETE, Some(hService), delay);
+            if waited != WFS_SUCCESS {
+                return waited;
+            }
+            backoff = (backoff * 2).min(NOT_READY_MAX_BACKOFF);
+            attempt += 1;
+        }
+    })
 }
 
+/// Waits until the provider stops reporting WFS_ERR_DEV_NOT_READY for the category, typically the status
+/// category of the service's device class. Returns WFS_ERR_TIMEOUT if it is still not ready after dwTimeOut ms,
+/// a dwTimeOut of WFS_INDEFINITE_WAIT waits for as long as it takes.
+///
+/// This is a manager extension, not part of the XFS API.
 #[allow(non_snake_case)]
 #[no_mangle]
 #[logfn(TRACE)]
 #[logfn_inputs(TRACE)]
-pub extern "stdcall" fn WFSAsyncGetInfo(hService: HSERVICE, dwCategory: DWORD, lpQueryDetails: LPVOID, dwTimeOut: DWORD, hWnd: HWND, lpRequestID: LPREQUESTID) -> HRESULT {
-    assert_started!();
-    // assert_unblocked!();
+pub extern "stdcall" fn WFMWaitUntilReady(hService: HSERVICE, dwCategory: DWORD, dwTimeOut: DWORD) -> HRESULT {
+    last_error::track("WFMWaitUntilReady", hService, || {
+        assert_started!();
+        manager::wait_until_ready(hService, dwCategory, deadline(dwTimeOut))
+    })
+}
 
-    let mut services = xfs_unwrap!(SERVICES.lock());
-    let service = get_service_req!(hService, services);
+/// Checks that every logical service resolves to a provider DLL that loads and exports the SPI, without opening a
+/// session, and logs the problems found. The number of problems is written to lpdwIssues.
+///
+/// This is a manager extension, not part of the XFS API.
+#[allow(non_snake_case)]
+#[no_mangle]
+#[logfn(TRACE)]
+#[logfn_inputs(TRACE)]
+pub extern "stdcall" fn WFMValidateConfiguration(lpdwIssues: LPDWORD) -> HRESULT {
+    last_error::track("WFMValidateConfiguration", 0, || {
+        assert_writable!(lpdwIssues);
 
-    let wfp_get_info = unsafe {
-        lpRequestID.write(service.request_id as u32);
-        xfs_unwrap!(service.library.get::<spi::WFPGetInfo>(b"WFPGetInfo"))
-    };
+        let issues = match manager::validate_configuration() {
+            Ok(issues) => issues,
+            Err(error) => return error,
+        };
+        for issue in &issues {
+            error!("{issue}");
+        }
+        // SAFETY: the pointer is checked to be writable
+        unsafe { lpdwIssues.write(issues.len() as DWORD) };
+        WFS_SUCCESS
+    })
+}
+
+fn not_ready_retries() -> u32 {
+    match std::env::var(NOT_READY_RETRIES_ENV) {
+        Ok(retries) => retries.trim().parse().unwrap_or_else(|error| {
+            error!("Invalid {NOT_READY_RETRIES_ENV} {retries:?}: {error}");
+            0
+        }),
+        Err(_) => 0,
+    }
+}
 
-    wfp_get_info(hService, dwCategory, lpQueryDetails, dwTimeOut, hWnd, unsafe { *lpRequestID })
+#[allow(non_snake_case)]
+#[no_mangle]
+#[logfn(TRACE)]
+#[logfn_inputs(TRACE)]
+pub extern "stdcall" fn WFSAsyncGetInfo(hService: HSERVICE, dwCategory: DWORD, lpQueryDetails: LPVOID, dwTimeOut: DWORD, hWnd: HWND, lpRequestID: LPREQUESTID) -> HRESULT {
+    last_error::track("WFSAsyncGetInfo", hService, || {
+        assert_started!();
+        assert_writable!(lpRequestID);
+        assert_unblocked!();
+
+        // Checked before the request is issued, so the provider never sees it and no completion is posted
+        if dwCategory == 0 {
+            xfs_reject!(WFS_ERR_INVALID_CATEGORY);
+        }
+        if hService == 0 {
+            // SAFETY: the request id pointer was checked above
+            return manager::get_info(dwCategory, hWnd, unsafe { &mut *lpRequestID });
+        }
+        trace!("WFSGetInfo category {dwCategory} on service {hService}, lpQueryDetails {}", traced_data(lpQueryDetails));
+        let timeout = floor_timeout(hService, dwTimeOut);
+
+        with_service::<spi::WFPGetInfo>(hService, lpRequestID, b"WFPGetInfo", |wfp_get_info, request_id| {
+            relay::forward(hService, request_id, hWnd, WFS_GETINFO_COMPLETE, dwCategory, |hwnd| {
+                wfp_get_info(hService, dwCategory, lpQueryDetails, timeout, hwnd, request_id)
+            })
+        })
+    })
 }
 
 #[allow(non_snake_case)]
@@ -383,9 +997,15 @@ pub extern "stdcall" fn WFSIsBlocking() -> bool {
 #[logfn(TRACE)]
 #[logfn_inputs(TRACE)]
 pub extern "stdcall" fn WFSLock(hService: HSERVICE, dwTimeOut: DWORD, lppResult: *mut LPWFSRESULT) -> HRESULT {
-    assert_started!();
-    // block_thread!();
-    call_async(WFS_LOCK_COMPLETE, |hwnd, request_id| WFSAsyncLock(hService, dwTimeOut, hwnd, request_id), lppResult)
+    last_error::track("WFSLock", hService, || {
+        assert_started!();
+        // The lock result may carry provider data, when the application does not want it, it is freed by the manager
+        if !lppResult.is_null() {
+            assert_writable!(lppResult);
+        }
+        assert_unblocked!();
+        call_async(WFS_LOCK_COMPLETE, Some(hService), |hwnd, request_id| WFSAsyncLock(hService, dwTimeOut, hwnd, request_id), lppResult)
+    })
 }
 
 #[allow(non_snake_case)]
@@ -393,24 +1013,25 @@ pub extern "stdcall" fn WFSLock(hService: HSERVICE, dwTimeOut: DWORD, lppResult:
 #[logfn(TRACE)]
 #[logfn_inputs(TRACE)]
 pub extern "stdcall" fn WFSAsyncLock(hService: HSERVICE, dwTimeOut: DWORD, hWnd: HWND, lpRequestID: LPREQUESTID) -> HRESULT {
-    assert_started!();
-    // assert_unblocked!();
-
-    let mut services = xfs_unwrap!(SERVICES.lock());
-    let service = get_service_req!(hService, services);
-
-    let wfp_lock = unsafe {
-        lpRequestID.write(service.request_id as u32);
-        xfs_unwrap!(service.library.get::<spi::WFPLock>(b"WFPLock"))
-    };
-
-    wfp_lock(hService, dwTimeOut, hWnd, unsafe { *lpRequestID })
+    last_error::track("WFSAsyncLock", hService, || {
+        assert_started!();
+        assert_writable!(lpRequestID);
+        assert_unblocked!();
+        let timeout = floor_timeout(hService, dwTimeOut);
+
+        with_service::<spi::WFPLock>(hService, lpRequestID, b"WFPLock", |wfp_lock, request_id| {
+            relay::forward(hService, request_id, hWnd, WFS_LOCK_COMPLETE, 0, |hwnd| wfp_lock(hService, timeout, hwnd, request_id))
+        })
+    })
 }
 
 /// Initiates a session (a series of service requests terminated with the WFSClose function) between the application and
 /// the specified service. This does not necessarily mean that the hardware is opened. This command will return with
 /// WFS_SUCCESS even if the hardware is inoperable, offline or powered off. The status of the device can be
 /// requested through a WFSGetInfo command.
+///
+/// An open the provider accepted but did not complete by the time the wait ended, on a timeout, a canceled blocking
+/// call or anything else, is rolled back with [`abandon_open`], so no slot is left pending.
 #[allow(non_snake_case)]
 #[no_mangle]
 #[logfn(TRACE)]
@@ -426,26 +1047,42 @@ pub extern "stdcall" fn WFSOpen(
     lpSPIVersion: LPWFSVERSION,
     lphService: LPHSERVICE,
 ) -> HRESULT {
-    assert_started!();
-    call_async(
-        WFS_OPEN_COMPLETE,
-        |hwnd, request_id| {
-            WFSAsyncOpen(
-                lpszLogicalName,
-                hApp,
-                lpszAppID,
-                dwTraceLevel,
-                dwTimeOut,
-                lphService,
-                hwnd,
-                dwSrvcVersionsRequired,
-                lpSrvcVersion,
-                lpSPIVersion,
-                request_id,
-            )
-        },
-        &mut ptr::null_mut(),
-    )
+    last_error::track("WFSOpen", 0, || {
+        assert_started!();
+        assert_unblocked!();
+        let deadline = deadline(dwTimeOut);
+        let request_id = Cell::new(0);
+        let accepted = Cell::new(false);
+        let result = call_async_until(
+            WFS_OPEN_COMPLETE,
+            None,
+            |hwnd, lp_request_id| {
+                let result = WFSAsyncOpen(
+                    lpszLogicalName,
+                    hApp,
+                    lpszAppID,
+                    dwTraceLevel,
+                    dwTimeOut,
+                    lphService,
+                    hwnd,
+                    dwSrvcVersionsRequired,
+                    lpSrvcVersion,
+                    lpSPIVersion,
+                    lp_request_id,
+                );
+                request_id.set(unsafe { *lp_request_id });
+                accepted.set(result == WFS_SUCCESS);
+                result
+            },
+            ptr::null_mut(),
+            deadline,
+        );
+        // A rejected open released its slot already, and a completion settles it before the wait returns it
+        if accepted.get() && result != WFS_SUCCESS {
+            abandon_open(unsafe { *lphService }, request_id.get(), result);
+        }
+        result
+    })
 }
 
 #[allow(non_snake_case)]
@@ -465,109 +1102,312 @@ pub extern "stdcall" fn WFSAsyncOpen(
     lpSPIVersion: LPWFSVERSION,
     lpRequestID: LPREQUESTID,
 ) -> HRESULT {
-    assert_started!();
-    // assert_unblocked!();
+    last_error::track("WFSAsyncOpen", 0, || {
+        assert_started!();
+        assert_unblocked!();
 
-    if lpszLogicalName.is_null() || lpSrvcVersion.is_null() || lpSPIVersion.is_null() || lphService.is_null() || lpRequestID.is_null() {
-        xfs_reject!(WFS_ERR_INVALID_POINTER);
-    }
+        if lpszLogicalName.is_null() {
+            xfs_reject!(WFS_ERR_INVALID_POINTER);
+        }
+        // NULL is the documented handle of applications that don't use application handles
+        if !hApp.is_null() && AppHandle::find(&xfs_unwrap!(APP_HANDLES.lock())[..], hApp).is_none() {
+            xfs_reject!(WFS_ERR_INVALID_APP_HANDLE);
+        }
+        assert_writable!(lpSrvcVersion, lpSPIVersion, lphService, lpRequestID);
+        // Some applications pass the same buffer for both versions, which makes the provider's writes clobber each other
+        if overlaps(lpSrvcVersion, lpSPIVersion) || overlaps(lphService, lpRequestID) {
+            xfs_reject!(WFS_ERR_INVALID_POINTER);
+        }
 
-    fn get_value(root: HKEY, path: CString, name: CString) -> Result<String, HRESULT> {
-        let mut key = ptr::null_mut();
+        let logical_name = xfs_unwrap!(unsafe { CStr::from_ptr(lpszLogicalName) }.to_str());
+        // SAFETY: the application id is optional, a null pointer traces as an empty buffer
+        trace!("Opening {logical_name} for application {}", unsafe { Redacted::c_str(lpszAppID) });
+        let Resolved {
+            provider: lgl_prov_path,
+            dll_name: phy_prov_path,
+        } = match xfs_unwrap!(PROVIDER_CACHE.lock()).resolve(logical_name) {
+            Ok(resolved) => resolved,
+            Err(error) => return error,
+        };
 
-        // SAFETY: the path pointer is function argument, and it is not null
-        unsafe {
-            if WFM_OPEN_KEY(root, path.as_ptr() as *mut _, &mut key) != WFS_SUCCESS {
-                error!("WFM_OPEN_KEY failed");
-                return Err(WFS_ERR_INVALID_SERVPROV);
-            }
-        }
+        let library = match load_provider(if is_isolated(&lgl_prov_path) { ISOLATED_PROVIDER } else { &phy_prov_path }) {
+            Ok(library) => library,
+            Err(error) => return error,
+        };
+        let dispatch = if is_reentrant(&lgl_prov_path) { None } else { Some(Arc::new(Dispatch::default())) };
+        let sync_register = is_sync_register(&lgl_prov_path);
+        let timeout_floor = timeout_floor(&lgl_prov_path);
 
-        let mut value_buffer: Vec<u8> = Vec::with_capacity(MAX_PATH);
-        let mut value_len = MAX_PATH as u32;
+        let spi_range = spi_versions(dwSrvcVersionsRequired);
 
-        // SAFETY:
-        // - the key pointer is not null as the WFM_OPEN_KEY call succeeded
-        // - the value pointer is function argument, and it is not null
-        // - the value buffer pointer is not null as the vector allocated this memory
-        unsafe {
-            if WFM_QUERY_VALUE(key, name.as_ptr() as *mut i8, value_buffer.as_mut_ptr() as *mut _, &mut value_len) != WFS_SUCCESS {
-                WFM_CLOSE_KEY(key);
-                error!("WFM_QUERY_VALUE failed");
-                return Err(WFS_ERR_INVALID_SERVPROV);
-            }
+        // Releases the slot on every way out but an open the provider accepted
+        let mut rollback = OpenRollback {
+            service_id: None,
+            result: WFS_ERR_INTERNAL_ERROR,
+        };
+        let mut services = xfs_unwrap!(SERVICES.lock());
+        let service_index = match services.iter().position(|s| s.is_none()) {
+            Some(index) => index,
+            None => xfs_reject!(WFS_ERR_INTERNAL_ERROR),
+        };
+        let service_id = service_index as u16 + 1;
+        let trace_level = effective_trace_level(dwTraceLevel.into(), trace_level_floor());
+        let provider = Service::provider_token(service_index);
+
+        // The slot is reserved as opening, so it takes no requests until the provider completed the open
+        services[service_index] = Some(Service {
+            service_id,
+            library: Arc::clone(&library),
+            request_id: 1,
+            logical_name: logical_name.to_string(),
+            app: hApp as usize,
+            trace_level,
+            draining: false,
+            opening: true,
+            closing: false,
+            locked: false,
+            registrations: Registrations::default(),
+            dispatch,
+            sync_register,
+            timeout_floor,
+            provider,
+        });
+        rollback.service_id = Some(service_id);
+        // The provider may call back into the manager from WFPOpen
+        drop(services);
+
+        // SAFETY: The service providers are safe to use. All pointers are checked and not null.
+        let result = unsafe {
+            *lphService = service_id;
+            *lpRequestID = 1;
+
+            let open = || {
+                let wfp_open = spi_unwrap!(library.get::<spi::WfpOpen>(b"WFPOpen"));
+                relay::forward(*lphService, *lpRequestID, hWnd, WFS_OPEN_COMPLETE, 0, |hwnd| {
+                    wfp_open(
+                        *lphService,
+                        lpszLogicalName,
+                        hApp,
+                        lpszAppID,
+                        trace_level.bits(),
+                        dwTimeOut,
+                        hwnd,
+                        *lpRequestID,
+                        provider as HPROVIDER,
+                        spi_range.value(),
+                        lpSPIVersion,
+                        dwSrvcVersionsRequired,
+                        lpSrvcVersion,
+                    )
+                })
+            };
+            open()
+        };
+
+        // No completion follows a rejected open, so the slot is released right away
+        if result == WFS_SUCCESS {
+            rollback.service_id = None;
         }
+        rollback.result = result;
+        result
+    })
+}
 
-        // SAFETY: We know that the buffer is at least as large as the value_len.
-        unsafe {
-            value_buffer.set_len(value_len as usize);
+/// Releases the slot WFSAsyncOpen took on every way out but the open the provider accepted, whether the provider
+/// rejected the open or does not export WFPOpen at all. The completion settles the slot of an accepted open.
+struct OpenRollback {
+    // service the slot was taken for, None until then and once the provider accepted the open
+    service_id: Option<HSERVICE>,
+    result: HRESULT,
+}
+
+impl Drop for OpenRollback {
+    fn drop(&mut self) {
+        if let Some(service_id) = self.service_id {
+            open_completed(service_id, self.result);
         }
+    }
+}
 
-        // SAFETY: the key was opened by WFM_OPEN_KEY, so it is a valid pointer.
-        unsafe {
-            WFM_CLOSE_KEY(key);
+/// Makes a service usable once the provider completed its open successfully, or releases the slot otherwise.
+/// Called by the relay before the completion is passed on, so the application never sees a stale slot.
+pub(crate) fn open_completed(service_id: HSERVICE, result: HRESULT) {
+    let mut services = match SERVICES.lock() {
+        Ok(services) => services,
+        Err(error) => {
+            error!("{:?}", error);
+            return;
         }
+    };
 
-        Ok(String::from_utf8(value_buffer).map_err(|error| {
-            error!("{}", error);
-            WFS_ERR_INTERNAL_ERROR
-        })?)
+    // The id comes from the provider's completion, 0 wraps to an index that does not exist
+    let index = (service_id as usize).wrapping_sub(1);
+    match services.get_mut(index).and_then(|service| service.as_mut()) {
+        Some(service) if service.opening && result == WFS_SUCCESS => {
+            service.opening = false;
+            return;
+        }
+        Some(service) if service.opening => {}
+        _ => return,
     }
 
-    let logical_name = xfs_unwrap!(unsafe { CStr::from_ptr(lpszLogicalName) }.to_str());
-    let path = xfs_unwrap!(CString::new(format!("LOGICAL_SERVICES\\{}", logical_name)));
-    let lgl_prov_path = match get_value(WFS_CFG_HKEY_USER_DEFAULT_XFS_ROOT, path, CString::new("provider").unwrap()) {
-        Ok(lgl_prov_path) => lgl_prov_path,
-        Err(error) => return error,
-    };
+    warn!("Provider failed to open service {service_id}: {result}");
+    let service = services[index].take();
+    // The provider may be unloaded here, which must not happen under the services lock
+    drop(services);
+    drop(service);
+}
 
-    let path = xfs_unwrap!(CString::new(format!("SERVICE_PROVIDERS\\{}", lgl_prov_path)));
-    let phy_prov_path = match get_value(WFS_CFG_HKEY_MACHINE_XFS_ROOT, path, CString::new("dllname").unwrap()) {
-        Ok(phy_prov_path) => phy_prov_path,
-        Err(error) => return error,
-    };
+/// Settles a close the provider did not complete successfully. Called by the relay before the completion is passed
+/// on, and by WFSAsyncClose for a close the provider rejected right away.
+///
+/// A cancelled close leaves the service open, so it is usable again. On any other failure the application considers
+/// the handle gone, whether it closed with WFSClose or WFSAsyncClose, so the slot is released.
+pub(crate) fn close_completed(service_id: HSERVICE, result: HRESULT) {
+    match result {
+        WFS_SUCCESS => {}
+        WFS_ERR_CANCELED => set_closing(service_id, false),
+        _ => release_failed_close(service_id, result),
+    }
+}
 
-    // SAFETY: The service providers are safe to use.
-    let library = unsafe { xfs_unwrap!(libloading::Library::new(phy_prov_path)) };
+/// Records the lock the provider granted the service. Called by the relay before the completion is passed on.
+pub(crate) fn lock_completed(service_id: HSERVICE, result: HRESULT) {
+    if result == WFS_SUCCESS {
+        set_locked(service_id, true);
+    }
+}
 
-    let mut services = xfs_unwrap!(SERVICES.lock());
-    let service_index = match services.iter().position(|s| s.is_none()) {
-        Some(index) => index,
-        None => xfs_reject!(WFS_ERR_INTERNAL_ERROR),
-    };
+/// Forgets the lock of the service once the provider released it. Called by the relay before the completion is
+/// passed on.
+pub(crate) fn unlock_completed(service_id: HSERVICE, result: HRESULT) {
+    if result == WFS_SUCCESS {
+        set_locked(service_id, false);
+    }
+}
 
-    services[service_index] = Some(Service {
-        service_id: service_index as u16 + 1,
-        library,
-        request_id: 1,
-        trace_level: dwTraceLevel,
-    });
-    let service = services[service_index].as_ref().unwrap();
+fn set_locked(service_id: HSERVICE, locked: bool) {
+    match SERVICES.lock() {
+        Ok(mut services) => {
+            // The id comes from the provider's completion, 0 wraps to an index that does not exist
+            if let Some(service) = services.get_mut((service_id as usize).wrapping_sub(1)).and_then(|service| service.as_mut()) {
+                service.locked = locked;
+            }
+        }
+        Err(error) => error!("{:?}", error),
+    }
+}
+
+/// Records a registration change the service requested, see [`Registrations::request`]. Returns false if the
+/// change makes no difference, true if the provider has to be asked, including when the service is gone meanwhile.
+fn request_registration(service_id: HSERVICE, request_id: REQUESTID, change: Change) -> Result<bool, HRESULT> {
+    let mut services = SERVICES.lock().map_err(|error| {
+        error!("{:?}", error);
+        WFS_ERR_INTERNAL_ERROR
+    })?;
+    Ok(match services.get_mut((service_id as usize).wrapping_sub(1)).and_then(|service| service.as_mut()) {
+        Some(service) => service.registrations.request(request_id, change),
+        None => true,
+    })
+}
+
+/// Updates the registrations of the service once the provider completed a registration change. Called by the relay
+/// before the completion is passed on, and for changes the provider completed synchronously.
+pub(crate) fn registration_completed(service_id: HSERVICE, request_id: REQUESTID, result: HRESULT) {
+    match SERVICES.lock() {
+        Ok(mut services) => {
+            // The id comes from the provider's completion, 0 wraps to an index that does not exist
+            if let Some(service) = services.get_mut((service_id as usize).wrapping_sub(1)).and_then(|service| service.as_mut()) {
+                service.registrations.completed(request_id, result);
+            }
+        }
+        Err(error) => error!("{:?}", error),
+    }
+    release_proxies();
+}
+
+/// Destroys the event proxies of the application windows no service has registered or is registering any longer.
+/// The proxies are released under the services lock, so a registration recorded meanwhile keeps its proxy.
+fn release_proxies() {
+    match SERVICES.lock() {
+        Ok(services) => {
+            let windows: HashSet<usize> = services.iter().flatten().flat_map(|service| service.registrations.windows()).collect();
+            relay::retain_proxies(|window| windows.contains(&window));
+        }
+        Err(error) => error!("{:?}", error),
+    }
+}
 
-    // SAFETY: The service providers are safe to use. All pointers are checked and not null.
+/// Posts the successful completion of a request the manager answered without asking the provider.
+fn complete_locally(service_id: HSERVICE, request_id: REQUESTID, window: HWND, message: u32) -> HRESULT {
+    // SAFETY: the result is allocated on the XFS heap and handed over to the window
     unsafe {
-        *lphService = service_index as u16 + 1;
-        *lpRequestID = 1;
-
-        let wfp_open = xfs_unwrap!(service.library.get::<spi::WfpOpen>(b"WFPOpen"));
-        let service_handle = ((&*services) as *const _ as HPROVIDER).add(service_index);
-
-        wfp_open(
-            *lphService,
-            lpszLogicalName,
-            hApp,
-            lpszAppID,
-            dwTraceLevel,
-            dwTimeOut,
-            hWnd,
-            *lpRequestID,
-            service_handle,
-            VersionRange::new_explicit(Version::new_explicit(3, 0), Version::new_explicit(3, 30)).value(),
-            lpSPIVersion,
-            dwSrvcVersionsRequired,
-            lpSrvcVersion,
-        )
+        match manager::allocate_result(service_id, request_id, WFS_SUCCESS, 0) {
+            Ok(result) => manager::post_result(window, message, result),
+            Err(error) => error,
+        }
+    }
+}
+
+/// Command data or query details for the trace, with their length if the application allocated them on the XFS heap.
+fn traced_data(data: LPVOID) -> RedactedData {
+    let mut length: ULONG = 0;
+    // called on xfs_supp directly, so tracing leaves the last error of the application alone
+    let length = (!data.is_null() && unsafe { WFM_GET_BUFFER_LENGTH(data, &mut length) } == WFS_SUCCESS).then_some(length as usize);
+    // SAFETY: a buffer on the XFS heap is readable for the length it was allocated with
+    unsafe { RedactedData::new(data, length) }
+}
+
+/// Rejects a command code that belongs to no known service class when `strict` is set, see
+/// [`commands::STRICT_COMMANDS_ENV`].
+fn check_strict(command: commands::Command, strict: bool) -> HRESULT {
+    if strict && command.is_invalid() {
+        warn!("Rejecting {command} in strict mode, it belongs to no known service class");
+        xfs_reject!(WFS_ERR_UNSUPP_COMMAND);
+    }
+    WFS_SUCCESS
+}
+
+/// Finds another service of the same logical service holding the lock on the device. Its provider would reject
+/// the request with WFS_ERR_LOCKED anyway, or worse, run it for a session that does not own the device.
+fn lock_owner(service_id: HSERVICE) -> Result<Option<HSERVICE>, HRESULT> {
+    let services = SERVICES.lock().map_err(|error| {
+        error!("{:?}", error);
+        WFS_ERR_INTERNAL_ERROR
+    })?;
+    let Some(service) = services.get((service_id as usize).wrapping_sub(1)).and_then(|service| service.as_ref()) else {
+        return Ok(None);
+    };
+    Ok(services
+        .iter()
+        .flatten()
+        .find(|other| other.service_id != service_id && other.locked && !other.draining && other.logical_name.eq_ignore_ascii_case(&service.logical_name))
+        .map(|owner| owner.service_id))
+}
+
+/// Marks a service as being closed, so it rejects new requests with WFS_ERR_INVALID_HSERVICE.
+fn set_closing(service_id: HSERVICE, closing: bool) {
+    match SERVICES.lock() {
+        Ok(mut services) => {
+            // The id may come from the provider's completion, 0 wraps to an index that does not exist
+            if let Some(service) = services.get_mut((service_id as usize).wrapping_sub(1)).and_then(|service| service.as_mut()) {
+                service.closing = closing;
+            }
+        }
+        Err(error) => error!("{:?}", error),
+    }
+}
+
+/// Rolls back an open the application stopped waiting for. The provider is asked to cancel the open, its completion
+/// is dropped whenever it arrives and the slot is released right away.
+fn abandon_open(service_id: HSERVICE, request_id: REQUESTID, result: HRESULT) {
+    if !relay::abandon(service_id, request_id) {
+        // The completion arrived in the meantime and already settled the slot
+        return;
     }
+    warn!("Open of service {service_id} ended with {result} before the provider completed it, rolling back");
+    WFSCancelAsyncRequest(service_id, request_id);
+    open_completed(service_id, result);
 }
 
 #[allow(non_snake_case)]
@@ -575,16 +1415,14 @@ pub extern "stdcall" fn WFSAsyncOpen(
 #[logfn(TRACE)]
 #[logfn_inputs(TRACE)]
 pub extern "stdcall" fn WFSRegister(hService: HSERVICE, dwEventClass: DWORD, hWndReg: HWND) -> HRESULT {
-    assert_started!();
-    // block_thread!();
-    if hService == 0 {
-        return WFS_SUCCESS;
-    }
-    call_async(
-        WFS_REGISTER_COMPLETE,
-        |hwnd, request_id| WFSAsyncRegister(hService, dwEventClass, hWndReg, hwnd, request_id),
-        &mut ptr::null_mut(),
-    )
+    last_error::track("WFSRegister", hService, || {
+        assert_started!();
+        assert_unblocked!();
+        if hService == 0 {
+            return WFS_SUCCESS;
+        }
+        call_async_or_sync(WFS_REGISTER_COMPLETE, hService, |hwnd, request_id| WFSAsyncRegister(hService, dwEventClass, hWndReg, hwnd, request_id))
+    })
 }
 
 #[allow(non_snake_case)]
@@ -592,73 +1430,179 @@ pub extern "stdcall" fn WFSRegister(hService: HSERVICE, dwEventClass: DWORD, hWn
 #[logfn(TRACE)]
 #[logfn_inputs(TRACE)]
 pub extern "stdcall" fn WFSAsyncRegister(hService: HSERVICE, dwEventClass: DWORD, hWndReg: HWND, hWnd: HWND, lpRequestID: LPREQUESTID) -> HRESULT {
-    assert_started!();
-    // assert_unblocked!();
-
-    let mut services = xfs_unwrap!(SERVICES.lock());
-    let service = get_service_req!(hService, services);
+    last_error::track("WFSAsyncRegister", hService, || {
+        assert_started!();
+        assert_writable!(lpRequestID);
+        assert_unblocked!();
+
+        with_service::<spi::WFPRegister>(hService, lpRequestID, b"WFPRegister", |wfp_register, request_id| {
+            let change = Change::Register {
+                window: hWndReg as usize,
+                classes: dwEventClass,
+            };
+            match request_registration(hService, request_id, change) {
+                Ok(true) => {}
+                Ok(false) => {
+                    trace!("Window {hWndReg:?} is registered for event classes {dwEventClass:#x} of service {hService} already");
+                    return complete_locally(hService, request_id, hWnd, WFS_REGISTER_COMPLETE);
+                }
+                Err(error) => return error,
+            }
+            let result = relay::forward(hService, request_id, hWnd, WFS_REGISTER_COMPLETE, 0, |hwnd| {
+                wfp_register(hService, dwEventClass, relay::proxy(hWndReg), hwnd, request_id)
+            });
+            if result != WFS_SUCCESS {
+                registration_completed(hService, request_id, result);
+            }
+            result
+        })
+    })
+}
 
-    let wfp_register = unsafe {
-        lpRequestID.write(service.request_id as u32);
-        xfs_unwrap!(service.library.get::<spi::WFPRegister>(b"WFPRegister"))
-    };
+/// Lets only the listed event ids of the service through to hWndReg for every event class in dwEventClass, the
+/// other events of those classes are freed by the manager instead of being posted. A null lpdwEventIDs or a dwCount
+/// of 0 lets all events of the classes through again, which is the default. Filters are kept until the service is
+/// closed and may be set before or after WFSRegister.
+///
+/// This is a manager extension, not part of the XFS API.
+#[allow(non_snake_case)]
+#[no_mangle]
+#[logfn(TRACE)]
+#[logfn_inputs(TRACE)]
+pub extern "stdcall" fn WFMSetEventFilter(hService: HSERVICE, dwEventClass: DWORD, hWndReg: HWND, lpdwEventIDs: LPDWORD, dwCount: DWORD) -> HRESULT {
+    last_error::track("WFMSetEventFilter", hService, || {
+        assert_started!();
+        if hWndReg.is_null() {
+            xfs_reject!(WFS_ERR_INVALID_HWND);
+        }
+        let classes = [
+            (SERVICE_EVENTS, WFS_SERVICE_EVENT),
+            (USER_EVENTS, WFS_USER_EVENT),
+            (SYSTEM_EVENTS, WFS_SYSTEM_EVENT),
+            (EXECUTE_EVENTS, WFS_EXECUTE_EVENT),
+        ];
+        if dwEventClass == 0 || dwEventClass & !(SERVICE_EVENTS | USER_EVENTS | SYSTEM_EVENTS | EXECUTE_EVENTS) != 0 {
+            xfs_reject!(WFS_ERR_INVALID_EVENT_CLASS);
+        }
+        let event_ids = if lpdwEventIDs.is_null() || dwCount == 0 {
+            None
+        } else {
+            let last = lpdwEventIDs.wrapping_add(dwCount as usize - 1);
+            if !is_readable(lpdwEventIDs) || !is_readable(last) {
+                xfs_reject!(WFS_ERR_INVALID_POINTER);
+            }
+            // SAFETY: both ends of the array are checked to be readable
+            Some(unsafe { std::slice::from_raw_parts(lpdwEventIDs, dwCount as usize) }.iter().copied().collect::<HashSet<_>>())
+        };
+        {
+            let services = xfs_unwrap!(SERVICES.lock());
+            let service = services.get((hService as usize).wrapping_sub(1)).and_then(|service| service.as_ref());
+            if !service.is_some_and(Service::is_active) {
+                xfs_reject!(WFS_ERR_INVALID_HSERVICE);
+            }
+        }
 
-    wfp_register(hService, dwEventClass, hWndReg, hWnd, unsafe { *lpRequestID })
+        for (_, message) in classes.iter().filter(|(class, _)| dwEventClass & class != 0) {
+            if let Err(error) = relay::set_filter(hWndReg, hService, *message, event_ids.clone()) {
+                return error;
+            }
+        }
+        WFS_SUCCESS
+    })
 }
 
 #[allow(non_snake_case)]
 #[no_mangle]
 #[logfn(TRACE)]
 pub extern "stdcall" fn WFSSetBlockingHook(lpBlockFunc: *mut XFSBLOCKINGHOOK, lppPrevFunc: *mut *mut XFSBLOCKINGHOOK) -> HRESULT {
-    assert_started!();
-    // assert_unblocked!();
-    let previous = BLOCKING_HOOK.swap(lpBlockFunc, Ordering::SeqCst);
-    if !previous.is_null() {
-        unsafe { lppPrevFunc.write(previous) };
-    }
-    WFS_SUCCESS
+    last_error::track("WFSSetBlockingHook", 0, || {
+        assert_started!();
+        assert_unblocked!();
+        let previous = BLOCKING_HOOK.swap(lpBlockFunc, Ordering::SeqCst);
+        if !previous.is_null() {
+            unsafe { lppPrevFunc.write(previous) };
+        }
+        WFS_SUCCESS
+    })
 }
 
+/// Starts the manager for the process.
+///
+/// As the XFS specification has it, the start up belongs to the process rather than the calling thread and is not
+/// counted: any further WFSStartUp, from the same thread or another one, fails with WFS_ERR_ALREADY_STARTED and
+/// leaves the manager as it is, its blocking hook and blocked threads included. Only WFSCleanUp resets them, after
+/// which the next WFSStartUp starts afresh.
 #[allow(non_snake_case)]
 #[no_mangle]
 #[logfn(TRACE)]
 #[logfn_inputs(TRACE)]
 pub extern "stdcall" fn WFSStartUp(dwVersionsRequired: DWORD, lpWFSVersion: LPWFSVERSION) -> HRESULT {
-    // TODO: debug why diebold works with pointer here
-    // let range = VersionRange::new(dwVersionsRequired);
-    // if range.start > range.end {
-    //     xfs_reject!(WFS_ERR_INTERNAL_ERROR);
-    // }
-    // if range.start > Version::new_explicit(3, 30) {
-    //     xfs_reject!(WFS_ERR_API_VER_TOO_HIGH);
-    // }
-    // if range.end < Version::new_explicit(2, 00) {
-    //     xfs_reject!(WFS_ERR_API_VER_TOO_LOW);
-    // }
-    // if lpWFSVersion.is_null() {
-    //     xfs_reject!(WFS_ERR_INVALID_POINTER);
-    // }
-    if STARTED.compare_exchange(false, true, Ordering::SeqCst, Ordering::SeqCst).is_err() {
-        return WFS_ERR_ALREADY_STARTED;
-    }
-    if !lpWFSVersion.is_null() {
-        let description = "Rust XFS Manager v2.00 to v3.30".as_bytes();
-        let mut sz_description = [0i8; WFSDDESCRIPTION_LEN + 1];
-        for i in 0..description.len() {
-            sz_description[i] = description[i] as i8;
-        }
-        let version = WFSVERSION {
-            w_version: Version::new_explicit(3, 0).value(),
-            w_low_version: Version::new_explicit(2, 0).value(),
-            w_high_version: Version::new_explicit(3, 30).value(),
-            sz_description,
-            sz_system_status: [0; WFSDSYSSTATUS_LEN + 1],
-        };
-        unsafe {
-            lpWFSVersion.write(version);
+    last_error::track("WFSStartUp", 0, || {
+        // The supported range is reported on a version mismatch as well, so the application can see what to ask for
+        let (result, version) = start_up_version(dwVersionsRequired);
+        if !lpWFSVersion.is_null() {
+            unsafe { lpWFSVersion.write(manager_version(version, SUPPORTED_VERSIONS.start, SUPPORTED_VERSIONS.end)) };
+        }
+        if result != WFS_SUCCESS {
+            xfs_reject!(result);
+        }
+
+        // STARTED lets every other call through assert_started!, so it is set last and never while a WFSCleanUp is
+        // still tearing down the previous start up
+        let _lifecycle = xfs_unwrap!(LIFECYCLE.lock());
+        if STARTED.compare_exchange(false, true, Ordering::SeqCst, Ordering::SeqCst).is_err() {
+            return WFS_ERR_ALREADY_STARTED;
+        }
+        xfs_unwrap!(LEAKED.lock()).clear();
+        if std::env::var_os(provider_cache::CONFIG_WATCH_ENV).is_some() {
+            provider_cache::watch(&PROVIDER_CACHE);
         }
+        WFS_SUCCESS
+    })
+}
+
+/// Checks the versions an application asks for on WFSStartUp, returns the result and the version reported to it.
+///
+/// Diebold's XFS stack passes a pointer in place of dwVersionsRequired, which does not decode to a range of XFS
+/// versions. Such a value is accepted as it always was, only a well formed range outside of the supported one is
+/// rejected. The version reported on success is 3.00, whatever the application asks for.
+fn start_up_version(versions_required: DWORD) -> (HRESULT, Version) {
+    let range = VersionRange::new(versions_required);
+    let well_formed = range.start <= range.end && [range.start, range.end].iter().all(|version| version.major > 0 && version.minor <= 99);
+    if !well_formed {
+        trace!("WFSStartUp asked for versions {versions_required:#010X}, which is not a version range, accepting it");
+        return (WFS_SUCCESS, Version::new_explicit(3, 0));
+    }
+    if range.start > SUPPORTED_VERSIONS.end {
+        (WFS_ERR_API_VER_TOO_HIGH, SUPPORTED_VERSIONS.end)
+    } else if range.end < SUPPORTED_VERSIONS.start {
+        (WFS_ERR_API_VER_TOO_LOW, SUPPORTED_VERSIONS.start)
+    } else {
+        (WFS_SUCCESS, Version::new_explicit(3, 0))
+    }
+}
+
+/// SPI versions offered to the provider on WFPOpen: the supported ones the application asks for as service
+/// versions, so a provider of an older release is not told the manager requires a newer one. When the application
+/// asks for none of them the whole supported range is offered and the provider reports the mismatch.
+fn spi_versions(srvc_versions_required: DWORD) -> VersionRange {
+    SUPPORTED_VERSIONS.intersect(&VersionRange::new(srvc_versions_required)).unwrap_or(SUPPORTED_VERSIONS)
+}
+
+/// Describes the manager implementation, `version` is the one the application is expected to use.
+fn manager_version(version: Version, low: Version, high: Version) -> WFSVERSION {
+    let description = "Rust XFS Manager v2.00 to v3.30".as_bytes();
+    let mut sz_description = [0i8; WFSDDESCRIPTION_LEN + 1];
+    for i in 0..description.len() {
+        sz_description[i] = description[i] as i8;
+    }
+    WFSVERSION {
+        w_version: version.value(),
+        w_low_version: low.value(),
+        w_high_version: high.value(),
+        sz_description,
+        sz_system_status: [0; WFSDSYSSTATUS_LEN + 1],
     }
-    WFS_SUCCESS
 }
 
 #[allow(non_snake_case)]
@@ -666,10 +1610,12 @@ pub extern "stdcall" fn WFSStartUp(dwVersionsRequired: DWORD, lpWFSVersion: LPWF
 #[logfn(TRACE)]
 #[logfn_inputs(TRACE)]
 pub extern "stdcall" fn WFSUnhookBlockingHook() -> HRESULT {
-    assert_started!();
-    // assert_unblocked!();
-    BLOCKING_HOOK.store(ptr::null_mut(), Ordering::SeqCst);
-    WFS_SUCCESS
+    last_error::track("WFSUnhookBlockingHook", 0, || {
+        assert_started!();
+        assert_unblocked!();
+        BLOCKING_HOOK.store(ptr::null_mut(), Ordering::SeqCst);
+        WFS_SUCCESS
+    })
 }
 
 #[allow(non_snake_case)]
@@ -677,9 +1623,11 @@ pub extern "stdcall" fn WFSUnhookBlockingHook() -> HRESULT {
 #[logfn(TRACE)]
 #[logfn_inputs(TRACE)]
 pub extern "stdcall" fn WFSUnlock(hService: HSERVICE) -> HRESULT {
-    assert_started!();
-    // block_thread!();
-    call_async(WFS_UNLOCK_COMPLETE, |hwnd, request_id| WFSAsyncUnlock(hService, hwnd, request_id), &mut ptr::null_mut())
+    last_error::track("WFSUnlock", hService, || {
+        assert_started!();
+        assert_unblocked!();
+        call_async(WFS_UNLOCK_COMPLETE, Some(hService), |hwnd, request_id| WFSAsyncUnlock(hService, hwnd, request_id), ptr::null_mut())
+    })
 }
 
 #[allow(non_snake_case)]
@@ -687,15 +1635,14 @@ pub extern "stdcall" fn WFSUnlock(hService: HSERVICE) -> HRESULT {
 #[logfn(TRACE)]
 #[logfn_inputs(TRACE)]
 pub extern "stdcall" fn WFSAsyncUnlock(hService: HSERVICE, hWnd: HWND, lpRequestID: LPREQUESTID) -> HRESULT {
-    assert_started!();
-    // assert_unblocked!();
-    let mut services = xfs_unwrap!(SERVICES.lock());
-    let service = get_service_req!(hService, services);
-    let wfp_unlock = unsafe {
-        lpRequestID.write(service.request_id as u32);
-        xfs_unwrap!(service.library.get::<spi::WFPUnlock>(b"WFPUnlock"))
-    };
-    wfp_unlock(hService, hWnd, unsafe { *lpRequestID })
+    last_error::track("WFSAsyncUnlock", hService, || {
+        assert_started!();
+        assert_writable!(lpRequestID);
+        assert_unblocked!();
+        with_service::<spi::WFPUnlock>(hService, lpRequestID, b"WFPUnlock", |wfp_unlock, request_id| {
+            relay::forward(hService, request_id, hWnd, WFS_UNLOCK_COMPLETE, 0, |hwnd| wfp_unlock(hService, hwnd, request_id))
+        })
+    })
 }
 
 #[allow(non_snake_case)]
@@ -703,25 +1650,78 @@ pub extern "stdcall" fn WFSAsyncUnlock(hService: HSERVICE, hWnd: HWND, lpRequest
 #[logfn(TRACE)]
 #[logfn_inputs(TRACE)]
 pub extern "stdcall" fn WFMGetTraceLevel(hService: HSERVICE, lpdwTraceLevel: LPDWORD) -> HRESULT {
-    assert_started!();
-    let services = xfs_unwrap!(SERVICES.lock());
-    if let Some(service) = services.get(hService as usize - 1).and_then(|service| service.as_ref()) {
-        unsafe { lpdwTraceLevel.write(service.trace_level) };
-        return WFS_SUCCESS;
-    }
-    xfs_reject!(WFS_ERR_INVALID_HSERVICE);
+    last_error::track("WFMGetTraceLevel", hService, || {
+        assert_started!();
+        assert_writable!(lpdwTraceLevel);
+        let services = xfs_unwrap!(SERVICES.lock());
+        // 0 wraps to an index that does not exist
+        if let Some(service) = services.get((hService as usize).wrapping_sub(1)).and_then(|service| service.as_ref()) {
+            unsafe { lpdwTraceLevel.write(service.trace_level.bits()) };
+            return WFS_SUCCESS;
+        }
+        xfs_reject!(WFS_ERR_INVALID_HSERVICE);
+    })
 }
 
+/// Releases the service the provider was handed `hProvider` for in WFPOpen.
+///
+/// Only the token issued for a service that is still loaded is accepted, anything else is rejected with
+/// WFS_ERR_INVALID_HPROVIDER.
 #[allow(non_snake_case)]
 #[no_mangle]
 #[logfn(TRACE)]
 #[logfn_inputs(TRACE)]
 pub extern "stdcall" fn WFMReleaseDLL(hProvider: HPROVIDER) -> HRESULT {
-    let mut services = xfs_unwrap!(SERVICES.lock());
-    let service_handle = (&*services) as *const _ as usize;
-    let index = hProvider as usize - service_handle;
-    services[index] = None;
-    WFS_SUCCESS
+    last_error::track("WFMReleaseDLL", 0, || {
+        let mut services = xfs_unwrap!(SERVICES.lock());
+        let index = match Service::provider_index(hProvider) {
+            Some(index) if services.get(index).and_then(|service| service.as_ref()).is_some_and(|service| service.provider == hProvider as usize) => index,
+            _ => xfs_reject!(WFS_ERR_INVALID_HPROVIDER),
+        };
+        let service = retire(&mut services, index);
+        // The provider may be unloaded here, which must not happen under the services lock
+        drop(services);
+        drop(service);
+        release_proxies();
+        WFS_SUCCESS
+    })
+}
+
+/// Releases a service slot and returns the service if it could be released right away.
+///
+/// While requests of the service are still in flight, a late completion could call back into a provider that
+/// has already been unloaded. Such a service is kept in a draining state instead: it rejects new requests,
+/// and it is released once the relay has seen the outstanding completions or DRAIN_GRACE_PERIOD has passed.
+fn retire(services: &mut [Option<Service>], index: usize) -> Option<Service> {
+    let service = services.get_mut(index)?.as_mut()?;
+    if service.draining {
+        return None;
+    }
+    if !relay::has_pending(service.service_id) {
+        return services[index].take();
+    }
+
+    service.draining = true;
+    let service_id = service.service_id;
+    trace!("Draining service {service_id}");
+
+    thread::spawn(move || {
+        let deadline = Instant::now() + DRAIN_GRACE_PERIOD;
+        while relay::has_pending(service_id) && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(10));
+        }
+        // Completions arriving after this point are freed by the relay
+        relay::forget(service_id);
+
+        match SERVICES.lock() {
+            Ok(mut services) if services[index].as_ref().is_some_and(|service| service.draining) => services[index] = None,
+            Ok(_) => {}
+            Err(error) => error!("{:?}", error),
+        }
+        release_proxies();
+        trace!("Drained service {service_id}");
+    });
+    None
 }
 
 #[allow(non_snake_case)]
@@ -729,7 +1729,18 @@ pub extern "stdcall" fn WFMReleaseDLL(hProvider: HPROVIDER) -> HRESULT {
 #[logfn(TRACE)]
 #[logfn_inputs(TRACE)]
 pub unsafe extern "stdcall" fn WFMAllocateBuffer(ulSize: ULONG, ulFlags: ULONG, lppvData: *mut LPVOID) -> HRESULT {
-    (WFM_ALLOCATE_BUFFER)(ulSize, ulFlags, lppvData)
+    last_error::track("WFMAllocateBuffer", 0, || (WFM_ALLOCATE_BUFFER)(ulSize, ulFlags, lppvData))
+}
+
+/// Allocates a buffer tagged with the service and command it is for, see `WFMAllocateTaggedBuffer` in xfs_supp.
+///
+/// This is a manager extension, not part of the XFS API.
+#[allow(non_snake_case)]
+#[no_mangle]
+#[logfn(TRACE)]
+#[logfn_inputs(TRACE)]
+pub unsafe extern "stdcall" fn WFMAllocateTaggedBuffer(ulSize: ULONG, ulFlags: ULONG, hService: HSERVICE, dwCommand: DWORD, lppvData: *mut LPVOID) -> HRESULT {
+    last_error::track("WFMAllocateTaggedBuffer", 0, || (WFM_ALLOCATE_TAGGED_BUFFER)(ulSize, ulFlags, hService, dwCommand, lppvData))
 }
 
 #[allow(non_snake_case)]
@@ -737,7 +1748,7 @@ pub unsafe extern "stdcall" fn WFMAllocateBuffer(ulSize: ULONG, ulFlags: ULONG,
 #[logfn(TRACE)]
 #[logfn_inputs(TRACE)]
 pub unsafe extern "stdcall" fn WFMAllocateMore(ulSize: ULONG, lpvOriginal: LPVOID, lppvData: *mut LPVOID) -> HRESULT {
-    (WFM_ALLOCATE_MORE)(ulSize, lpvOriginal, lppvData)
+    last_error::track("WFMAllocateMore", 0, || (WFM_ALLOCATE_MORE)(ulSize, lpvOriginal, lppvData))
 }
 
 #[allow(non_snake_case)]
@@ -745,7 +1756,63 @@ pub unsafe extern "stdcall" fn WFMAllocateMore(ulSize: ULONG, lpvOriginal: LPVOI
 #[logfn(TRACE)]
 #[logfn_inputs(TRACE)]
 pub unsafe extern "stdcall" fn WFMFreeBuffer(lpvData: LPVOID) -> HRESULT {
-    (WFM_FREE_BUFFER)(lpvData)
+    last_error::track("WFMFreeBuffer", 0, || (WFM_FREE_BUFFER)(lpvData))
+}
+
+/// Reports the live buffers on the XFS heap, see `WFMGetHeapStats` in xfs_supp.
+///
+/// This is a manager extension, not part of the XFS API.
+#[allow(non_snake_case)]
+#[no_mangle]
+#[logfn(TRACE)]
+#[logfn_inputs(TRACE)]
+pub unsafe extern "stdcall" fn WFMGetHeapStats(lpdwBuffers: LPDWORD, lpdwBytes: LPDWORD) -> HRESULT {
+    last_error::track("WFMGetHeapStats", 0, || (WFM_GET_HEAP_STATS)(lpdwBuffers, lpdwBytes))
+}
+
+/// Reports the live buffers on the XFS heap tagged with a service and command, see `WFMGetTaggedHeapStats` in
+/// xfs_supp.
+///
+/// This is a manager extension, not part of the XFS API.
+#[allow(non_snake_case)]
+#[no_mangle]
+#[logfn(TRACE)]
+#[logfn_inputs(TRACE)]
+pub unsafe extern "stdcall" fn WFMGetTaggedHeapStats(hService: HSERVICE, dwCommand: DWORD, lpdwBuffers: LPDWORD, lpdwBytes: LPDWORD) -> HRESULT {
+    last_error::track("WFMGetTaggedHeapStats", 0, || (WFM_GET_TAGGED_HEAP_STATS)(hService, dwCommand, lpdwBuffers, lpdwBytes))
+}
+
+/// Reports the number of buffers attached to a buffer by WFMAllocateMore, see `WFMGetChildCount` in xfs_supp.
+///
+/// This is a manager extension, not part of the XFS API.
+#[allow(non_snake_case)]
+#[no_mangle]
+#[logfn(TRACE)]
+#[logfn_inputs(TRACE)]
+pub unsafe extern "stdcall" fn WFMGetChildCount(lpvData: LPVOID, lpdwChildren: LPDWORD) -> HRESULT {
+    last_error::track("WFMGetChildCount", 0, || (WFM_GET_CHILD_COUNT)(lpvData, lpdwChildren))
+}
+
+/// Reports the size a buffer was allocated with, see `WFMGetBufferLength` in xfs_supp.
+///
+/// This is a manager extension, not part of the XFS API.
+#[allow(non_snake_case)]
+#[no_mangle]
+#[logfn(TRACE)]
+#[logfn_inputs(TRACE)]
+pub unsafe extern "stdcall" fn WFMGetBufferLength(lpvData: LPVOID, lpulLength: *mut ULONG) -> HRESULT {
+    last_error::track("WFMGetBufferLength", 0, || (WFM_GET_BUFFER_LENGTH)(lpvData, lpulLength))
+}
+
+/// Copies a chain of buffers into one contiguous buffer, see `WFMGetChainedBuffer` in xfs_supp.
+///
+/// This is a manager extension, not part of the XFS API.
+#[allow(non_snake_case)]
+#[no_mangle]
+#[logfn(TRACE)]
+#[logfn_inputs(TRACE)]
+pub unsafe extern "stdcall" fn WFMGetChainedBuffer(lpvData: LPVOID, lppvData: *mut LPVOID, lpulLength: *mut ULONG) -> HRESULT {
+    last_error::track("WFMGetChainedBuffer", 0, || (WFM_GET_CHAINED_BUFFER)(lpvData, lppvData, lpulLength))
 }
 
 #[allow(non_snake_case)]
@@ -753,14 +1820,13 @@ pub unsafe extern "stdcall" fn WFMFreeBuffer(lpvData: LPVOID) -> HRESULT {
 #[logfn(TRACE)]
 #[logfn_inputs(TRACE)]
 pub unsafe extern "stdcall" fn WFMKillTimer(wTimerID: WORD) -> HRESULT {
-    (WFM_KILL_TIMER)(wTimerID)
+    last_error::track("WFMKillTimer", 0, || (WFM_KILL_TIMER)(wTimerID))
 }
 
 #[allow(non_snake_case)]
 #[no_mangle]
-pub extern "stdcall" fn WFMOutputTraceData(lpszData: LPSTR) -> HRESULT {
-    trace!("WFMOutputTraceData: {}", xfs_unwrap!(unsafe { CStr::from_ptr(lpszData).to_str() }));
-    WFS_SUCCESS
+pub unsafe extern "stdcall" fn WFMOutputTraceData(lpszData: LPSTR) -> HRESULT {
+    last_error::track("WFMOutputTraceData", 0, || (WFM_OUTPUT_TRACE_DATA)(lpszData))
 }
 
 #[allow(non_snake_case)]
@@ -768,7 +1834,7 @@ pub extern "stdcall" fn WFMOutputTraceData(lpszData: LPSTR) -> HRESULT {
 #[logfn(TRACE)]
 #[logfn_inputs(TRACE)]
 pub unsafe extern "stdcall" fn WFMSetTimer(hWnd: HWND, lpContext: LPVOID, dwTimeVal: DWORD, lpwTimerID: LPWORD) -> HRESULT {
-    (WFM_SET_TIMER)(hWnd, lpContext, dwTimeVal, lpwTimerID)
+    last_error::track("WFMSetTimer", 0, || (WFM_SET_TIMER)(hWnd, lpContext, dwTimeVal, lpwTimerID))
 }
 
 #[allow(non_snake_case)]
@@ -776,17 +1842,103 @@ pub unsafe extern "stdcall" fn WFMSetTimer(hWnd: HWND, lpContext: LPVOID, dwTime
 #[logfn(TRACE)]
 #[logfn_inputs(TRACE)]
 pub extern "stdcall" fn WFMSetTraceLevel(hService: HSERVICE, dwTraceLevel: DWORD) -> HRESULT {
-    assert_started!();
-    if hService == 0 {
-        xfs_reject!(WFS_ERR_INVALID_HSERVICE);
+    last_error::track("WFMSetTraceLevel", hService, || {
+        assert_started!();
+        let trace_level = effective_trace_level(dwTraceLevel.into(), trace_level_floor());
+        with_provider::<spi::WFPSetTraceLevel>(
+            hService,
+            b"WFPSetTraceLevel",
+            Service::is_active,
+            |service| service.trace_level = trace_level,
+            |wfp_set_trace_level| wfp_set_trace_level(hService, trace_level.bits()),
+        )
+    })
+}
+
+/// Number of services that are open and not released yet.
+fn open_service_count() -> Result<usize, HRESULT> {
+    let services = SERVICES.lock().map_err(|error| {
+        error!("{:?}", error);
+        WFS_ERR_INTERNAL_ERROR
+    })?;
+    Ok(services.iter().flatten().filter(|service| service.is_active()).count())
+}
+
+/// Warns about services the application left open and remembers them for [`manager::leaked_handles`].
+fn record_leaks(services: Vec<HSERVICE>, reason: &str) {
+    for service in &services {
+        warn!("Service {service} was still open when {reason}, the application did not close it");
+    }
+    match LEAKED.lock() {
+        Ok(mut leaked) => leaked.extend(services),
+        Err(error) => error!("{:?}", error),
+    }
+}
+
+/// Services left open by the application since WFSStartUp.
+fn leaked_services() -> Result<Vec<HSERVICE>, HRESULT> {
+    let leaked = LEAKED.lock().map_err(|error| {
+        error!("{:?}", error);
+        WFS_ERR_INTERNAL_ERROR
+    })?;
+    Ok(leaked.clone())
+}
+
+/// Logical name and trace level of the services that are open and not released yet, by service handle.
+fn service_trace_levels() -> Result<Vec<(HSERVICE, String, TraceLevel)>, HRESULT> {
+    let services = SERVICES.lock().map_err(|error| {
+        error!("{:?}", error);
+        WFS_ERR_INTERNAL_ERROR
+    })?;
+    Ok(services
+        .iter()
+        .flatten()
+        .filter(|service| service.is_active())
+        .map(|service| (service.service_id, service.logical_name.clone(), service.trace_level))
+        .collect())
+}
+
+/// Number of threads with a blocking call in progress.
+fn blocked_thread_count() -> Result<usize, HRESULT> {
+    let blocked_threads = BLOCKED_THREADS.lock().map_err(|error| {
+        error!("{:?}", error);
+        WFS_ERR_INTERNAL_ERROR
+    })?;
+    Ok(blocked_threads.len())
+}
+
+/// Number of logical services the provider cache holds.
+fn resolved_service_count() -> Result<usize, HRESULT> {
+    let cache = PROVIDER_CACHE.lock().map_err(|error| {
+        error!("{:?}", error);
+        WFS_ERR_INTERNAL_ERROR
+    })?;
+    Ok(cache.resolved_count())
+}
+
+/// Adds the operator's trace level floor to the level requested by the application.
+fn effective_trace_level(requested: TraceLevel, floor: TraceLevel) -> TraceLevel {
+    requested | floor
+}
+
+/// Reads the operator's trace level floor, see [`TRACE_LEVEL_FLOOR_ENV`].
+fn trace_level_floor() -> TraceLevel {
+    match std::env::var(TRACE_LEVEL_FLOOR_ENV) {
+        Ok(floor) => parse_trace_level_floor(&floor),
+        Err(_) => TraceLevel::NONE,
     }
-    let mut services = xfs_unwrap!(SERVICES.lock());
-    let service = match services.get_mut(hService as usize - 1).and_then(|service| service.as_mut()) {
-        Some(service) => service,
-        None => xfs_reject!(WFS_ERR_INVALID_HSERVICE),
+}
+
+/// Parses a trace level floor given in decimal or in hex with a 0x prefix, an invalid one is logged and ignored.
+fn parse_trace_level_floor(floor: &str) -> TraceLevel {
+    let parsed = match floor.trim().strip_prefix("0x").or_else(|| floor.trim().strip_prefix("0X")) {
+        Some(hex) => DWORD::from_str_radix(hex, 16),
+        None => floor.trim().parse(),
     };
-    service.trace_level = dwTraceLevel;
-    unsafe { xfs_unwrap!(service.library.get::<spi::WFPSetTraceLevel>(b"WFPSetTraceLevel"))(hService, dwTraceLevel) }
+    parsed.map(TraceLevel::from_bits).unwrap_or_else(|error| {
+        error!("Invalid {TRACE_LEVEL_FLOOR_ENV} {floor:?}: {error}");
+        TraceLevel::NONE
+    })
 }
 
 #[allow(non_snake_case)]
@@ -797,47 +1949,780 @@ pub extern "stdcall" fn DllMain(hinst_dll: HINSTANCE, fdw_reason: DWORD, _: LPVO
 }
 
 /// Calls asynchronous function on the current thread.
-fn call_async(message: u32, async_fn: impl Fn(HWND, LPREQUESTID) -> HRESULT, lpp_result: *mut LPWFSRESULT) -> HRESULT {
+///
+/// The completion result is handed to the caller through `lpp_result`, who then owns it and releases it with
+/// WFSFreeResult. When `lpp_result` is null the result is freed here, so wrappers that only return the HRESULT
+/// (and applications passing NULL) don't leak the provider's buffers.
+///
+/// Only a result carrying the request id of this call, and `service` unless the call assigns the handle itself
+/// (WFSOpen), is accepted. Stray completions are logged, freed and the wait continues.
+fn call_async(message: u32, service: Option<HSERVICE>, async_fn: impl Fn(HWND, LPREQUESTID) -> HRESULT, lpp_result: *mut LPWFSRESULT) -> HRESULT {
+    call_async_until(message, service, async_fn, lpp_result, None)
+}
+
+/// Like [`call_async`], but gives up waiting with WFS_ERR_TIMEOUT once the deadline has passed.
+/// The request is left to the caller to roll back in that case.
+///
+/// Beyond [`MAX_BLOCKING_CALLS_ENV`] calls waiting at the same time the call is rejected with WFS_ERR_INTERNAL_ERROR,
+/// before a window is created or the request issued.
+fn call_async_until(message: u32, service: Option<HSERVICE>, async_fn: impl Fn(HWND, LPREQUESTID) -> HRESULT, lpp_result: *mut LPWFSRESULT, deadline: Option<Instant>) -> HRESULT {
+    let _slot = match BlockingSlot::acquire(message) {
+        Ok(slot) => slot,
+        Err(error) => return error,
+    };
     let window = SyncWindow::new(message);
     let mut request_id = 0;
-    let result = async_fn(window.handle(), &mut request_id);
-    if result != WFS_SUCCESS {
-        return result;
+    if let Err(error) = async_fn(window.handle(), &mut request_id).ok() {
+        return error;
     }
+    // Registered once the request is on its way, so the asynchronous function itself is not taken for a nested call
+    let blocking = match Blocking::new(BlockingCall {
+        operation: BlockingCall::operation(message),
+        service,
+        canceled: false,
+    }) {
+        Ok(blocking) => blocking,
+        Err(error) => return error,
+    };
     loop {
-        // Execute application hook or default hook dispatching window messages
-        let hook = BLOCKING_HOOK.load(Ordering::SeqCst);
-        if hook.is_null() {
-            unsafe { default_block_hook() };
-        } else {
-            unsafe { (*hook)() };
-        }
-
-        let thread_id = unsafe { GetCurrentThreadId() };
-        let mut blocked_threads = xfs_unwrap!(BLOCKED_THREADS.lock());
+        run_blocking_hook();
 
         // Check if the call was cancelled
-        if blocked_threads.get(&thread_id).unwrap_or(&false) == &true {
-            blocked_threads.remove(&thread_id); // cleanup
+        if blocking.canceled() {
             return WFS_ERR_CANCELED;
         }
 
         // Check if we received result from the async call
         if let Some(resultptr) = xfs_unwrap!(window.try_receive()) {
-            unsafe { lpp_result.write(resultptr as LPWFSRESULT) };
-            let wfs_result = resultptr as *const WFSRESULT;
-            let wfs_result = unsafe { std::mem::transmute::<*const WFSRESULT, &WFSRESULT>(wfs_result) };
-            return unsafe { ptr::addr_of!(wfs_result.hResult).read_unaligned() };
+            let wfs_result = resultptr as LPWFSRESULT;
+            let (result_service, result_request_id) = unsafe { (ptr::addr_of!((*wfs_result).hService).read_unaligned(), ptr::addr_of!((*wfs_result).RequestID).read_unaligned()) };
+            if result_request_id != request_id || service.is_some_and(|service| service != result_service) {
+                warn!("Ignoring completion of service {result_service} request {result_request_id}, waiting for {service:?} request {request_id}");
+                unsafe { WFMFreeBuffer(wfs_result as LPVOID) };
+                continue;
+            }
+
+            let result = unsafe { ptr::addr_of!((*wfs_result).hResult).read_unaligned() };
+            if lpp_result.is_null() {
+                // Nobody is going to call WFSFreeResult for this one
+                unsafe { WFMFreeBuffer(wfs_result as LPVOID) };
+            } else {
+                unsafe { lpp_result.write(wfs_result) };
+            }
+            return result;
+        }
+
+        if deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+            warn!("Request {request_id} timed out waiting for message {message}");
+            return WFS_ERR_TIMEOUT;
+        }
+    }
+}
+
+/// Waits out the delay between two requests of a synchronous call the way [`call_async_until`] waits for a completion.
+/// The thread counts as blocked meanwhile, so the blocking hook is run and WFSCancelBlockingCall ends the wait with
+/// WFS_ERR_CANCELED. Returns WFS_SUCCESS once the delay has passed.
+fn block_for(message: u32, service: Option<HSERVICE>, delay: Duration) -> HRESULT {
+    let blocking = match Blocking::new(BlockingCall {
+        operation: BlockingCall::operation(message),
+        service,
+        canceled: false,
+    }) {
+        Ok(blocking) => blocking,
+        Err(error) => return error,
+    };
+    let deadline = Instant::now() + delay;
+    loop {
+        run_blocking_hook();
+
+        if blocking.canceled() {
+            return WFS_ERR_CANCELED;
+        }
+        if Instant::now() >= deadline {
+            return WFS_SUCCESS;
+        }
+    }
+}
+
+/// Executes the application hook, or the default hook dispatching window messages.
+fn run_blocking_hook() {
+    let hook = BLOCKING_HOOK.load(Ordering::SeqCst);
+    if hook.is_null() {
+        unsafe { default_block_hook() };
+    } else {
+        unsafe { (*hook)() };
+    }
+}
+
+/// Gets the point in time a synchronous call given the timeout gives up at, None for WFS_INDEFINITE_WAIT.
+fn deadline(timeout: DWORD) -> Option<Instant> {
+    match timeout {
+        WFS_INDEFINITE_WAIT => None,
+        timeout => Some(Instant::now() + Duration::from_millis(timeout as u64)),
+    }
+}
+
+/// Gets the timeout to pass on for what is left until the deadline, WFS_INDEFINITE_WAIT for none.
+fn timeout_left(deadline: Option<Instant>) -> DWORD {
+    match deadline {
+        // A last request with less than a millisecond left must not turn into WFS_INDEFINITE_WAIT
+        Some(deadline) => (deadline.saturating_duration_since(Instant::now()).as_millis().min(DWORD::MAX as u128) as DWORD).max(WFS_INDEFINITE_WAIT + 1),
+        None => WFS_INDEFINITE_WAIT,
+    }
+}
+
+/// Like [`call_async`] without a result, for requests providers may complete synchronously.
+///
+/// A status other than WFS_SUCCESS returned by the SPI function is final without waiting. Providers configured with
+/// `sync_register = 1` return the final status without ever posting the completion, which would leave the caller
+/// waiting forever, so their WFS_SUCCESS is final right away too. The request is abandoned then, a completion arriving
+/// anyway is dropped by the relay. All other providers are waited for like any asynchronous request.
+fn call_async_or_sync(message: u32, service: HSERVICE, async_fn: impl Fn(HWND, LPREQUESTID) -> HRESULT) -> HRESULT {
+    if !completes_synchronously(service) {
+        return call_async(message, Some(service), async_fn, ptr::null_mut());
+    }
+
+    let request_id = Cell::new(0);
+    let async_fn = |hwnd, lp_request_id: LPREQUESTID| {
+        let result = async_fn(hwnd, lp_request_id);
+        // SAFETY: the request id is written by the asynchronous function before it returns
+        request_id.set(unsafe { *lp_request_id });
+        result
+    };
+
+    let result = call_async_until(message, Some(service), async_fn, ptr::null_mut(), Some(Instant::now()));
+    if result == WFS_ERR_TIMEOUT && relay::abandon(service, request_id.get()) {
+        trace!("Service {service} completed request {} synchronously", request_id.get());
+        registration_completed(service, request_id.get(), WFS_SUCCESS);
+        return WFS_SUCCESS;
+    }
+    result
+}
+
+/// Whether the provider of the service was configured with `sync_register = 1`, false for unknown services.
+fn completes_synchronously(service_id: HSERVICE) -> bool {
+    let services = match SERVICES.lock() {
+        Ok(services) => services,
+        Err(error) => {
+            error!("{:?}", error);
+            return false;
+        }
+    };
+    let service = services.get((service_id as usize).wrapping_sub(1)).and_then(|service| service.as_ref());
+    service.is_some_and(|service| service.sync_register)
+}
+
+/// Reads the call serialization policy of a service provider from its `reentrant` registry value.
+///
+/// Only providers configured with `reentrant = 1` are called concurrently, all others get their calls
+/// serialized per service in arrival order, as most providers were written against managers that never overlapped them.
+/// Cancels are not serialized, so they still reach a provider that is busy with another call.
+fn is_reentrant(provider: &str) -> bool {
+    let reentrant = provider_flag(provider, "reentrant");
+    trace!("Provider {provider} is {}", if reentrant { "reentrant" } else { "exclusive" });
+    reentrant
+}
+
+/// Reads from the `isolated` registry value whether a service provider is hosted in a surrogate process.
+///
+/// Providers configured with `isolated = 1` are loaded through [`ISOLATED_PROVIDER`], which starts an
+/// `xfs_host.exe` per service to run the provider DLL in, so a crashing provider fails the requests of its
+/// service with WFS_ERR_HARDWARE_ERROR instead of taking the application down.
+fn is_isolated(provider: &str) -> bool {
+    let isolated = provider_flag(provider, "isolated");
+    if isolated {
+        trace!("Provider {provider} is isolated");
+    }
+    isolated
+}
+
+/// Reads from the `sync_register` registry value whether a service provider completes WFPRegister and WFPDeregister
+/// synchronously, returning the final status without ever posting the completion.
+///
+/// WFSRegister and WFSDeregister take the status such a provider returned as final instead of waiting for a
+/// completion that never comes, see [`call_async_or_sync`].
+fn is_sync_register(provider: &str) -> bool {
+    let sync_register = provider_flag(provider, "sync_register");
+    if sync_register {
+        trace!("Provider {provider} completes registrations synchronously");
+    }
+    sync_register
+}
+
+/// Reads the shortest timeout in milliseconds a service provider is to be given from its `timeout_floor` registry
+/// value, 0 when unset or invalid.
+///
+/// Lets operators keep applications from flooding a device with requests that time out right away. Smaller timeouts
+/// of WFSExecute, WFSGetInfo and WFSLock are raised to the floor, see [`floor_timeout`].
+fn timeout_floor(provider: &str) -> DWORD {
+    let value = RegKey::open(&CONFIG_API, WFS_CFG_HKEY_MACHINE_XFS_ROOT, &format!("SERVICE_PROVIDERS\\{provider}")).and_then(|key| key.query_value("timeout_floor"));
+    match value {
+        Ok(value) => value.trim().parse().unwrap_or_else(|error| {
+            error!("Invalid timeout_floor {value:?} of provider {provider}: {error}");
+            0
+        }),
+        Err(_) => 0,
+    }
+}
+
+/// Raises a timeout the application passed for the service to the floor of its provider. WFS_INDEFINITE_WAIT is
+/// passed on as it is.
+fn floor_timeout(service_id: HSERVICE, timeout: DWORD) -> DWORD {
+    if timeout == WFS_INDEFINITE_WAIT {
+        return timeout;
+    }
+    let services = match SERVICES.lock() {
+        Ok(services) => services,
+        Err(error) => {
+            error!("{:?}", error);
+            return timeout;
+        }
+    };
+    let service = services.get((service_id as usize).wrapping_sub(1)).and_then(|service| service.as_ref());
+    let floor = service.map_or(0, |service| service.timeout_floor);
+    drop(services);
+    if timeout >= floor {
+        return timeout;
+    }
+    trace!("Raising the timeout of service {service_id} from {timeout} ms to the floor of {floor} ms");
+    floor
+}
+
+/// Whether a value of the service provider's registry key is set to 1.
+fn provider_flag(provider: &str, name: &str) -> bool {
+    let value = RegKey::open(&CONFIG_API, WFS_CFG_HKEY_MACHINE_XFS_ROOT, &format!("SERVICE_PROVIDERS\\{provider}")).and_then(|key| key.query_value(name));
+    value.as_deref() == Ok("1")
+}
+
+/// Loads the service provider DLL, or returns the library already loaded for another service on the same path.
+/// The library is unloaded when the last service using it is released.
+///
+/// Loading a provider built for another architecture fails with an opaque ERROR_BAD_EXE_FORMAT,
+/// so on failure the PE header is inspected to tell integrators about the bitness mismatch.
+fn load_provider(path: &str) -> Result<Arc<libloading::Library>, HRESULT> {
+    let mut providers = PROVIDERS.lock().map_err(|error| {
+        error!("{:?}", error);
+        WFS_ERR_INTERNAL_ERROR
+    })?;
+
+    let key = path.to_ascii_lowercase();
+    if let Some(library) = providers.get(&key).and_then(Weak::upgrade) {
+        return Ok(library);
+    }
+
+    // SAFETY: The service providers are safe to use.
+    let library = unsafe { libloading::Library::new(path) }.map_err(|error| {
+        match read_image_header(path).as_deref().and_then(foreign_machine) {
+            Some(machine) => error!("Provider architecture mismatch: {path} is built for machine {machine:#06x}, the manager for {HOST_MACHINE:#06x}"),
+            None => error!("Failed to load provider {path}: {error:?}"),
         }
+        WFS_ERR_INVALID_SERVPROV
+    })?;
+    trace!("Loaded provider {path}");
+
+    let library = Arc::new(library);
+    providers.retain(|_, library| library.strong_count() > 0);
+    providers.insert(key, Arc::downgrade(&library));
+    Ok(library)
+}
+
+#[cfg(target_arch = "x86")]
+const HOST_MACHINE: WORD = winapi::um::winnt::IMAGE_FILE_MACHINE_I386;
+#[cfg(target_arch = "x86_64")]
+const HOST_MACHINE: WORD = winapi::um::winnt::IMAGE_FILE_MACHINE_AMD64;
+#[cfg(target_arch = "aarch64")]
+const HOST_MACHINE: WORD = winapi::um::winnt::IMAGE_FILE_MACHINE_ARM64;
+
+/// Reads the start of a module image, which is enough to hold the DOS and PE headers.
+fn read_image_header(path: &str) -> Option<Vec<u8>> {
+    let mut header = Vec::with_capacity(4096);
+    std::fs::File::open(path).ok()?.take(4096).read_to_end(&mut header).ok()?;
+    Some(header)
+}
+
+/// Returns the machine type of a module image if it differs from the machine the manager was built for.
+fn foreign_machine(image: &[u8]) -> Option<WORD> {
+    if image.get(..2)? != b"MZ" {
+        return None;
     }
+    let offset = u32::from_le_bytes(image.get(0x3c..0x40)?.try_into().ok()?) as usize;
+    if image.get(offset..offset + 4)? != b"PE\0\0" {
+        return None;
+    }
+    let machine = u16::from_le_bytes(image.get(offset + 4..offset + 6)?.try_into().ok()?);
+    Some(machine).filter(|machine| *machine != HOST_MACHINE)
+}
+
+/// Checks whether the memory regions behind two out-parameters overlap.
+fn overlaps<A, B>(a: *const A, b: *const B) -> bool {
+    let (a, b) = (a as usize, b as usize);
+    a < b + mem::size_of::<B>() && b < a + mem::size_of::<A>()
 }
 
 /// Default blocking hook for synchronous calls
+///
+/// The completion arrives on the SyncWindow thread, not in this thread's queue, so waiting in GetMessageW
+/// would stall threads that have no message traffic of their own.
 unsafe fn default_block_hook() -> bool {
     let mut msg = mem::zeroed();
-    if GetMessageW(&mut msg, std::ptr::null_mut(), 0, 0) != 0 {
+    if PeekMessageW(&mut msg, std::ptr::null_mut(), 0, 0, PM_REMOVE) != 0 {
         TranslateMessage(&msg);
         DispatchMessageW(&msg);
+        return true;
+    }
+    thread::sleep(Duration::from_millis(1));
+    false
+}
+
+#[cfg(test)]
+mod tests {
+    use std::ffi::CString;
+
+    use log::{Level, LevelFilter, Log, Metadata, Record};
+    use winapi::um::winuser::IsWindow;
+
+    use super::*;
+
+    /// Keeps the formatted warnings and errors so tests can check the diagnostics, and the call id of each.
+    struct CaptureLogger(Mutex<Vec<String>>, Mutex<Vec<(String, Option<u32>)>>);
+
+    impl Log for CaptureLogger {
+        fn enabled(&self, metadata: &Metadata) -> bool {
+            metadata.level() <= Level::Warn
+        }
+
+        fn log(&self, record: &Record) {
+            if self.enabled(record.metadata()) {
+                self.0.lock().unwrap().push(record.args().to_string());
+                self.1.lock().unwrap().push((record.args().to_string(), call_id()));
+            }
+        }
+
+        fn flush(&self) {}
+    }
+
+    static LOGGER: CaptureLogger = CaptureLogger(Mutex::new(Vec::new()), Mutex::new(Vec::new()));
+
+    fn captured_logs() -> &'static Mutex<Vec<String>> {
+        // Only the first test installs it, all of them share it
+        if log::set_logger(&LOGGER).is_ok() {
+            log::set_max_level(LevelFilter::Warn);
+        }
+        &LOGGER.0
+    }
+
+    fn start_up() {
+        let mut version = unsafe { mem::zeroed::<WFSVERSION>() };
+        WFSStartUp(Version::new_explicit(3, 0).value() as DWORD, &mut version);
+    }
+
+    /// Last SERVICES slot handed out by [`free_slot`].
+    static NEXT_SLOT: AtomicUsize = AtomicUsize::new(8192);
+
+    /// Hands every caller its own SERVICES slot, counting down from the last one so the tests that open services
+    /// from the first slot up never get it.
+    fn free_slot() -> usize {
+        NEXT_SLOT.fetch_sub(1, Ordering::SeqCst) - 1
+    }
+
+    /// Service opened in the slot without an application handle or provider token, as far as the manager can tell.
+    fn test_service(slot: usize, library: Arc<libloading::Library>) -> Service {
+        Service {
+            service_id: slot as HSERVICE + 1,
+            request_id: 1,
+            logical_name: String::new(),
+            app: 0,
+            library,
+            trace_level: TraceLevel::NONE,
+            draining: false,
+            opening: false,
+            closing: false,
+            locked: false,
+            registrations: Registrations::default(),
+            dispatch: None,
+            sync_register: false,
+            timeout_floor: 0,
+            provider: 0,
+        }
+    }
+
+    fn assert_supported_range(version: &WFSVERSION) {
+        assert_eq!({ version.w_low_version }, Version::new_explicit(2, 0).value());
+        assert_eq!({ version.w_high_version }, Version::new_explicit(3, 30).value());
+        assert_eq!(version.sz_description[..31].iter().map(|c| *c as u8).collect::<Vec<_>>(), b"Rust XFS Manager v2.00 to v3.30");
+    }
+
+    #[test]
+    fn test_start_up_version_too_high() {
+        let mut version = unsafe { mem::zeroed::<WFSVERSION>() };
+        let required = VersionRange::new_explicit(Version::new_explicit(3, 40), Version::new_explicit(4, 0)).value();
+        assert_eq!(WFSStartUp(required, &mut version), WFS_ERR_API_VER_TOO_HIGH);
+        assert_supported_range(&version);
+        assert_eq!({ version.w_version }, Version::new_explicit(3, 30).value());
+    }
+
+    #[test]
+    fn test_start_up_version_too_low() {
+        let mut version = unsafe { mem::zeroed::<WFSVERSION>() };
+        let required = VersionRange::new_explicit(Version::new_explicit(1, 0), Version::new_explicit(1, 10)).value();
+        assert_eq!(WFSStartUp(required, &mut version), WFS_ERR_API_VER_TOO_LOW);
+        assert_supported_range(&version);
+        assert_eq!({ version.w_version }, Version::new_explicit(2, 0).value());
+    }
+
+    #[test]
+    fn test_start_up_version() {
+        let required = VersionRange::new_explicit(Version::new_explicit(2, 0), Version::new_explicit(2, 30)).value();
+        assert_eq!(start_up_version(required), (WFS_SUCCESS, Version::new_explicit(3, 0)));
+        // a stack address as Diebold's XFS stack passes it
+        assert_eq!(start_up_version(0x0019_FF2C), (WFS_SUCCESS, Version::new_explicit(3, 0)));
+    }
+
+    #[test]
+    fn test_overlaps() {
+        let versions = unsafe { mem::zeroed::<[WFSVERSION; 2]>() };
+        assert!(overlaps(&versions[0], &versions[0]));
+        assert!(!overlaps(&versions[0], &versions[1]));
+        assert!(overlaps(versions.as_ptr() as *const u8, &versions[0]));
+    }
+
+    fn image(machine: WORD) -> Vec<u8> {
+        let mut image = vec![0u8; 0x100];
+        image[..2].copy_from_slice(b"MZ");
+        image[0x3c..0x40].copy_from_slice(&0x80u32.to_le_bytes());
+        image[0x80..0x84].copy_from_slice(b"PE\0\0");
+        image[0x84..0x86].copy_from_slice(&machine.to_le_bytes());
+        image
+    }
+
+    #[test]
+    fn test_foreign_machine() {
+        use winapi::um::winnt::{IMAGE_FILE_MACHINE_AMD64, IMAGE_FILE_MACHINE_I386};
+
+        let foreign = if HOST_MACHINE == IMAGE_FILE_MACHINE_I386 {
+            IMAGE_FILE_MACHINE_AMD64
+        } else {
+            IMAGE_FILE_MACHINE_I386
+        };
+        assert_eq!(foreign_machine(&image(foreign)), Some(foreign));
+        assert_eq!(foreign_machine(&image(HOST_MACHINE)), None);
+        assert_eq!(foreign_machine(b"MZ"), None);
+        assert_eq!(foreign_machine(b"not an image"), None);
+    }
+
+    #[test]
+    fn test_nested_call_diagnostics() {
+        start_up();
+        let logs = captured_logs();
+        let thread_id = unsafe { GetCurrentThreadId() };
+        assert!(!WFSIsBlocking());
+
+        let blocking = Blocking::new(BlockingCall {
+            operation: BlockingCall::operation(WFS_EXECUTE_COMPLETE),
+            service: Some(3),
+            canceled: false,
+        })
+        .unwrap();
+        assert!(WFSIsBlocking());
+
+        // the rejection names the call the thread is blocked in, not just the nested one
+        let mut request_id = 0;
+        assert_eq!(WFSAsyncExecute(1, 101, ptr::null_mut(), 0, ptr::null_mut(), &mut request_id), WFS_ERR_OP_IN_PROGRESS);
+        assert!(logs
+            .lock()
+            .unwrap()
+            .iter()
+            .any(|log| log == &format!("Thread {thread_id} is blocked in WFSExecute on service 3, rejecting the nested call")));
+
+        let nested = Blocking::new(BlockingCall {
+            operation: BlockingCall::operation(WFS_OPEN_COMPLETE),
+            service: None,
+            canceled: false,
+        });
+        assert_eq!(nested.err(), Some(WFS_ERR_OP_IN_PROGRESS));
+        assert!(logs
+            .lock()
+            .unwrap()
+            .iter()
+            .any(|log| log == &format!("Thread {thread_id} is blocked in WFSExecute on service 3, rejecting WFSOpen")));
+
+        assert_eq!(WFSCancelBlockingCall(0), WFS_SUCCESS);
+        assert!(blocking.canceled());
+        drop(blocking);
+        assert!(!WFSIsBlocking());
+    }
+
+    #[test]
+    fn test_call_id_of_completion() {
+        captured_logs();
+        let call_of = |line: &str| LOGGER.1.lock().unwrap().iter().find(|(log, _)| log == line).map(|(_, call_id)| *call_id);
+
+        // the provider accepts the request and never completes it
+        let service_id = free_slot() as HSERVICE + 1;
+        let window = SyncWindow::new(WFS_EXECUTE_COMPLETE);
+        let mut request_call = None;
+        catch_panic(|| {
+            request_call = call_id();
+            relay::forward(service_id, 1, window.handle(), WFS_EXECUTE_COMPLETE, 101, |_| {
+                warn!("Provider called for request 1 of service {service_id}");
+                WFS_SUCCESS
+            })
+        });
+        assert!(request_call.is_some());
+        assert_eq!(call_of(&format!("Provider called for request 1 of service {service_id}")), Some(request_call));
+
+        // the completion the manager posts once the cancel is ignored is traced under the call of the request
+        catch_panic(|| {
+            assert_ne!(call_id(), request_call);
+            relay::cancel(service_id, 1);
+            WFS_SUCCESS
+        });
+        let line = format!("Provider did not complete cancelled request 1 of service {service_id}, posting WFS_ERR_CANCELED");
+        let deadline = Instant::now() + relay::CANCEL_GRACE_PERIOD + Duration::from_secs(5);
+        while call_of(&line).is_none() {
+            assert!(Instant::now() < deadline, "no cancel completion posted");
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(call_of(&line), Some(request_call));
+        let result = loop {
+            if let Some(result) = window.try_receive().unwrap() {
+                break result;
+            }
+            assert!(Instant::now() < deadline, "cancel completion not delivered");
+        };
+        unsafe { WFM_FREE_BUFFER(result as LPVOID) };
+    }
+
+    #[test]
+    fn test_strict_commands() {
+        let logs = captured_logs();
+
+        // known classes pass in strict mode, unknown ones only without it
+        assert_eq!(check_strict(commands::Command(302), true), WFS_SUCCESS);
+        assert_eq!(check_strict(commands::Command(12345), true), WFS_ERR_UNSUPP_COMMAND);
+        assert_eq!(check_strict(commands::Command(12345), false), WFS_SUCCESS);
+
+        assert!(logs
+            .lock()
+            .unwrap()
+            .iter()
+            .any(|log| log == "Rejecting command 12345 in strict mode, it belongs to no known service class"));
+    }
+
+    #[test]
+    fn test_async_rejects_zero_codes() {
+        start_up();
+        let mut request_id = 0;
+        let service_id = free_slot() as HSERVICE + 1;
+
+        // rejected before the service is even looked up, the null handle and the empty slot would fail that
+        assert_eq!(WFSAsyncExecute(0, 0, ptr::null_mut(), 0, ptr::null_mut(), &mut request_id), WFS_ERR_INVALID_COMMAND);
+        assert_eq!(WFSAsyncGetInfo(0, 0, ptr::null_mut(), 0, ptr::null_mut(), &mut request_id), WFS_ERR_INVALID_CATEGORY);
+        assert_eq!(WFSAsyncExecute(service_id, 0, ptr::null_mut(), 0, ptr::null_mut(), &mut request_id), WFS_ERR_INVALID_COMMAND);
+        assert_eq!(WFSAsyncGetInfo(service_id, 0, ptr::null_mut(), 0, ptr::null_mut(), &mut request_id), WFS_ERR_INVALID_CATEGORY);
+        assert_eq!(request_id, 0);
+
+        assert_eq!(WFSAsyncExecute(service_id, 302, ptr::null_mut(), 0, ptr::null_mut(), &mut request_id), WFS_ERR_INVALID_HSERVICE);
+    }
+
+    #[test]
+    fn test_spi_versions() {
+        let range = |start: (u8, u8), end: (u8, u8)| VersionRange::new_explicit(Version::new_explicit(start.0, start.1), Version::new_explicit(end.0, end.1));
+
+        assert_eq!(spi_versions(range((2, 0), (2, 30)).value()), range((2, 0), (2, 30)));
+        assert_eq!(spi_versions(range((3, 0), (4, 0)).value()), range((3, 0), (3, 30)));
+        assert_eq!(spi_versions(range((1, 0), (1, 10)).value()), SUPPORTED_VERSIONS);
+    }
+
+    #[test]
+    fn test_app_handle_generation() {
+        start_up();
+        let mut first = ptr::null_mut();
+        assert_eq!(WFSCreateAppHandle(&mut first), WFS_SUCCESS);
+        assert_eq!(WFSDestroyAppHandle(first), WFS_SUCCESS);
+
+        let mut second = ptr::null_mut();
+        assert_eq!(WFSCreateAppHandle(&mut second), WFS_SUCCESS);
+        assert_eq!(AppHandle::from_happ(first).map(|(index, _)| index), AppHandle::from_happ(second).map(|(index, _)| index));
+        assert_ne!(first, second);
+
+        assert_eq!(WFSDestroyAppHandle(first), WFS_ERR_INVALID_APP_HANDLE);
+        assert_eq!(WFSDestroyAppHandle(second), WFS_SUCCESS);
+        assert_eq!(WFSDestroyAppHandle(ptr::null_mut()), WFS_ERR_INVALID_APP_HANDLE);
+    }
+
+    #[test]
+    fn test_leaked_handle_reported() {
+        start_up();
+        let logs = captured_logs();
+        let mut app = ptr::null_mut();
+        assert_eq!(WFSCreateAppHandle(&mut app), WFS_SUCCESS);
+
+        // kernel32 stands in for the provider
+        let slot = free_slot();
+        let service_id = slot as HSERVICE + 1;
+        SERVICES.lock().unwrap()[slot] = Some(Service {
+            app: app as usize,
+            ..test_service(slot, load_provider("kernel32.dll").unwrap())
+        });
+        assert!(!manager::leaked_handles().unwrap().contains(&service_id));
+
+        assert_eq!(WFSDestroyAppHandle(app), WFS_SUCCESS);
+        assert!(manager::leaked_handles().unwrap().contains(&service_id));
+        let warning = format!("Service {service_id} was still open when application handle {app:?} was destroyed, the application did not close it");
+        assert!(logs.lock().unwrap().iter().any(|log| log == &warning));
+
+        SERVICES.lock().unwrap()[slot] = None;
+    }
+
+    #[test]
+    fn test_load_provider_shared() {
+        let libraries: Vec<_> = ["kernel32.dll", "KERNEL32.DLL", "kernel32.dll"].iter().map(|path| load_provider(path).unwrap()).collect();
+        assert!(libraries.iter().all(|library| Arc::ptr_eq(library, &libraries[0])));
+        assert_eq!(Arc::strong_count(&libraries[0]), 3);
+
+        let weak = Arc::downgrade(&libraries[0]);
+        drop(libraries);
+        assert!(weak.upgrade().is_none());
+        assert!(load_provider("kernel32.dll").is_ok());
+    }
+
+    #[test]
+    fn test_trace_level_floor() {
+        let floor = parse_trace_level_floor("0x5");
+        assert_eq!(effective_trace_level(TraceLevel::NONE, floor), TraceLevel::API | TraceLevel::SPI);
+        assert_eq!(effective_trace_level(TraceLevel::ALL_API, floor).bits(), 0x7);
+        assert_eq!(effective_trace_level(TraceLevel::API, parse_trace_level_floor("16")), TraceLevel::API | TraceLevel::MGR);
+        assert_eq!(parse_trace_level_floor("bogus"), TraceLevel::NONE);
+        assert_eq!(effective_trace_level(TraceLevel::API, TraceLevel::NONE), TraceLevel::API);
+    }
+
+    #[test]
+    fn test_unload_service_unsupported() {
+        // kernel32 does not export any provider function
+        let library = load_provider("kernel32.dll").unwrap();
+        assert_eq!(unload_service(&library), WFS_ERR_UNSUPP_COMMAND);
+    }
+
+    #[test]
+    fn test_with_service() {
+        type CurrentThreadId = unsafe extern "system" fn() -> DWORD;
+
+        let slot = free_slot();
+        let service_id = slot as HSERVICE + 1;
+        let mut request_id = 0;
+        let mut called = false;
+        // the null handle, one past the last slot and the slot before it is filled
+        for invalid in [0, 8193, service_id] {
+            let result = with_service::<CurrentThreadId>(invalid, &mut request_id, b"GetCurrentThreadId", |_, _| {
+                called = true;
+                WFS_SUCCESS
+            });
+            assert_eq!(result, WFS_ERR_INVALID_HSERVICE);
+        }
+        assert!(!called);
+
+        // kernel32 stands in for the provider
+        SERVICES.lock().unwrap()[slot] = Some(test_service(slot, load_provider("kernel32.dll").unwrap()));
+
+        let result = with_service::<CurrentThreadId>(service_id, &mut request_id, b"GetCurrentThreadId", |get_current_thread_id, id| {
+            assert_eq!(id, 2);
+            assert_eq!(unsafe { get_current_thread_id() }, unsafe { GetCurrentThreadId() });
+            WFS_SUCCESS
+        });
+        assert_eq!(result, WFS_SUCCESS);
+        assert_eq!(request_id, 2);
+
+        let result = with_service::<CurrentThreadId>(service_id, &mut request_id, b"WFPExecute", |_, _| WFS_SUCCESS);
+        assert_eq!(result, WFS_ERR_UNSUPP_COMMAND);
+        assert_eq!(request_id, 3);
+
+        // a service being closed takes no new requests until the close is cancelled
+        set_closing(service_id, true);
+        let result = with_service::<CurrentThreadId>(service_id, &mut request_id, b"GetCurrentThreadId", |_, _| WFS_SUCCESS);
+        assert_eq!(result, WFS_ERR_INVALID_HSERVICE);
+        // its requests, the close among them, may still be cancelled
+        let result = with_provider::<CurrentThreadId>(service_id, b"GetCurrentThreadId", Service::is_cancellable, |_| {}, |_| WFS_SUCCESS);
+        assert_eq!(result, WFS_SUCCESS);
+        close_completed(service_id, WFS_ERR_CANCELED);
+        let result = with_service::<CurrentThreadId>(service_id, &mut request_id, b"GetCurrentThreadId", |_, _| WFS_SUCCESS);
+        assert_eq!(result, WFS_SUCCESS);
+        assert_eq!(request_id, 4);
+
+        // a failed close releases the slot
+        set_closing(service_id, true);
+        close_completed(service_id, WFS_ERR_HARDWARE_ERROR);
+        assert!(SERVICES.lock().unwrap()[slot].is_none());
+    }
+
+    #[test]
+    fn test_release_dll_token() {
+        let slot = free_slot();
+        let provider = Service::provider_token(slot);
+        SERVICES.lock().unwrap()[slot] = Some(Service {
+            provider,
+            ..test_service(slot, load_provider("kernel32.dll").unwrap())
+        });
+
+        // a token of an earlier service in the slot, the bare slot and garbage are all turned away
+        for forged in [0, provider.wrapping_sub(1 << 16), slot + 1, provider + 1, usize::MAX] {
+            assert_eq!(WFMReleaseDLL(forged as HPROVIDER), WFS_ERR_INVALID_HPROVIDER);
+        }
+        assert!(SERVICES.lock().unwrap()[slot].is_some());
+
+        assert_eq!(WFMReleaseDLL(provider as HPROVIDER), WFS_SUCCESS);
+        assert!(SERVICES.lock().unwrap()[slot].is_none());
+        assert_eq!(WFMReleaseDLL(provider as HPROVIDER), WFS_ERR_INVALID_HPROVIDER);
+    }
+
+    #[test]
+    fn test_execute_unwritable_result() {
+        start_up();
+        let result = WFSExecute(1, 1, ptr::null_mut(), 0, 1 as *mut LPWFSRESULT);
+        assert_eq!(result, WFS_ERR_INVALID_POINTER);
+    }
+
+    #[test]
+    fn test_open_aliased_versions() {
+        start_up();
+        let mut version = unsafe { mem::zeroed::<WFSVERSION>() };
+        let mut service = 0;
+        let mut request_id = 0;
+        let name = CString::new("cwd").unwrap();
+        let result = WFSAsyncOpen(
+            name.as_ptr() as LPSTR,
+            ptr::null_mut(),
+            ptr::null_mut(),
+            0,
+            0,
+            &mut service,
+            ptr::null_mut(),
+            0,
+            &mut version,
+            &mut version,
+            &mut request_id,
+        );
+        assert_eq!(result, WFS_ERR_INVALID_POINTER);
+    }
+
+    #[test]
+    fn test_proxy_released() {
+        // the windows are only stored as the targets of their proxies, no other test uses them
+        let (window, other) = (0x4711 as HWND, 0x4712 as HWND);
+        let proxy = relay::proxy(window);
+        let other_proxy = relay::proxy(other);
+        assert_eq!(relay::proxy(window), proxy);
+        assert_ne!(unsafe { IsWindow(proxy) }, 0);
+
+        // the relay thread destroys the proxy of the released window only
+        relay::retain_proxies(|registered| registered != window as usize);
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while unsafe { IsWindow(proxy) } != 0 {
+            assert!(Instant::now() < deadline, "proxy not destroyed");
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert_ne!(unsafe { IsWindow(other_proxy) }, 0);
+        assert_eq!(relay::proxy(other), other_proxy);
+        relay::retain_proxies(|registered| registered != other as usize);
     }
-    true
 }
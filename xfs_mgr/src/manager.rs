@@ -0,0 +1,487 @@
+//! Requests the manager answers itself instead of forwarding them to a service provider.
+
+use std::{
+    fmt, mem, ptr,
+    sync::atomic::{AtomicU32, Ordering},
+    time::{Duration, Instant},
+};
+
+use log::error;
+use winapi::{
+    shared::minwindef::{DWORD, HKEY, LPARAM, LPVOID, UINT, ULONG},
+    shared::windef::HWND,
+    um::{sysinfoapi::GetSystemTime, winnt::LPSTR, winuser::PostMessageA},
+};
+use xfslib::{registry::RegKey, *};
+
+use crate::{block_for, call_async, conf::*, last_error::LastError, load_provider, relay, supp::*, timeout_left, WFSAsyncGetInfo, NOT_READY_BACKOFF, NOT_READY_MAX_BACKOFF};
+
+/// Request ids for the manager's own requests, which have no service to count them.
+static REQUEST_ID: AtomicU32 = AtomicU32::new(1);
+
+/// SPI functions every service provider has to export.
+pub const MANDATORY_EXPORTS: [&str; 11] = [
+    "WFPCancelAsyncRequest",
+    "WFPClose",
+    "WFPDeregister",
+    "WFPExecute",
+    "WFPGetInfo",
+    "WFPLock",
+    "WFPOpen",
+    "WFPRegister",
+    "WFPSetTraceLevel",
+    "WFPUnloadService",
+    "WFPUnlock",
+];
+
+/// Broken logical service mapping found by [`validate_configuration`].
+#[derive(Debug, PartialEq, Eq)]
+pub enum ConfigIssue {
+    /// The logical service has no `provider` value.
+    MissingProvider { service: String },
+    /// The provider the logical service points at has no `dllname` value.
+    MissingDllName { service: String, provider: String },
+    /// The provider DLL does not exist or cannot be loaded into this process.
+    UnloadableDll { service: String, dll_name: String },
+    /// The provider DLL lacks some of the [`MANDATORY_EXPORTS`].
+    MissingExports { service: String, dll_name: String, exports: Vec<&'static str> },
+}
+
+impl fmt::Display for ConfigIssue {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ConfigIssue::MissingProvider { service } => write!(f, "Logical service {service} has no provider"),
+            ConfigIssue::MissingDllName { service, provider } => write!(f, "Provider {provider} of logical service {service} has no dllname"),
+            ConfigIssue::UnloadableDll { service, dll_name } => write!(f, "Provider DLL {dll_name} of logical service {service} cannot be loaded"),
+            ConfigIssue::MissingExports { service, dll_name, exports } => write!(f, "Provider DLL {dll_name} of logical service {service} does not export {}", exports.join(", ")),
+        }
+    }
+}
+
+/// Returns the names of the logical services configured under `LOGICAL_SERVICES`.
+pub fn enumerate_logical_services() -> Result<Vec<String>, HRESULT> {
+    RegKey::open(&CONFIG_API, WFS_CFG_HKEY_USER_DEFAULT_XFS_ROOT, "LOGICAL_SERVICES")?.enum_keys()
+}
+
+/// Checks that every logical service resolves to a provider DLL that loads and exports the SPI, without opening
+/// a session. Most broken mappings only show once an application opens the service, so operators run this after
+/// an install instead.
+pub fn validate_configuration() -> Result<Vec<ConfigIssue>, HRESULT> {
+    Ok(enumerate_logical_services()?.iter().filter_map(|service| validate_logical_service(service).err()).collect())
+}
+
+/// Checks a single logical service, see [`validate_configuration`].
+pub fn validate_logical_service(service: &str) -> Result<(), ConfigIssue> {
+    let provider = query_value(WFS_CFG_HKEY_USER_DEFAULT_XFS_ROOT, &format!("LOGICAL_SERVICES\\{service}"), "provider").ok_or_else(|| ConfigIssue::MissingProvider { service: service.to_owned() })?;
+    let dll_name = query_value(WFS_CFG_HKEY_MACHINE_XFS_ROOT, &format!("SERVICE_PROVIDERS\\{provider}"), "dllname").ok_or_else(|| ConfigIssue::MissingDllName {
+        service: service.to_owned(),
+        provider: provider.clone(),
+    })?;
+    let library = load_provider(&dll_name).map_err(|_| ConfigIssue::UnloadableDll {
+        service: service.to_owned(),
+        dll_name: dll_name.clone(),
+    })?;
+
+    // SAFETY: the symbols are only looked up, never called
+    let exports: Vec<_> = MANDATORY_EXPORTS
+        .into_iter()
+        .filter(|export| unsafe { library.get::<unsafe extern "stdcall" fn()>(export.as_bytes()) }.is_err())
+        .collect();
+    if !exports.is_empty() {
+        return Err(ConfigIssue::MissingExports {
+            service: service.to_owned(),
+            dll_name,
+            exports,
+        });
+    }
+    Ok(())
+}
+
+/// Reads a string value below one of the XFS configuration roots, None if the key or the value does not exist.
+fn query_value(root: HKEY, path: &str, name: &str) -> Option<String> {
+    RegKey::open(&CONFIG_API, root, path).and_then(|key| key.query_value(name)).ok()
+}
+
+/// Answers a WFSAsyncGetInfo addressed to the manager (hService 0) by posting the completion to the window.
+pub fn get_info(category: DWORD, window: HWND, request_id: &mut REQUESTID) -> HRESULT {
+    *request_id = REQUEST_ID.fetch_add(1, Ordering::SeqCst);
+
+    let info = match category {
+        WFS_INF_MGR_LOGICAL_SERVICES => enumerate_logical_services().map(ManagerInfo::LogicalServices),
+        WFS_INF_MGR_STATISTICS => statistics().map(ManagerInfo::Statistics),
+        WFS_INF_MGR_IN_FLIGHT => dump_in_flight().map(ManagerInfo::InFlight),
+        WFS_INF_MGR_TRACE_LEVELS => trace_levels().map(ManagerInfo::TraceLevels),
+        WFS_INF_MGR_LATENCIES => command_latencies().map(ManagerInfo::Latencies),
+        WFS_INF_MGR_LAST_ERROR => Ok(ManagerInfo::LastError(last_error())),
+        _ => xfs_reject!(WFS_ERR_INVALID_CATEGORY),
+    };
+
+    // SAFETY: the result and its strings are allocated on the XFS heap and handed over to the window
+    unsafe {
+        let result = match allocate_result(0, *request_id, WFS_SUCCESS, category) {
+            Ok(result) => result,
+            Err(error) => return error,
+        };
+        let h_result = match info.and_then(|info| info.allocate(result as LPVOID)) {
+            Ok(buffer) => {
+                ptr::addr_of_mut!((*result).lpBuffer).write_unaligned(buffer);
+                WFS_SUCCESS
+            }
+            Err(error) => error,
+        };
+        ptr::addr_of_mut!((*result).hResult).write_unaligned(h_result);
+
+        post_result(window, WFS_GETINFO_COMPLETE, result)
+    }
+}
+
+/// Polls a status category of the service until the provider stops reporting WFS_ERR_DEV_NOT_READY.
+/// Returns the result of the last poll, or WFS_ERR_TIMEOUT if the provider was still not ready at the deadline.
+///
+/// Each poll is a single request given the time left, not a WFSGetInfo with its own retries, and the waits in between
+/// block the thread like a synchronous call, so they can be canceled with WFSCancelBlockingCall.
+pub fn wait_until_ready(service: HSERVICE, category: DWORD, deadline: Option<Instant>) -> HRESULT {
+    let mut backoff = NOT_READY_BACKOFF;
+
+    loop {
+        let mut result: LPWFSRESULT = ptr::null_mut();
+        let h_result = call_async(
+            WFS_GETINFO_COMPLETE,
+            Some(service),
+            |hwnd, request_id| WFSAsyncGetInfo(service, category, ptr::null_mut(), timeout_left(deadline), hwnd, request_id),
+            &mut result,
+        );
+        if !result.is_null() {
+            // SAFETY: the result was handed over by call_async and is not used afterwards
+            unsafe { WFM_FREE_BUFFER(result as LPVOID) };
+        }
+
+        if h_result != WFS_ERR_DEV_NOT_READY {
+            return h_result;
+        }
+        let delay = match deadline {
+            Some(deadline) => backoff.min(deadline.saturating_duration_since(Instant::now())),
+            None => backoff,
+        };
+        if delay.is_zero() {
+            xfs_reject!(WFS_ERR_TIMEOUT);
+        }
+        let waited = block_for(WFS_GETINFO_COMPLETE, Some(service), delay);
+        if waited != WFS_SUCCESS {
+            return waited;
+        }
+        backoff = (backoff * 2).min(NOT_READY_MAX_BACKOFF);
+    }
+}
+
+/// Answer to a WFSGetInfo addressed to the manager.
+enum ManagerInfo {
+    LogicalServices(Vec<String>),
+    Statistics(WFSMGRSTATISTICS),
+    InFlight(Vec<InFlightInfo>),
+    TraceLevels(Vec<TraceLevelInfo>),
+    Latencies(Vec<CommandLatency>),
+    LastError(Option<LastError>),
+}
+
+impl ManagerInfo {
+    /// Lays out the answer in buffers allocated with WFMAllocateMore on `parent`.
+    unsafe fn allocate(self, parent: LPVOID) -> Result<LPVOID, HRESULT> {
+        match self {
+            ManagerInfo::LogicalServices(names) => allocate_string_array(&names, parent),
+            ManagerInfo::Statistics(statistics) => allocate_value(statistics, parent),
+            ManagerInfo::InFlight(requests) => {
+                let requests: Vec<WFSMGRINFLIGHT> = requests.iter().map(InFlightInfo::to_wfs).collect();
+                allocate_pointer_array(&requests, parent)
+            }
+            ManagerInfo::TraceLevels(services) => {
+                let mut levels = Vec::with_capacity(services.len());
+                for service in services {
+                    levels.push(WFSMGRTRACELEVEL {
+                        hService: service.service,
+                        lpszLogicalName: allocate_string(&service.logical_name, parent)? as LPSTR,
+                        dwTraceLevel: service.trace_level.bits(),
+                    });
+                }
+                allocate_pointer_array(&levels, parent)
+            }
+            ManagerInfo::Latencies(latencies) => {
+                let latencies: Vec<WFSMGRLATENCY> = latencies.iter().map(CommandLatency::to_wfs).collect();
+                allocate_pointer_array(&latencies, parent)
+            }
+            ManagerInfo::LastError(None) => Ok(ptr::null_mut()),
+            ManagerInfo::LastError(Some(error)) => allocate_value(
+                WFSMGRLASTERROR {
+                    hResult: error.h_result,
+                    lpszOperation: allocate_string(error.operation, parent)? as LPSTR,
+                    hService: error.service,
+                    tsTimestamp: error.timestamp,
+                },
+                parent,
+            ),
+        }
+    }
+}
+
+/// Takes a snapshot of the manager's runtime statistics.
+fn statistics() -> Result<WFSMGRSTATISTICS, HRESULT> {
+    let mut statistics = WFSMGRSTATISTICS::default();
+    let (mut buffers, mut bytes, mut timers) = (0, 0, 0);
+
+    // SAFETY: the out-parameters are locals
+    unsafe {
+        WFM_GET_HEAP_STATS(&mut buffers, &mut bytes).ok()?;
+        WFM_GET_TIMER_COUNT(&mut timers).ok()?;
+    }
+    statistics.dwHeapBuffers = buffers;
+    statistics.dwHeapBytes = bytes;
+    statistics.dwTimers = timers;
+    statistics.dwOpenServices = crate::open_service_count()? as DWORD;
+    statistics.dwBlockedThreads = crate::blocked_thread_count()? as DWORD;
+    statistics.dwResolvedServices = crate::resolved_service_count()? as DWORD;
+    Ok(statistics)
+}
+
+/// Request a provider has not completed yet.
+#[derive(Debug)]
+pub struct InFlightInfo {
+    pub service: HSERVICE,
+    pub request_id: REQUESTID,
+    /// Completion message the request ends with.
+    pub message: UINT,
+    /// Command or category of the request.
+    pub command: DWORD,
+    /// Application window the completion is relayed to.
+    pub window: HWND,
+    pub elapsed: Duration,
+}
+
+impl InFlightInfo {
+    fn to_wfs(&self) -> WFSMGRINFLIGHT {
+        WFSMGRINFLIGHT {
+            hService: self.service,
+            RequestID: self.request_id,
+            dwMessage: self.message,
+            dwCommand: self.command,
+            hWnd: self.window,
+            dwElapsed: millis(self.elapsed),
+        }
+    }
+}
+
+/// Lists the requests the providers have not completed yet, by service and request id, for finding the ones stuck.
+pub fn dump_in_flight() -> Result<Vec<InFlightInfo>, HRESULT> {
+    let mut requests = relay::in_flight()?;
+    requests.sort_by_key(|request| (request.service, request.request_id));
+    Ok(requests)
+}
+
+/// Trace level of an open service.
+#[derive(Debug)]
+pub struct TraceLevelInfo {
+    pub service: HSERVICE,
+    pub logical_name: String,
+    pub trace_level: TraceLevel,
+}
+
+/// Lists the trace levels in effect for the open services, by service handle, so they can be audited in one go.
+pub fn trace_levels() -> Result<Vec<TraceLevelInfo>, HRESULT> {
+    Ok(crate::service_trace_levels()?
+        .into_iter()
+        .map(|(service, logical_name, trace_level)| TraceLevelInfo { service, logical_name, trace_level })
+        .collect())
+}
+
+/// Time the requests of one command of a service took from the hand over to the provider to their completion.
+#[derive(Clone, Debug)]
+pub struct CommandLatency {
+    pub service: HSERVICE,
+    /// Completion message of the requests.
+    pub message: UINT,
+    /// Command or category of the requests.
+    pub command: DWORD,
+    pub count: u32,
+    pub min: Duration,
+    pub max: Duration,
+    pub total: Duration,
+}
+
+impl CommandLatency {
+    pub fn new(service: HSERVICE, message: UINT, command: DWORD) -> Self {
+        CommandLatency {
+            service,
+            message,
+            command,
+            count: 0,
+            min: Duration::MAX,
+            max: Duration::ZERO,
+            total: Duration::ZERO,
+        }
+    }
+
+    /// Adds the time one request took.
+    pub fn record(&mut self, elapsed: Duration) {
+        self.count = self.count.saturating_add(1);
+        self.min = self.min.min(elapsed);
+        self.max = self.max.max(elapsed);
+        self.total = self.total.saturating_add(elapsed);
+    }
+
+    pub fn average(&self) -> Duration {
+        self.total / self.count.max(1)
+    }
+
+    fn to_wfs(&self) -> WFSMGRLATENCY {
+        WFSMGRLATENCY {
+            hService: self.service,
+            dwMessage: self.message,
+            dwCommand: self.command,
+            dwCount: self.count,
+            dwMinimum: millis(self.min),
+            dwAverage: millis(self.average()),
+            dwMaximum: millis(self.max),
+        }
+    }
+}
+
+/// Lists the completion times recorded since WFSStartUp while [`relay::COMMAND_METRICS_ENV`] was set, by service,
+/// completion message and command, for latency dashboards.
+pub fn command_latencies() -> Result<Vec<CommandLatency>, HRESULT> {
+    let mut latencies = relay::latencies()?;
+    latencies.sort_by_key(|latency| (latency.service, latency.message, latency.command));
+    Ok(latencies)
+}
+
+/// Gets the last call of the calling thread that failed, None if the call before the current one succeeded.
+pub fn last_error() -> Option<LastError> {
+    crate::last_error::last_error()
+}
+
+/// Lists the services the application left open since WFSStartUp: still open when the application handle they
+/// were opened under was destroyed, or when WFSCleanUp closed them.
+pub fn leaked_handles() -> Result<Vec<HSERVICE>, HRESULT> {
+    crate::leaked_services()
+}
+
+/// Converts to the whole milliseconds the manager info structures carry.
+fn millis(duration: Duration) -> DWORD {
+    duration.as_millis().min(DWORD::MAX as u128) as DWORD
+}
+
+/// Allocates a WFSRESULT without a buffer on the XFS heap. Every result the manager makes up itself comes from here,
+/// so the application frees it with WFSFreeResult like the ones of the providers. It is tagged with the service and
+/// command, a result the application never frees shows up in WFMGetTaggedHeapStats.
+pub unsafe fn allocate_result(service: HSERVICE, request_id: REQUESTID, h_result: HRESULT, command: DWORD) -> Result<LPWFSRESULT, HRESULT> {
+    let mut result: LPVOID = ptr::null_mut();
+    WFM_ALLOCATE_TAGGED_BUFFER(mem::size_of::<WFSRESULT>() as ULONG, WFS_MEM_ZEROINIT, service, command, &mut result).ok()?;
+
+    let mut timestamp = mem::zeroed();
+    GetSystemTime(&mut timestamp);
+
+    (result as LPWFSRESULT).write_unaligned(WFSRESULT {
+        RequestID: request_id,
+        hService: service,
+        tsTimestamp: timestamp,
+        hResult: h_result,
+        u: U { dwCommandCode: command },
+        lpBuffer: ptr::null_mut(),
+    });
+    Ok(result as LPWFSRESULT)
+}
+
+/// Posts the result to the window, freeing it if the window cannot receive it.
+pub unsafe fn post_result(window: HWND, message: UINT, result: LPWFSRESULT) -> HRESULT {
+    if PostMessageA(window, message, 0, result as LPARAM) == 0 {
+        error!("Failed to post message {message} to window {window:?}");
+        WFM_FREE_BUFFER(result as LPVOID);
+        return WFS_ERR_INVALID_HWND;
+    }
+    WFS_SUCCESS
+}
+
+/// Copies the value into a buffer allocated with WFMAllocateMore on `parent`.
+unsafe fn allocate_value<T: Copy>(value: T, parent: LPVOID) -> Result<LPVOID, HRESULT> {
+    let mut buffer: LPVOID = ptr::null_mut();
+    WFM_ALLOCATE_MORE(mem::size_of::<T>() as ULONG, parent, &mut buffer).ok()?;
+    (buffer as *mut T).write_unaligned(value);
+    Ok(buffer)
+}
+
+/// Lays out the values as a NULL terminated array of pointers to them, all allocated with WFMAllocateMore on `parent`.
+unsafe fn allocate_pointer_array<T: Copy>(values: &[T], parent: LPVOID) -> Result<LPVOID, HRESULT> {
+    let mut array: LPVOID = ptr::null_mut();
+    WFM_ALLOCATE_MORE(((values.len() + 1) * mem::size_of::<LPVOID>()) as ULONG, parent, &mut array).ok()?;
+
+    for (index, value) in values.iter().enumerate() {
+        (array as *mut LPVOID).add(index).write(allocate_value(*value, parent)?);
+    }
+    (array as *mut LPVOID).add(values.len()).write(ptr::null_mut());
+
+    Ok(array)
+}
+
+/// Lays out the strings as a NULL terminated array of LPSTR, all allocated with WFMAllocateMore on `parent`
+/// so that freeing the result frees them too.
+unsafe fn allocate_string_array(strings: &[String], parent: LPVOID) -> Result<LPVOID, HRESULT> {
+    let mut array: LPVOID = ptr::null_mut();
+    WFM_ALLOCATE_MORE(((strings.len() + 1) * mem::size_of::<LPSTR>()) as ULONG, parent, &mut array).ok()?;
+
+    for (index, string) in strings.iter().enumerate() {
+        (array as *mut LPSTR).add(index).write(allocate_string(string, parent)? as LPSTR);
+    }
+    (array as *mut LPSTR).add(strings.len()).write(ptr::null_mut());
+
+    Ok(array)
+}
+
+/// Copies the string with a terminating NUL into a buffer allocated with WFMAllocateMore on `parent`.
+unsafe fn allocate_string(string: &str, parent: LPVOID) -> Result<LPVOID, HRESULT> {
+    let mut buffer: LPVOID = ptr::null_mut();
+    WFM_ALLOCATE_MORE((string.len() + 1) as ULONG, parent, &mut buffer).ok()?;
+    ptr::copy_nonoverlapping(string.as_ptr(), buffer as *mut u8, string.len());
+    (buffer as *mut u8).add(string.len()).write(0);
+    Ok(buffer)
+}
+
+#[cfg(test)]
+mod tests {
+    use xfslib::sandbox::Sandbox;
+
+    use super::*;
+
+    #[test]
+    fn test_enumerate_logical_services() {
+        let sandbox = Sandbox::new("manager");
+        sandbox.map_service("enumerated", "enumerated", None);
+
+        assert_eq!(enumerate_logical_services(), Ok(vec!["enumerated".to_owned()]));
+    }
+
+    #[test]
+    fn test_validate_configuration() {
+        let sandbox = Sandbox::new("manager");
+        sandbox.map_service("validate_healthy", "validate_healthy", Some("xfs_mock.dll"));
+        sandbox.map_service("validate_no_dll_name", "validate_no_dll_name", None);
+        sandbox.map_service("validate_no_exports", "validate_no_exports", Some("kernel32.dll"));
+
+        assert_eq!(validate_logical_service("validate_healthy"), Ok(()));
+        assert_eq!(
+            validate_logical_service("validate_no_dll_name"),
+            Err(ConfigIssue::MissingDllName {
+                service: "validate_no_dll_name".to_owned(),
+                provider: "validate_no_dll_name".to_owned(),
+            })
+        );
+        assert_eq!(
+            validate_logical_service("validate_no_exports"),
+            Err(ConfigIssue::MissingExports {
+                service: "validate_no_exports".to_owned(),
+                dll_name: "kernel32.dll".to_owned(),
+                exports: MANDATORY_EXPORTS.to_vec(),
+            })
+        );
+
+        // the sandbox holds no other services
+        assert_eq!(validate_configuration().unwrap().len(), 2);
+    }
+}
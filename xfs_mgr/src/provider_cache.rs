@@ -0,0 +1,346 @@
+//! Cache of the provider DLL each logical service resolves to.
+//!
+//! WFSOpen resolves the logical service to its provider and the provider to its DLL, two registry reads per open.
+//! Test rigs opening and closing the same services in a loop spend much of the open there, so resolved services are
+//! kept for [`CACHE_TTL_ENV`] and dropped early once anything below LOGICAL_SERVICES or SERVICE_PROVIDERS changes,
+//! as reported by RegNotifyChangeKeyValue. Lookups that failed are not cached, so a fixed configuration is picked up
+//! by the next open. With [`CONFIG_WATCH_ENV`] set, a background thread drops them as soon as the change is reported.
+
+use std::{
+    collections::HashMap,
+    ptr,
+    sync::{Mutex, Once},
+    thread,
+    time::{Duration, Instant},
+};
+
+use log::{error, info, warn};
+use winapi::{
+    shared::{
+        minwindef::{DWORD, FALSE, HKEY, TRUE},
+        winerror::{ERROR_SUCCESS, HRESULT},
+    },
+    um::{
+        handleapi::CloseHandle,
+        synchapi::{CreateEventA, ResetEvent, WaitForMultipleObjects, WaitForSingleObject},
+        winbase::{INFINITE, WAIT_OBJECT_0},
+        winnt::{HANDLE, REG_NOTIFY_CHANGE_LAST_SET, REG_NOTIFY_CHANGE_NAME, REG_NOTIFY_THREAD_AGNOSTIC},
+        winreg::RegNotifyChangeKeyValue,
+    },
+};
+use xfslib::{
+    registry::{ConfigApi, RegKey},
+    *,
+};
+
+/// Milliseconds a resolved logical service is cached, 0 disables the cache. Read when the first service is opened.
+pub const CACHE_TTL_ENV: &str = "XFS_PROVIDER_CACHE_TTL";
+
+/// When set, WFSStartUp starts a thread that drops the resolved logical services the moment the configuration
+/// changes and logs a notice, so long running processes see the change without waiting for the next open.
+pub const CONFIG_WATCH_ENV: &str = "XFS_CONFIG_WATCH";
+
+/// Cache lifetime without [`CACHE_TTL_ENV`].
+const DEFAULT_TTL: Duration = Duration::from_secs(60);
+
+/// Provider and DLL a logical service is configured with.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Resolved {
+    pub provider: String,
+    pub dll_name: String,
+}
+
+/// Change notification on one configuration key, re-armed whenever it fired.
+struct Watch<'a> {
+    key: RegKey<'a>,
+    event: HANDLE,
+}
+
+// SAFETY: the key and event handles are owned by the watch and usable from any thread
+unsafe impl Send for Watch<'_> {}
+
+impl<'a> Watch<'a> {
+    fn new(api: &'a ConfigApi, root: HKEY, path: &str) -> Option<Self> {
+        let key = RegKey::open(api, root, path).ok()?;
+        // SAFETY: an unnamed manual reset event, so the watcher thread waiting on it does not consume the change
+        let event = unsafe { CreateEventA(ptr::null_mut(), TRUE, FALSE, ptr::null()) };
+        if event.is_null() {
+            return None;
+        }
+        let watch = Watch { key, event };
+        watch.arm().then_some(watch)
+    }
+
+    fn arm(&self) -> bool {
+        // Thread agnostic, the registering application thread may exit long before the change
+        let filter = REG_NOTIFY_CHANGE_NAME | REG_NOTIFY_CHANGE_LAST_SET | REG_NOTIFY_THREAD_AGNOSTIC;
+        // SAFETY: key and event are open
+        let result = unsafe { RegNotifyChangeKeyValue(self.key.handle(), TRUE, filter, self.event, TRUE) };
+        if result as u32 != ERROR_SUCCESS {
+            warn!("RegNotifyChangeKeyValue failed: {result}, resolved providers expire by time only");
+            return false;
+        }
+        true
+    }
+
+    /// Whether the key changed since the last call.
+    fn fired(&self) -> bool {
+        // SAFETY: the event is open
+        if unsafe { WaitForSingleObject(self.event, 0) } != WAIT_OBJECT_0 {
+            return false;
+        }
+        // SAFETY: the event is open
+        unsafe { ResetEvent(self.event) };
+        self.arm();
+        true
+    }
+}
+
+impl Drop for Watch<'_> {
+    fn drop(&mut self) {
+        // SAFETY: the event is owned by the watch and closed exactly once
+        unsafe { CloseHandle(self.event) };
+    }
+}
+
+/// Logical services resolved so far, by lower case name.
+pub struct ProviderCache<'a> {
+    api: &'a ConfigApi,
+    ttl: Duration,
+    entries: HashMap<String, (Resolved, Instant)>,
+    watches: Vec<Watch<'a>>,
+}
+
+impl<'a> ProviderCache<'a> {
+    /// Creates a cache keeping entries for `ttl`, watching the configuration for changes where the registry allows it.
+    pub fn new(api: &'a ConfigApi, ttl: Duration) -> Self {
+        let watches = [(WFS_CFG_HKEY_USER_DEFAULT_XFS_ROOT, "LOGICAL_SERVICES"), (WFS_CFG_HKEY_MACHINE_XFS_ROOT, "SERVICE_PROVIDERS")]
+            .into_iter()
+            .filter_map(|(root, path)| Watch::new(api, root, path))
+            .collect();
+        ProviderCache {
+            api,
+            ttl,
+            entries: HashMap::new(),
+            watches,
+        }
+    }
+
+    /// Resolves a logical service, from the cache while the entry is fresh and the configuration unchanged.
+    pub fn resolve(&mut self, logical_name: &str) -> Result<Resolved, HRESULT> {
+        self.refresh();
+
+        let name = logical_name.to_ascii_lowercase();
+        if let Some((resolved, at)) = self.entries.get(&name) {
+            if at.elapsed() < self.ttl {
+                return Ok(resolved.clone());
+            }
+        }
+
+        let provider = self.query(WFS_CFG_HKEY_USER_DEFAULT_XFS_ROOT, &format!("LOGICAL_SERVICES\\{logical_name}"), "provider")?;
+        let dll_name = self.query(WFS_CFG_HKEY_MACHINE_XFS_ROOT, &format!("SERVICE_PROVIDERS\\{provider}"), "dllname")?;
+        let resolved = Resolved { provider, dll_name };
+        if !self.ttl.is_zero() {
+            self.entries.insert(name, (resolved.clone(), Instant::now()));
+        }
+        Ok(resolved)
+    }
+
+    /// Number of resolved services kept, expired ones included.
+    pub fn resolved_count(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Drops the resolved services if the configuration changed since the last call.
+    fn refresh(&mut self) {
+        // every watch is checked, so each one gets re-armed
+        if self.watches.iter().fold(false, |changed, watch| watch.fired() || changed) {
+            info!("XFS configuration changed, dropping {} resolved services", self.entries.len());
+            self.entries.clear();
+        }
+    }
+
+    fn query(&self, root: HKEY, path: &str, name: &str) -> Result<String, HRESULT> {
+        let key = RegKey::open(self.api, root, path).map_err(|error| {
+            error!("WFM_OPEN_KEY failed: {error}");
+            WFS_ERR_INVALID_SERVPROV
+        })?;
+        key.query_value(name).map_err(|error| {
+            error!("WFM_QUERY_VALUE failed: {error}");
+            WFS_ERR_INVALID_SERVPROV
+        })
+    }
+}
+
+/// Starts the thread refreshing the cache whenever the configuration changes, see [`CONFIG_WATCH_ENV`]. Only the
+/// first call starts it, it runs until the process exits.
+pub fn watch(cache: &'static Mutex<ProviderCache<'static>>) {
+    static WATCHING: Once = Once::new();
+
+    WATCHING.call_once(|| {
+        let events: Vec<usize> = match cache.lock() {
+            Ok(cache) => cache.watches.iter().map(|watch| watch.event as usize).collect(),
+            Err(error) => {
+                error!("{:?}", error);
+                return;
+            }
+        };
+        if events.is_empty() {
+            warn!("XFS configuration cannot be watched, resolved providers expire by time only");
+            return;
+        }
+
+        thread::spawn(move || {
+            let events: Vec<HANDLE> = events.into_iter().map(|event| event as HANDLE).collect();
+            loop {
+                // SAFETY: the events belong to the static cache and are never closed
+                let result = unsafe { WaitForMultipleObjects(events.len() as DWORD, events.as_ptr(), FALSE, INFINITE) };
+                if result >= WAIT_OBJECT_0 + events.len() as DWORD {
+                    error!("WaitForMultipleObjects failed: {result}, no longer watching the XFS configuration");
+                    return;
+                }
+                match cache.lock() {
+                    Ok(mut cache) => cache.refresh(),
+                    Err(error) => {
+                        error!("{:?}", error);
+                        return;
+                    }
+                }
+            }
+        });
+    });
+}
+
+/// Reads the cache lifetime, see [`CACHE_TTL_ENV`].
+pub fn ttl() -> Duration {
+    match std::env::var(CACHE_TTL_ENV) {
+        Ok(ttl) => ttl.trim().parse().map(Duration::from_millis).unwrap_or_else(|error| {
+            error!("Invalid {CACHE_TTL_ENV} {ttl:?}: {error}");
+            DEFAULT_TTL
+        }),
+        Err(_) => DEFAULT_TTL,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::ffi::CStr;
+
+    use winapi::{
+        shared::minwindef::{LPDWORD, PFILETIME, PHKEY},
+        um::winnt::LPSTR,
+    };
+
+    use super::*;
+
+    const FAKE_KEY_BASE: usize = 0x7000_0000;
+
+    lazy_static::lazy_static! {
+        // holds the paths the test backend opened and how often
+        static ref OPENED: Mutex<HashMap<String, usize>> = Mutex::new(HashMap::new());
+
+        // holds the keys the test backend handed out, by path
+        static ref KEYS: Mutex<Vec<String>> = Mutex::new(Vec::new());
+    }
+
+    unsafe extern "stdcall" fn open_key(_root: HKEY, path: LPSTR, key: PHKEY) -> HRESULT {
+        let path = CStr::from_ptr(path).to_string_lossy().into_owned();
+        if path.ends_with("missing") {
+            return WFS_ERR_CFG_INVALID_SUBKEY;
+        }
+        *OPENED.lock().unwrap().entry(path.clone()).or_insert(0) += 1;
+        let mut keys = KEYS.lock().unwrap();
+        keys.push(path);
+        // far from any real handle, RegNotifyChangeKeyValue must reject them
+        key.write((FAKE_KEY_BASE + keys.len()) as HKEY);
+        WFS_SUCCESS
+    }
+
+    unsafe extern "stdcall" fn create_key(_root: HKEY, _path: LPSTR, _key: PHKEY, _disposition: LPDWORD) -> HRESULT {
+        WFS_ERR_CFG_INVALID_SUBKEY
+    }
+
+    unsafe extern "stdcall" fn close_key(_key: HKEY) -> HRESULT {
+        WFS_SUCCESS
+    }
+
+    /// Logical services resolve to the provider `<name>_provider`, which resolves to `<provider>.dll`.
+    unsafe extern "stdcall" fn query_value(key: HKEY, _name: LPSTR, value: LPSTR, value_len: LPDWORD) -> HRESULT {
+        let path = KEYS.lock().unwrap()[key as usize - FAKE_KEY_BASE - 1].clone();
+        let data = match path.split_once('\\') {
+            Some(("LOGICAL_SERVICES", service)) => format!("{service}_provider"),
+            Some((_, provider)) => format!("{provider}.dll"),
+            None => return WFS_ERR_CFG_INVALID_NAME,
+        };
+        ptr::copy_nonoverlapping(data.as_ptr(), value as *mut u8, data.len());
+        value_len.write(data.len() as DWORD);
+        WFS_SUCCESS
+    }
+
+    unsafe extern "stdcall" fn enum_key(_key: HKEY, _index: DWORD, _name: LPSTR, _name_len: LPDWORD, _last_write: PFILETIME) -> HRESULT {
+        WFS_ERR_CFG_NO_MORE_ITEMS
+    }
+
+    unsafe extern "stdcall" fn enum_value(_key: HKEY, _index: DWORD, _name: LPSTR, _name_len: LPDWORD, _data: LPSTR, _data_len: LPDWORD) -> HRESULT {
+        WFS_ERR_CFG_NO_MORE_ITEMS
+    }
+
+    unsafe extern "stdcall" fn set_value(_key: HKEY, _name: LPSTR, _value: LPSTR, _len: DWORD) -> HRESULT {
+        WFS_SUCCESS
+    }
+
+    const API: ConfigApi = ConfigApi {
+        open_key,
+        create_key,
+        create_volatile_key: create_key,
+        close_key,
+        query_value,
+        enum_key,
+        enum_value,
+        set_value,
+    };
+
+    fn opened(path: &str) -> usize {
+        OPENED.lock().unwrap().get(path).copied().unwrap_or(0)
+    }
+
+    #[test]
+    fn test_second_resolve_cached() {
+        let mut cache = ProviderCache::new(&API, Duration::from_secs(60));
+        let resolved = Resolved {
+            provider: "cached_provider".to_string(),
+            dll_name: "cached_provider.dll".to_string(),
+        };
+        assert_eq!(cache.resolve("cached"), Ok(resolved.clone()));
+        assert_eq!(opened("LOGICAL_SERVICES\\cached"), 1);
+        assert_eq!(opened("SERVICE_PROVIDERS\\cached_provider"), 1);
+
+        // logical names are case insensitive, like the registry they come from
+        assert_eq!(cache.resolve("CACHED"), Ok(resolved));
+        assert_eq!(opened("LOGICAL_SERVICES\\cached"), 1);
+        assert_eq!(opened("SERVICE_PROVIDERS\\cached_provider"), 1);
+
+        // failures are looked up again
+        assert_eq!(cache.resolve("missing"), Err(WFS_ERR_INVALID_SERVPROV));
+        assert_eq!(cache.resolve("missing"), Err(WFS_ERR_INVALID_SERVPROV));
+    }
+
+    #[test]
+    fn test_expired_resolve_looked_up() {
+        let mut cache = ProviderCache::new(&API, Duration::from_millis(50));
+        cache.resolve("expiring").unwrap();
+        cache.resolve("expiring").unwrap();
+        assert_eq!(opened("LOGICAL_SERVICES\\expiring"), 1);
+
+        thread::sleep(Duration::from_millis(60));
+        cache.resolve("expiring").unwrap();
+        assert_eq!(opened("LOGICAL_SERVICES\\expiring"), 2);
+        assert_eq!(opened("SERVICE_PROVIDERS\\expiring_provider"), 2);
+
+        // a TTL of 0 turns the cache off
+        let mut cache = ProviderCache::new(&API, Duration::ZERO);
+        cache.resolve("uncached").unwrap();
+        cache.resolve("uncached").unwrap();
+        assert_eq!(opened("LOGICAL_SERVICES\\uncached"), 2);
+    }
+}
@@ -0,0 +1,173 @@
+//! Event registrations of a service, as the provider accepted them.
+//!
+//! Providers disagree on registering a window for event classes it is registered for already, and on deregistering
+//! one that is not registered: some succeed, others fail with WFS_ERR_INVALID_EVENT_CLASS or WFS_ERR_INVALID_HWNDREG.
+//! The manager answers both itself with WFS_SUCCESS, from the registrations the provider completed successfully, so
+//! applications see the same outcome whatever the provider. Anything still in flight for the window is passed on.
+
+use std::collections::HashMap;
+
+use winapi::shared::{minwindef::DWORD, winerror::HRESULT};
+use xfslib::*;
+
+/// Registration or deregistration of an application window, 0 for all windows, for a set of event classes,
+/// 0 for all classes.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Change {
+    Register { window: usize, classes: DWORD },
+    Deregister { window: usize, classes: DWORD },
+}
+
+impl Change {
+    /// Whether the change is about the window and any of the classes.
+    fn touches(&self, window: usize, classes: DWORD) -> bool {
+        let (own_window, own_classes) = match *self {
+            Change::Register { window, classes } | Change::Deregister { window, classes } => (window, classes),
+        };
+        windows_match(own_window, window) && classes_match(own_classes, classes)
+    }
+
+    fn window(&self) -> usize {
+        match *self {
+            Change::Register { window, .. } | Change::Deregister { window, .. } => window,
+        }
+    }
+}
+
+/// Registrations of one service.
+#[derive(Default)]
+pub struct Registrations {
+    // event classes each application window is registered for
+    windows: HashMap<usize, DWORD>,
+    // changes the provider has not completed yet, by request id
+    requested: HashMap<REQUESTID, Change>,
+}
+
+impl Registrations {
+    /// Records the change under the request id until the provider completes it. Returns false, without recording
+    /// anything, if the change makes no difference and the provider need not be asked.
+    pub fn request(&mut self, request_id: REQUESTID, change: Change) -> bool {
+        let redundant = match change {
+            Change::Register { window, classes } => self.covers(window, classes) && !self.in_flight(window, classes),
+            Change::Deregister { window, classes } => !self.overlaps(window, classes) && !self.registering(window, classes),
+        };
+        if !redundant {
+            self.requested.insert(request_id, change);
+        }
+        !redundant
+    }
+
+    /// Applies the change of the request if the provider completed it successfully, forgets the request either way.
+    pub fn completed(&mut self, request_id: REQUESTID, result: HRESULT) {
+        let Some(change) = self.requested.remove(&request_id) else {
+            return;
+        };
+        if result != WFS_SUCCESS {
+            return;
+        }
+        match change {
+            Change::Register { window, classes } => *self.windows.entry(window).or_insert(0) |= classes,
+            Change::Deregister { window, classes } => self.windows.retain(|&registered_window, registered| {
+                if windows_match(window, registered_window) {
+                    *registered &= if classes == 0 { 0 } else { !classes };
+                }
+                *registered != 0
+            }),
+        }
+    }
+
+    /// Application windows registered for any class, or with a change still in flight. The provider may post to
+    /// any of them, 0 stands for the changes about all windows.
+    pub fn windows(&self) -> impl Iterator<Item = usize> + '_ {
+        self.windows.keys().copied().chain(self.requested.values().map(Change::window))
+    }
+
+    /// Whether the window is registered for all of the classes.
+    fn covers(&self, window: usize, classes: DWORD) -> bool {
+        self.windows.get(&window).is_some_and(|registered| registered & classes == classes)
+    }
+
+    /// Whether any matching window is registered for any of the classes.
+    fn overlaps(&self, window: usize, classes: DWORD) -> bool {
+        self.windows
+            .iter()
+            .any(|(&registered_window, &registered)| windows_match(window, registered_window) && classes_match(classes, registered))
+    }
+
+    /// Whether any change about the window and the classes is still in flight.
+    fn in_flight(&self, window: usize, classes: DWORD) -> bool {
+        self.requested.values().any(|change| change.touches(window, classes))
+    }
+
+    /// Whether any registration about the window and the classes is still in flight.
+    fn registering(&self, window: usize, classes: DWORD) -> bool {
+        self.requested.values().any(|change| matches!(change, Change::Register { .. }) && change.touches(window, classes))
+    }
+}
+
+fn windows_match(window: usize, other: usize) -> bool {
+    window == 0 || other == 0 || window == other
+}
+
+fn classes_match(classes: DWORD, other: DWORD) -> bool {
+    classes == 0 || other == 0 || classes & other != 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_redundant_changes() {
+        let mut registrations = Registrations::default();
+
+        // nothing registered, deregistering changes nothing
+        assert!(!registrations.request(1, Change::Deregister { window: 10, classes: SERVICE_EVENTS }));
+        assert!(!registrations.request(2, Change::Deregister { window: 0, classes: 0 }));
+
+        // a registration in flight is passed on, and so is a deregistration racing it
+        let classes = SERVICE_EVENTS | USER_EVENTS;
+        assert!(registrations.request(3, Change::Register { window: 10, classes }));
+        assert!(registrations.request(4, Change::Deregister { window: 0, classes: USER_EVENTS }));
+        registrations.completed(4, WFS_ERR_INVALID_HWNDREG);
+        registrations.completed(3, WFS_SUCCESS);
+
+        // registering again for the same or fewer classes changes nothing, for another class it does
+        assert!(!registrations.request(5, Change::Register { window: 10, classes: USER_EVENTS }));
+        assert!(registrations.request(6, Change::Register { window: 10, classes: SYSTEM_EVENTS }));
+        registrations.completed(6, WFS_ERR_INVALID_EVENT_CLASS);
+        assert!(registrations.request(7, Change::Register { window: 11, classes: USER_EVENTS }));
+        registrations.completed(7, WFS_SUCCESS);
+
+        // partial deregistration keeps the other classes of the window
+        assert!(registrations.request(8, Change::Deregister { window: 10, classes: USER_EVENTS }));
+        registrations.completed(8, WFS_SUCCESS);
+        assert!(!registrations.request(9, Change::Deregister { window: 10, classes: USER_EVENTS }));
+        assert!(!registrations.request(10, Change::Register { window: 10, classes: SERVICE_EVENTS }));
+
+        // a null window and no classes deregister everything
+        assert!(registrations.request(11, Change::Deregister { window: 0, classes: 0 }));
+        registrations.completed(11, WFS_SUCCESS);
+        assert!(!registrations.request(12, Change::Deregister { window: 11, classes: USER_EVENTS }));
+        assert!(registrations.request(13, Change::Register { window: 10, classes: SERVICE_EVENTS }));
+    }
+
+    #[test]
+    fn test_windows() {
+        let mut registrations = Registrations::default();
+
+        // a window counts from the request on until its deregistration completed
+        assert!(registrations.request(1, Change::Register { window: 10, classes: USER_EVENTS }));
+        assert_eq!(registrations.windows().collect::<Vec<_>>(), [10]);
+        registrations.completed(1, WFS_SUCCESS);
+        assert!(registrations.request(2, Change::Deregister { window: 10, classes: USER_EVENTS }));
+        assert_eq!(registrations.windows().collect::<Vec<_>>(), [10, 10]);
+        registrations.completed(2, WFS_SUCCESS);
+        assert_eq!(registrations.windows().count(), 0);
+
+        // a failed registration leaves nothing behind
+        assert!(registrations.request(3, Change::Register { window: 11, classes: USER_EVENTS }));
+        registrations.completed(3, WFS_ERR_INVALID_EVENT_CLASS);
+        assert_eq!(registrations.windows().count(), 0);
+    }
+}
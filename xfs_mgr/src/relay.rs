@@ -0,0 +1,547 @@
+//! Relays provider completions to the application windows.
+//!
+//! The manager hands its own message-only window to the providers instead of the application window,
+//! so it sees every completion before the application does. This lets it synthesize the WFS_ERR_CANCELED
+//! completion for providers that ignore WFPCancelAsyncRequest, without ever delivering a request twice.
+//!
+//! Completions are matched to their request by service and request id, never by arrival order. Requests sharing an
+//! application window may complete in any order, and each completion is re-posted as it arrives, so two arriving
+//! back to back both reach the window.
+//!
+//! Open completions update the manager's service table before they are passed on, a service whose open failed is
+//! released so the application never holds a handle to it. Lock and unlock completions record which service holds
+//! the lock on its device, register and deregister completions which windows each service has registered.
+//!
+//! Windows registered for events get a proxy window on the relay thread. The provider posts its events to the
+//! proxy, which re-posts them to the application window as soon as they arrive, whatever the application's
+//! own message pump is doing. Events the application filtered out with WFMSetEventFilter are freed there instead.
+//! A proxy is destroyed once no service has its window registered any longer, see [`retain_proxies`].
+
+use std::{
+    collections::{HashMap, HashSet},
+    ffi::CString,
+    mem, ptr,
+    sync::Mutex,
+    thread,
+    time::{Duration, Instant},
+};
+
+use lazy_static::lazy_static;
+use log::{error, trace, warn};
+use winapi::{
+    shared::{
+        minwindef::{DWORD, LPARAM, LPVOID, LRESULT, UINT, ULONG, WPARAM},
+        windef::{HWND, POINT},
+    },
+    um::{
+        libloaderapi::GetModuleHandleW,
+        winuser::{
+            CreateWindowExA, DefWindowProcA, DestroyWindow, DispatchMessageA, GetMessageA, GetWindowLongPtrA, PostMessageA, RegisterClassExA, SendMessageA, SetWindowLongPtrA, GWLP_USERDATA,
+            HWND_MESSAGE, MSG, WM_APP, WNDCLASSEXA,
+        },
+    },
+};
+use xfslib::*;
+
+use crate::{
+    manager::{self, CommandLatency, InFlightInfo},
+    supp::*,
+};
+
+/// Time a provider gets to post its own completion for a cancelled request before the manager posts one.
+pub const CANCEL_GRACE_PERIOD: Duration = Duration::from_millis(500);
+
+/// When set, the time each request takes from the hand over to the provider to its completion is recorded, see
+/// [`manager::command_latencies`]. Off by default, recording takes another lock on every completion.
+pub const COMMAND_METRICS_ENV: &str = "XFS_COMMAND_METRICS";
+
+/// Asks the relay thread to create a proxy for the application window in WPARAM, returns the proxy.
+const WM_CREATE_PROXY: UINT = WM_APP + 1;
+
+/// Asks the relay thread to destroy the proxy window in WPARAM.
+const WM_DESTROY_PROXY: UINT = WM_APP + 2;
+
+/// Request the application is still waiting for.
+struct Pending {
+    window: usize,
+    message: UINT,
+    command: DWORD,
+    since: Instant,
+    // call the request was made in, its completion is traced under the same id
+    call_id: Option<u32>,
+}
+
+lazy_static! {
+    // holds outstanding requests by service and request id
+    static ref PENDING: Mutex<HashMap<(HSERVICE, REQUESTID), Pending>> = Mutex::new(HashMap::new());
+
+    // holds the relay window handle
+    static ref RELAY: usize = create_relay_window();
+
+    // holds the event proxy window by application window
+    static ref PROXIES: Mutex<HashMap<usize, usize>> = Mutex::new(HashMap::new());
+
+    // holds the event ids let through to an application window by window, service and event message
+    static ref FILTERS: Mutex<HashMap<(usize, HSERVICE, UINT), HashSet<DWORD>>> = Mutex::new(HashMap::new());
+
+    // holds the completion times recorded under COMMAND_METRICS_ENV by service, completion message and command
+    static ref LATENCIES: Mutex<HashMap<(HSERVICE, UINT, DWORD), CommandLatency>> = Mutex::new(HashMap::new());
+}
+
+/// Registers the request and calls the provider with the relay window in place of the application window.
+/// The registration is dropped again if the provider rejects the request synchronously.
+pub fn forward(service: HSERVICE, request_id: REQUESTID, window: HWND, message: UINT, command: DWORD, call: impl FnOnce(HWND) -> HRESULT) -> HRESULT {
+    let pending = Pending {
+        window: window as usize,
+        message,
+        command,
+        since: Instant::now(),
+        call_id: call_id(),
+    };
+    xfs_unwrap!(PENDING.lock()).insert((service, request_id), pending);
+
+    let result = call(*RELAY as HWND);
+    if result != WFS_SUCCESS {
+        xfs_unwrap!(PENDING.lock()).remove(&(service, request_id));
+    }
+    result
+}
+
+/// Drops the registration of a request the application stopped waiting for, so its completion is freed when it
+/// arrives. Returns false if the request has completed already.
+pub fn abandon(service: HSERVICE, request_id: REQUESTID) -> bool {
+    match PENDING.lock() {
+        Ok(mut pending) => pending.remove(&(service, request_id)).is_some(),
+        Err(error) => {
+            error!("{:?}", error);
+            false
+        }
+    }
+}
+
+/// Posts a WFS_ERR_CANCELED completion for every matching request the provider has not completed
+/// within [`CANCEL_GRACE_PERIOD`]. A request id of 0 matches all requests of the service.
+pub fn cancel(service: HSERVICE, request_id: REQUESTID) {
+    thread::spawn(move || {
+        thread::sleep(CANCEL_GRACE_PERIOD);
+
+        let cancelled: Vec<_> = match PENDING.lock() {
+            Ok(mut pending) => {
+                let keys: Vec<_> = pending.keys().filter(|(s, r)| *s == service && (request_id == 0 || *r == request_id)).copied().collect();
+                keys.into_iter().filter_map(|key| pending.remove(&key).map(|p| (key.1, p))).collect()
+            }
+            Err(error) => {
+                error!("{:?}", error);
+                return;
+            }
+        };
+
+        for (request_id, pending) in cancelled {
+            let _call = pending.call_id.map(CallScope::enter);
+            warn!("Provider did not complete cancelled request {request_id} of service {service}, posting WFS_ERR_CANCELED");
+            // SAFETY: the result is allocated on the XFS heap and handed over to the application window
+            unsafe { post_canceled(service, request_id, pending) };
+        }
+    });
+}
+
+/// Returns the proxy window to register with the provider in place of the application's event window.
+/// The same application window always gets the same proxy, so deregistration matches the registration.
+pub fn proxy(window: HWND) -> HWND {
+    if window.is_null() {
+        return window;
+    }
+
+    match PROXIES.lock() {
+        Ok(proxies) => {
+            if let Some(&proxy) = proxies.get(&(window as usize)) {
+                return proxy as HWND;
+            }
+        }
+        Err(error) => {
+            error!("{:?}", error);
+            return window;
+        }
+    }
+
+    // SAFETY: the relay thread creates the proxy, SendMessageA waits until it has. The lock is not held meanwhile,
+    // so a relay thread busy with a completion holds up no other window's lookup
+    let created = unsafe { SendMessageA(*RELAY as HWND, WM_CREATE_PROXY, window as WPARAM, 0) } as usize;
+    match PROXIES.lock() {
+        Ok(mut proxies) => {
+            let proxy = *proxies.entry(window as usize).or_insert(created);
+            if proxy != created {
+                // Another thread created one for the window meanwhile
+                destroy_proxy(created);
+            }
+            proxy as HWND
+        }
+        Err(error) => {
+            error!("{:?}", error);
+            destroy_proxy(created);
+            window
+        }
+    }
+}
+
+/// Destroys the proxies of the application windows `keep` returns false for. The caller makes sure no provider
+/// has them registered any longer, events queued for a proxy before are still passed on.
+pub fn retain_proxies(keep: impl Fn(usize) -> bool) {
+    let mut released = Vec::new();
+    match PROXIES.lock() {
+        Ok(mut proxies) => proxies.retain(|&window, &mut proxy| {
+            let kept = keep(window);
+            if !kept {
+                released.push(proxy);
+            }
+            kept
+        }),
+        Err(error) => error!("{:?}", error),
+    }
+    for proxy in released {
+        destroy_proxy(proxy);
+    }
+}
+
+/// Has the relay thread destroy a proxy once it has passed on the events queued for it.
+fn destroy_proxy(proxy: usize) {
+    // SAFETY: posting is all that happens here, the relay thread owns the proxy and destroys it
+    if unsafe { PostMessageA(*RELAY as HWND, WM_DESTROY_PROXY, proxy as WPARAM, 0) } == 0 {
+        error!("Failed to release proxy {proxy:#x}");
+    }
+}
+
+/// Lets only the listed event ids of the service through to the application window for the event message, or all
+/// of them again for None.
+pub fn set_filter(window: HWND, service: HSERVICE, message: UINT, event_ids: Option<HashSet<DWORD>>) -> Result<(), HRESULT> {
+    let mut filters = FILTERS.lock().map_err(|error| {
+        error!("{:?}", error);
+        WFS_ERR_INTERNAL_ERROR
+    })?;
+    let key = (window as usize, service, message);
+    match event_ids {
+        Some(event_ids) => filters.insert(key, event_ids),
+        None => filters.remove(&key),
+    };
+    Ok(())
+}
+
+/// Whether the event passes the filter the application set for the window, events without a filter always do.
+fn passes(window: HWND, service: HSERVICE, message: UINT, event_id: DWORD) -> bool {
+    match FILTERS.lock() {
+        Ok(filters) => filters.get(&(window as usize, service, message)).is_none_or(|event_ids| event_ids.contains(&event_id)),
+        Err(error) => {
+            error!("{:?}", error);
+            true
+        }
+    }
+}
+
+/// Returns true while the service has requests the provider has not completed.
+pub fn has_pending(service: HSERVICE) -> bool {
+    match PENDING.lock() {
+        Ok(pending) => pending.keys().any(|(s, _)| *s == service),
+        Err(error) => {
+            error!("{:?}", error);
+            false
+        }
+    }
+}
+
+/// Lists the request ids of the service the provider has not completed, in no particular order.
+pub fn pending_requests(service: HSERVICE) -> Vec<REQUESTID> {
+    match PENDING.lock() {
+        Ok(pending) => pending.keys().filter(|(s, _)| *s == service).map(|&(_, request_id)| request_id).collect(),
+        Err(error) => {
+            error!("{:?}", error);
+            Vec::new()
+        }
+    }
+}
+
+/// Lists the outstanding requests, in no particular order.
+pub fn in_flight() -> Result<Vec<InFlightInfo>, HRESULT> {
+    let pending = PENDING.lock().map_err(|error| {
+        error!("{:?}", error);
+        WFS_ERR_INTERNAL_ERROR
+    })?;
+    Ok(pending
+        .iter()
+        .map(|(&(service, request_id), pending)| InFlightInfo {
+            service,
+            request_id,
+            message: pending.message,
+            command: pending.command,
+            window: pending.window as HWND,
+            elapsed: pending.since.elapsed(),
+        })
+        .collect())
+}
+
+/// Lists the completion times recorded so far, in no particular order.
+pub fn latencies() -> Result<Vec<CommandLatency>, HRESULT> {
+    let latencies = LATENCIES.lock().map_err(|error| {
+        error!("{:?}", error);
+        WFS_ERR_INTERNAL_ERROR
+    })?;
+    Ok(latencies.values().cloned().collect())
+}
+
+/// Adds the time the request took to the latencies of its command, if [`COMMAND_METRICS_ENV`] is set.
+fn record_latency(service: HSERVICE, pending: &Pending) {
+    if std::env::var_os(COMMAND_METRICS_ENV).is_none() {
+        return;
+    }
+    match LATENCIES.lock() {
+        Ok(mut latencies) => latencies
+            .entry((service, pending.message, pending.command))
+            .or_insert_with(|| CommandLatency::new(service, pending.message, pending.command))
+            .record(pending.since.elapsed()),
+        Err(error) => error!("{:?}", error),
+    }
+}
+
+/// Forgets the outstanding requests and the event filters of the service, its completions are freed when they
+/// arrive.
+pub fn forget(service: HSERVICE) {
+    match PENDING.lock() {
+        Ok(mut pending) => pending.retain(|(s, _), _| *s != service),
+        Err(error) => error!("{:?}", error),
+    }
+    match FILTERS.lock() {
+        Ok(mut filters) => filters.retain(|(_, s, _), _| *s != service),
+        Err(error) => error!("{:?}", error),
+    }
+}
+
+/// Forgets all outstanding requests, event filters and the recorded latencies, and destroys the event proxies.
+pub fn clear() {
+    retain_proxies(|_| false);
+    match PENDING.lock() {
+        Ok(mut pending) => pending.clear(),
+        Err(error) => error!("{:?}", error),
+    }
+    match FILTERS.lock() {
+        Ok(mut filters) => filters.clear(),
+        Err(error) => error!("{:?}", error),
+    }
+    match LATENCIES.lock() {
+        Ok(mut latencies) => latencies.clear(),
+        Err(error) => error!("{:?}", error),
+    }
+}
+
+unsafe fn post_canceled(service: HSERVICE, request_id: REQUESTID, pending: Pending) {
+    match pending.message {
+        WFS_OPEN_COMPLETE => crate::open_completed(service, WFS_ERR_CANCELED),
+        WFS_CLOSE_COMPLETE => crate::close_completed(service, WFS_ERR_CANCELED),
+        _ => {}
+    }
+    match manager::allocate_result(service, request_id, WFS_ERR_CANCELED, pending.command) {
+        Ok(result) => {
+            manager::post_result(pending.window as HWND, pending.message, result);
+        }
+        Err(error) => error!("Failed to allocate the cancel completion: {error}"),
+    }
+}
+
+fn create_relay_window() -> usize {
+    let (sender, receiver) = std::sync::mpsc::channel();
+
+    thread::spawn(move || unsafe {
+        let instance = GetModuleHandleW(ptr::null());
+        let class_name = CString::new("XFS_RELAY_WINDOW").unwrap();
+        let wx = WNDCLASSEXA {
+            cbSize: mem::size_of::<WNDCLASSEXA>() as u32,
+            style: 0,
+            lpfnWndProc: Some(wndproc),
+            cbClsExtra: 0,
+            cbWndExtra: 0,
+            hInstance: instance,
+            hIcon: ptr::null_mut(),
+            hCursor: ptr::null_mut(),
+            hbrBackground: ptr::null_mut(),
+            lpszMenuName: ptr::null_mut(),
+            lpszClassName: class_name.as_ptr(),
+            hIconSm: ptr::null_mut(),
+        };
+
+        RegisterClassExA(&wx);
+
+        let proxy_class_name = CString::new("XFS_PROXY_WINDOW").unwrap();
+        RegisterClassExA(&WNDCLASSEXA {
+            lpfnWndProc: Some(proxy_wndproc),
+            lpszClassName: proxy_class_name.as_ptr(),
+            ..wx
+        });
+
+        let hwnd = CreateWindowExA(0, class_name.as_ptr(), ptr::null(), 0, 0, 0, 0, 0, HWND_MESSAGE, ptr::null_mut(), instance, ptr::null_mut());
+        sender.send(hwnd as usize).unwrap();
+
+        let mut message = MSG {
+            hwnd: ptr::null_mut(),
+            message: 0,
+            wParam: 0,
+            lParam: 0,
+            time: 0,
+            pt: POINT { x: 0, y: 0 },
+        };
+
+        while GetMessageA(&mut message, ptr::null_mut(), 0, 0) != 0 {
+            DispatchMessageA(&message);
+        }
+    });
+
+    receiver.recv().unwrap()
+}
+
+/// Creates a proxy forwarding to the application window. Runs on the relay thread.
+unsafe fn create_proxy(target: WPARAM) -> LRESULT {
+    let instance = GetModuleHandleW(ptr::null());
+    let class_name = CString::new("XFS_PROXY_WINDOW").unwrap();
+    let proxy = CreateWindowExA(0, class_name.as_ptr(), ptr::null(), 0, 0, 0, 0, 0, HWND_MESSAGE, ptr::null_mut(), instance, ptr::null_mut());
+    SetWindowLongPtrA(proxy, GWLP_USERDATA, target as _);
+    proxy as LRESULT
+}
+
+extern "system" fn proxy_wndproc(window: HWND, message: UINT, wparam: WPARAM, lparam: LPARAM) -> LRESULT {
+    match message {
+        WFS_EXECUTE_EVENT..=WFS_SYSTEM_EVENT => unsafe {
+            let target = GetWindowLongPtrA(window, GWLP_USERDATA) as HWND;
+            let event = lparam as LPWFSRESULT;
+            if !event.is_null() {
+                // SAFETY: providers post a WFSRESULT allocated on the XFS heap with every event
+                let (service, event_id) = (ptr::addr_of!((*event).hService).read_unaligned(), ptr::addr_of!((*event).u.dwEventID).read_unaligned());
+                if !passes(target, service, message, event_id) {
+                    trace!("Filtered out event {event_id} of service {service} for window {target:?}");
+                    WFM_FREE_BUFFER(lparam as LPVOID);
+                    return 0;
+                }
+            }
+            if PostMessageA(target, message, wparam, lparam) == 0 {
+                // The application window is gone, nobody will free the event
+                warn!("Dropping event {message} for window {target:?}");
+                WFM_FREE_BUFFER(lparam as LPVOID);
+            }
+            0
+        },
+        _ => unsafe { DefWindowProcA(window, message, wparam, lparam) },
+    }
+}
+
+/// Moves a result the provider did not allocate with WFMAllocateBuffer onto the XFS heap, so WFSFreeResult accepts
+/// it. lpBuffer is copied into a buffer chained to the copy with WFMAllocateMore, which takes knowing its length: the
+/// provider's data is accepted only if it is an XFS heap buffer without children of its own, whose pointers into them
+/// would outlive the original. A buffer of its own is freed once copied, one chained to another is left to the
+/// provider along with the header, which the manager has no way to free. Data that cannot be copied is not passed
+/// on, the copy completes with WFS_ERR_INTERNAL_ERROR instead.
+unsafe fn adopt(result: LPWFSRESULT) -> LPWFSRESULT {
+    let mut length = 0;
+    if WFM_GET_BUFFER_LENGTH(result as LPVOID, &mut length) == WFS_SUCCESS {
+        return result;
+    }
+
+    warn!("Provider posted result {result:?} not allocated with WFMAllocateBuffer, passing on a copy");
+    let mut copy: LPVOID = ptr::null_mut();
+    if let Err(error) = WFM_ALLOCATE_BUFFER(mem::size_of::<WFSRESULT>() as ULONG, WFS_MEM_ZEROINIT, &mut copy).ok() {
+        error!("Failed to allocate the copy of result {result:?}: {error}");
+        return result;
+    }
+    let copy = copy as LPWFSRESULT;
+    copy.write_unaligned(result.read_unaligned());
+
+    let data = ptr::addr_of!((*result).lpBuffer).read_unaligned();
+    if data.is_null() {
+        return copy;
+    }
+    match copy_data(data, copy as LPVOID) {
+        Ok(buffer) => ptr::addr_of_mut!((*copy).lpBuffer).write_unaligned(buffer),
+        Err(error) => {
+            error!("Not passing on lpBuffer {data:?} of result {result:?}: {error}");
+            ptr::addr_of_mut!((*copy).lpBuffer).write_unaligned(ptr::null_mut());
+            ptr::addr_of_mut!((*copy).hResult).write_unaligned(WFS_ERR_INTERNAL_ERROR);
+        }
+    }
+    copy
+}
+
+/// Copies an XFS heap buffer without children into a new buffer chained to `parent` and frees the original, unless
+/// it is chained to another buffer itself. Returns the new buffer.
+unsafe fn copy_data(data: LPVOID, parent: LPVOID) -> Result<LPVOID, HRESULT> {
+    let mut length = 0;
+    WFM_GET_BUFFER_LENGTH(data, &mut length).ok()?;
+    let mut children = 0;
+    let chained = match WFM_GET_CHILD_COUNT(data, &mut children) {
+        WFS_SUCCESS if children > 0 => return Err(WFS_ERR_UNSUPP_DATA),
+        WFS_SUCCESS => false,
+        _ => true,
+    };
+
+    let mut buffer: LPVOID = ptr::null_mut();
+    WFM_ALLOCATE_MORE(length, parent, &mut buffer).ok()?;
+    ptr::copy_nonoverlapping(data as *const u8, buffer as *mut u8, length as usize);
+    if !chained {
+        WFM_FREE_BUFFER(data);
+    }
+    Ok(buffer)
+}
+
+extern "system" fn wndproc(window: HWND, message: UINT, wparam: WPARAM, lparam: LPARAM) -> LRESULT {
+    let completes = match message {
+        WM_CREATE_PROXY => return unsafe { create_proxy(wparam) },
+        WM_DESTROY_PROXY => return unsafe { DestroyWindow(wparam as HWND) } as LRESULT,
+        WFS_OPEN_COMPLETE..=WFS_EXECUTE_COMPLETE => true,
+        WFS_EXECUTE_EVENT => false,
+        _ => return unsafe { DefWindowProcA(window, message, wparam, lparam) },
+    };
+
+    let result = lparam as LPWFSRESULT;
+    if result.is_null() {
+        error!("Provider posted message {message} without a result");
+        return 0;
+    }
+
+    // SAFETY: providers post a WFSRESULT allocated on the XFS heap with every completion and execute event
+    let key = unsafe { (ptr::addr_of!((*result).hService).read_unaligned(), ptr::addr_of!((*result).RequestID).read_unaligned()) };
+
+    let (target, call_id) = match PENDING.lock() {
+        Ok(mut pending) if completes => pending
+            .remove(&key)
+            .map(|p| {
+                record_latency(key.0, &p);
+                (p.window, p.call_id)
+            })
+            .unzip(),
+        Ok(pending) => pending.get(&key).map(|p| (p.window, p.call_id)).unzip(),
+        Err(error) => {
+            error!("{:?}", error);
+            (None, None)
+        }
+    };
+    let _call = call_id.flatten().map(CallScope::enter);
+    // SAFETY: see above
+    let result = if target.is_some() { unsafe { adopt(result) } } else { result };
+
+    if target.is_some() {
+        // SAFETY: see above
+        let h_result = unsafe { ptr::addr_of!((*result).hResult).read_unaligned() };
+        match message {
+            WFS_OPEN_COMPLETE => crate::open_completed(key.0, h_result),
+            WFS_CLOSE_COMPLETE => crate::close_completed(key.0, h_result),
+            WFS_LOCK_COMPLETE => crate::lock_completed(key.0, h_result),
+            WFS_UNLOCK_COMPLETE => crate::unlock_completed(key.0, h_result),
+            WFS_REGISTER_COMPLETE | WFS_DEREGISTER_COMPLETE => crate::registration_completed(key.0, key.1, h_result),
+            _ => {}
+        }
+    }
+
+    match target {
+        Some(target) if unsafe { PostMessageA(target as HWND, message, wparam, result as LPARAM) } != 0 => trace!("Relayed message {message} of request {key:?}"),
+        _ => {
+            // Either completed already (e.g. cancelled by the manager) or the window is gone, nobody will free it
+            warn!("Dropping message {message} of request {key:?}");
+            unsafe { WFM_FREE_BUFFER(result as LPVOID) };
+        }
+    }
+    0
+}
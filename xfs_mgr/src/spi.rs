@@ -1,3 +1,5 @@
+//! Signatures of the SPI functions the manager resolves in the service provider DLLs.
+
 use winapi::{
     shared::{
         minwindef::{DWORD, LPVOID},
@@ -24,7 +26,6 @@ pub type WFPRegister = extern "stdcall" fn(hService: HSERVICE, dwEventClass: DWO
 
 pub type WFPSetTraceLevel = extern "stdcall" fn(hService: HSERVICE, dwTraceLevel: DWORD) -> HRESULT;
 
-#[allow(dead_code)]
 pub type WFPUnloadService = extern "stdcall" fn() -> HRESULT;
 
 pub type WFPUnlock = extern "stdcall" fn(hService: HSERVICE, hWnd: HWND, ReqID: REQUESTID) -> HRESULT;
@@ -1,7 +1,10 @@
+//! Support functions of `xfs_supp.dll`, the XFS heap and timers, which the manager re-exports and allocates its
+//! own results with.
+
 use lazy_static::lazy_static;
 use libloading::Symbol;
 use winapi::shared::{
-    minwindef::{DWORD, LPVOID, LPWORD, ULONG, WORD},
+    minwindef::{DWORD, LPDWORD, LPVOID, LPWORD, ULONG, WORD},
     ntdef::LPSTR,
     windef::HWND,
     winerror::HRESULT,
@@ -11,8 +14,17 @@ use xfslib::HSERVICE;
 lazy_static! {
     static ref XFS_LIB: libloading::Library = unsafe { libloading::Library::new("xfs_supp.dll").unwrap() };
     pub static ref WFM_ALLOCATE_BUFFER: Symbol<'static, unsafe extern "stdcall" fn(ULONG, ULONG, *mut LPVOID) -> HRESULT> = unsafe { XFS_LIB.get(b"WFMAllocateBuffer").unwrap() };
+    pub static ref WFM_ALLOCATE_TAGGED_BUFFER: Symbol<'static, unsafe extern "stdcall" fn(ULONG, ULONG, HSERVICE, DWORD, *mut LPVOID) -> HRESULT> =
+        unsafe { XFS_LIB.get(b"WFMAllocateTaggedBuffer").unwrap() };
     pub static ref WFM_ALLOCATE_MORE: Symbol<'static, unsafe extern "stdcall" fn(ULONG, LPVOID, *mut LPVOID) -> HRESULT> = unsafe { XFS_LIB.get(b"WFMAllocateMore").unwrap() };
     pub static ref WFM_FREE_BUFFER: Symbol<'static, unsafe extern "stdcall" fn(LPVOID) -> HRESULT> = unsafe { XFS_LIB.get(b"WFMFreeBuffer").unwrap() };
+    pub static ref WFM_FREE_BUFFER_DEEP: Symbol<'static, unsafe extern "stdcall" fn(LPVOID, LPDWORD) -> HRESULT> = unsafe { XFS_LIB.get(b"WFMFreeBufferDeep").unwrap() };
+    pub static ref WFM_GET_CHILD_COUNT: Symbol<'static, unsafe extern "stdcall" fn(LPVOID, LPDWORD) -> HRESULT> = unsafe { XFS_LIB.get(b"WFMGetChildCount").unwrap() };
+    pub static ref WFM_GET_BUFFER_LENGTH: Symbol<'static, unsafe extern "stdcall" fn(LPVOID, *mut ULONG) -> HRESULT> = unsafe { XFS_LIB.get(b"WFMGetBufferLength").unwrap() };
+    pub static ref WFM_GET_CHAINED_BUFFER: Symbol<'static, unsafe extern "stdcall" fn(LPVOID, *mut LPVOID, *mut ULONG) -> HRESULT> = unsafe { XFS_LIB.get(b"WFMGetChainedBuffer").unwrap() };
+    pub static ref WFM_GET_HEAP_STATS: Symbol<'static, unsafe extern "stdcall" fn(LPDWORD, LPDWORD) -> HRESULT> = unsafe { XFS_LIB.get(b"WFMGetHeapStats").unwrap() };
+    pub static ref WFM_GET_TAGGED_HEAP_STATS: Symbol<'static, unsafe extern "stdcall" fn(HSERVICE, DWORD, LPDWORD, LPDWORD) -> HRESULT> = unsafe { XFS_LIB.get(b"WFMGetTaggedHeapStats").unwrap() };
+    pub static ref WFM_GET_TIMER_COUNT: Symbol<'static, unsafe extern "stdcall" fn(LPDWORD) -> HRESULT> = unsafe { XFS_LIB.get(b"WFMGetTimerCount").unwrap() };
     pub static ref WFM_KILL_TIMER: Symbol<'static, unsafe extern "stdcall" fn(WORD) -> HRESULT> = unsafe { XFS_LIB.get(b"WFMKillTimer").unwrap() };
     pub static ref WFM_OUTPUT_TRACE_DATA: Symbol<'static, unsafe extern "stdcall" fn(LPSTR) -> HRESULT> = unsafe { XFS_LIB.get(b"WFMOutputTraceData").unwrap() };
     pub static ref WFM_SET_TIMER: Symbol<'static, unsafe extern "stdcall" fn(HWND, LPVOID, DWORD, LPWORD) -> HRESULT> = unsafe { XFS_LIB.get(b"WFMSetTimer").unwrap() };
@@ -0,0 +1,110 @@
+//! Opens a logical service again after its provider mapping changed, with the configuration watched in the background.
+//! The manager statistics show the watcher dropping the cached mapping before the service is opened again.
+//!
+//! XFS_REGISTRY_ROOT and XFS_CONFIG_WATCH are process wide and have to be set before the manager resolves its first
+//! service, so this lives in its own test binary. It needs no admin rights, only `msxfs.dll`, `xfs_conf.dll`,
+//! `xfs_supp.dll` and `xfs_mock.dll` on the DLL search path.
+#![cfg(windows)]
+
+use std::{
+    ffi::CString,
+    mem, ptr, thread,
+    time::{Duration, Instant},
+};
+
+use libloading::{Library, Symbol};
+use winapi::{
+    shared::{
+        minwindef::{DWORD, LPVOID},
+        winerror::HRESULT,
+    },
+    um::winnt::LPSTR,
+};
+use xfslib::{
+    sandbox::{Sandbox, LOGICAL_SERVICES},
+    *,
+};
+
+/// Sets XFS_CONFIG_WATCH for the manager and removes it again on drop.
+struct ConfigWatch;
+
+impl ConfigWatch {
+    fn new() -> Self {
+        std::env::set_var("XFS_CONFIG_WATCH", "1");
+        ConfigWatch
+    }
+}
+
+impl Drop for ConfigWatch {
+    fn drop(&mut self) {
+        std::env::remove_var("XFS_CONFIG_WATCH");
+    }
+}
+
+#[test]
+fn test_open_after_mapping_changed() {
+    // the `watched` logical service is mapped to a provider DLL that does not exist
+    let sandbox = Sandbox::new("config_watch");
+    sandbox.map_service("watched", "watched_old", Some("xfs_missing.dll"));
+    let _watch = ConfigWatch::new();
+
+    unsafe {
+        let lib = Library::new("msxfs.dll").unwrap();
+        let start_up: Symbol<unsafe extern "stdcall" fn(DWORD, LPWFSVERSION) -> HRESULT> = lib.get(b"WFSStartUp").unwrap();
+        let open: Symbol<unsafe extern "stdcall" fn(LPSTR, HAPP, LPSTR, DWORD, DWORD, DWORD, LPWFSVERSION, LPWFSVERSION, LPHSERVICE) -> HRESULT> = lib.get(b"WFSOpen").unwrap();
+        let close: Symbol<unsafe extern "stdcall" fn(HSERVICE) -> HRESULT> = lib.get(b"WFSClose").unwrap();
+        let clean_up: Symbol<unsafe extern "stdcall" fn() -> HRESULT> = lib.get(b"WFSCleanUp").unwrap();
+        let get_info: Symbol<unsafe extern "stdcall" fn(HSERVICE, DWORD, LPVOID, DWORD, *mut LPWFSRESULT) -> HRESULT> = lib.get(b"WFSGetInfo").unwrap();
+        let free_result: Symbol<unsafe extern "stdcall" fn(LPWFSRESULT) -> HRESULT> = lib.get(b"WFSFreeResult").unwrap();
+
+        let versions = VersionRange::new_explicit(Version::new_explicit(3, 0), Version::new_explicit(3, 30)).value();
+        let mut version = mem::zeroed::<WFSVERSION>();
+        assert_eq!(start_up(versions, &mut version), WFS_SUCCESS);
+
+        let logical_name = CString::new("watched").unwrap();
+        let open_watched = || {
+            let mut srvc_version = mem::zeroed::<WFSVERSION>();
+            let mut spi_version = mem::zeroed::<WFSVERSION>();
+            let mut service: HSERVICE = 0;
+            let result = open(
+                logical_name.as_ptr() as LPSTR,
+                ptr::null_mut(),
+                ptr::null_mut(),
+                0,
+                0,
+                versions,
+                &mut srvc_version,
+                &mut spi_version,
+                &mut service,
+            );
+            (result, service)
+        };
+
+        let resolved_services = || {
+            let mut result_ptr: LPWFSRESULT = ptr::null_mut();
+            assert_eq!(get_info(0, WFS_INF_MGR_STATISTICS, ptr::null_mut(), 0, &mut result_ptr), WFS_SUCCESS);
+            let statistics = (ptr::addr_of!((*result_ptr).lpBuffer).read_unaligned() as *const WFSMGRSTATISTICS).read_unaligned();
+            assert_eq!(free_result(result_ptr), WFS_SUCCESS);
+            statistics.dwResolvedServices
+        };
+
+        // the mapping resolves, so it is cached even though the DLL it names does not load
+        assert_eq!(open_watched().0, WFS_ERR_INVALID_SERVPROV);
+        assert_eq!(resolved_services(), 1);
+
+        // the watcher drops the cached mapping on its own, before the next open would have
+        sandbox.set_provider_value("watched_new", "dllname", "xfs_mock.dll");
+        sandbox.set_value(&format!("{LOGICAL_SERVICES}\\watched"), "provider", "watched_new");
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while resolved_services() != 0 {
+            assert!(Instant::now() < deadline, "the configuration change was not picked up in the background");
+            thread::sleep(Duration::from_millis(10));
+        }
+
+        let (result, service) = open_watched();
+        assert_eq!(result, WFS_SUCCESS);
+
+        assert_eq!(close(service), WFS_SUCCESS);
+        assert_eq!(clean_up(), WFS_SUCCESS);
+    }
+}
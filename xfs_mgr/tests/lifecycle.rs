@@ -0,0 +1,1744 @@
+//! Drives the exported manager API end to end against the mock provider (`xfs_mock.dll`).
+//!
+//! The test writes its own logical service into a registry sandbox below HKEY_CURRENT_USER, so it needs no admin
+//! rights, only `msxfs.dll`, `xfs_conf.dll`, `xfs_supp.dll` and `xfs_mock.dll` on the DLL search path, and for the isolated
+//! provider tests `xfs_isolate.dll` and `xfs_host.exe` as well.
+#![cfg(windows)]
+
+use std::{
+    ffi::{CStr, CString, OsString},
+    mem, ptr,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc, Barrier, Mutex, MutexGuard,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use libloading::{Library, Symbol};
+use winapi::{
+    shared::{
+        minwindef::{DWORD, LPVOID},
+        windef::HWND,
+        winerror::HRESULT,
+    },
+    um::{processthreadsapi::GetCurrentThreadId, winnt::LPSTR},
+};
+use xfslib::{sandbox::Sandbox, *};
+
+/// The manager state is process wide, so the tests must not overlap.
+static SERIAL: Mutex<()> = Mutex::new(());
+
+/// Command the mock provider accepts but never completes, mirrors `xfs_mock::HANG_COMMAND`.
+const HANG_COMMAND: DWORD = 999;
+
+/// Command the mock provider completes after [`DELAY`], mirrors `xfs_mock::DELAY_COMMAND`.
+const DELAY_COMMAND: DWORD = 998;
+const DELAY: Duration = Duration::from_millis(200);
+
+/// Command the mock provider completes after a stray completion for another request, mirrors `xfs_mock::STRAY_COMMAND`.
+const STRAY_COMMAND: DWORD = 997;
+
+/// Category the mock reports not ready for [`NOT_READY_COUNT`] times per service, mirrors `xfs_mock::NOT_READY_CATEGORY`.
+const NOT_READY_CATEGORY: DWORD = 999;
+const NOT_READY_COUNT: u32 = 2;
+
+/// Category the mock fails to allocate the result of once per service, mirrors `xfs_mock::OUT_OF_MEMORY_CATEGORY`.
+const OUT_OF_MEMORY_CATEGORY: DWORD = 998;
+const OUT_OF_MEMORY_DATA: &[u8] = b"FULL RESULT";
+
+/// Application id the mock fails the open for, mirrors `xfs_mock::FAIL_OPEN_APP_ID`.
+const FAIL_OPEN_APP_ID: &str = "FAIL_OPEN";
+
+/// Application id the mock never completes the open for, mirrors `xfs_mock::HANG_OPEN_APP_ID`.
+const HANG_OPEN_APP_ID: &str = "HANG_OPEN";
+
+/// Application id the mock calls back into the manager from WFPOpen for, mirrors `xfs_mock::REENTER_OPEN_APP_ID`.
+const REENTER_OPEN_APP_ID: &str = "REENTER_OPEN";
+
+/// Application id the mock supports only the SPI versions 2.00 to 2.30 for, mirrors `xfs_mock::V2_APP_ID`.
+const V2_APP_ID: &str = "V2_ONLY";
+
+/// Command the mock completes with a result it allocated itself, mirrors `xfs_mock::FOREIGN_RESULT_COMMAND`.
+const FOREIGN_RESULT_COMMAND: DWORD = 993;
+
+/// Command that makes the mock call WFSCleanUp from its next WFPClose, mirrors `xfs_mock::REENTER_COMMAND`.
+const REENTER_COMMAND: DWORD = 996;
+
+/// Command the mock provider stays inside WFPExecute for a while, mirrors `xfs_mock::BUSY_COMMAND`.
+const BUSY_COMMAND: DWORD = 995;
+const BUSY_TIME: Duration = Duration::from_millis(100);
+
+/// Command that aborts the process hosting the mock, mirrors `xfs_mock::CRASH_COMMAND`.
+const CRASH_COMMAND: DWORD = 994;
+
+/// Command that makes the mock fail its next WFPClose of the service, mirrors `xfs_mock::FAIL_CLOSE_COMMAND`.
+const FAIL_CLOSE_COMMAND: DWORD = 992;
+
+/// Event class bit that makes the mock complete registrations synchronously, mirrors `xfs_mock::SYNC_EVENT_CLASS`.
+const SYNC_EVENT_CLASS: DWORD = 0x8000;
+
+/// Bytes xfs_supp scans for the end of trace data at most, mirrors `xfs_supp::MAX_TRACE_LEN`.
+const MAX_TRACE_LEN: usize = 4096;
+
+/// Versions the tests start up with and open the mock for.
+const VERSIONS: VersionRange = VersionRange::new_explicit(Version::new_explicit(3, 0), Version::new_explicit(3, 30));
+
+/// Versions of an application of the 2.xx era.
+const V2_VERSIONS: VersionRange = VersionRange::new_explicit(Version::new_explicit(2, 0), Version::new_explicit(2, 30));
+
+type Open = unsafe extern "stdcall" fn(LPSTR, HAPP, LPSTR, DWORD, DWORD, DWORD, LPWFSVERSION, LPWFSVERSION, LPHSERVICE) -> HRESULT;
+
+type Execute = unsafe extern "stdcall" fn(HSERVICE, DWORD, LPVOID, DWORD, *mut LPWFSRESULT) -> HRESULT;
+
+/// Creates the registry sandbox with the `xfs_mock` logical service pointed at the mock provider.
+fn mock_sandbox() -> Sandbox {
+    let sandbox = Sandbox::new("lifecycle");
+    sandbox.map_service("xfs_mock", "xfs_mock", Some("xfs_mock.dll"));
+    sandbox
+}
+
+/// Sets an environment variable the manager reads, and puts its previous value back on drop, also when the test fails.
+struct EnvVar {
+    name: &'static str,
+    previous: Option<OsString>,
+}
+
+impl EnvVar {
+    fn set(name: &'static str, value: &str) -> Self {
+        let previous = std::env::var_os(name);
+        std::env::set_var(name, value);
+        EnvVar { name, previous }
+    }
+}
+
+impl Drop for EnvVar {
+    fn drop(&mut self) {
+        match &self.previous {
+            Some(previous) => std::env::set_var(self.name, previous),
+            None => std::env::remove_var(self.name),
+        }
+    }
+}
+
+/// Calls WFSOpen for the logical service, returning the service and the SPI version the provider agreed to.
+unsafe fn open_service(open: Open, logical_name: &str, app: HAPP, app_id: Option<&str>, trace_level: DWORD, versions: VersionRange, timeout: DWORD) -> Result<(HSERVICE, WFSVERSION), HRESULT> {
+    let logical_name = CString::new(logical_name).unwrap();
+    let app_id = app_id.map(|app_id| CString::new(app_id).unwrap());
+    let mut srvc_version = mem::zeroed::<WFSVERSION>();
+    let mut spi_version = mem::zeroed::<WFSVERSION>();
+    let mut service: HSERVICE = 0;
+    let result = open(
+        logical_name.as_ptr() as LPSTR,
+        app,
+        app_id.as_ref().map_or(ptr::null_mut(), |app_id| app_id.as_ptr() as LPSTR),
+        trace_level,
+        timeout,
+        versions.value(),
+        &mut srvc_version,
+        &mut spi_version,
+        &mut service,
+    );
+    match result {
+        WFS_SUCCESS => Ok((service, spi_version)),
+        result => Err(result),
+    }
+}
+
+/// Live buffers on the XFS heap at one point in time, used to catch result buffers nobody freed.
+///
+/// Every [`Session`] takes a snapshot right after WFSStartUp and asserts it is unchanged after WFSCleanUp, so
+/// tests built on a session are covered without doing anything. Tests that drive the manager by hand take a
+/// snapshot after WFSStartUp and call [`HeapSnapshot::assert_unchanged`] once they freed all their results and
+/// cleaned up. Completions still in flight count as leaks, so wait for them before the check.
+#[derive(Debug, PartialEq)]
+struct HeapSnapshot {
+    buffers: DWORD,
+    bytes: DWORD,
+}
+
+impl HeapSnapshot {
+    fn take(lib: &Library) -> Self {
+        let mut snapshot = HeapSnapshot { buffers: 0, bytes: 0 };
+        unsafe {
+            let get_heap_stats: Symbol<unsafe extern "stdcall" fn(*mut DWORD, *mut DWORD) -> HRESULT> = lib.get(b"WFMGetHeapStats").unwrap();
+            assert_eq!(get_heap_stats(&mut snapshot.buffers, &mut snapshot.bytes), WFS_SUCCESS);
+        }
+        snapshot
+    }
+
+    fn assert_unchanged(&self, lib: &Library) {
+        assert_eq!(&HeapSnapshot::take(lib), self, "XFS heap buffers leaked");
+    }
+}
+
+/// Started manager with the mock service open. The service is closed and the manager cleaned up on drop,
+/// after which the XFS heap must be back where it was after WFSStartUp.
+struct Session {
+    lib: Library,
+    service: HSERVICE,
+    heap: HeapSnapshot,
+    sandbox: Sandbox,
+    _serial: MutexGuard<'static, ()>,
+}
+
+impl Session {
+    fn new() -> Self {
+        let serial = SERIAL.lock().unwrap_or_else(|error| error.into_inner());
+        let sandbox = mock_sandbox();
+
+        unsafe {
+            let lib = Library::new("msxfs.dll").unwrap();
+            let start_up: Symbol<unsafe extern "stdcall" fn(DWORD, LPWFSVERSION) -> HRESULT> = lib.get(b"WFSStartUp").unwrap();
+
+            let mut version = mem::zeroed::<WFSVERSION>();
+            assert_eq!(start_up(VERSIONS.value(), &mut version), WFS_SUCCESS);
+            let heap = HeapSnapshot::take(&lib);
+            let (service, _) = open_service(*lib.get(b"WFSOpen").unwrap(), "xfs_mock", ptr::null_mut(), None, 0, VERSIONS, 0).unwrap();
+
+            Session {
+                lib,
+                service,
+                heap,
+                sandbox,
+                _serial: serial,
+            }
+        }
+    }
+
+    /// Opens another `xfs_mock` service with the application id, versions and timeout given.
+    fn open(&self, app_id: Option<&str>, versions: VersionRange, timeout: DWORD) -> Result<HSERVICE, HRESULT> {
+        unsafe { open_service(*self.lib.get(b"WFSOpen").unwrap(), "xfs_mock", ptr::null_mut(), app_id, 0, versions, timeout).map(|(service, _)| service) }
+    }
+
+    /// Number of threads blocked in a manager call, from the manager statistics.
+    fn blocked_threads(&self) -> DWORD {
+        unsafe {
+            let get_info: Symbol<unsafe extern "stdcall" fn(HSERVICE, DWORD, LPVOID, DWORD, *mut LPWFSRESULT) -> HRESULT> = self.lib.get(b"WFSGetInfo").unwrap();
+            let free_result: Symbol<unsafe extern "stdcall" fn(LPWFSRESULT) -> HRESULT> = self.lib.get(b"WFSFreeResult").unwrap();
+
+            let mut result_ptr: LPWFSRESULT = ptr::null_mut();
+            assert_eq!(get_info(0, WFS_INF_MGR_STATISTICS, ptr::null_mut(), 0, &mut result_ptr), WFS_SUCCESS);
+            let statistics = (ptr::addr_of!((*result_ptr).lpBuffer).read_unaligned() as *const WFSMGRSTATISTICS).read_unaligned();
+            assert_eq!(free_result(result_ptr), WFS_SUCCESS);
+            statistics.dwBlockedThreads
+        }
+    }
+}
+
+impl Drop for Session {
+    fn drop(&mut self) {
+        unsafe {
+            let close: Symbol<unsafe extern "stdcall" fn(HSERVICE) -> HRESULT> = self.lib.get(b"WFSClose").unwrap();
+            let clean_up: Symbol<unsafe extern "stdcall" fn() -> HRESULT> = self.lib.get(b"WFSCleanUp").unwrap();
+            close(self.service);
+            clean_up();
+        }
+        // a failed test already leaves its results behind
+        if !thread::panicking() {
+            self.heap.assert_unchanged(&self.lib);
+        }
+    }
+}
+
+#[test]
+fn test_open_execute_close() {
+    let _serial = SERIAL.lock().unwrap_or_else(|error| error.into_inner());
+    let _sandbox = mock_sandbox();
+
+    unsafe {
+        let lib = Library::new("msxfs.dll").unwrap();
+        let start_up: Symbol<unsafe extern "stdcall" fn(DWORD, LPWFSVERSION) -> HRESULT> = lib.get(b"WFSStartUp").unwrap();
+        let open: Open = *lib.get(b"WFSOpen").unwrap();
+        let execute: Symbol<unsafe extern "stdcall" fn(HSERVICE, DWORD, LPVOID, DWORD, *mut LPWFSRESULT) -> HRESULT> = lib.get(b"WFSExecute").unwrap();
+        let free_result: Symbol<unsafe extern "stdcall" fn(LPWFSRESULT) -> HRESULT> = lib.get(b"WFSFreeResult").unwrap();
+        let close: Symbol<unsafe extern "stdcall" fn(HSERVICE) -> HRESULT> = lib.get(b"WFSClose").unwrap();
+        let clean_up: Symbol<unsafe extern "stdcall" fn() -> HRESULT> = lib.get(b"WFSCleanUp").unwrap();
+
+        let mut version = mem::zeroed::<WFSVERSION>();
+        assert_eq!(start_up(VERSIONS.value(), &mut version), WFS_SUCCESS);
+        let heap = HeapSnapshot::take(&lib);
+
+        let (service, _) = open_service(open, "xfs_mock", ptr::null_mut(), Some("lifecycle"), 0, VERSIONS, 0).unwrap();
+        assert_ne!(service, 0);
+
+        let mut payload: DWORD = 0xC0FFEE;
+        let mut result_ptr: LPWFSRESULT = ptr::null_mut();
+        assert_eq!(execute(service, 101, &mut payload as *mut _ as LPVOID, 0, &mut result_ptr), WFS_SUCCESS);
+        assert!(!result_ptr.is_null());
+        let buffer = ptr::addr_of!((*result_ptr).lpBuffer).read_unaligned();
+        assert_eq!((buffer as *const DWORD).read_unaligned(), payload);
+        assert_eq!(free_result(result_ptr), WFS_SUCCESS);
+
+        assert_eq!(close(service), WFS_SUCCESS);
+        assert_eq!(clean_up(), WFS_SUCCESS);
+        heap.assert_unchanged(&lib);
+    }
+}
+
+#[test]
+fn test_lock_result() {
+    let session = Session::new();
+
+    unsafe {
+        let lock: Symbol<unsafe extern "stdcall" fn(HSERVICE, DWORD, *mut LPWFSRESULT) -> HRESULT> = session.lib.get(b"WFSLock").unwrap();
+        let async_lock: Symbol<unsafe extern "stdcall" fn(HSERVICE, DWORD, HWND, LPREQUESTID) -> HRESULT> = session.lib.get(b"WFSAsyncLock").unwrap();
+        let unlock: Symbol<unsafe extern "stdcall" fn(HSERVICE) -> HRESULT> = session.lib.get(b"WFSUnlock").unwrap();
+        let free_result: Symbol<unsafe extern "stdcall" fn(LPWFSRESULT) -> HRESULT> = session.lib.get(b"WFSFreeResult").unwrap();
+
+        // synchronous lock hands the result to the application
+        let mut result_ptr: LPWFSRESULT = ptr::null_mut();
+        assert_eq!(lock(session.service, 0, &mut result_ptr), WFS_SUCCESS);
+        let buffer = ptr::addr_of!((*result_ptr).lpBuffer).read_unaligned();
+        assert_eq!(CStr::from_ptr(buffer as *const _).to_bytes(), b"LOCKED");
+        assert_eq!(free_result(result_ptr), WFS_SUCCESS);
+        assert_eq!(unlock(session.service), WFS_SUCCESS);
+
+        // synchronous lock without a result pointer frees the result itself
+        assert_eq!(lock(session.service, 0, ptr::null_mut()), WFS_SUCCESS);
+        assert_eq!(unlock(session.service), WFS_SUCCESS);
+
+        // asynchronous lock delivers the result to the application window
+        let window = SyncWindow::new(WFS_LOCK_COMPLETE);
+        let mut request_id = 0;
+        assert_eq!(async_lock(session.service, 0, window.handle(), &mut request_id), WFS_SUCCESS);
+        let result_ptr = loop {
+            if let Some(result) = window.try_receive().unwrap() {
+                break result as LPWFSRESULT;
+            }
+        };
+        let buffer = ptr::addr_of!((*result_ptr).lpBuffer).read_unaligned();
+        assert_eq!(CStr::from_ptr(buffer as *const _).to_bytes(), b"LOCKED");
+        assert_eq!(free_result(result_ptr), WFS_SUCCESS);
+        assert_eq!(unlock(session.service), WFS_SUCCESS);
+    }
+}
+
+#[test]
+fn test_execute_locked_by_other_service() {
+    let session = Session::new();
+
+    unsafe {
+        let lock: unsafe extern "stdcall" fn(HSERVICE, DWORD, *mut LPWFSRESULT) -> HRESULT = *session.lib.get(b"WFSLock").unwrap();
+        let unlock: Symbol<unsafe extern "stdcall" fn(HSERVICE) -> HRESULT> = session.lib.get(b"WFSUnlock").unwrap();
+        let execute: Execute = *session.lib.get(b"WFSExecute").unwrap();
+        let close: Symbol<unsafe extern "stdcall" fn(HSERVICE) -> HRESULT> = session.lib.get(b"WFSClose").unwrap();
+
+        let other = session.open(None, VERSIONS, 0).unwrap();
+
+        // one thread takes the lock, the mock itself would run the other thread's command regardless
+        let owner = session.service;
+        assert_eq!(thread::spawn(move || lock(owner, 0, ptr::null_mut())).join().unwrap(), WFS_SUCCESS);
+        assert_eq!(thread::spawn(move || execute(other, 101, ptr::null_mut(), 0, ptr::null_mut())).join().unwrap(), WFS_ERR_LOCKED);
+        assert_eq!(execute(owner, 101, ptr::null_mut(), 0, ptr::null_mut()), WFS_SUCCESS);
+
+        assert_eq!(unlock(owner), WFS_SUCCESS);
+        assert_eq!(execute(other, 101, ptr::null_mut(), 0, ptr::null_mut()), WFS_SUCCESS);
+        assert_eq!(close(other), WFS_SUCCESS);
+    }
+}
+
+#[test]
+fn test_cancel_ignored_by_provider() {
+    let session = Session::new();
+
+    unsafe {
+        let async_execute: Symbol<unsafe extern "stdcall" fn(HSERVICE, DWORD, LPVOID, DWORD, HWND, LPREQUESTID) -> HRESULT> = session.lib.get(b"WFSAsyncExecute").unwrap();
+        let cancel: Symbol<unsafe extern "stdcall" fn(HSERVICE, REQUESTID) -> HRESULT> = session.lib.get(b"WFSCancelAsyncRequest").unwrap();
+        let free_result: Symbol<unsafe extern "stdcall" fn(LPWFSRESULT) -> HRESULT> = session.lib.get(b"WFSFreeResult").unwrap();
+
+        let window = SyncWindow::new(WFS_EXECUTE_COMPLETE);
+        let mut request_id = 0;
+        assert_eq!(async_execute(session.service, HANG_COMMAND, ptr::null_mut(), 0, window.handle(), &mut request_id), WFS_SUCCESS);
+        assert_eq!(cancel(session.service, request_id), WFS_SUCCESS);
+
+        // the mock ignores the cancel, so the completion must come from the manager
+        let deadline = Instant::now() + Duration::from_secs(5);
+        let result_ptr = loop {
+            if let Some(result) = window.try_receive().unwrap() {
+                break result as LPWFSRESULT;
+            }
+            assert!(Instant::now() < deadline, "no cancel completion posted");
+        };
+        assert_eq!(ptr::addr_of!((*result_ptr).hResult).read_unaligned(), WFS_ERR_CANCELED);
+        assert_eq!(ptr::addr_of!((*result_ptr).RequestID).read_unaligned(), request_id);
+        assert_eq!(ptr::addr_of!((*result_ptr).u.dwCommandCode).read_unaligned(), HANG_COMMAND);
+        assert_eq!(free_result(result_ptr), WFS_SUCCESS);
+    }
+}
+
+#[test]
+fn test_close_cancels_in_flight() {
+    let session = Session::new();
+
+    unsafe {
+        let async_execute: Symbol<unsafe extern "stdcall" fn(HSERVICE, DWORD, LPVOID, DWORD, HWND, LPREQUESTID) -> HRESULT> = session.lib.get(b"WFSAsyncExecute").unwrap();
+        let close: Symbol<unsafe extern "stdcall" fn(HSERVICE) -> HRESULT> = session.lib.get(b"WFSClose").unwrap();
+        let free_result: Symbol<unsafe extern "stdcall" fn(LPWFSRESULT) -> HRESULT> = session.lib.get(b"WFSFreeResult").unwrap();
+        let release_dll: Symbol<unsafe extern "stdcall" fn(HPROVIDER) -> HRESULT> = session.lib.get(b"WFMReleaseDLL").unwrap();
+        let mock = Library::new("xfs_mock.dll").unwrap();
+        let cancelled_requests: unsafe extern "stdcall" fn(LPREQUESTID, DWORD) -> DWORD = *mock.get(b"MockCancelledRequests").unwrap();
+        let get_provider: Symbol<unsafe extern "stdcall" fn(HSERVICE) -> HPROVIDER> = mock.get(b"MockGetProvider").unwrap();
+        let provider = get_provider(session.service);
+        let mut cancelled = [0; 8];
+        cancelled_requests(cancelled.as_mut_ptr(), cancelled.len() as DWORD);
+
+        let window = SyncWindow::new(WFS_EXECUTE_COMPLETE);
+        let (mut first_id, mut second_id) = (0, 0);
+        assert_eq!(async_execute(session.service, HANG_COMMAND, ptr::null_mut(), 0, window.handle(), &mut first_id), WFS_SUCCESS);
+        assert_eq!(async_execute(session.service, HANG_COMMAND, ptr::null_mut(), 0, window.handle(), &mut second_id), WFS_SUCCESS);
+
+        // the provider is asked to cancel both before it sees the close, which is not held up by them
+        let start = Instant::now();
+        assert_eq!(close(session.service), WFS_SUCCESS);
+        assert!(start.elapsed() < Duration::from_millis(500));
+        let count = cancelled_requests(cancelled.as_mut_ptr(), cancelled.len() as DWORD) as usize;
+        let mut requests = cancelled[..count].to_vec();
+        requests.sort_unstable();
+        assert_eq!(requests, [first_id, second_id]);
+
+        // the mock ignores the cancels, so the manager completes both
+        let deadline = Instant::now() + Duration::from_secs(5);
+        let mut completed = Vec::new();
+        while completed.len() < 2 {
+            if let Some(result) = window.try_receive().unwrap() {
+                let result_ptr = result as LPWFSRESULT;
+                assert_eq!(ptr::addr_of!((*result_ptr).hResult).read_unaligned(), WFS_ERR_CANCELED);
+                completed.push(ptr::addr_of!((*result_ptr).RequestID).read_unaligned());
+                assert_eq!(free_result(result_ptr), WFS_SUCCESS);
+            }
+            assert!(Instant::now() < deadline, "cancel completion dropped");
+        }
+        completed.sort_unstable();
+        assert_eq!(completed, [first_id, second_id]);
+
+        // nothing else arrives, and with nothing in flight the slot is released right away instead of draining
+        thread::sleep(Duration::from_secs(1));
+        assert!(window.try_receive().unwrap().is_none());
+        // the mock never releases the service it closed, so the provider's part is played here
+        assert_eq!(release_dll(provider), WFS_SUCCESS);
+        assert_eq!(session.open(None, VERSIONS, 0), Ok(session.service));
+    }
+}
+
+#[test]
+fn test_completion_relayed_unchanged() {
+    let session = Session::new();
+
+    unsafe {
+        let async_execute: Symbol<unsafe extern "stdcall" fn(HSERVICE, DWORD, LPVOID, DWORD, HWND, LPREQUESTID) -> HRESULT> = session.lib.get(b"WFSAsyncExecute").unwrap();
+        let free_result: Symbol<unsafe extern "stdcall" fn(LPWFSRESULT) -> HRESULT> = session.lib.get(b"WFSFreeResult").unwrap();
+        let mock = Library::new("xfs_mock.dll").unwrap();
+        let last_posted: Symbol<unsafe extern "stdcall" fn(*mut HWND, *mut u32, *mut LPWFSRESULT)> = mock.get(b"MockLastPosted").unwrap();
+
+        let window = SyncWindow::new(WFS_EXECUTE_COMPLETE);
+        let mut request_id = 0;
+        let mut data: DWORD = 7;
+        assert_eq!(async_execute(session.service, 101, &mut data as *mut _ as LPVOID, 0, window.handle(), &mut request_id), WFS_SUCCESS);
+
+        // the provider posted to the manager's window, not the application's
+        let (mut posted_window, mut posted_message, mut posted_result) = (ptr::null_mut(), 0, ptr::null_mut());
+        last_posted(&mut posted_window, &mut posted_message, &mut posted_result);
+        assert_ne!(posted_window, window.handle());
+        assert_eq!(posted_message, WFS_EXECUTE_COMPLETE);
+
+        // the application gets the same message with the very same result
+        let deadline = Instant::now() + Duration::from_secs(5);
+        let result_ptr = loop {
+            if let Some(result) = window.try_receive().unwrap() {
+                break result as LPWFSRESULT;
+            }
+            assert!(Instant::now() < deadline, "completion not relayed");
+            thread::sleep(Duration::from_millis(1));
+        };
+        assert_eq!(result_ptr, posted_result);
+        assert_eq!(ptr::addr_of!((*result_ptr).RequestID).read_unaligned(), request_id);
+        assert_eq!((ptr::addr_of!((*result_ptr).lpBuffer).read_unaligned() as *const DWORD).read_unaligned(), 7);
+        assert_eq!(free_result(result_ptr), WFS_SUCCESS);
+    }
+}
+
+#[test]
+fn test_foreign_result_copied() {
+    let session = Session::new();
+
+    unsafe {
+        let execute: Execute = *session.lib.get(b"WFSExecute").unwrap();
+        let free_result: Symbol<unsafe extern "stdcall" fn(LPWFSRESULT) -> HRESULT> = session.lib.get(b"WFSFreeResult").unwrap();
+        let heap = HeapSnapshot::take(&session.lib);
+
+        // the application gets a copy on the XFS heap, along with a copy of the provider's data chained to it
+        let mut data: DWORD = 0xF0E1;
+        let mut result_ptr: LPWFSRESULT = ptr::null_mut();
+        assert_eq!(execute(session.service, FOREIGN_RESULT_COMMAND, &mut data as *mut _ as LPVOID, 0, &mut result_ptr), WFS_SUCCESS);
+        assert_eq!(ptr::addr_of!((*result_ptr).u.dwCommandCode).read_unaligned(), FOREIGN_RESULT_COMMAND);
+        assert_eq!((ptr::addr_of!((*result_ptr).lpBuffer).read_unaligned() as *const DWORD).read_unaligned(), data);
+        assert_eq!(free_result(result_ptr), WFS_SUCCESS);
+        // the provider's data was freed when it was copied, so freeing the copy frees everything
+        heap.assert_unchanged(&session.lib);
+
+        // data the manager cannot tell the size of is not passed on
+        assert_eq!(execute(session.service, FOREIGN_RESULT_COMMAND, ptr::null_mut(), 0, &mut result_ptr), WFS_ERR_INTERNAL_ERROR);
+        assert!(ptr::addr_of!((*result_ptr).lpBuffer).read_unaligned().is_null());
+        assert_eq!(free_result(result_ptr), WFS_SUCCESS);
+        heap.assert_unchanged(&session.lib);
+    }
+}
+
+#[test]
+fn test_completions_out_of_order() {
+    let session = Session::new();
+
+    unsafe {
+        let async_execute: Symbol<unsafe extern "stdcall" fn(HSERVICE, DWORD, LPVOID, DWORD, HWND, LPREQUESTID) -> HRESULT> = session.lib.get(b"WFSAsyncExecute").unwrap();
+        let execute: Execute = *session.lib.get(b"WFSExecute").unwrap();
+        let free_result: Symbol<unsafe extern "stdcall" fn(LPWFSRESULT) -> HRESULT> = session.lib.get(b"WFSFreeResult").unwrap();
+
+        // two requests on one window, the second completes first and neither completion is dropped
+        let window = SyncWindow::new(WFS_EXECUTE_COMPLETE);
+        let (mut delayed_id, mut echo_id) = (0, 0);
+        let mut data: DWORD = 42;
+        assert_eq!(async_execute(session.service, DELAY_COMMAND, ptr::null_mut(), 0, window.handle(), &mut delayed_id), WFS_SUCCESS);
+        assert_eq!(async_execute(session.service, 101, &mut data as *mut _ as LPVOID, 0, window.handle(), &mut echo_id), WFS_SUCCESS);
+
+        let deadline = Instant::now() + Duration::from_secs(5);
+        let mut completed = Vec::new();
+        while completed.len() < 2 {
+            if let Some(result) = window.try_receive().unwrap() {
+                let result = result as LPWFSRESULT;
+                completed.push((ptr::addr_of!((*result).RequestID).read_unaligned(), ptr::addr_of!((*result).u.dwCommandCode).read_unaligned()));
+                assert_eq!(free_result(result), WFS_SUCCESS);
+            }
+            assert!(Instant::now() < deadline, "completion dropped");
+        }
+        assert_eq!(completed, [(echo_id, 101), (delayed_id, DELAY_COMMAND)]);
+
+        // two synchronous calls waiting at once, each gets its own result although they complete in reverse order
+        let delayed = thread::spawn(move || {
+            let mut result_ptr: LPWFSRESULT = ptr::null_mut();
+            assert_eq!(execute(session.service, DELAY_COMMAND, ptr::null_mut(), 0, &mut result_ptr), WFS_SUCCESS);
+            result_ptr as usize
+        });
+        thread::sleep(DELAY / 4);
+        let mut echo_ptr: LPWFSRESULT = ptr::null_mut();
+        assert_eq!(execute(session.service, 101, &mut data as *mut _ as LPVOID, 0, &mut echo_ptr), WFS_SUCCESS);
+        assert!(!delayed.is_finished());
+        let delayed_ptr = delayed.join().unwrap() as LPWFSRESULT;
+
+        assert_eq!(ptr::addr_of!((*echo_ptr).u.dwCommandCode).read_unaligned(), 101);
+        assert_eq!((ptr::addr_of!((*echo_ptr).lpBuffer).read_unaligned() as *const DWORD).read_unaligned(), 42);
+        assert_eq!(ptr::addr_of!((*delayed_ptr).u.dwCommandCode).read_unaligned(), DELAY_COMMAND);
+        assert_ne!(ptr::addr_of!((*delayed_ptr).RequestID).read_unaligned(), ptr::addr_of!((*echo_ptr).RequestID).read_unaligned());
+        assert_eq!(free_result(echo_ptr), WFS_SUCCESS);
+        assert_eq!(free_result(delayed_ptr), WFS_SUCCESS);
+    }
+}
+
+#[test]
+fn test_late_completion_after_release() {
+    let session = Session::new();
+
+    unsafe {
+        let async_execute: Symbol<unsafe extern "stdcall" fn(HSERVICE, DWORD, LPVOID, DWORD, HWND, LPREQUESTID) -> HRESULT> = session.lib.get(b"WFSAsyncExecute").unwrap();
+        let release_dll: Symbol<unsafe extern "stdcall" fn(HPROVIDER) -> HRESULT> = session.lib.get(b"WFMReleaseDLL").unwrap();
+        // only the manager keeps the mock loaded, so unloading it too early would crash the completing thread
+        let provider = {
+            let mock = Library::new("xfs_mock.dll").unwrap();
+            let get_provider: Symbol<unsafe extern "stdcall" fn(HSERVICE) -> HPROVIDER> = mock.get(b"MockGetProvider").unwrap();
+            get_provider(session.service)
+        };
+
+        // the application gives up on the request and its window is gone before the provider completes
+        let window = SyncWindow::new(WFS_EXECUTE_COMPLETE);
+        let mut request_id = 0;
+        assert_eq!(async_execute(session.service, DELAY_COMMAND, ptr::null_mut(), 0, window.handle(), &mut request_id), WFS_SUCCESS);
+        drop(window);
+
+        // the provider releases the service while the request is still in flight
+        assert_eq!(release_dll(provider), WFS_SUCCESS);
+        assert_eq!(async_execute(session.service, 101, ptr::null_mut(), 0, ptr::null_mut(), &mut request_id), WFS_ERR_INVALID_HSERVICE);
+
+        // the late completion is dropped by the manager and the slot is released afterwards
+        thread::sleep(DELAY * 3);
+        assert_eq!(session.open(None, VERSIONS, 0), Ok(session.service));
+    }
+}
+
+#[test]
+fn test_trace_level_floor() {
+    let _floor = EnvVar::set("XFS_TRACE_LEVEL_FLOOR", "0x4");
+    let session = Session::new();
+
+    unsafe {
+        let get_trace_level: Symbol<unsafe extern "stdcall" fn(HSERVICE, *mut DWORD) -> HRESULT> = session.lib.get(b"WFMGetTraceLevel").unwrap();
+        let set_trace_level: Symbol<unsafe extern "stdcall" fn(HSERVICE, DWORD) -> HRESULT> = session.lib.get(b"WFMSetTraceLevel").unwrap();
+
+        // the session opened with trace level 0
+        let mut trace_level = 0;
+        assert_eq!(get_trace_level(session.service, &mut trace_level), WFS_SUCCESS);
+        assert_eq!(trace_level, 0x4);
+
+        assert_eq!(set_trace_level(session.service, 0x1), WFS_SUCCESS);
+        assert_eq!(get_trace_level(session.service, &mut trace_level), WFS_SUCCESS);
+        assert_eq!(trace_level, 0x5);
+
+        assert_eq!(get_trace_level(0, &mut trace_level), WFS_ERR_INVALID_HSERVICE);
+        assert_eq!(set_trace_level(0, 0x1), WFS_ERR_INVALID_HSERVICE);
+    }
+}
+
+#[test]
+fn test_logical_services_info() {
+    let session = Session::new();
+    session.sandbox.map_service("xfs_second", "xfs_mock", None);
+
+    unsafe {
+        let get_info: Symbol<unsafe extern "stdcall" fn(HSERVICE, DWORD, LPVOID, DWORD, *mut LPWFSRESULT) -> HRESULT> = session.lib.get(b"WFSGetInfo").unwrap();
+        let free_result: Symbol<unsafe extern "stdcall" fn(LPWFSRESULT) -> HRESULT> = session.lib.get(b"WFSFreeResult").unwrap();
+
+        // lpBuffer is a NULL terminated array of the names
+        let mut result_ptr: LPWFSRESULT = ptr::null_mut();
+        assert_eq!(get_info(0, WFS_INF_MGR_LOGICAL_SERVICES, ptr::null_mut(), 0, &mut result_ptr), WFS_SUCCESS);
+        let mut names = Vec::new();
+        let mut entry = ptr::addr_of!((*result_ptr).lpBuffer).read_unaligned() as *const LPSTR;
+        while !entry.read_unaligned().is_null() {
+            names.push(CStr::from_ptr(entry.read_unaligned()).to_str().unwrap().to_string());
+            entry = entry.add(1);
+        }
+        assert_eq!(free_result(result_ptr), WFS_SUCCESS);
+        names.sort();
+        assert_eq!(names, ["xfs_mock", "xfs_second"]);
+    }
+}
+
+#[test]
+fn test_trace_levels_info() {
+    let session = Session::new();
+
+    unsafe {
+        let open: Open = *session.lib.get(b"WFSOpen").unwrap();
+        let close: Symbol<unsafe extern "stdcall" fn(HSERVICE) -> HRESULT> = session.lib.get(b"WFSClose").unwrap();
+        let get_info: Symbol<unsafe extern "stdcall" fn(HSERVICE, DWORD, LPVOID, DWORD, *mut LPWFSRESULT) -> HRESULT> = session.lib.get(b"WFSGetInfo").unwrap();
+        let free_result: Symbol<unsafe extern "stdcall" fn(LPWFSRESULT) -> HRESULT> = session.lib.get(b"WFSFreeResult").unwrap();
+
+        // the session opened with trace level 0, the second service traces the API and the SPI
+        let (service, _) = open_service(open, "xfs_mock", ptr::null_mut(), None, WFS_TRACE_API | WFS_TRACE_SPI, VERSIONS, 0).unwrap();
+
+        let mut result_ptr: LPWFSRESULT = ptr::null_mut();
+        assert_eq!(get_info(0, WFS_INF_MGR_TRACE_LEVELS, ptr::null_mut(), 0, &mut result_ptr), WFS_SUCCESS);
+        let mut levels = Vec::new();
+        let mut entry = ptr::addr_of!((*result_ptr).lpBuffer).read_unaligned() as *const *const WFSMGRTRACELEVEL;
+        while !entry.read_unaligned().is_null() {
+            let level = entry.read_unaligned().read_unaligned();
+            let name = CStr::from_ptr(level.lpszLogicalName).to_str().unwrap().to_string();
+            levels.push((level.hService, name, level.dwTraceLevel));
+            entry = entry.add(1);
+        }
+        assert_eq!(free_result(result_ptr), WFS_SUCCESS);
+        assert_eq!(levels, [(session.service, "xfs_mock".to_string(), 0), (service, "xfs_mock".to_string(), WFS_TRACE_API | WFS_TRACE_SPI)]);
+
+        assert_eq!(close(service), WFS_SUCCESS);
+    }
+}
+
+#[test]
+fn test_command_latencies() {
+    let session = Session::new();
+
+    unsafe {
+        let execute: Execute = *session.lib.get(b"WFSExecute").unwrap();
+        let get_info: Symbol<unsafe extern "stdcall" fn(HSERVICE, DWORD, LPVOID, DWORD, *mut LPWFSRESULT) -> HRESULT> = session.lib.get(b"WFSGetInfo").unwrap();
+        let free_result: Symbol<unsafe extern "stdcall" fn(LPWFSRESULT) -> HRESULT> = session.lib.get(b"WFSFreeResult").unwrap();
+
+        let latencies = || {
+            let mut result_ptr: LPWFSRESULT = ptr::null_mut();
+            assert_eq!(get_info(0, WFS_INF_MGR_LATENCIES, ptr::null_mut(), 0, &mut result_ptr), WFS_SUCCESS);
+            let mut latencies = Vec::new();
+            let mut entry = ptr::addr_of!((*result_ptr).lpBuffer).read_unaligned() as *const *const WFSMGRLATENCY;
+            while !entry.read_unaligned().is_null() {
+                latencies.push(entry.read_unaligned().read_unaligned());
+                entry = entry.add(1);
+            }
+            assert_eq!(free_result(result_ptr), WFS_SUCCESS);
+            latencies
+        };
+
+        // nothing is recorded unless asked for
+        assert_eq!(execute(session.service, DELAY_COMMAND, ptr::null_mut(), 0, ptr::null_mut()), WFS_SUCCESS);
+        assert!(latencies().is_empty());
+
+        let metrics = EnvVar::set("XFS_COMMAND_METRICS", "1");
+        for _ in 0..3 {
+            assert_eq!(execute(session.service, DELAY_COMMAND, ptr::null_mut(), 0, ptr::null_mut()), WFS_SUCCESS);
+        }
+        drop(metrics);
+
+        let latencies = latencies();
+        assert_eq!(latencies.len(), 1);
+        let latency = latencies[0];
+        assert_eq!(
+            ({ latency.hService }, { latency.dwMessage }, { latency.dwCommand }),
+            (session.service, WFS_EXECUTE_COMPLETE, DELAY_COMMAND)
+        );
+        assert_eq!({ latency.dwCount }, 3);
+        let (minimum, average, maximum) = (latency.dwMinimum, latency.dwAverage, latency.dwMaximum);
+        assert!(minimum <= average && average <= maximum);
+    }
+}
+
+#[test]
+fn test_last_error() {
+    let session = Session::new();
+
+    unsafe {
+        let lock: Symbol<unsafe extern "stdcall" fn(HSERVICE, DWORD, *mut LPWFSRESULT) -> HRESULT> = session.lib.get(b"WFSLock").unwrap();
+        let get_info: Symbol<unsafe extern "stdcall" fn(HSERVICE, DWORD, LPVOID, DWORD, *mut LPWFSRESULT) -> HRESULT> = session.lib.get(b"WFSGetInfo").unwrap();
+        let free_result: Symbol<unsafe extern "stdcall" fn(LPWFSRESULT) -> HRESULT> = session.lib.get(b"WFSFreeResult").unwrap();
+        let free_buffer: Symbol<unsafe extern "stdcall" fn(LPVOID) -> HRESULT> = session.lib.get(b"WFMFreeBuffer").unwrap();
+
+        let last_error = || {
+            let mut result_ptr: LPWFSRESULT = ptr::null_mut();
+            assert_eq!(get_info(0, WFS_INF_MGR_LAST_ERROR, ptr::null_mut(), 0, &mut result_ptr), WFS_SUCCESS);
+            let error = ptr::addr_of!((*result_ptr).lpBuffer).read_unaligned() as *const WFSMGRLASTERROR;
+            let error = (!error.is_null()).then(|| {
+                let error = error.read_unaligned();
+                let operation = CStr::from_ptr(error.lpszOperation).to_str().unwrap().to_string();
+                (error.hResult, operation, error.hService)
+            });
+            assert_eq!(free_result(result_ptr), WFS_SUCCESS);
+            error
+        };
+
+        // the service handle is never handed out by the tests
+        assert_eq!(lock(4000, 0, ptr::null_mut()), WFS_ERR_INVALID_HSERVICE);
+        // a support function failing for a provider on the application thread is not the application's error
+        assert_eq!(free_buffer(ptr::null_mut()), WFS_ERR_INVALID_POINTER);
+        assert_eq!(last_error(), Some((WFS_ERR_INVALID_HSERVICE, "WFSLock".to_string(), 4000)));
+
+        // the query itself succeeded, which cleared the record
+        assert_eq!(last_error(), None);
+
+        // the record is per thread
+        assert_eq!(lock(4000, 0, ptr::null_mut()), WFS_ERR_INVALID_HSERVICE);
+        thread::scope(|scope| scope.spawn(|| assert_eq!(last_error(), None)).join().unwrap());
+        assert_eq!(last_error().map(|(h_result, ..)| h_result), Some(WFS_ERR_INVALID_HSERVICE));
+    }
+}
+
+#[test]
+fn test_stray_completion_ignored() {
+    let session = Session::new();
+
+    unsafe {
+        let execute: Symbol<unsafe extern "stdcall" fn(HSERVICE, DWORD, LPVOID, DWORD, *mut LPWFSRESULT) -> HRESULT> = session.lib.get(b"WFSExecute").unwrap();
+        let free_result: Symbol<unsafe extern "stdcall" fn(LPWFSRESULT) -> HRESULT> = session.lib.get(b"WFSFreeResult").unwrap();
+
+        // the stray completion carries a buffer, the real one does not
+        let mut result_ptr: LPWFSRESULT = ptr::null_mut();
+        assert_eq!(execute(session.service, STRAY_COMMAND, ptr::null_mut(), 0, &mut result_ptr), WFS_SUCCESS);
+        assert_eq!(ptr::addr_of!((*result_ptr).hService).read_unaligned(), session.service);
+        assert!(ptr::addr_of!((*result_ptr).lpBuffer).read_unaligned().is_null());
+        assert_eq!(free_result(result_ptr), WFS_SUCCESS);
+    }
+}
+
+#[test]
+fn test_service_event_delivery() {
+    let session = Session::new();
+
+    unsafe {
+        let register: Symbol<unsafe extern "stdcall" fn(HSERVICE, DWORD, HWND) -> HRESULT> = session.lib.get(b"WFSRegister").unwrap();
+        let deregister: Symbol<unsafe extern "stdcall" fn(HSERVICE, DWORD, HWND) -> HRESULT> = session.lib.get(b"WFSDeregister").unwrap();
+        let free_result: Symbol<unsafe extern "stdcall" fn(LPWFSRESULT) -> HRESULT> = session.lib.get(b"WFSFreeResult").unwrap();
+
+        // the mock posts a service event on registration, this thread does not pump any messages while waiting
+        let window = SyncWindow::new(WFS_SERVICE_EVENT);
+        assert_eq!(register(session.service, SERVICE_EVENTS, window.handle()), WFS_SUCCESS);
+
+        let deadline = Instant::now() + Duration::from_secs(1);
+        let event = loop {
+            if let Some(event) = window.try_receive().unwrap() {
+                break event as LPWFSRESULT;
+            }
+            assert!(Instant::now() < deadline, "service event not delivered");
+            thread::sleep(Duration::from_millis(1));
+        };
+        assert_eq!(ptr::addr_of!((*event).hService).read_unaligned(), session.service);
+        assert_eq!(free_result(event), WFS_SUCCESS);
+
+        assert_eq!(deregister(session.service, SERVICE_EVENTS, window.handle()), WFS_SUCCESS);
+    }
+}
+
+#[test]
+fn test_service_event_filter() {
+    let session = Session::new();
+
+    unsafe {
+        let register: Symbol<unsafe extern "stdcall" fn(HSERVICE, DWORD, HWND) -> HRESULT> = session.lib.get(b"WFSRegister").unwrap();
+        let deregister: Symbol<unsafe extern "stdcall" fn(HSERVICE, DWORD, HWND) -> HRESULT> = session.lib.get(b"WFSDeregister").unwrap();
+        let set_event_filter: Symbol<unsafe extern "stdcall" fn(HSERVICE, DWORD, HWND, *const DWORD, DWORD) -> HRESULT> = session.lib.get(b"WFMSetEventFilter").unwrap();
+        let free_result: Symbol<unsafe extern "stdcall" fn(LPWFSRESULT) -> HRESULT> = session.lib.get(b"WFSFreeResult").unwrap();
+        let mock = Library::new("xfs_mock.dll").unwrap();
+        let post_events: Symbol<unsafe extern "stdcall" fn(HSERVICE, *const DWORD, DWORD) -> DWORD> = mock.get(b"MockPostServiceEvents").unwrap();
+
+        let window = SyncWindow::new(WFS_SERVICE_EVENT);
+        let receive = |timeout: Duration| {
+            let deadline = Instant::now() + timeout;
+            let mut event_ids = Vec::new();
+            while Instant::now() < deadline {
+                match window.try_receive().unwrap() {
+                    Some(event) => {
+                        let event = event as LPWFSRESULT;
+                        event_ids.push(ptr::addr_of!((*event).u.dwEventID).read_unaligned());
+                        assert_eq!(free_result(event), WFS_SUCCESS);
+                    }
+                    None => thread::sleep(Duration::from_millis(1)),
+                }
+            }
+            event_ids
+        };
+
+        // the filter drops the event with id 0 the mock posts on registration as well
+        let wanted = [7, 11];
+        assert_eq!(set_event_filter(session.service, SERVICE_EVENTS, window.handle(), wanted.as_ptr(), wanted.len() as DWORD), WFS_SUCCESS);
+        assert_eq!(register(session.service, SERVICE_EVENTS, window.handle()), WFS_SUCCESS);
+        let posted = [5, 7, 9, 11, 7];
+        assert_eq!(post_events(session.service, posted.as_ptr(), posted.len() as DWORD), 5);
+        assert_eq!(receive(Duration::from_millis(300)), [7, 11, 7]);
+
+        // without a filter every event comes through again
+        assert_eq!(set_event_filter(session.service, SERVICE_EVENTS, window.handle(), ptr::null(), 0), WFS_SUCCESS);
+        assert_eq!(post_events(session.service, posted.as_ptr(), posted.len() as DWORD), 5);
+        assert_eq!(receive(Duration::from_millis(300)), posted);
+
+        assert_eq!(set_event_filter(session.service, 0x10, window.handle(), ptr::null(), 0), WFS_ERR_INVALID_EVENT_CLASS);
+        assert_eq!(set_event_filter(0, SERVICE_EVENTS, window.handle(), ptr::null(), 0), WFS_ERR_INVALID_HSERVICE);
+        assert_eq!(deregister(session.service, SERVICE_EVENTS, window.handle()), WFS_SUCCESS);
+    }
+}
+
+#[test]
+fn test_register_completion() {
+    let session = Session::new();
+
+    unsafe {
+        let register: Symbol<unsafe extern "stdcall" fn(HSERVICE, DWORD, HWND) -> HRESULT> = session.lib.get(b"WFSRegister").unwrap();
+        let deregister: Symbol<unsafe extern "stdcall" fn(HSERVICE, DWORD, HWND) -> HRESULT> = session.lib.get(b"WFSDeregister").unwrap();
+        let window = SyncWindow::new(WFS_USER_EVENT);
+
+        // the status of a provider posting the completion is the one it completed with
+        assert_eq!(register(session.service, USER_EVENTS, window.handle()), WFS_SUCCESS);
+        assert_eq!(deregister(session.service, USER_EVENTS, window.handle()), WFS_SUCCESS);
+    }
+}
+
+#[test]
+fn test_register_normalized() {
+    let session = Session::new();
+
+    unsafe {
+        let register: Symbol<unsafe extern "stdcall" fn(HSERVICE, DWORD, HWND) -> HRESULT> = session.lib.get(b"WFSRegister").unwrap();
+        let deregister: Symbol<unsafe extern "stdcall" fn(HSERVICE, DWORD, HWND) -> HRESULT> = session.lib.get(b"WFSDeregister").unwrap();
+        let window = SyncWindow::new(WFS_USER_EVENT);
+
+        // the mock rejects registering twice and deregistering what is not registered, the manager answers both
+        assert_eq!(register(session.service, USER_EVENTS, window.handle()), WFS_SUCCESS);
+        assert_eq!(register(session.service, USER_EVENTS, window.handle()), WFS_SUCCESS);
+        assert_eq!(deregister(session.service, USER_EVENTS, window.handle()), WFS_SUCCESS);
+        assert_eq!(deregister(session.service, USER_EVENTS, window.handle()), WFS_SUCCESS);
+        assert_eq!(deregister(session.service, SERVICE_EVENTS, ptr::null_mut()), WFS_SUCCESS);
+    }
+}
+
+#[test]
+fn test_sync_register_policy() {
+    let session = Session::new();
+
+    unsafe {
+        let register: Symbol<unsafe extern "stdcall" fn(HSERVICE, DWORD, HWND) -> HRESULT> = session.lib.get(b"WFSRegister").unwrap();
+        let deregister: Symbol<unsafe extern "stdcall" fn(HSERVICE, DWORD, HWND) -> HRESULT> = session.lib.get(b"WFSDeregister").unwrap();
+        let close: Symbol<unsafe extern "stdcall" fn(HSERVICE) -> HRESULT> = session.lib.get(b"WFSClose").unwrap();
+        let window = SyncWindow::new(WFS_USER_EVENT);
+
+        // the policy is read when the service is opened
+        session.sandbox.set_provider_value("xfs_mock", "sync_register", "1");
+        let service = session.open(None, VERSIONS, 0).unwrap();
+
+        // the status the provider returned is final right away
+        let start = Instant::now();
+        assert_eq!(register(service, USER_EVENTS | SYNC_EVENT_CLASS, window.handle()), WFS_SUCCESS);
+        assert_eq!(deregister(service, USER_EVENTS | SYNC_EVENT_CLASS, window.handle()), WFS_SUCCESS);
+        assert!(start.elapsed() < Duration::from_millis(500));
+        assert_eq!(close(service), WFS_SUCCESS);
+    }
+}
+
+#[test]
+fn test_timeout_floor() {
+    let session = Session::new();
+
+    unsafe {
+        let execute: Execute = *session.lib.get(b"WFSExecute").unwrap();
+        let get_info: Symbol<unsafe extern "stdcall" fn(HSERVICE, DWORD, LPVOID, DWORD, *mut LPWFSRESULT) -> HRESULT> = session.lib.get(b"WFSGetInfo").unwrap();
+        let close: Symbol<unsafe extern "stdcall" fn(HSERVICE) -> HRESULT> = session.lib.get(b"WFSClose").unwrap();
+        let free_result: Symbol<unsafe extern "stdcall" fn(LPWFSRESULT) -> HRESULT> = session.lib.get(b"WFSFreeResult").unwrap();
+        let mock = Library::new("xfs_mock.dll").unwrap();
+        let last_timeout: unsafe extern "stdcall" fn() -> DWORD = *mock.get(b"MockLastTimeout").unwrap();
+
+        // providers without a floor get the timeout the application passed
+        assert_eq!(execute(session.service, 101, ptr::null_mut(), 10, ptr::null_mut()), WFS_SUCCESS);
+        assert_eq!(last_timeout(), 10);
+
+        // the floor is read when the service is opened
+        session.sandbox.set_provider_value("xfs_mock", "timeout_floor", "1000");
+        let service = session.open(None, VERSIONS, 0).unwrap();
+
+        // smaller timeouts are raised to the floor, larger ones and an indefinite wait are passed on
+        assert_eq!(execute(service, 101, ptr::null_mut(), 10, ptr::null_mut()), WFS_SUCCESS);
+        assert_eq!(last_timeout(), 1000);
+        assert_eq!(execute(service, 101, ptr::null_mut(), 5000, ptr::null_mut()), WFS_SUCCESS);
+        assert_eq!(last_timeout(), 5000);
+        assert_eq!(execute(service, 101, ptr::null_mut(), WFS_INDEFINITE_WAIT, ptr::null_mut()), WFS_SUCCESS);
+        assert_eq!(last_timeout(), WFS_INDEFINITE_WAIT);
+        let mut result_ptr: LPWFSRESULT = ptr::null_mut();
+        assert_eq!(get_info(service, 101, ptr::null_mut(), 1, &mut result_ptr), WFS_SUCCESS);
+        assert_eq!(last_timeout(), 1000);
+        assert_eq!(free_result(result_ptr), WFS_SUCCESS);
+        assert_eq!(close(service), WFS_SUCCESS);
+    }
+}
+
+#[test]
+fn test_reentrant_clean_up() {
+    let session = Session::new();
+
+    unsafe {
+        let execute: Symbol<unsafe extern "stdcall" fn(HSERVICE, DWORD, LPVOID, DWORD, *mut LPWFSRESULT) -> HRESULT> = session.lib.get(b"WFSExecute").unwrap();
+        let clean_up: unsafe extern "stdcall" fn() -> HRESULT = *session.lib.get(b"WFSCleanUp").unwrap();
+        // keeps the mock and its recorded result loaded after the manager unloads it
+        let mock = Library::new("xfs_mock.dll").unwrap();
+        let reentry_result: Symbol<unsafe extern "stdcall" fn() -> HRESULT> = mock.get(b"MockReentryResult").unwrap();
+
+        assert_eq!(execute(session.service, REENTER_COMMAND, ptr::null_mut(), 0, ptr::null_mut()), WFS_SUCCESS);
+
+        // clean up closes the service left open, and the mock calls WFSCleanUp again from WFPClose
+        let (sender, receiver) = mpsc::channel();
+        thread::spawn(move || sender.send(clean_up()).unwrap());
+        assert_eq!(receiver.recv_timeout(Duration::from_secs(5)), Ok(WFS_SUCCESS), "clean up deadlocked");
+        assert_eq!(reentry_result(), WFS_ERR_OP_IN_PROGRESS);
+    }
+}
+
+#[test]
+fn test_reentrant_open() {
+    let session = Session::new();
+
+    unsafe {
+        let open: Open = *session.lib.get(b"WFSOpen").unwrap();
+        let close: Symbol<unsafe extern "stdcall" fn(HSERVICE) -> HRESULT> = session.lib.get(b"WFSClose").unwrap();
+        let mock = Library::new("xfs_mock.dll").unwrap();
+        let reentry_result: Symbol<unsafe extern "stdcall" fn() -> HRESULT> = mock.get(b"MockReentryResult").unwrap();
+
+        // the mock calls WFMGetTraceLevel from WFPOpen, which needs the services lock
+        let (sender, receiver) = mpsc::channel();
+        thread::spawn(move || {
+            let result = open_service(open, "xfs_mock", ptr::null_mut(), Some(REENTER_OPEN_APP_ID), 0, VERSIONS, 0);
+            sender.send(result.map(|(service, _)| service)).unwrap();
+        });
+        let service = receiver.recv_timeout(Duration::from_secs(5)).expect("open deadlocked").unwrap();
+        assert_eq!(reentry_result(), WFS_SUCCESS);
+        assert_eq!(close(service), WFS_SUCCESS);
+    }
+}
+
+#[test]
+fn test_failed_open_releases_slot() {
+    let session = Session::new();
+
+    unsafe {
+        let async_open: Symbol<unsafe extern "stdcall" fn(LPSTR, HAPP, LPSTR, DWORD, DWORD, LPHSERVICE, HWND, DWORD, LPWFSVERSION, LPWFSVERSION, LPREQUESTID) -> HRESULT> =
+            session.lib.get(b"WFSAsyncOpen").unwrap();
+        let execute: Symbol<unsafe extern "stdcall" fn(HSERVICE, DWORD, LPVOID, DWORD, *mut LPWFSRESULT) -> HRESULT> = session.lib.get(b"WFSExecute").unwrap();
+        let free_result: Symbol<unsafe extern "stdcall" fn(LPWFSRESULT) -> HRESULT> = session.lib.get(b"WFSFreeResult").unwrap();
+
+        let logical_name = CString::new("xfs_mock").unwrap();
+        let app_id = CString::new(FAIL_OPEN_APP_ID).unwrap();
+        let mut srvc_version = mem::zeroed::<WFSVERSION>();
+        let mut spi_version = mem::zeroed::<WFSVERSION>();
+        let mut service: HSERVICE = 0;
+        let mut request_id = 0;
+
+        // the open is accepted, the provider reports the failure in the completion
+        let window = SyncWindow::new(WFS_OPEN_COMPLETE);
+        let result = async_open(
+            logical_name.as_ptr() as LPSTR,
+            ptr::null_mut(),
+            app_id.as_ptr() as LPSTR,
+            0,
+            0,
+            &mut service,
+            window.handle(),
+            VERSIONS.value(),
+            &mut srvc_version,
+            &mut spi_version,
+            &mut request_id,
+        );
+        assert_eq!(result, WFS_SUCCESS);
+        assert_ne!(service, session.service);
+
+        let deadline = Instant::now() + Duration::from_secs(5);
+        let result_ptr = loop {
+            if let Some(result) = window.try_receive().unwrap() {
+                break result as LPWFSRESULT;
+            }
+            assert!(Instant::now() < deadline, "no open completion posted");
+        };
+        assert_eq!(ptr::addr_of!((*result_ptr).hResult).read_unaligned(), WFS_ERR_HARDWARE_ERROR);
+        assert_eq!(free_result(result_ptr), WFS_SUCCESS);
+
+        // the slot is gone by the time the application sees the completion, and is handed out again
+        assert_eq!(execute(service, 101, ptr::null_mut(), 0, ptr::null_mut()), WFS_ERR_INVALID_HSERVICE);
+        assert_eq!(session.open(None, VERSIONS, 0), Ok(service));
+    }
+}
+
+#[test]
+fn test_not_ready_retries() {
+    let session = Session::new();
+
+    unsafe {
+        let get_info: Symbol<unsafe extern "stdcall" fn(HSERVICE, DWORD, LPVOID, DWORD, *mut LPWFSRESULT) -> HRESULT> = session.lib.get(b"WFSGetInfo").unwrap();
+        let free_result: Symbol<unsafe extern "stdcall" fn(LPWFSRESULT) -> HRESULT> = session.lib.get(b"WFSFreeResult").unwrap();
+
+        // without a retry policy the first not ready is passed through
+        let mut result_ptr: LPWFSRESULT = ptr::null_mut();
+        assert_eq!(get_info(session.service, NOT_READY_CATEGORY, ptr::null_mut(), 0, &mut result_ptr), WFS_ERR_DEV_NOT_READY);
+        assert_eq!(free_result(result_ptr), WFS_SUCCESS);
+
+        let retries = EnvVar::set("XFS_NOT_READY_RETRIES", &(NOT_READY_COUNT - 1).to_string());
+        let result = get_info(session.service, NOT_READY_CATEGORY, ptr::null_mut(), 0, &mut result_ptr);
+        drop(retries);
+        assert_eq!(result, WFS_SUCCESS);
+        assert_eq!(ptr::addr_of!((*result_ptr).hResult).read_unaligned(), WFS_SUCCESS);
+        assert_eq!(free_result(result_ptr), WFS_SUCCESS);
+    }
+}
+
+#[test]
+fn test_get_info_out_of_memory() {
+    let session = Session::new();
+
+    unsafe {
+        let get_info: Symbol<unsafe extern "stdcall" fn(HSERVICE, DWORD, LPVOID, DWORD, *mut LPWFSRESULT) -> HRESULT> = session.lib.get(b"WFSGetInfo").unwrap();
+        let free_result: Symbol<unsafe extern "stdcall" fn(LPWFSRESULT) -> HRESULT> = session.lib.get(b"WFSFreeResult").unwrap();
+
+        // the manager asks again after the failed allocation and hands over the complete result
+        let mut result_ptr: LPWFSRESULT = ptr::null_mut();
+        assert_eq!(get_info(session.service, OUT_OF_MEMORY_CATEGORY, ptr::null_mut(), 0, &mut result_ptr), WFS_SUCCESS);
+        let buffer = ptr::addr_of!((*result_ptr).lpBuffer).read_unaligned();
+        assert_eq!(CStr::from_ptr(buffer as *const _).to_bytes(), OUT_OF_MEMORY_DATA);
+        assert_eq!(free_result(result_ptr), WFS_SUCCESS);
+    }
+}
+
+#[test]
+fn test_wait_until_ready() {
+    let session = Session::new();
+
+    unsafe {
+        let wait_until_ready: Symbol<unsafe extern "stdcall" fn(HSERVICE, DWORD, DWORD) -> HRESULT> = session.lib.get(b"WFMWaitUntilReady").unwrap();
+
+        let started = Instant::now();
+        assert_eq!(wait_until_ready(session.service, NOT_READY_CATEGORY, 5000), WFS_SUCCESS);
+        assert!(started.elapsed() < Duration::from_secs(5));
+        assert_eq!(wait_until_ready(0, NOT_READY_CATEGORY, 0), WFS_ERR_INVALID_CATEGORY);
+    }
+}
+
+/// Runs two [`BUSY_COMMAND`]s on the service at the same time and returns how many of them the mock saw overlap.
+unsafe fn busy_concurrency(execute: Execute, max_concurrency: unsafe extern "stdcall" fn() -> DWORD, service: HSERVICE) -> DWORD {
+    max_concurrency();
+    let barrier = Barrier::new(2);
+    thread::scope(|scope| {
+        for _ in 0..2 {
+            scope.spawn(|| {
+                barrier.wait();
+                assert_eq!(execute(service, BUSY_COMMAND, ptr::null_mut(), 0, ptr::null_mut()), WFS_SUCCESS);
+            });
+        }
+    });
+    max_concurrency()
+}
+
+#[test]
+fn test_dispatch_policy() {
+    let session = Session::new();
+
+    unsafe {
+        let execute: Execute = *session.lib.get(b"WFSExecute").unwrap();
+        let close: Symbol<unsafe extern "stdcall" fn(HSERVICE) -> HRESULT> = session.lib.get(b"WFSClose").unwrap();
+        let mock = Library::new("xfs_mock.dll").unwrap();
+        let max_concurrency: unsafe extern "stdcall" fn() -> DWORD = *mock.get(b"MockMaxConcurrency").unwrap();
+
+        // providers without a policy are exclusive
+        assert_eq!(busy_concurrency(execute, max_concurrency, session.service), 1);
+
+        // the policy is read when the service is opened
+        session.sandbox.set_provider_value("xfs_mock", "reentrant", "1");
+        let service = session.open(None, VERSIONS, 0).unwrap();
+
+        assert_eq!(busy_concurrency(execute, max_concurrency, service), 2);
+        assert_eq!(busy_concurrency(execute, max_concurrency, session.service), 1);
+        assert_eq!(close(service), WFS_SUCCESS);
+    }
+}
+
+#[test]
+fn test_exclusive_dispatch_order() {
+    let session = Session::new();
+
+    unsafe {
+        let execute: Execute = *session.lib.get(b"WFSExecute").unwrap();
+        let mock = Library::new("xfs_mock.dll").unwrap();
+        let busy_order: unsafe extern "stdcall" fn(LPREQUESTID, DWORD) -> DWORD = *mock.get(b"MockBusyOrder").unwrap();
+        let mut order = [0; 8];
+        busy_order(order.as_mut_ptr(), order.len() as DWORD);
+
+        // callers pile up one after another while the provider is busy with the first one, then get in in that order
+        let service = session.service;
+        thread::scope(|scope| {
+            for _ in 0..6 {
+                scope.spawn(move || assert_eq!(execute(service, BUSY_COMMAND, ptr::null_mut(), 0, ptr::null_mut()), WFS_SUCCESS));
+                thread::sleep(BUSY_TIME / 10);
+            }
+        });
+        let count = busy_order(order.as_mut_ptr(), order.len() as DWORD) as usize;
+        assert_eq!(count, 6);
+        assert!(order[..count].windows(2).all(|pair| pair[0] < pair[1]), "{order:?}");
+    }
+}
+
+#[test]
+fn test_open_timeout() {
+    let session = Session::new();
+
+    unsafe {
+        let execute: Symbol<unsafe extern "stdcall" fn(HSERVICE, DWORD, LPVOID, DWORD, *mut LPWFSRESULT) -> HRESULT> = session.lib.get(b"WFSExecute").unwrap();
+
+        let started = Instant::now();
+        assert_eq!(session.open(Some(HANG_OPEN_APP_ID), VERSIONS, 200), Err(WFS_ERR_TIMEOUT));
+        assert!(started.elapsed() >= Duration::from_millis(200));
+        assert!(started.elapsed() < Duration::from_secs(5));
+
+        // the pending service took the slot after the session's, it is rolled back and the slot handed out again
+        let service = session.service + 1;
+        assert_eq!(execute(service, 101, ptr::null_mut(), 0, ptr::null_mut()), WFS_ERR_INVALID_HSERVICE);
+        assert_eq!(session.open(None, VERSIONS, WFS_INDEFINITE_WAIT), Ok(service));
+    }
+}
+
+#[test]
+fn test_open_rollback() {
+    let session = Session::new();
+    session.sandbox.map_service("xfs_rollback", "xfs_rollback", Some("xfs_missing.dll"));
+
+    unsafe {
+        let open: Open = *session.lib.get(b"WFSOpen").unwrap();
+        let close: Symbol<unsafe extern "stdcall" fn(HSERVICE) -> HRESULT> = session.lib.get(b"WFSClose").unwrap();
+        let cancel_blocking_call: Symbol<unsafe extern "stdcall" fn(DWORD) -> HRESULT> = session.lib.get(b"WFSCancelBlockingCall").unwrap();
+        let heap = HeapSnapshot::take(&session.lib);
+
+        // the provider DLL does not load, before a slot is taken
+        let result = open_service(open, "xfs_rollback", ptr::null_mut(), None, 0, VERSIONS, WFS_INDEFINITE_WAIT);
+        assert_eq!(result.map(|(service, _)| service), Err(WFS_ERR_INVALID_SERVPROV));
+
+        // the provider rejects the open right away
+        assert_eq!(session.open(None, V2_VERSIONS, WFS_INDEFINITE_WAIT), Err(WFS_ERR_SPI_VER_TOO_HIGH));
+
+        // the provider fails the open in the completion
+        assert_eq!(session.open(Some(FAIL_OPEN_APP_ID), VERSIONS, WFS_INDEFINITE_WAIT), Err(WFS_ERR_HARDWARE_ERROR));
+
+        // the application gives up on an open the provider never completes
+        let (sender, receiver) = mpsc::channel();
+        let hung = thread::spawn(move || {
+            sender.send(GetCurrentThreadId()).unwrap();
+            open_service(open, "xfs_mock", ptr::null_mut(), Some(HANG_OPEN_APP_ID), 0, VERSIONS, WFS_INDEFINITE_WAIT).map(|(service, _)| service)
+        });
+        let thread_id = receiver.recv().unwrap();
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while session.blocked_threads() == 0 {
+            assert!(Instant::now() < deadline, "open did not block");
+            thread::sleep(Duration::from_millis(1));
+        }
+        assert_eq!(cancel_blocking_call(thread_id), WFS_SUCCESS);
+        assert_eq!(hung.join().unwrap(), Err(WFS_ERR_CANCELED));
+
+        // none of the failed opens holds a slot or a buffer, the next one gets the slot after the session's
+        let service = session.open(None, VERSIONS, WFS_INDEFINITE_WAIT).unwrap();
+        assert_eq!(service, session.service + 1);
+        assert_eq!(close(service), WFS_SUCCESS);
+        heap.assert_unchanged(&session.lib);
+    }
+}
+
+#[test]
+fn test_manager_statistics() {
+    let session = Session::new();
+
+    unsafe {
+        let get_info: Symbol<unsafe extern "stdcall" fn(HSERVICE, DWORD, LPVOID, DWORD, *mut LPWFSRESULT) -> HRESULT> = session.lib.get(b"WFSGetInfo").unwrap();
+        let free_result: Symbol<unsafe extern "stdcall" fn(LPWFSRESULT) -> HRESULT> = session.lib.get(b"WFSFreeResult").unwrap();
+        let close: Symbol<unsafe extern "stdcall" fn(HSERVICE) -> HRESULT> = session.lib.get(b"WFSClose").unwrap();
+        let set_timer: Symbol<unsafe extern "stdcall" fn(HWND, LPVOID, DWORD, *mut u16) -> HRESULT> = session.lib.get(b"WFMSetTimer").unwrap();
+        let kill_timer: Symbol<unsafe extern "stdcall" fn(u16) -> HRESULT> = session.lib.get(b"WFMKillTimer").unwrap();
+
+        let service = session.open(None, VERSIONS, 0).unwrap();
+
+        let window = SyncWindow::new(WFS_TIMER_EVENT);
+        let mut timer_id = 0;
+        assert_eq!(set_timer(window.handle(), ptr::null_mut(), 60_000, &mut timer_id), WFS_SUCCESS);
+
+        let heap = HeapSnapshot::take(&session.lib);
+        let mut result_ptr: LPWFSRESULT = ptr::null_mut();
+        assert_eq!(get_info(0, WFS_INF_MGR_STATISTICS, ptr::null_mut(), 0, &mut result_ptr), WFS_SUCCESS);
+        let statistics = (ptr::addr_of!((*result_ptr).lpBuffer).read_unaligned() as *const WFSMGRSTATISTICS).read_unaligned();
+        assert_eq!({ statistics.dwOpenServices }, 2);
+        assert_eq!({ statistics.dwTimers }, 1);
+        assert_eq!({ statistics.dwBlockedThreads }, 0);
+        assert!({ statistics.dwResolvedServices } >= 1);
+        assert_eq!({ statistics.dwHeapBuffers }, heap.buffers);
+        assert_eq!({ statistics.dwHeapBytes }, heap.bytes);
+        assert_eq!(free_result(result_ptr), WFS_SUCCESS);
+
+        assert_eq!(kill_timer(timer_id), WFS_SUCCESS);
+        assert_eq!(close(service), WFS_SUCCESS);
+    }
+}
+
+#[test]
+fn test_deregister_while_closing() {
+    let session = Session::new();
+
+    unsafe {
+        let deregister: unsafe extern "stdcall" fn(HSERVICE, DWORD, HWND) -> HRESULT = *session.lib.get(b"WFSDeregister").unwrap();
+        let close: unsafe extern "stdcall" fn(HSERVICE) -> HRESULT = *session.lib.get(b"WFSClose").unwrap();
+        let service = session.service;
+
+        let barrier = Barrier::new(2);
+        let (closed, deregistered) = thread::scope(|scope| {
+            let closer = scope.spawn(|| {
+                barrier.wait();
+                close(service)
+            });
+            let deregisterer = scope.spawn(|| {
+                barrier.wait();
+                (0..50).map(|_| deregister(service, SERVICE_EVENTS, ptr::null_mut())).collect::<Vec<_>>()
+            });
+            (closer.join().unwrap(), deregisterer.join().unwrap())
+        });
+
+        assert_eq!(closed, WFS_SUCCESS);
+        // every deregister either made it before the close or was turned away, never both out of order
+        let accepted = deregistered.iter().take_while(|&&result| result == WFS_SUCCESS).count();
+        assert!(deregistered[accepted..].iter().all(|&result| result == WFS_ERR_INVALID_HSERVICE), "{deregistered:?}");
+        assert_eq!(deregister(service, SERVICE_EVENTS, ptr::null_mut()), WFS_ERR_INVALID_HSERVICE);
+    }
+}
+
+#[test]
+fn test_failed_close_releases_slot() {
+    let session = Session::new();
+
+    unsafe {
+        let execute: Execute = *session.lib.get(b"WFSExecute").unwrap();
+        let close: Symbol<unsafe extern "stdcall" fn(HSERVICE) -> HRESULT> = session.lib.get(b"WFSClose").unwrap();
+        let async_close: Symbol<unsafe extern "stdcall" fn(HSERVICE, HWND, LPREQUESTID) -> HRESULT> = session.lib.get(b"WFSAsyncClose").unwrap();
+        let free_result: Symbol<unsafe extern "stdcall" fn(LPWFSRESULT) -> HRESULT> = session.lib.get(b"WFSFreeResult").unwrap();
+        let mut result_ptr: LPWFSRESULT = ptr::null_mut();
+
+        // a failed WFSClose releases the slot, the handle is gone and the next open gets the slot
+        assert_eq!(execute(session.service, FAIL_CLOSE_COMMAND, ptr::null_mut(), 0, &mut result_ptr), WFS_SUCCESS);
+        assert_eq!(free_result(result_ptr), WFS_SUCCESS);
+        assert_eq!(close(session.service), WFS_ERR_HARDWARE_ERROR);
+        assert_eq!(execute(session.service, 101, ptr::null_mut(), 0, &mut result_ptr), WFS_ERR_INVALID_HSERVICE);
+        assert_eq!(session.open(None, VERSIONS, 0), Ok(session.service));
+
+        // so does a failed WFSAsyncClose, by the time the application sees the completion
+        assert_eq!(execute(session.service, FAIL_CLOSE_COMMAND, ptr::null_mut(), 0, &mut result_ptr), WFS_SUCCESS);
+        assert_eq!(free_result(result_ptr), WFS_SUCCESS);
+        let window = SyncWindow::new(WFS_CLOSE_COMPLETE);
+        let mut request_id = 0;
+        assert_eq!(async_close(session.service, window.handle(), &mut request_id), WFS_SUCCESS);
+        let deadline = Instant::now() + Duration::from_secs(5);
+        let result_ptr = loop {
+            if let Some(result) = window.try_receive().unwrap() {
+                break result as LPWFSRESULT;
+            }
+            assert!(Instant::now() < deadline, "no close completion posted");
+        };
+        assert_eq!(ptr::addr_of!((*result_ptr).hResult).read_unaligned(), WFS_ERR_HARDWARE_ERROR);
+        assert_eq!(ptr::addr_of!((*result_ptr).RequestID).read_unaligned(), request_id);
+        assert_eq!(free_result(result_ptr), WFS_SUCCESS);
+        let mut result_ptr: LPWFSRESULT = ptr::null_mut();
+        assert_eq!(execute(session.service, 101, ptr::null_mut(), 0, &mut result_ptr), WFS_ERR_INVALID_HSERVICE);
+        assert_eq!(session.open(None, VERSIONS, 0), Ok(session.service));
+    }
+}
+
+#[test]
+fn test_release_dll_slot() {
+    let session = Session::new();
+
+    unsafe {
+        let execute: Execute = *session.lib.get(b"WFSExecute").unwrap();
+        let release_dll: Symbol<unsafe extern "stdcall" fn(HPROVIDER) -> HRESULT> = session.lib.get(b"WFMReleaseDLL").unwrap();
+        let mock = Library::new("xfs_mock.dll").unwrap();
+        let get_provider: Symbol<unsafe extern "stdcall" fn(HSERVICE) -> HPROVIDER> = mock.get(b"MockGetProvider").unwrap();
+
+        let service = session.open(None, VERSIONS, 0).unwrap();
+
+        // the token releases exactly the service it was issued for
+        let provider = get_provider(service);
+        assert_ne!(provider, get_provider(session.service));
+        assert_eq!(release_dll(provider), WFS_SUCCESS);
+        assert_eq!(execute(service, 101, ptr::null_mut(), 0, ptr::null_mut()), WFS_ERR_INVALID_HSERVICE);
+        assert_eq!(execute(session.service, 101, ptr::null_mut(), 0, ptr::null_mut()), WFS_SUCCESS);
+
+        // a spent token does not release whatever takes the slot next
+        assert_eq!(release_dll(provider), WFS_ERR_INVALID_HPROVIDER);
+    }
+}
+
+#[test]
+fn test_open_app_handle() {
+    let session = Session::new();
+
+    unsafe {
+        let open: Open = *session.lib.get(b"WFSOpen").unwrap();
+        let close: Symbol<unsafe extern "stdcall" fn(HSERVICE) -> HRESULT> = session.lib.get(b"WFSClose").unwrap();
+        let create_app_handle: Symbol<unsafe extern "stdcall" fn(LPHAPP) -> HRESULT> = session.lib.get(b"WFSCreateAppHandle").unwrap();
+        let destroy_app_handle: Symbol<unsafe extern "stdcall" fn(HAPP) -> HRESULT> = session.lib.get(b"WFSDestroyAppHandle").unwrap();
+
+        let open_under = |app: HAPP| match open_service(open, "xfs_mock", app, None, 0, VERSIONS, 0) {
+            Ok((service, _)) => {
+                assert_eq!(close(service), WFS_SUCCESS);
+                WFS_SUCCESS
+            }
+            Err(result) => result,
+        };
+
+        let mut app: HAPP = ptr::null_mut();
+        assert_eq!(create_app_handle(&mut app), WFS_SUCCESS);
+        assert_eq!(open_under(app), WFS_SUCCESS);
+
+        assert_eq!(destroy_app_handle(app), WFS_SUCCESS);
+        assert_eq!(open_under(app), WFS_ERR_INVALID_APP_HANDLE);
+        assert_eq!(open_under(0xDEAD_0001 as HAPP), WFS_ERR_INVALID_APP_HANDLE);
+
+        assert_eq!(open_under(ptr::null_mut()), WFS_SUCCESS);
+    }
+}
+
+#[test]
+fn test_clean_up_while_blocked() {
+    let session = Session::new();
+
+    unsafe {
+        let execute: Execute = *session.lib.get(b"WFSExecute").unwrap();
+        let clean_up: Symbol<unsafe extern "stdcall" fn() -> HRESULT> = session.lib.get(b"WFSCleanUp").unwrap();
+        let cancel_blocking_call: Symbol<unsafe extern "stdcall" fn(DWORD) -> HRESULT> = session.lib.get(b"WFSCancelBlockingCall").unwrap();
+
+        // one thread blocks in a request the mock never completes
+        let service = session.service;
+        let (sender, receiver) = mpsc::channel();
+        let blocked = thread::spawn(move || {
+            sender.send(GetCurrentThreadId()).unwrap();
+            execute(service, HANG_COMMAND, ptr::null_mut(), 0, ptr::null_mut())
+        });
+        let thread_id = receiver.recv().unwrap();
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while session.blocked_threads() == 0 {
+            assert!(Instant::now() < deadline, "thread did not block");
+            thread::sleep(Duration::from_millis(1));
+        }
+
+        // clean up on another thread leaves the services alone while the call is in progress
+        assert_eq!(clean_up(), WFS_ERR_OP_IN_PROGRESS);
+        assert!(!blocked.is_finished());
+
+        // once the blocked call has unwound clean up goes ahead
+        assert_eq!(cancel_blocking_call(thread_id), WFS_SUCCESS);
+        assert_eq!(blocked.join().unwrap(), WFS_ERR_CANCELED);
+        assert_eq!(session.blocked_threads(), 0);
+    }
+}
+
+#[test]
+fn test_max_blocking_calls() {
+    let session = Session::new();
+
+    unsafe {
+        let execute: Execute = *session.lib.get(b"WFSExecute").unwrap();
+        let cancel_blocking_call: Symbol<unsafe extern "stdcall" fn(DWORD) -> HRESULT> = session.lib.get(b"WFSCancelBlockingCall").unwrap();
+
+        // two threads block in requests the mock never completes, which is all the limit allows
+        let _max_blocking_calls = EnvVar::set("XFS_MAX_BLOCKING_CALLS", "2");
+        let service = session.service;
+        let (sender, receiver) = mpsc::channel();
+        let blocked: Vec<_> = (0..2)
+            .map(|_| {
+                let sender = sender.clone();
+                thread::spawn(move || {
+                    sender.send(GetCurrentThreadId()).unwrap();
+                    execute(service, HANG_COMMAND, ptr::null_mut(), 0, ptr::null_mut())
+                })
+            })
+            .collect();
+        let thread_ids: Vec<DWORD> = receiver.iter().take(2).collect();
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while session.blocked_threads() < 2 {
+            assert!(Instant::now() < deadline, "threads did not block");
+            thread::sleep(Duration::from_millis(1));
+        }
+
+        // the third call is turned away without reaching the provider
+        assert_eq!(execute(service, DELAY_COMMAND, ptr::null_mut(), 0, ptr::null_mut()), WFS_ERR_INTERNAL_ERROR);
+
+        for thread_id in thread_ids {
+            assert_eq!(cancel_blocking_call(thread_id), WFS_SUCCESS);
+        }
+        for blocked in blocked {
+            assert_eq!(blocked.join().unwrap(), WFS_ERR_CANCELED);
+        }
+
+        // the slots are given back once the calls unwound
+        assert_eq!(execute(service, DELAY_COMMAND, ptr::null_mut(), 0, ptr::null_mut()), WFS_SUCCESS);
+    }
+}
+
+#[test]
+fn test_open_older_provider() {
+    let session = Session::new();
+
+    unsafe {
+        let open: Open = *session.lib.get(b"WFSOpen").unwrap();
+        let close: Symbol<unsafe extern "stdcall" fn(HSERVICE) -> HRESULT> = session.lib.get(b"WFSClose").unwrap();
+
+        // an application of the 2.xx era gets a 2.xx provider opened, the manager no longer insists on a 3.xx SPI
+        let (service, spi_version) = open_service(open, "xfs_mock", ptr::null_mut(), Some(V2_APP_ID), 0, V2_VERSIONS, 0).unwrap();
+        assert_eq!({ spi_version.w_version }, Version::new_explicit(2, 30).value());
+        assert_eq!(close(service), WFS_SUCCESS);
+    }
+}
+
+#[test]
+fn test_isolated_provider_crash() {
+    let session = Session::new();
+
+    unsafe {
+        let execute: Execute = *session.lib.get(b"WFSExecute").unwrap();
+        let async_execute: Symbol<unsafe extern "stdcall" fn(HSERVICE, DWORD, LPVOID, DWORD, HWND, LPREQUESTID) -> HRESULT> = session.lib.get(b"WFSAsyncExecute").unwrap();
+        let free_result: Symbol<unsafe extern "stdcall" fn(LPWFSRESULT) -> HRESULT> = session.lib.get(b"WFSFreeResult").unwrap();
+        let open: Open = *session.lib.get(b"WFSOpen").unwrap();
+        let close: Symbol<unsafe extern "stdcall" fn(HSERVICE) -> HRESULT> = session.lib.get(b"WFSClose").unwrap();
+
+        // the isolation is read when the service is opened, the session's own service stays in process
+        session.sandbox.set_provider_value("xfs_mock", "isolated", "1");
+        let (service, spi_version) = open_service(open, "xfs_mock", ptr::null_mut(), None, 0, VERSIONS, 0).unwrap();
+        assert_eq!({ spi_version.w_version }, Version::new_explicit(3, 30).value());
+
+        // requests travel to the surrogate and back
+        assert_eq!(execute(service, 101, ptr::null_mut(), 0, ptr::null_mut()), WFS_SUCCESS);
+
+        // the crash fails the request and the service, not the application
+        let window = SyncWindow::new(WFS_EXECUTE_COMPLETE);
+        let mut request_id = 0;
+        assert_eq!(async_execute(service, HANG_COMMAND, ptr::null_mut(), 0, window.handle(), &mut request_id), WFS_SUCCESS);
+        assert_eq!(execute(service, CRASH_COMMAND, ptr::null_mut(), 0, ptr::null_mut()), WFS_ERR_HARDWARE_ERROR);
+
+        // the completion made up for the pending request lives on the XFS heap like any other
+        let deadline = Instant::now() + Duration::from_secs(5);
+        let result_ptr = loop {
+            if let Some(result) = window.try_receive().unwrap() {
+                break result as LPWFSRESULT;
+            }
+            assert!(Instant::now() < deadline, "no completion for the pending request");
+        };
+        assert_eq!(ptr::addr_of!((*result_ptr).hResult).read_unaligned(), WFS_ERR_HARDWARE_ERROR);
+        assert_eq!(ptr::addr_of!((*result_ptr).RequestID).read_unaligned(), request_id);
+        assert_eq!(free_result(result_ptr), WFS_SUCCESS);
+        assert_eq!(execute(service, 101, ptr::null_mut(), 0, ptr::null_mut()), WFS_ERR_HARDWARE_ERROR);
+        assert_eq!(execute(session.service, 101, ptr::null_mut(), 0, ptr::null_mut()), WFS_SUCCESS);
+        assert_eq!(close(service), WFS_SUCCESS);
+    }
+}
+
+#[test]
+fn test_free_result_deep() {
+    let session = Session::new();
+
+    unsafe {
+        let lock: Symbol<unsafe extern "stdcall" fn(HSERVICE, DWORD, *mut LPWFSRESULT) -> HRESULT> = session.lib.get(b"WFSLock").unwrap();
+        let unlock: Symbol<unsafe extern "stdcall" fn(HSERVICE) -> HRESULT> = session.lib.get(b"WFSUnlock").unwrap();
+        let child_count: Symbol<unsafe extern "stdcall" fn(LPVOID, *mut DWORD) -> HRESULT> = session.lib.get(b"WFMGetChildCount").unwrap();
+        let free_result_deep: Symbol<unsafe extern "stdcall" fn(LPWFSRESULT, *mut DWORD) -> HRESULT> = session.lib.get(b"WFSFreeResultDeep").unwrap();
+        let buffer_length: Symbol<unsafe extern "stdcall" fn(LPVOID, *mut u32) -> HRESULT> = session.lib.get(b"WFMGetBufferLength").unwrap();
+
+        // the mock chains its lock data to the result, the session checks the heap is back to where it was
+        let mut result_ptr: LPWFSRESULT = ptr::null_mut();
+        assert_eq!(lock(session.service, 0, &mut result_ptr), WFS_SUCCESS);
+        let mut children = 0;
+        assert_eq!(child_count(result_ptr as LPVOID, &mut children), WFS_SUCCESS);
+        assert_eq!(children, 1);
+        // the lock data, `xfs_mock::LOCK_DATA`, bounded by the length it was allocated with
+        let mut length = 0;
+        assert_eq!(buffer_length(ptr::addr_of!((*result_ptr).lpBuffer).read_unaligned(), &mut length), WFS_SUCCESS);
+        assert_eq!(length as usize, b"LOCKED\0".len());
+        children = 0;
+        assert_eq!(free_result_deep(result_ptr, &mut children), WFS_SUCCESS);
+        assert_eq!(children, 1);
+        assert_eq!(free_result_deep(result_ptr, &mut children), WFS_ERR_INVALID_BUFFER);
+        assert_eq!(unlock(session.service), WFS_SUCCESS);
+    }
+}
+
+#[test]
+fn test_start_up_racing_clean_up() {
+    let _serial = SERIAL.lock().unwrap_or_else(|error| error.into_inner());
+
+    unsafe {
+        let lib = Library::new("msxfs.dll").unwrap();
+        let start_up: unsafe extern "stdcall" fn(DWORD, LPWFSVERSION) -> HRESULT = *lib.get(b"WFSStartUp").unwrap();
+        let clean_up: unsafe extern "stdcall" fn() -> HRESULT = *lib.get(b"WFSCleanUp").unwrap();
+        let create_app_handle: unsafe extern "stdcall" fn(LPHAPP) -> HRESULT = *lib.get(b"WFSCreateAppHandle").unwrap();
+        let destroy_app_handle: unsafe extern "stdcall" fn(HAPP) -> HRESULT = *lib.get(b"WFSDestroyAppHandle").unwrap();
+
+        let versions = VERSIONS.value();
+        let cycling = AtomicBool::new(true);
+        thread::scope(|scope| {
+            // one thread keeps starting up and cleaning up, the other keeps calling in between
+            scope.spawn(|| {
+                for _ in 0..500 {
+                    let mut version = mem::zeroed::<WFSVERSION>();
+                    assert_eq!(start_up(versions, &mut version), WFS_SUCCESS);
+                    assert_eq!(clean_up(), WFS_SUCCESS);
+                }
+                cycling.store(false, Ordering::SeqCst);
+            });
+            scope.spawn(|| {
+                while cycling.load(Ordering::SeqCst) {
+                    let mut app: HAPP = ptr::null_mut();
+                    match create_app_handle(&mut app) {
+                        WFS_ERR_NOT_STARTED => continue,
+                        result => assert_eq!(result, WFS_SUCCESS),
+                    }
+                    // a clean up in between releases the handle
+                    let result = destroy_app_handle(app);
+                    assert!(result == WFS_SUCCESS || result == WFS_ERR_INVALID_APP_HANDLE, "{result}");
+                }
+            });
+        });
+
+        // every clean up completed, the manager is back to not started
+        let mut app: HAPP = ptr::null_mut();
+        assert_eq!(create_app_handle(&mut app), WFS_ERR_NOT_STARTED);
+    }
+}
+
+/// Blocking hook for the start up tests, it only needs to be told apart from the default one.
+unsafe extern "stdcall" fn idle_hook() -> bool {
+    thread::sleep(Duration::from_millis(1));
+    false
+}
+
+static IDLE_HOOK: XFSBLOCKINGHOOK = idle_hook;
+
+#[test]
+fn test_double_start_up() {
+    let session = Session::new();
+
+    unsafe {
+        let start_up: unsafe extern "stdcall" fn(DWORD, LPWFSVERSION) -> HRESULT = *session.lib.get(b"WFSStartUp").unwrap();
+        let set_blocking_hook: Symbol<unsafe extern "stdcall" fn(*mut XFSBLOCKINGHOOK, *mut *mut XFSBLOCKINGHOOK) -> HRESULT> = session.lib.get(b"WFSSetBlockingHook").unwrap();
+        let execute: Execute = *session.lib.get(b"WFSExecute").unwrap();
+        let cancel_blocking_call: Symbol<unsafe extern "stdcall" fn(DWORD) -> HRESULT> = session.lib.get(b"WFSCancelBlockingCall").unwrap();
+
+        let hook = &IDLE_HOOK as *const XFSBLOCKINGHOOK as *mut XFSBLOCKINGHOOK;
+        let mut previous: *mut XFSBLOCKINGHOOK = ptr::null_mut();
+        assert_eq!(set_blocking_hook(hook, &mut previous), WFS_SUCCESS);
+
+        // one thread blocks in a request the mock never completes
+        let service = session.service;
+        let (sender, receiver) = mpsc::channel();
+        let blocked = thread::spawn(move || {
+            sender.send(GetCurrentThreadId()).unwrap();
+            execute(service, HANG_COMMAND, ptr::null_mut(), 0, ptr::null_mut())
+        });
+        let thread_id = receiver.recv().unwrap();
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while session.blocked_threads() == 0 {
+            assert!(Instant::now() < deadline, "thread did not block");
+            thread::sleep(Duration::from_millis(1));
+        }
+
+        // the start up belongs to the process, neither this thread nor another one starts it again
+        let versions = VERSIONS.value();
+        let mut version = mem::zeroed::<WFSVERSION>();
+        assert_eq!(start_up(versions, &mut version), WFS_ERR_ALREADY_STARTED);
+        let other = thread::spawn(move || {
+            let mut version = mem::zeroed::<WFSVERSION>();
+            start_up(versions, &mut version)
+        });
+        assert_eq!(other.join().unwrap(), WFS_ERR_ALREADY_STARTED);
+
+        // and the rejected start ups left the hook and the blocked thread alone
+        assert_eq!(session.blocked_threads(), 1);
+        assert_eq!(set_blocking_hook(hook, &mut previous), WFS_SUCCESS);
+        assert_eq!(previous, hook);
+
+        assert_eq!(cancel_blocking_call(thread_id), WFS_SUCCESS);
+        assert_eq!(blocked.join().unwrap(), WFS_ERR_CANCELED);
+    }
+}
+
+#[test]
+fn test_clean_up_balances_start_up() {
+    let _serial = SERIAL.lock().unwrap_or_else(|error| error.into_inner());
+
+    unsafe {
+        let lib = Library::new("msxfs.dll").unwrap();
+        let start_up: unsafe extern "stdcall" fn(DWORD, LPWFSVERSION) -> HRESULT = *lib.get(b"WFSStartUp").unwrap();
+        let clean_up: unsafe extern "stdcall" fn() -> HRESULT = *lib.get(b"WFSCleanUp").unwrap();
+        let set_blocking_hook: Symbol<unsafe extern "stdcall" fn(*mut XFSBLOCKINGHOOK, *mut *mut XFSBLOCKINGHOOK) -> HRESULT> = lib.get(b"WFSSetBlockingHook").unwrap();
+
+        let versions = VERSIONS.value();
+        let mut version = mem::zeroed::<WFSVERSION>();
+        let hook = &IDLE_HOOK as *const XFSBLOCKINGHOOK as *mut XFSBLOCKINGHOOK;
+        let mut previous: *mut XFSBLOCKINGHOOK = ptr::null_mut();
+
+        assert_eq!(start_up(versions, &mut version), WFS_SUCCESS);
+        assert_eq!(set_blocking_hook(hook, &mut previous), WFS_SUCCESS);
+        assert_eq!(start_up(versions, &mut version), WFS_ERR_ALREADY_STARTED);
+
+        // one clean up balances the start up, the rejected one is not counted
+        assert_eq!(clean_up(), WFS_SUCCESS);
+        assert_eq!(clean_up(), WFS_ERR_NOT_STARTED);
+        assert_eq!(set_blocking_hook(hook, &mut previous), WFS_ERR_NOT_STARTED);
+
+        // the next start up begins without the hook of the last one
+        assert_eq!(start_up(versions, &mut version), WFS_SUCCESS);
+        assert_eq!(set_blocking_hook(hook, &mut previous), WFS_SUCCESS);
+        assert!(previous.is_null());
+        assert_eq!(clean_up(), WFS_SUCCESS);
+    }
+}
+
+#[test]
+fn test_clean_up_cleans_up_supp() {
+    let _serial = SERIAL.lock().unwrap_or_else(|error| error.into_inner());
+
+    unsafe {
+        let lib = Library::new("msxfs.dll").unwrap();
+        let start_up: unsafe extern "stdcall" fn(DWORD, LPWFSVERSION) -> HRESULT = *lib.get(b"WFSStartUp").unwrap();
+        let clean_up: unsafe extern "stdcall" fn() -> HRESULT = *lib.get(b"WFSCleanUp").unwrap();
+        let set_timer: Symbol<unsafe extern "stdcall" fn(HWND, LPVOID, DWORD, *mut u16) -> HRESULT> = lib.get(b"WFMSetTimer").unwrap();
+        let kill_timer: Symbol<unsafe extern "stdcall" fn(u16) -> HRESULT> = lib.get(b"WFMKillTimer").unwrap();
+        // the same module the manager loaded, the manager itself does not export the timer count
+        let supp = Library::new("xfs_supp.dll").unwrap();
+        let get_timer_count: Symbol<unsafe extern "stdcall" fn(*mut DWORD) -> HRESULT> = supp.get(b"WFMGetTimerCount").unwrap();
+        let timers = || {
+            let mut count = 0;
+            assert_eq!(get_timer_count(&mut count), WFS_SUCCESS);
+            count
+        };
+
+        let versions = VERSIONS.value();
+        let mut version = mem::zeroed::<WFSVERSION>();
+        assert_eq!(start_up(versions, &mut version), WFS_SUCCESS);
+        let window = SyncWindow::new(WFS_TIMER_EVENT);
+        let mut timer_id = 0;
+        assert_eq!(set_timer(window.handle(), ptr::null_mut(), 60_000, &mut timer_id), WFS_SUCCESS);
+
+        assert_eq!(clean_up(), WFS_SUCCESS);
+        assert_eq!(timers(), 0);
+        assert_eq!(kill_timer(timer_id), WFS_ERR_INVALID_TIMER);
+
+        // the rejected clean up does not reach the support DLL a second time, a timer set since survives it
+        assert_eq!(set_timer(window.handle(), ptr::null_mut(), 60_000, &mut timer_id), WFS_SUCCESS);
+        assert_eq!(clean_up(), WFS_ERR_NOT_STARTED);
+        assert_eq!(timers(), 1);
+        assert_eq!(kill_timer(timer_id), WFS_SUCCESS);
+    }
+}
+
+#[test]
+fn test_in_flight_dump() {
+    let session = Session::new();
+
+    unsafe {
+        let async_execute: Symbol<unsafe extern "stdcall" fn(HSERVICE, DWORD, LPVOID, DWORD, HWND, LPREQUESTID) -> HRESULT> = session.lib.get(b"WFSAsyncExecute").unwrap();
+        let get_info: Symbol<unsafe extern "stdcall" fn(HSERVICE, DWORD, LPVOID, DWORD, *mut LPWFSRESULT) -> HRESULT> = session.lib.get(b"WFSGetInfo").unwrap();
+        let free_result: Symbol<unsafe extern "stdcall" fn(LPWFSRESULT) -> HRESULT> = session.lib.get(b"WFSFreeResult").unwrap();
+
+        let in_flight = || {
+            let mut result_ptr: LPWFSRESULT = ptr::null_mut();
+            assert_eq!(get_info(0, WFS_INF_MGR_IN_FLIGHT, ptr::null_mut(), 0, &mut result_ptr), WFS_SUCCESS);
+            let mut requests = Vec::new();
+            let mut entry = ptr::addr_of!((*result_ptr).lpBuffer).read_unaligned() as *const *const WFSMGRINFLIGHT;
+            while !entry.read_unaligned().is_null() {
+                requests.push(entry.read_unaligned().read_unaligned());
+                entry = entry.add(1);
+            }
+            assert_eq!(free_result(result_ptr), WFS_SUCCESS);
+            requests
+        };
+
+        let window = SyncWindow::new(WFS_EXECUTE_COMPLETE);
+        let (mut first_id, mut second_id) = (0, 0);
+        assert_eq!(async_execute(session.service, DELAY_COMMAND, ptr::null_mut(), 0, window.handle(), &mut first_id), WFS_SUCCESS);
+        assert_eq!(async_execute(session.service, DELAY_COMMAND, ptr::null_mut(), 0, window.handle(), &mut second_id), WFS_SUCCESS);
+
+        let requests = in_flight();
+        assert_eq!(requests.len(), 2);
+        for (request, request_id) in requests.iter().zip([first_id, second_id]) {
+            assert_eq!({ request.hService }, session.service);
+            assert_eq!({ request.RequestID }, request_id);
+            assert_eq!({ request.dwMessage }, WFS_EXECUTE_COMPLETE);
+            assert_eq!({ request.dwCommand }, DELAY_COMMAND);
+            assert_eq!({ request.hWnd }, window.handle());
+            assert!({ request.dwElapsed } < DELAY.as_millis() as DWORD);
+        }
+
+        let deadline = Instant::now() + Duration::from_secs(5);
+        let mut completed = 0;
+        while completed < 2 {
+            if let Some(result) = window.try_receive().unwrap() {
+                assert_eq!(free_result(result as LPWFSRESULT), WFS_SUCCESS);
+                completed += 1;
+            }
+            assert!(Instant::now() < deadline, "completion dropped");
+        }
+        assert!(in_flight().is_empty());
+    }
+}
+
+#[test]
+fn test_output_trace_data() {
+    let session = Session::new();
+
+    unsafe {
+        let output_trace_data: Symbol<unsafe extern "stdcall" fn(LPSTR) -> HRESULT> = session.lib.get(b"WFMOutputTraceData").unwrap();
+
+        let data = CString::new("provider trace").unwrap();
+        assert_eq!(output_trace_data(data.as_ptr() as LPSTR), WFS_SUCCESS);
+        // text a provider passes is traced as it is, not rejected for not being UTF-8
+        assert_eq!(output_trace_data(b"\xff\xfe\0".as_ptr() as LPSTR), WFS_SUCCESS);
+        assert_eq!(output_trace_data(ptr::null_mut()), WFS_ERR_INVALID_POINTER);
+
+        // a buffer without a terminator is cut off at the bound instead of being read past its end
+        let mut unterminated = vec![b'x'; MAX_TRACE_LEN];
+        assert_eq!(output_trace_data(unterminated.as_mut_ptr() as LPSTR), WFS_SUCCESS);
+    }
+}
@@ -26,7 +26,7 @@ pub extern "stdcall" fn DllMain(_hinst_dll: HINSTANCE, fdw_reason: DWORD, _: LPV
             .build(Root::builder().appender("logfile").build(LevelFilter::Trace))
             .unwrap();
 
-        log4rs::init_config(config).unwrap();
+        init_logger(config);
     }
     true
 }
@@ -114,6 +114,20 @@ lazy_static! {
             WFMSetTraceLevel: XFS_LIB.get(b"WFMSetTraceLevel").unwrap(),
         }
     };
+    // a manager extension, vendor managers do not export it
+    static ref WFM_GET_BUFFER_LENGTH: Option<Symbol<'static, unsafe extern "stdcall" fn(LPVOID, *mut ULONG) -> HRESULT>> =
+        unsafe { XFS_LIB.get(b"WFMGetBufferLength").ok() };
+}
+
+/// Command data or query details for the trace, with their length if the manager knows it.
+unsafe fn traced_data(data: LPVOID) -> RedactedData {
+    let mut length: ULONG = 0;
+    let length = match &*WFM_GET_BUFFER_LENGTH {
+        Some(get_buffer_length) if !data.is_null() && get_buffer_length(data, &mut length) == WFS_SUCCESS => Some(length as usize),
+        _ => None,
+    };
+    // SAFETY: a buffer on the XFS heap is readable for the length it was allocated with
+    RedactedData::new(data, length)
 }
 
 #[allow(non_snake_case)]
@@ -184,14 +198,14 @@ pub unsafe extern "stdcall" fn WFSDestroyAppHandle(hApp: HAPP) -> HRESULT {
 #[allow(non_snake_case)]
 #[no_mangle]
 pub unsafe extern "stdcall" fn WFSExecute(hService: HSERVICE, dwCommand: DWORD, lpCmdData: LPVOID, dwTimeOut: DWORD, lppResult: *mut LPWFSRESULT) -> HRESULT {
-    trace!("WFSExecute");
+    trace!("WFSExecute: {}, {}, lpCmdData: {}", hService, dwCommand, traced_data(lpCmdData));
     (XFS.WFSExecute)(hService, dwCommand, lpCmdData, dwTimeOut, lppResult)
 }
 
 #[allow(non_snake_case)]
 #[no_mangle]
 pub unsafe extern "stdcall" fn WFSAsyncExecute(hService: HSERVICE, dwCommand: DWORD, lpCmdData: LPVOID, dwTimeOut: DWORD, hWnd: HWND, lpRequestID: LPREQUESTID) -> HRESULT {
-    trace!("WFSAsyncExecute");
+    trace!("WFSAsyncExecute: {}, {}, lpCmdData: {}", hService, dwCommand, traced_data(lpCmdData));
     (XFS.WFSAsyncExecute)(hService, dwCommand, lpCmdData, dwTimeOut, hWnd, lpRequestID)
 }
 
@@ -206,10 +220,10 @@ pub unsafe extern "stdcall" fn WFSFreeResult(lpResult: LPWFSRESULT) -> HRESULT {
 #[no_mangle]
 pub unsafe extern "stdcall" fn WFSGetInfo(hService: HSERVICE, dwCategory: DWORD, lpQueryDetails: LPVOID, dwTimeOut: DWORD, lppResult: *mut LPWFSRESULT) -> HRESULT {
     trace!(
-        "WFSGetInfo CAL: hService: {}, dwCategory: {}, lpQueryDetails: {:?}, dwTimeOut: {}, lppResult: {:?}",
+        "WFSGetInfo CAL: hService: {}, dwCategory: {}, lpQueryDetails: {}, dwTimeOut: {}, lppResult: {:?}",
         hService,
         dwCategory,
-        *lpQueryDetails,
+        traced_data(lpQueryDetails),
         dwTimeOut,
         *lppResult,
     );
@@ -222,10 +236,10 @@ pub unsafe extern "stdcall" fn WFSGetInfo(hService: HSERVICE, dwCategory: DWORD,
 #[no_mangle]
 pub unsafe extern "stdcall" fn WFSAsyncGetInfo(hService: HSERVICE, dwCategory: DWORD, lpQueryDetails: LPVOID, dwTimeOut: DWORD, hWnd: HWND, lpRequestID: LPREQUESTID) -> HRESULT {
     trace!(
-        "WFSAsyncGetInfo CAL: hService: {}, dwCategory: {}, lpQueryDetails: {:?}, dwTimeOut: {}, lpRequestID: {:?}",
+        "WFSAsyncGetInfo CAL: hService: {}, dwCategory: {}, lpQueryDetails: {}, dwTimeOut: {}, lpRequestID: {:?}",
         hService,
         dwCategory,
-        *lpQueryDetails,
+        traced_data(lpQueryDetails),
         dwTimeOut,
         lpRequestID
     );
@@ -268,10 +282,10 @@ pub unsafe extern "stdcall" fn WFSOpen(
     lphService: LPHSERVICE,
 ) -> HRESULT {
     trace!(
-        "WFSOpen CAL: lpszLogicalName: {:?}, hApp: {:?}, lpszAppID: {:?}, dwTraceLevel: {}, dwTimeOut: {}, dwSrvcVersionsRequired: {}, lpSrvcVersion: {:?}, lpSPIVersion: {:?}, lphService: {:?}",
+        "WFSOpen CAL: lpszLogicalName: {:?}, hApp: {:?}, lpszAppID: {}, dwTraceLevel: {}, dwTimeOut: {}, dwSrvcVersionsRequired: {}, lpSrvcVersion: {:?}, lpSPIVersion: {:?}, lphService: {:?}",
         *lpszLogicalName,
         hApp,
-        *lpszAppID,
+        Redacted::c_str(lpszAppID),
         dwTraceLevel,
         dwTimeOut,
         dwSrvcVersionsRequired,
@@ -291,10 +305,10 @@ pub unsafe extern "stdcall" fn WFSOpen(
         lphService,
     );
     trace!(
-        "WFSOpen RES: lpszLogicalName: {:?}, hApp: {:?}, lpszAppID: {:?}, dwTraceLevel: {}, dwTimeOut: {}, dwSrvcVersionsRequired: {}, lpSrvcVersion: {:?}, lpSPIVersion: {:?}, lphService: {:?}",
+        "WFSOpen RES: lpszLogicalName: {:?}, hApp: {:?}, lpszAppID: {}, dwTraceLevel: {}, dwTimeOut: {}, dwSrvcVersionsRequired: {}, lpSrvcVersion: {:?}, lpSPIVersion: {:?}, lphService: {:?}",
         *lpszLogicalName,
         hApp,
-        *lpszAppID,
+        Redacted::c_str(lpszAppID),
         dwTraceLevel,
         dwTimeOut,
         dwSrvcVersionsRequired,
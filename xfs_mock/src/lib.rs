@@ -0,0 +1,531 @@
+//! Mock service provider used by the manager tests.
+//!
+//! Every request completes immediately with WFS_SUCCESS by posting a result allocated on the XFS heap to the
+//! supplied window. WFPExecute echoes the DWORD pointed to by lpCmdData back in the result buffer and WFPLock
+//! returns [`LOCK_DATA`] in its result buffer. Executing [`HANG_COMMAND`] never completes, and
+//! WFPCancelAsyncRequest ignores the cancel, so tests can exercise the manager's own cancel handling.
+//! Executing [`DELAY_COMMAND`] completes from another thread after [`DELAY`]. Executing [`STRAY_COMMAND`]
+//! first posts a completion for another request id, carrying [`STRAY_DATA`], then the real one without data.
+//! Registering for SERVICE_EVENTS immediately posts one WFS_SERVICE_EVENT to the registered window. Registering or
+//! deregistering with [`SYNC_EVENT_CLASS`] set returns the final status without posting a completion. Like some real
+//! providers, the mock completes registering a window for classes it is registered for already with
+//! WFS_ERR_INVALID_EVENT_CLASS, and deregistering a window that is not registered with WFS_ERR_INVALID_HWNDREG.
+//! WFPGetInfo for [`NOT_READY_CATEGORY`] reports WFS_ERR_DEV_NOT_READY [`NOT_READY_COUNT`] times per service, then
+//! completes successfully. WFPGetInfo for [`OUT_OF_MEMORY_CATEGORY`] reports WFS_ERR_OUT_OF_MEMORY once per service,
+//! then completes with [`OUT_OF_MEMORY_DATA`].
+//! Opening with the application id [`FAIL_OPEN_APP_ID`] completes the open with WFS_ERR_HARDWARE_ERROR, opening
+//! with [`HANG_OPEN_APP_ID`] never completes. Opening with [`V2_APP_ID`] behaves like a provider of the 2.00 to 2.30
+//! SPI, which rejects the open with WFS_ERR_SPI_VER_TOO_HIGH unless the manager offers one of those versions.
+//! Opening with [`REENTER_OPEN_APP_ID`] calls back into WFMGetTraceLevel for the service from WFPOpen, its result is
+//! returned by `MockReentryResult`.
+//! Executing [`REENTER_COMMAND`] makes the next WFPClose of the service call back into WFSCleanUp, its result is
+//! returned by `MockReentryResult`. Executing [`FAIL_CLOSE_COMMAND`] makes the next WFPClose of the service complete
+//! with WFS_ERR_HARDWARE_ERROR.
+//! Executing [`BUSY_COMMAND`] stays inside WFPExecute for [`BUSY_TIME`] before completing, `MockMaxConcurrency`
+//! returns the highest number of WFPExecute calls that overlapped since it was last called, and `MockBusyOrder` the
+//! request ids of the busy commands in the order they came in.
+//! Executing [`CRASH_COMMAND`] aborts the process, which only a mock hosted by `xfs_host.exe` may be asked to do.
+//! Executing [`FOREIGN_RESULT_COMMAND`] completes with a result allocated by the mock itself instead of on the XFS
+//! heap. Its lpBuffer echoes the DWORD lpCmdData points to in a buffer of its own on the XFS heap, or, without
+//! lpCmdData, points to a 0 the mock allocated itself too.
+//!
+//! `MockGetProvider` returns the hProvider the manager passed to WFPOpen, so tests can play the provider's
+//! part in WFMReleaseDLL. `MockLastPosted` returns the window, message and lParam of the last completion posted,
+//! so tests can check what reaches the application. `MockPostServiceEvents` posts service events with the given
+//! event ids to the window registered for them. `MockLastTimeout` returns the dwTimeOut of the last WFPExecute,
+//! WFPGetInfo or WFPLock call. `MockCancelledRequests` returns the request ids WFPCancelAsyncRequest was called for.
+
+use std::{
+    collections::{HashMap, HashSet},
+    ffi::CStr,
+    mem, ptr,
+    sync::{
+        atomic::{AtomicI32, AtomicU32, Ordering},
+        Mutex,
+    },
+    thread,
+    time::Duration,
+};
+
+use lazy_static::lazy_static;
+use libloading::Symbol;
+use winapi::{
+    shared::{
+        minwindef::{DWORD, LPARAM, LPVOID, ULONG},
+        windef::HWND,
+        winerror::HRESULT,
+    },
+    um::{sysinfoapi::GetSystemTime, winnt::LPSTR, winuser::PostMessageA},
+};
+use xfslib::*;
+
+/// Buffer returned with every lock completion.
+pub const LOCK_DATA: &[u8] = b"LOCKED\0";
+
+/// Command that is accepted but never completed.
+pub const HANG_COMMAND: DWORD = 999;
+
+/// Command that completes after [`DELAY`].
+pub const DELAY_COMMAND: DWORD = 998;
+
+/// Time until [`DELAY_COMMAND`] completes.
+pub const DELAY: Duration = Duration::from_millis(200);
+
+/// Command that is preceded by a completion for a request that does not exist.
+pub const STRAY_COMMAND: DWORD = 997;
+
+/// Buffer of the stray completion.
+pub const STRAY_DATA: &[u8] = b"STRAY\0";
+
+/// Command that makes the next WFPClose of the service re-enter the manager.
+pub const REENTER_COMMAND: DWORD = 996;
+
+/// GetInfo category the service is not ready for at first.
+pub const NOT_READY_CATEGORY: DWORD = 999;
+
+/// Number of times [`NOT_READY_CATEGORY`] reports WFS_ERR_DEV_NOT_READY.
+pub const NOT_READY_COUNT: u32 = 2;
+
+/// GetInfo category the service fails to allocate the result of at first.
+pub const OUT_OF_MEMORY_CATEGORY: DWORD = 998;
+
+/// Buffer returned for [`OUT_OF_MEMORY_CATEGORY`] once the allocation succeeds.
+pub const OUT_OF_MEMORY_DATA: &[u8] = b"FULL RESULT\0";
+
+/// Application id that makes WFPOpen complete with an error.
+pub const FAIL_OPEN_APP_ID: &str = "FAIL_OPEN";
+
+/// Application id that makes WFPOpen accept the open without ever completing it.
+pub const HANG_OPEN_APP_ID: &str = "HANG_OPEN";
+
+/// Application id that makes WFPOpen call back into the manager before it completes the open.
+pub const REENTER_OPEN_APP_ID: &str = "REENTER_OPEN";
+
+/// Application id that makes WFPOpen support only the SPI versions 2.00 to 2.30.
+pub const V2_APP_ID: &str = "V2_ONLY";
+
+/// Command that keeps the calling thread inside WFPExecute for [`BUSY_TIME`].
+pub const BUSY_COMMAND: DWORD = 995;
+
+/// Time [`BUSY_COMMAND`] spends inside WFPExecute.
+pub const BUSY_TIME: Duration = Duration::from_millis(100);
+
+/// Command that aborts the process hosting the mock, standing in for a provider crashing mid-execute.
+pub const CRASH_COMMAND: DWORD = 994;
+
+/// Command completed with a result the mock allocated itself, as a provider ignoring WFMAllocateBuffer would.
+pub const FOREIGN_RESULT_COMMAND: DWORD = 993;
+
+/// Command that makes the next WFPClose of the service complete with WFS_ERR_HARDWARE_ERROR.
+pub const FAIL_CLOSE_COMMAND: DWORD = 992;
+
+/// Event class bit that makes WFPRegister and WFPDeregister complete synchronously, without posting the completion.
+pub const SYNC_EVENT_CLASS: DWORD = 0x8000;
+
+/// Number of WFPExecute calls currently running.
+static IN_FLIGHT: AtomicU32 = AtomicU32::new(0);
+
+/// Highest number of overlapping WFPExecute calls.
+static MAX_IN_FLIGHT: AtomicU32 = AtomicU32::new(0);
+
+/// Request ids of the [`BUSY_COMMAND`]s executed, in the order they came in.
+static BUSY_ORDER: Mutex<Vec<REQUESTID>> = Mutex::new(Vec::new());
+
+/// Request ids WFPCancelAsyncRequest was called for, in the order it was called.
+static CANCELLED: Mutex<Vec<REQUESTID>> = Mutex::new(Vec::new());
+
+/// Window, message and lParam of the last posted completion.
+static LAST_POSTED: Mutex<(usize, u32, usize)> = Mutex::new((0, 0, 0));
+
+/// Result of the last call back into the manager, WFSCleanUp from WFPClose or WFMGetTraceLevel from WFPOpen.
+static REENTRY_RESULT: AtomicI32 = AtomicI32::new(WFS_SUCCESS);
+
+/// Timeout the last WFPExecute, WFPGetInfo or WFPLock was called with.
+static LAST_TIMEOUT: AtomicU32 = AtomicU32::new(0);
+
+lazy_static! {
+    static ref XFS_LIB: libloading::Library = unsafe { libloading::Library::new("msxfs.dll").unwrap() };
+    static ref WFM_ALLOCATE_BUFFER: Symbol<'static, unsafe extern "stdcall" fn(ULONG, ULONG, *mut LPVOID) -> HRESULT> = unsafe { XFS_LIB.get(b"WFMAllocateBuffer").unwrap() };
+    static ref WFM_ALLOCATE_MORE: Symbol<'static, unsafe extern "stdcall" fn(ULONG, LPVOID, *mut LPVOID) -> HRESULT> = unsafe { XFS_LIB.get(b"WFMAllocateMore").unwrap() };
+    static ref WFS_CLEAN_UP: Symbol<'static, unsafe extern "stdcall" fn() -> HRESULT> = unsafe { XFS_LIB.get(b"WFSCleanUp").unwrap() };
+    static ref WFM_GET_TRACE_LEVEL: Symbol<'static, unsafe extern "stdcall" fn(HSERVICE, *mut DWORD) -> HRESULT> = unsafe { XFS_LIB.get(b"WFMGetTraceLevel").unwrap() };
+
+    // holds the provider handles passed to WFPOpen by service
+    static ref PROVIDERS: Mutex<HashMap<HSERVICE, usize>> = Mutex::new(HashMap::new());
+
+    // holds the number of not ready reports by service
+    static ref NOT_READY: Mutex<HashMap<HSERVICE, u32>> = Mutex::new(HashMap::new());
+
+    // holds the services that already reported WFS_ERR_OUT_OF_MEMORY
+    static ref OUT_OF_MEMORY: Mutex<HashSet<HSERVICE>> = Mutex::new(HashSet::new());
+
+    // holds the services whose next WFPClose re-enters the manager
+    static ref REENTRANT: Mutex<HashSet<HSERVICE>> = Mutex::new(HashSet::new());
+
+    // holds the services whose next WFPClose fails
+    static ref FAILING_CLOSE: Mutex<HashSet<HSERVICE>> = Mutex::new(HashSet::new());
+
+    // holds the window last registered for SERVICE_EVENTS by service
+    static ref EVENT_WINDOWS: Mutex<HashMap<HSERVICE, usize>> = Mutex::new(HashMap::new());
+
+    // holds the event classes registered by service and window
+    static ref REGISTERED: Mutex<HashMap<(HSERVICE, usize), DWORD>> = Mutex::new(HashMap::new());
+}
+
+/// Allocates a successful WFSRESULT on the XFS heap and posts it to the window.
+unsafe fn complete(message: u32, service: HSERVICE, window: HWND, request_id: REQUESTID, command: DWORD, data: Option<&[u8]>) -> HRESULT {
+    complete_with(message, service, window, request_id, command, data, WFS_SUCCESS)
+}
+
+/// Allocates a WFSRESULT carrying `h_result` on the XFS heap and posts it to the window.
+unsafe fn complete_with(message: u32, service: HSERVICE, window: HWND, request_id: REQUESTID, command: DWORD, data: Option<&[u8]>, h_result: HRESULT) -> HRESULT {
+    let mut result: LPVOID = ptr::null_mut();
+    let hr = (WFM_ALLOCATE_BUFFER)(mem::size_of::<WFSRESULT>() as ULONG, WFS_MEM_ZEROINIT, &mut result);
+    if hr != WFS_SUCCESS {
+        return hr;
+    }
+
+    let mut buffer: LPVOID = ptr::null_mut();
+    if let Some(data) = data {
+        let hr = (WFM_ALLOCATE_MORE)(data.len() as ULONG, result, &mut buffer);
+        if hr != WFS_SUCCESS {
+            return hr;
+        }
+        ptr::copy_nonoverlapping(data.as_ptr(), buffer as *mut u8, data.len());
+    }
+
+    let mut timestamp = mem::zeroed();
+    GetSystemTime(&mut timestamp);
+
+    (result as LPWFSRESULT).write_unaligned(WFSRESULT {
+        RequestID: request_id,
+        hService: service,
+        tsTimestamp: timestamp,
+        hResult: h_result,
+        u: U { dwCommandCode: command },
+        lpBuffer: buffer,
+    });
+
+    *LAST_POSTED.lock().unwrap() = (window as usize, message, result as usize);
+    PostMessageA(window, message, 0, result as LPARAM);
+    WFS_SUCCESS
+}
+
+#[allow(non_snake_case)]
+#[no_mangle]
+pub extern "stdcall" fn WFPCancelAsyncRequest(_hService: HSERVICE, RequestID: REQUESTID) -> HRESULT {
+    CANCELLED.lock().unwrap().push(RequestID);
+    WFS_SUCCESS
+}
+
+#[allow(non_snake_case)]
+#[no_mangle]
+pub extern "stdcall" fn WFPClose(hService: HSERVICE, hWnd: HWND, ReqID: REQUESTID) -> HRESULT {
+    if REENTRANT.lock().unwrap().remove(&hService) {
+        REENTRY_RESULT.store(unsafe { (WFS_CLEAN_UP)() }, Ordering::SeqCst);
+    }
+    if FAILING_CLOSE.lock().unwrap().remove(&hService) {
+        return unsafe { complete_with(WFS_CLOSE_COMPLETE, hService, hWnd, ReqID, 0, None, WFS_ERR_HARDWARE_ERROR) };
+    }
+    REGISTERED.lock().unwrap().retain(|(service, _), _| *service != hService);
+    unsafe { complete(WFS_CLOSE_COMPLETE, hService, hWnd, ReqID, 0, None) }
+}
+
+#[allow(non_snake_case)]
+#[no_mangle]
+pub extern "stdcall" fn WFPDeregister(hService: HSERVICE, dwEventClass: DWORD, hWndReg: HWND, hWnd: HWND, ReqID: REQUESTID) -> HRESULT {
+    // no classes deregisters all of them, a null window all windows
+    let classes = match dwEventClass & !SYNC_EVENT_CLASS {
+        0 => !0,
+        classes => classes,
+    };
+    let mut registered = false;
+    REGISTERED.lock().unwrap().retain(|&(service, window), window_classes| {
+        if service == hService && (hWndReg.is_null() || window == hWndReg as usize) {
+            registered |= *window_classes & classes != 0;
+            *window_classes &= !classes;
+        }
+        *window_classes != 0
+    });
+
+    if dwEventClass & SYNC_EVENT_CLASS != 0 {
+        return WFS_SUCCESS;
+    }
+    let h_result = if registered { WFS_SUCCESS } else { WFS_ERR_INVALID_HWNDREG };
+    unsafe { complete_with(WFS_DEREGISTER_COMPLETE, hService, hWnd, ReqID, 0, None, h_result) }
+}
+
+#[allow(non_snake_case)]
+#[no_mangle]
+pub extern "stdcall" fn WFPExecute(hService: HSERVICE, dwCommand: DWORD, lpCmdData: LPVOID, dwTimeOut: DWORD, hWnd: HWND, ReqID: REQUESTID) -> HRESULT {
+    LAST_TIMEOUT.store(dwTimeOut, Ordering::SeqCst);
+    let in_flight = IN_FLIGHT.fetch_add(1, Ordering::SeqCst) + 1;
+    MAX_IN_FLIGHT.fetch_max(in_flight, Ordering::SeqCst);
+    let result = execute(hService, dwCommand, lpCmdData, hWnd, ReqID);
+    IN_FLIGHT.fetch_sub(1, Ordering::SeqCst);
+    result
+}
+
+/// Handles the command of a WFPExecute call.
+#[allow(non_snake_case)]
+fn execute(hService: HSERVICE, dwCommand: DWORD, lpCmdData: LPVOID, hWnd: HWND, ReqID: REQUESTID) -> HRESULT {
+    if dwCommand == HANG_COMMAND {
+        return WFS_SUCCESS;
+    }
+    if dwCommand == CRASH_COMMAND {
+        std::process::abort();
+    }
+    if dwCommand == STRAY_COMMAND {
+        let hr = unsafe { complete(WFS_EXECUTE_COMPLETE, hService, hWnd, ReqID.wrapping_add(1000), dwCommand, Some(STRAY_DATA)) };
+        if hr != WFS_SUCCESS {
+            return hr;
+        }
+        return unsafe { complete(WFS_EXECUTE_COMPLETE, hService, hWnd, ReqID, dwCommand, None) };
+    }
+    if dwCommand == FOREIGN_RESULT_COMMAND {
+        let mut result: WFSRESULT = unsafe { mem::zeroed() };
+        result.RequestID = ReqID;
+        result.hService = hService;
+        result.u.dwCommandCode = dwCommand;
+        result.lpBuffer = if lpCmdData.is_null() {
+            Box::into_raw(Box::new(0 as DWORD)) as LPVOID
+        } else {
+            let mut buffer: LPVOID = ptr::null_mut();
+            let hr = unsafe { (WFM_ALLOCATE_BUFFER)(mem::size_of::<DWORD>() as ULONG, WFS_MEM_ZEROINIT, &mut buffer) };
+            if hr != WFS_SUCCESS {
+                return hr;
+            }
+            unsafe { ptr::copy_nonoverlapping(lpCmdData as *const u8, buffer as *mut u8, mem::size_of::<DWORD>()) };
+            buffer
+        };
+        let result = Box::into_raw(Box::new(result));
+        *LAST_POSTED.lock().unwrap() = (hWnd as usize, WFS_EXECUTE_COMPLETE, result as usize);
+        unsafe { PostMessageA(hWnd, WFS_EXECUTE_COMPLETE, 0, result as LPARAM) };
+        return WFS_SUCCESS;
+    }
+    if dwCommand == REENTER_COMMAND {
+        REENTRANT.lock().unwrap().insert(hService);
+    }
+    if dwCommand == FAIL_CLOSE_COMMAND {
+        FAILING_CLOSE.lock().unwrap().insert(hService);
+    }
+    if dwCommand == BUSY_COMMAND {
+        BUSY_ORDER.lock().unwrap().push(ReqID);
+        thread::sleep(BUSY_TIME);
+    }
+    if dwCommand == DELAY_COMMAND {
+        let window = hWnd as usize;
+        thread::spawn(move || {
+            thread::sleep(DELAY);
+            unsafe { complete(WFS_EXECUTE_COMPLETE, hService, window as HWND, ReqID, dwCommand, None) };
+        });
+        return WFS_SUCCESS;
+    }
+    if lpCmdData.is_null() {
+        return unsafe { complete(WFS_EXECUTE_COMPLETE, hService, hWnd, ReqID, dwCommand, None) };
+    }
+    let data = unsafe { (lpCmdData as *const DWORD).read_unaligned() }.to_ne_bytes();
+    unsafe { complete(WFS_EXECUTE_COMPLETE, hService, hWnd, ReqID, dwCommand, Some(&data)) }
+}
+
+#[allow(non_snake_case)]
+#[no_mangle]
+pub extern "stdcall" fn WFPGetInfo(hService: HSERVICE, dwCategory: DWORD, _lpQueryDetails: LPVOID, dwTimeOut: DWORD, hWnd: HWND, ReqID: REQUESTID) -> HRESULT {
+    LAST_TIMEOUT.store(dwTimeOut, Ordering::SeqCst);
+    if dwCategory == NOT_READY_CATEGORY {
+        let mut not_ready = NOT_READY.lock().unwrap();
+        let count = not_ready.entry(hService).or_insert(0);
+        if *count < NOT_READY_COUNT {
+            *count += 1;
+            return unsafe { complete_with(WFS_GETINFO_COMPLETE, hService, hWnd, ReqID, dwCategory, None, WFS_ERR_DEV_NOT_READY) };
+        }
+    }
+    if dwCategory == OUT_OF_MEMORY_CATEGORY {
+        if OUT_OF_MEMORY.lock().unwrap().insert(hService) {
+            return unsafe { complete_with(WFS_GETINFO_COMPLETE, hService, hWnd, ReqID, dwCategory, None, WFS_ERR_OUT_OF_MEMORY) };
+        }
+        return unsafe { complete(WFS_GETINFO_COMPLETE, hService, hWnd, ReqID, dwCategory, Some(OUT_OF_MEMORY_DATA)) };
+    }
+    unsafe { complete(WFS_GETINFO_COMPLETE, hService, hWnd, ReqID, dwCategory, None) }
+}
+
+#[allow(non_snake_case)]
+#[no_mangle]
+pub extern "stdcall" fn WFPLock(hService: HSERVICE, dwTimeOut: DWORD, hWnd: HWND, ReqID: REQUESTID) -> HRESULT {
+    LAST_TIMEOUT.store(dwTimeOut, Ordering::SeqCst);
+    unsafe { complete(WFS_LOCK_COMPLETE, hService, hWnd, ReqID, 0, Some(LOCK_DATA)) }
+}
+
+#[allow(non_snake_case)]
+#[no_mangle]
+pub extern "stdcall" fn WFPOpen(
+    hService: HSERVICE,
+    _lpszLogicalName: LPSTR,
+    _hApp: HAPP,
+    lpszAppID: LPSTR,
+    _dwTraceLevel: DWORD,
+    _dwTimeOut: DWORD,
+    hWnd: HWND,
+    ReqID: REQUESTID,
+    hProvider: HPROVIDER,
+    dwSPIVersionsRequired: DWORD,
+    lpSPIVersion: LPWFSVERSION,
+    _dwSrvcVersionsRequired: DWORD,
+    lpSrvcVersion: LPWFSVERSION,
+) -> HRESULT {
+    let app_id = if lpszAppID.is_null() { &[][..] } else { unsafe { CStr::from_ptr(lpszAppID) }.to_bytes() };
+    let supported = if app_id == V2_APP_ID.as_bytes() {
+        VersionRange::new_explicit(Version::new_explicit(2, 0), Version::new_explicit(2, 30))
+    } else {
+        VersionRange::new_explicit(Version::new_explicit(3, 0), Version::new_explicit(3, 30))
+    };
+    let version = |version: Version| WFSVERSION {
+        w_version: version.value(),
+        w_low_version: supported.start.value(),
+        w_high_version: supported.end.value(),
+        sz_description: [0; WFSDDESCRIPTION_LEN + 1],
+        sz_system_status: [0; WFSDSYSSTATUS_LEN + 1],
+    };
+    let spi_version = match supported.intersect(&VersionRange::new(dwSPIVersionsRequired)) {
+        Some(range) => range.end,
+        None => return WFS_ERR_SPI_VER_TOO_HIGH,
+    };
+    PROVIDERS.lock().unwrap().insert(hService, hProvider as usize);
+    NOT_READY.lock().unwrap().remove(&hService);
+    unsafe {
+        lpSPIVersion.write_unaligned(version(spi_version));
+        lpSrvcVersion.write_unaligned(version(supported.start));
+        if app_id == FAIL_OPEN_APP_ID.as_bytes() {
+            return complete_with(WFS_OPEN_COMPLETE, hService, hWnd, ReqID, 0, None, WFS_ERR_HARDWARE_ERROR);
+        }
+        if app_id == HANG_OPEN_APP_ID.as_bytes() {
+            return WFS_SUCCESS;
+        }
+        if app_id == REENTER_OPEN_APP_ID.as_bytes() {
+            let mut trace_level = 0;
+            REENTRY_RESULT.store((WFM_GET_TRACE_LEVEL)(hService, &mut trace_level), Ordering::SeqCst);
+        }
+        complete(WFS_OPEN_COMPLETE, hService, hWnd, ReqID, 0, None)
+    }
+}
+
+#[allow(non_snake_case)]
+#[no_mangle]
+pub extern "stdcall" fn WFPRegister(hService: HSERVICE, dwEventClass: DWORD, hWndReg: HWND, hWnd: HWND, ReqID: REQUESTID) -> HRESULT {
+    let classes = dwEventClass & !SYNC_EVENT_CLASS;
+    let duplicate = {
+        let mut registered = REGISTERED.lock().unwrap();
+        let window_classes = registered.entry((hService, hWndReg as usize)).or_insert(0);
+        let duplicate = *window_classes & classes == classes;
+        *window_classes |= classes;
+        duplicate
+    };
+
+    if dwEventClass & SYNC_EVENT_CLASS != 0 {
+        return WFS_SUCCESS;
+    }
+    if duplicate {
+        return unsafe { complete_with(WFS_REGISTER_COMPLETE, hService, hWnd, ReqID, 0, None, WFS_ERR_INVALID_EVENT_CLASS) };
+    }
+    let hr = unsafe { complete(WFS_REGISTER_COMPLETE, hService, hWnd, ReqID, 0, None) };
+    if hr != WFS_SUCCESS || dwEventClass & SERVICE_EVENTS == 0 {
+        return hr;
+    }
+    EVENT_WINDOWS.lock().unwrap().insert(hService, hWndReg as usize);
+    unsafe { complete(WFS_SERVICE_EVENT, hService, hWndReg, 0, 0, None) }
+}
+
+#[allow(non_snake_case)]
+#[no_mangle]
+pub extern "stdcall" fn WFPSetTraceLevel(_hService: HSERVICE, _dwTraceLevel: DWORD) -> HRESULT {
+    WFS_SUCCESS
+}
+
+#[allow(non_snake_case)]
+#[no_mangle]
+pub extern "stdcall" fn WFPUnloadService() -> HRESULT {
+    WFS_SUCCESS
+}
+
+#[allow(non_snake_case)]
+#[no_mangle]
+pub extern "stdcall" fn WFPUnlock(hService: HSERVICE, hWnd: HWND, ReqID: REQUESTID) -> HRESULT {
+    unsafe { complete(WFS_UNLOCK_COMPLETE, hService, hWnd, ReqID, 0, None) }
+}
+
+/// Returns the hProvider the manager passed to WFPOpen for the service, or NULL.
+#[allow(non_snake_case)]
+#[no_mangle]
+pub extern "stdcall" fn MockGetProvider(hService: HSERVICE) -> HPROVIDER {
+    PROVIDERS.lock().unwrap().get(&hService).map_or(ptr::null_mut(), |provider| *provider as HPROVIDER)
+}
+
+/// Posts a WFS_SERVICE_EVENT with each of the `dwCount` event ids at `lpdwEventIDs` to the window last registered
+/// for SERVICE_EVENTS of the service, in order. Returns how many it posted.
+#[allow(non_snake_case)]
+#[no_mangle]
+pub extern "stdcall" fn MockPostServiceEvents(hService: HSERVICE, lpdwEventIDs: *const DWORD, dwCount: DWORD) -> DWORD {
+    let Some(window) = EVENT_WINDOWS.lock().unwrap().get(&hService).copied() else {
+        return 0;
+    };
+    let event_ids = unsafe { std::slice::from_raw_parts(lpdwEventIDs, dwCount as usize) };
+    event_ids
+        .iter()
+        .take_while(|&&event_id| unsafe { complete(WFS_SERVICE_EVENT, hService, window as HWND, 0, event_id, None) } == WFS_SUCCESS)
+        .count() as DWORD
+}
+
+/// Writes the window, message and lParam of the last completion the mock posted.
+#[allow(non_snake_case)]
+#[no_mangle]
+pub extern "stdcall" fn MockLastPosted(lpWindow: *mut HWND, lpMessage: *mut u32, lpResult: *mut LPWFSRESULT) {
+    let (window, message, result) = *LAST_POSTED.lock().unwrap();
+    unsafe {
+        lpWindow.write(window as HWND);
+        lpMessage.write(message);
+        lpResult.write(result as LPWFSRESULT);
+    }
+}
+
+/// Returns the timeout the last WFPExecute, WFPGetInfo or WFPLock was called with.
+#[allow(non_snake_case)]
+#[no_mangle]
+pub extern "stdcall" fn MockLastTimeout() -> DWORD {
+    LAST_TIMEOUT.load(Ordering::SeqCst)
+}
+
+/// Returns the result of the last call the mock made back into the manager.
+#[allow(non_snake_case)]
+#[no_mangle]
+pub extern "stdcall" fn MockReentryResult() -> HRESULT {
+    REENTRY_RESULT.load(Ordering::SeqCst)
+}
+
+/// Returns the highest number of overlapping WFPExecute calls and starts counting anew.
+#[allow(non_snake_case)]
+#[no_mangle]
+pub extern "stdcall" fn MockMaxConcurrency() -> DWORD {
+    MAX_IN_FLIGHT.swap(IN_FLIGHT.load(Ordering::SeqCst), Ordering::SeqCst)
+}
+
+/// Copies up to `dwCount` request ids of the busy commands executed so far to `lpRequestIDs`, returns how many
+/// it copied and starts recording anew.
+#[allow(non_snake_case)]
+#[no_mangle]
+pub extern "stdcall" fn MockBusyOrder(lpRequestIDs: *mut REQUESTID, dwCount: DWORD) -> DWORD {
+    let order = mem::take(&mut *BUSY_ORDER.lock().unwrap());
+    let count = order.len().min(dwCount as usize);
+    unsafe { ptr::copy_nonoverlapping(order.as_ptr(), lpRequestIDs, count) };
+    count as DWORD
+}
+
+/// Copies up to `dwCount` request ids WFPCancelAsyncRequest was called for to `lpRequestIDs`, returns how many it
+/// copied and starts recording anew.
+#[allow(non_snake_case)]
+#[no_mangle]
+pub extern "stdcall" fn MockCancelledRequests(lpRequestIDs: *mut REQUESTID, dwCount: DWORD) -> DWORD {
+    let cancelled = mem::take(&mut *CANCELLED.lock().unwrap());
+    let count = cancelled.len().min(dwCount as usize);
+    unsafe { ptr::copy_nonoverlapping(cancelled.as_ptr(), lpRequestIDs, count) };
+    count as DWORD
+}
@@ -1,18 +1,18 @@
-use std::ffi::CStr;
+use std::collections::VecDeque;
 use std::ptr;
 use std::sync::atomic::{AtomicPtr, AtomicUsize, Ordering};
 use std::sync::Arc;
 use std::{collections::HashMap, sync::Mutex};
 
 use lazy_static::lazy_static;
-use log::{error, trace};
+use log::{error, trace, warn};
 use log_derive::{logfn, logfn_inputs};
 use winapi::shared::basetsd::UINT_PTR;
 use winapi::shared::minwindef::UINT;
 use winapi::um::winuser::{KillTimer, PostMessageA, SetTimer};
 use winapi::{
     shared::{
-        minwindef::{DWORD, HINSTANCE, LPVOID, LPWORD, ULONG, WORD},
+        minwindef::{DWORD, HINSTANCE, LPDWORD, LPVOID, LPWORD, ULONG, WORD},
         windef::HWND,
         winerror::HRESULT,
     },
@@ -29,6 +29,13 @@ lazy_static! {
 
 const MAX_HEAP_SIZE: usize = 1 * 1000 * 1000 * 1000; // 1 GB
 
+/// Number of freed buffers the heap remembers, to tell a parent freed by another thread from a pointer that never was
+/// a buffer when WFMAllocateMore cannot find it.
+const RECENTLY_FREED_LEN: usize = 256;
+
+/// Longest trace data read from a provider, providers sometimes pass unterminated buffers.
+const MAX_TRACE_LEN: usize = 4096;
+
 struct Timer {
     hwnd: HWND,
     context: LPVOID,
@@ -37,25 +44,51 @@ struct Timer {
 struct Heap {
     allocations: HashMap<usize, Allocation>,
     total_bytes: Arc<AtomicUsize>,
+    // addresses of the buffers freed last, forgotten once an allocation reuses them
+    recently_freed: VecDeque<usize>,
+}
+
+/// Service and command a buffer was allocated for, so the bytes it holds can be attributed when it leaks.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct Tag {
+    service: HSERVICE,
+    command: DWORD,
+}
+
+impl Tag {
+    /// Whether the tag is the service's and the command's, a command of 0 matches all commands of the service.
+    fn matches(&self, service: HSERVICE, command: DWORD) -> bool {
+        self.service == service && (command == 0 || self.command == command)
+    }
 }
 
 struct Allocation {
     buffer: Vec<u8>,
     flags: ULONG,
+    // tag of the buffer from WFMAllocateBuffer, shared by the buffers attached to it
+    tag: Option<Tag>,
     child: Vec<Allocation>,
     heap: Arc<AtomicUsize>,
 }
 
 impl Allocation {
-    fn new(buffer: Vec<u8>, flags: ULONG, heap: Arc<AtomicUsize>) -> Self {
+    fn new(buffer: Vec<u8>, flags: ULONG, tag: Option<Tag>, heap: Arc<AtomicUsize>) -> Self {
         let child = Vec::with_capacity(0);
-        Self { buffer, flags, child, heap }
+        Self { buffer, flags, tag, child, heap }
+    }
+
+    /// Bytes held by the buffer and the buffers attached to it.
+    fn total_len(&self) -> usize {
+        self.buffer.len() + self.child.iter().map(|child| child.buffer.len()).sum::<usize>()
     }
 }
 
 impl Drop for Allocation {
     fn drop(&mut self) {
-        self.heap.fetch_sub(self.buffer.len(), Ordering::SeqCst);
+        // Saturate so broken accounting can never wrap around and fail every later allocation
+        let len = self.buffer.len();
+        let previous = self.heap.fetch_update(Ordering::SeqCst, Ordering::SeqCst, |value| Some(value.saturating_sub(len)));
+        debug_assert!(matches!(previous, Ok(value) if value >= len), "heap accounting underflow");
     }
 }
 
@@ -65,10 +98,15 @@ impl Heap {
     fn new() -> Self {
         let allocations = HashMap::new();
         let total_bytes = Arc::new(AtomicUsize::new(0));
-        Heap { allocations, total_bytes }
+        let recently_freed = VecDeque::with_capacity(RECENTLY_FREED_LEN);
+        Heap {
+            allocations,
+            total_bytes,
+            recently_freed,
+        }
     }
 
-    fn try_allocate(&mut self, size: usize, flags: ULONG) -> Result<Allocation, HRESULT> {
+    fn try_allocate(&mut self, size: usize, flags: ULONG, tag: Option<Tag>) -> Result<Allocation, HRESULT> {
         let new_size = self.total_bytes.fetch_update(Ordering::SeqCst, Ordering::SeqCst, |value| {
             value.checked_add(size).and_then(|new| if new > MAX_HEAP_SIZE { None } else { Some(new) })
         });
@@ -76,33 +114,144 @@ impl Heap {
             return Err(WFS_ERR_OUT_OF_MEMORY);
         }
         let buffer = vec![0; size];
-        let allocation = Allocation::new(buffer, flags, self.total_bytes.clone());
+        self.recently_freed.retain(|&freed| freed != buffer.as_ptr() as usize);
+        let allocation = Allocation::new(buffer, flags, tag, self.total_bytes.clone());
         Ok(allocation)
     }
 
     fn allocate_buffer(&mut self, size: usize, flags: ULONG) -> Result<LPVOID, HRESULT> {
-        let mut allocation = self.try_allocate(size, flags)?;
+        self.allocate_tagged(size, flags, None)
+    }
+
+    fn allocate_tagged(&mut self, size: usize, flags: ULONG, tag: Option<Tag>) -> Result<LPVOID, HRESULT> {
+        let mut allocation = self.try_allocate(size, flags, tag)?;
         let pointer = allocation.buffer.as_mut_ptr() as LPVOID;
         self.allocations.insert(pointer as usize, allocation);
         Ok(pointer)
     }
 
+    /// Attaches a buffer to the parent. The parent is looked up under the heap lock, so a parent freed concurrently is
+    /// either found with the child attached before the free, or not found at all.
     fn allocate_more(&mut self, size: usize, parent_buffer: LPVOID) -> Result<LPVOID, HRESULT> {
-        let flags = match self.allocations.get(&(parent_buffer as usize)) {
-            Some(allocation) => allocation.flags,
-            None => return Err(WFS_ERR_INVALID_BUFFER),
+        let (flags, tag) = match self.allocations.get(&(parent_buffer as usize)) {
+            Some(allocation) => (allocation.flags, allocation.tag),
+            None if self.recently_freed.contains(&(parent_buffer as usize)) => {
+                warn!("Parent buffer {parent_buffer:?} was freed before WFMAllocateMore got to it, likely by another thread");
+                return Err(WFS_ERR_INVALID_BUFFER);
+            }
+            None => {
+                error!("Parent buffer {parent_buffer:?} was not allocated with WFMAllocateBuffer");
+                return Err(WFS_ERR_INVALID_BUFFER);
+            }
         };
-        let mut allocation = self.try_allocate(size, flags)?;
+        let mut allocation = self.try_allocate(size, flags, tag)?;
         let pointer = allocation.buffer.as_mut_ptr() as LPVOID;
         self.allocations.get_mut(&(parent_buffer as usize)).unwrap().child.push(allocation);
         Ok(pointer)
     }
 
-    fn deallocate(&mut self, buffer: LPVOID) -> Result<(), HRESULT> {
-        if self.allocations.remove(&(buffer as usize)).is_none() {
-            return Err(WFS_ERR_INVALID_BUFFER);
+    /// Frees a buffer along with its children, returning how many children it had.
+    fn deallocate(&mut self, buffer: LPVOID) -> Result<usize, HRESULT> {
+        match self.allocations.remove(&(buffer as usize)) {
+            Some(allocation) => {
+                let children = allocation.child.len();
+                if self.recently_freed.len() == RECENTLY_FREED_LEN {
+                    self.recently_freed.pop_front();
+                }
+                self.recently_freed.push_back(buffer as usize);
+                self.release(allocation);
+                Ok(children)
+            }
+            None => {
+                if self.is_child(buffer) {
+                    error!("Attempt to free child buffer {buffer:?}; free the parent instead");
+                }
+                Err(WFS_ERR_INVALID_BUFFER)
+            }
+        }
+    }
+
+    /// Drops a buffer taken out of the map along with its children. Debug builds check that none of the children is
+    /// still known as a buffer of its own and that the accounted total shrinks by exactly the bytes released.
+    fn release(&self, allocation: Allocation) {
+        #[cfg(debug_assertions)]
+        let (before, released) = {
+            let orphaned = allocation.child.iter().any(|child| self.allocations.contains_key(&(child.buffer.as_ptr() as usize)));
+            debug_assert!(!orphaned, "child of freed buffer {:?} still referenced", allocation.buffer.as_ptr());
+            let released = allocation.total_len();
+            (self.total_bytes.load(Ordering::SeqCst), released)
+        };
+        drop(allocation);
+        #[cfg(debug_assertions)]
+        {
+            let after = self.total_bytes.load(Ordering::SeqCst);
+            debug_assert_eq!(before.checked_sub(after), Some(released), "heap accounting mismatch");
+        }
+    }
+
+    /// Number of buffers WFMAllocateMore attached to the buffer.
+    fn child_count(&self, buffer: LPVOID) -> Result<usize, HRESULT> {
+        self.allocations.get(&(buffer as usize)).map(|allocation| allocation.child.len()).ok_or(WFS_ERR_INVALID_BUFFER)
+    }
+
+    /// Size the buffer was allocated with, by WFMAllocateBuffer or WFMAllocateMore. None for a pointer that is not
+    /// the start of a live buffer.
+    fn buffer_len(&self, buffer: LPVOID) -> Option<usize> {
+        if let Some(allocation) = self.allocations.get(&(buffer as usize)) {
+            return Some(allocation.buffer.len());
+        }
+        self.allocations
+            .values()
+            .flat_map(|allocation| &allocation.child)
+            .find(|child| child.buffer.as_ptr() as LPVOID == buffer)
+            .map(|child| child.buffer.len())
+    }
+
+    /// Segments of the chain headed by the buffer, in allocation order. A buffer from WFMAllocateBuffer heads itself
+    /// and all its children, a child heads itself and the children attached after it. None for a pointer that is not
+    /// the start of a live buffer.
+    fn chain(&self, head: LPVOID) -> Option<Vec<&[u8]>> {
+        if let Some(allocation) = self.allocations.get(&(head as usize)) {
+            return Some(std::iter::once(allocation).chain(&allocation.child).map(|segment| &segment.buffer[..]).collect());
         }
-        Ok(())
+        self.allocations.values().find_map(|allocation| {
+            let first = allocation.child.iter().position(|child| child.buffer.as_ptr() as LPVOID == head)?;
+            Some(allocation.child[first..].iter().map(|segment| &segment.buffer[..]).collect())
+        })
+    }
+
+    /// Checks whether the buffer was attached to another one by WFMAllocateMore.
+    fn is_child(&self, buffer: LPVOID) -> bool {
+        self.allocations
+            .values()
+            .flat_map(|allocation| &allocation.child)
+            .any(|child| child.buffer.as_ptr() as LPVOID == buffer)
+    }
+
+    /// Number of live buffers allocated by WFMAllocateBuffer and the bytes held by them and their children.
+    fn stats(&self) -> (usize, usize) {
+        (self.allocations.len(), self.total_bytes.load(Ordering::SeqCst))
+    }
+
+    /// Number of live buffers allocated with the tag and the bytes held by them and their children. A command of 0
+    /// covers all commands of the service. Untagged buffers are never counted.
+    fn tagged_stats(&self, service: HSERVICE, command: DWORD) -> (usize, usize) {
+        self.allocations
+            .values()
+            .filter(|allocation| allocation.tag.is_some_and(|tag| tag.matches(service, command)))
+            .fold((0, 0), |(buffers, bytes), allocation| (buffers + 1, bytes + allocation.total_len()))
+    }
+
+    /// Sum of the sizes of all live buffers, including the ones attached by WFMAllocateMore.
+    #[cfg(test)]
+    fn live_bytes(&self) -> usize {
+        self.allocations.values().map(Allocation::total_len).sum()
+    }
+
+    /// Checks that the accounted total matches the live buffers.
+    #[cfg(test)]
+    fn is_consistent(&self) -> bool {
+        self.total_bytes.load(Ordering::SeqCst) == self.live_bytes()
     }
 }
 
@@ -111,16 +260,45 @@ impl Heap {
 #[logfn(TRACE)]
 #[logfn_inputs(TRACE)]
 pub extern "stdcall" fn WFMAllocateBuffer(ulSize: ULONG, ulFlags: ULONG, lppvData: *mut LPVOID) -> HRESULT {
-    if lppvData.is_null() {
-        xfs_reject!(WFS_ERR_INVALID_POINTER);
-    }
-    let mut heap = xfs_unwrap!(HEAP.lock());
-    let buffer = match heap.allocate_buffer(ulSize as usize, ulFlags) {
-        Ok(buffer) => buffer,
-        Err(error) => return error,
-    };
-    unsafe { lppvData.write(buffer) };
-    WFS_SUCCESS
+    catch_panic(|| {
+        if lppvData.is_null() {
+            xfs_reject!(WFS_ERR_INVALID_POINTER);
+        }
+        let mut heap = xfs_unwrap!(HEAP.lock());
+        let buffer = match heap.allocate_buffer(ulSize as usize, ulFlags) {
+            Ok(buffer) => buffer,
+            Err(error) => return error,
+        };
+        unsafe { lppvData.write(buffer) };
+        WFS_SUCCESS
+    })
+}
+
+/// Allocates a buffer like WFMAllocateBuffer, tagged with the service and command it is allocated for. The buffers
+/// WFMAllocateMore attaches to it carry the same tag, WFMGetTaggedHeapStats reports what each tag still holds.
+/// Buffers from WFMAllocateBuffer are not tagged and cost nothing extra.
+///
+/// This is a manager extension, not part of the XFS API.
+#[allow(non_snake_case)]
+#[no_mangle]
+#[logfn(TRACE)]
+#[logfn_inputs(TRACE)]
+pub extern "stdcall" fn WFMAllocateTaggedBuffer(ulSize: ULONG, ulFlags: ULONG, hService: HSERVICE, dwCommand: DWORD, lppvData: *mut LPVOID) -> HRESULT {
+    catch_panic(|| {
+        if lppvData.is_null() {
+            xfs_reject!(WFS_ERR_INVALID_POINTER);
+        }
+        let tag = Tag {
+            service: hService,
+            command: dwCommand,
+        };
+        let buffer = match xfs_unwrap!(HEAP.lock()).allocate_tagged(ulSize as usize, ulFlags, Some(tag)) {
+            Ok(buffer) => buffer,
+            Err(error) => return error,
+        };
+        unsafe { lppvData.write(buffer) };
+        WFS_SUCCESS
+    })
 }
 
 #[allow(non_snake_case)]
@@ -128,16 +306,18 @@ pub extern "stdcall" fn WFMAllocateBuffer(ulSize: ULONG, ulFlags: ULONG, lppvDat
 #[logfn(TRACE)]
 #[logfn_inputs(TRACE)]
 pub extern "stdcall" fn WFMAllocateMore(ulSize: ULONG, lpvOriginal: LPVOID, lppvData: *mut LPVOID) -> HRESULT {
-    if lppvData.is_null() || lpvOriginal.is_null() {
-        xfs_reject!(WFS_ERR_INVALID_POINTER);
-    }
-    let mut heap = xfs_unwrap!(HEAP.lock());
-    let buffer = match heap.allocate_more(ulSize as usize, lpvOriginal) {
-        Ok(buffer) => buffer,
-        Err(error) => return error,
-    };
-    unsafe { lppvData.write(buffer) };
-    WFS_SUCCESS
+    catch_panic(|| {
+        if lppvData.is_null() || lpvOriginal.is_null() {
+            xfs_reject!(WFS_ERR_INVALID_POINTER);
+        }
+        let mut heap = xfs_unwrap!(HEAP.lock());
+        let buffer = match heap.allocate_more(ulSize as usize, lpvOriginal) {
+            Ok(buffer) => buffer,
+            Err(error) => return error,
+        };
+        unsafe { lppvData.write(buffer) };
+        WFS_SUCCESS
+    })
 }
 
 #[allow(non_snake_case)]
@@ -145,15 +325,189 @@ pub extern "stdcall" fn WFMAllocateMore(ulSize: ULONG, lpvOriginal: LPVOID, lppv
 #[logfn(TRACE)]
 #[logfn_inputs(TRACE)]
 pub extern "stdcall" fn WFMFreeBuffer(lpvData: LPVOID) -> HRESULT {
-    if lpvData.is_null() {
-        xfs_reject!(WFS_ERR_INVALID_POINTER);
-    }
-    let mut heap = xfs_unwrap!(HEAP.lock());
+    catch_panic(|| {
+        if lpvData.is_null() {
+            xfs_reject!(WFS_ERR_INVALID_POINTER);
+        }
+        let mut heap = xfs_unwrap!(HEAP.lock());
 
-    match heap.deallocate(lpvData) {
-        Ok(_) => WFS_SUCCESS,
-        Err(error) => error,
-    }
+        match heap.deallocate(lpvData) {
+            Ok(_) => WFS_SUCCESS,
+            Err(error) => error,
+        }
+    })
+}
+
+/// Frees a buffer like WFMFreeBuffer and reports the number of buffers WFMAllocateMore attached to it, which are
+/// freed along with it. Lets leak audits confirm a result went away with everything the provider chained to it.
+///
+/// This is a manager extension, not part of the XFS API.
+#[allow(non_snake_case)]
+#[no_mangle]
+#[logfn(TRACE)]
+#[logfn_inputs(TRACE)]
+pub extern "stdcall" fn WFMFreeBufferDeep(lpvData: LPVOID, lpdwChildren: LPDWORD) -> HRESULT {
+    catch_panic(|| {
+        if lpvData.is_null() || lpdwChildren.is_null() {
+            xfs_reject!(WFS_ERR_INVALID_POINTER);
+        }
+        let children = match xfs_unwrap!(HEAP.lock()).deallocate(lpvData) {
+            Ok(children) => children,
+            Err(error) => return error,
+        };
+        // SAFETY: the pointer is checked for null
+        unsafe { lpdwChildren.write(children as DWORD) };
+        WFS_SUCCESS
+    })
+}
+
+/// Reports the number of buffers WFMAllocateMore attached to a buffer allocated by WFMAllocateBuffer.
+///
+/// This is a manager extension, not part of the XFS API.
+#[allow(non_snake_case)]
+#[no_mangle]
+#[logfn(TRACE)]
+#[logfn_inputs(TRACE)]
+pub extern "stdcall" fn WFMGetChildCount(lpvData: LPVOID, lpdwChildren: LPDWORD) -> HRESULT {
+    catch_panic(|| {
+        if lpvData.is_null() || lpdwChildren.is_null() {
+            xfs_reject!(WFS_ERR_INVALID_POINTER);
+        }
+        let children = match xfs_unwrap!(HEAP.lock()).child_count(lpvData) {
+            Ok(children) => children,
+            Err(error) => return error,
+        };
+        // SAFETY: the pointer is checked for null
+        unsafe { lpdwChildren.write(children as DWORD) };
+        WFS_SUCCESS
+    })
+}
+
+/// Reports the size a buffer was allocated with, by WFMAllocateBuffer or WFMAllocateMore, so a reader of a result's
+/// lpBuffer can bound its access without knowing the layout of the command. Pointers into a buffer other than its
+/// start are rejected with WFS_ERR_INVALID_BUFFER like unknown ones.
+///
+/// This is a manager extension, not part of the XFS API.
+#[allow(non_snake_case)]
+#[no_mangle]
+#[logfn(TRACE)]
+#[logfn_inputs(TRACE)]
+pub extern "stdcall" fn WFMGetBufferLength(lpvData: LPVOID, lpulLength: *mut ULONG) -> HRESULT {
+    catch_panic(|| {
+        if lpvData.is_null() || lpulLength.is_null() {
+            xfs_reject!(WFS_ERR_INVALID_POINTER);
+        }
+        let length = match xfs_unwrap!(HEAP.lock()).buffer_len(lpvData) {
+            Some(length) => length,
+            None => xfs_reject!(WFS_ERR_INVALID_BUFFER),
+        };
+        // SAFETY: the pointer is checked for null
+        unsafe { lpulLength.write(length as ULONG) };
+        WFS_SUCCESS
+    })
+}
+
+/// Copies the chain of buffers headed by lpvData into one new buffer, so data a provider returned in
+/// WFMAllocateMore segments, like a captured image, can be read contiguously. A buffer from WFMAllocateBuffer heads
+/// itself and all its children, a child heads itself and the children attached after it. The copy is allocated like
+/// by WFMAllocateBuffer and freed with WFMFreeBuffer, the chain itself is left alone.
+///
+/// This is a manager extension, not part of the XFS API.
+#[allow(non_snake_case)]
+#[no_mangle]
+#[logfn(TRACE)]
+#[logfn_inputs(TRACE)]
+pub extern "stdcall" fn WFMGetChainedBuffer(lpvData: LPVOID, lppvData: *mut LPVOID, lpulLength: *mut ULONG) -> HRESULT {
+    catch_panic(|| {
+        if lpvData.is_null() || lppvData.is_null() || lpulLength.is_null() {
+            xfs_reject!(WFS_ERR_INVALID_POINTER);
+        }
+        let mut heap = xfs_unwrap!(HEAP.lock());
+        let data = match heap.chain(lpvData) {
+            Some(segments) => segments.concat(),
+            None => xfs_reject!(WFS_ERR_INVALID_BUFFER),
+        };
+        let buffer = match heap.allocate_buffer(data.len(), WFS_MEM_ZEROINIT) {
+            Ok(buffer) => buffer,
+            Err(error) => return error,
+        };
+        // SAFETY: the buffer was just allocated with the length of the data and the out pointers are checked for null
+        unsafe {
+            ptr::copy_nonoverlapping(data.as_ptr(), buffer as *mut u8, data.len());
+            lppvData.write(buffer);
+            lpulLength.write(data.len() as ULONG);
+        }
+        WFS_SUCCESS
+    })
+}
+
+/// Reports the number of live buffers allocated by WFMAllocateBuffer and the bytes they hold, including the
+/// buffers attached by WFMAllocateMore. Lets tests and diagnostics spot results nobody freed.
+///
+/// This is a manager extension, not part of the XFS API.
+#[allow(non_snake_case)]
+#[no_mangle]
+#[logfn(TRACE)]
+#[logfn_inputs(TRACE)]
+pub extern "stdcall" fn WFMGetHeapStats(lpdwBuffers: LPDWORD, lpdwBytes: LPDWORD) -> HRESULT {
+    catch_panic(|| {
+        if lpdwBuffers.is_null() || lpdwBytes.is_null() {
+            xfs_reject!(WFS_ERR_INVALID_POINTER);
+        }
+
+        let (buffers, bytes) = xfs_unwrap!(HEAP.lock()).stats();
+        // SAFETY: both pointers are checked for null
+        unsafe {
+            lpdwBuffers.write(buffers as DWORD);
+            lpdwBytes.write(bytes as DWORD);
+        }
+        WFS_SUCCESS
+    })
+}
+
+/// Reports the number of live buffers WFMAllocateTaggedBuffer tagged with the service and command, and the bytes they
+/// hold including the buffers attached by WFMAllocateMore. A command of 0 covers all commands of the service, so
+/// operators can tell which service is holding on to the heap.
+///
+/// This is a manager extension, not part of the XFS API.
+#[allow(non_snake_case)]
+#[no_mangle]
+#[logfn(TRACE)]
+#[logfn_inputs(TRACE)]
+pub extern "stdcall" fn WFMGetTaggedHeapStats(hService: HSERVICE, dwCommand: DWORD, lpdwBuffers: LPDWORD, lpdwBytes: LPDWORD) -> HRESULT {
+    catch_panic(|| {
+        if lpdwBuffers.is_null() || lpdwBytes.is_null() {
+            xfs_reject!(WFS_ERR_INVALID_POINTER);
+        }
+
+        let (buffers, bytes) = xfs_unwrap!(HEAP.lock()).tagged_stats(hService, dwCommand);
+        // SAFETY: both pointers are checked for null
+        unsafe {
+            lpdwBuffers.write(buffers as DWORD);
+            lpdwBytes.write(bytes as DWORD);
+        }
+        WFS_SUCCESS
+    })
+}
+
+/// Reports the number of timers set with WFMSetTimer that have neither fired nor been killed yet.
+///
+/// This is a manager extension, not part of the XFS API.
+#[allow(non_snake_case)]
+#[no_mangle]
+#[logfn(TRACE)]
+#[logfn_inputs(TRACE)]
+pub extern "stdcall" fn WFMGetTimerCount(lpdwTimers: LPDWORD) -> HRESULT {
+    catch_panic(|| {
+        if lpdwTimers.is_null() {
+            xfs_reject!(WFS_ERR_INVALID_POINTER);
+        }
+
+        let timers = TIMERS.iter().filter(|timer| !timer.load(Ordering::SeqCst).is_null()).count();
+        // SAFETY: the pointer is checked for null
+        unsafe { lpdwTimers.write(timers as DWORD) };
+        WFS_SUCCESS
+    })
 }
 
 #[allow(non_snake_case)]
@@ -161,21 +515,27 @@ pub extern "stdcall" fn WFMFreeBuffer(lpvData: LPVOID) -> HRESULT {
 #[logfn(TRACE)]
 #[logfn_inputs(TRACE)]
 pub extern "stdcall" fn WFMKillTimer(wTimerID: WORD) -> HRESULT {
-    if wTimerID == 0 {
-        xfs_reject!(WFS_ERR_INVALID_TIMER);
-    }
+    catch_panic(|| {
+        if wTimerID == 0 || !kill_timer(wTimerID as usize) {
+            xfs_reject!(WFS_ERR_INVALID_TIMER);
+        }
 
-    let timer = TIMERS[wTimerID as usize - 1].swap(ptr::null_mut(), Ordering::SeqCst);
+        WFS_SUCCESS
+    })
+}
+
+/// Kills the timer with the id if it is still set, returns false if it is not.
+fn kill_timer(timer_id: usize) -> bool {
+    let timer = TIMERS[timer_id - 1].swap(ptr::null_mut(), Ordering::SeqCst);
     if timer.is_null() {
-        xfs_reject!(WFS_ERR_INVALID_TIMER);
+        return false;
     }
 
     // SAFETY: we checked that timer is not null and we know it's not dropped yet since we are using atomic swap
     let timer = unsafe { Box::from_raw(timer) };
     // SAFETY: all parameters are valid
-    unsafe { KillTimer(timer.hwnd, wTimerID as usize) };
-
-    WFS_SUCCESS
+    unsafe { KillTimer(timer.hwnd, timer_id) };
+    true
 }
 
 #[allow(non_snake_case)]
@@ -183,8 +543,25 @@ pub extern "stdcall" fn WFMKillTimer(wTimerID: WORD) -> HRESULT {
 #[logfn(TRACE)]
 #[logfn_inputs(TRACE)]
 pub extern "stdcall" fn WFMOutputTraceData(lpszData: LPSTR) -> HRESULT {
-    trace!("XFS TRACE --- {}", xfs_unwrap!(unsafe { CStr::from_ptr(lpszData).to_str() }));
-    WFS_SUCCESS
+    catch_panic(|| {
+        if lpszData.is_null() {
+            xfs_reject!(WFS_ERR_INVALID_POINTER);
+        }
+
+        // SAFETY: the pointer is not null and at most MAX_TRACE_LEN bytes are read
+        let (data, terminated) = unsafe { bounded_str(lpszData as *const u8, MAX_TRACE_LEN) };
+        if !terminated {
+            warn!("Trace data not terminated within {MAX_TRACE_LEN} bytes, truncated");
+        }
+        trace!("XFS TRACE --- {}", String::from_utf8_lossy(data));
+        WFS_SUCCESS
+    })
+}
+
+/// Returns the bytes up to the null terminator, scanning at most `max_len` bytes, and whether a terminator was found.
+unsafe fn bounded_str<'a>(data: *const u8, max_len: usize) -> (&'a [u8], bool) {
+    let len = (0..max_len).find(|&i| *data.add(i) == 0);
+    (std::slice::from_raw_parts(data, len.unwrap_or(max_len)), len.is_some())
 }
 
 #[allow(non_snake_case)]
@@ -192,47 +569,49 @@ pub extern "stdcall" fn WFMOutputTraceData(lpszData: LPSTR) -> HRESULT {
 #[logfn(TRACE)]
 #[logfn_inputs(TRACE)]
 pub extern "stdcall" fn WFMSetTimer(hWnd: HWND, lpContext: LPVOID, dwTimeVal: DWORD, lpwTimerID: LPWORD) -> HRESULT {
-    if hWnd.is_null() {
-        xfs_reject!(WFS_ERR_INVALID_HWND);
-    }
-    if lpwTimerID.is_null() {
-        xfs_reject!(WFS_ERR_INVALID_POINTER);
-    }
-    if dwTimeVal == 0 {
-        xfs_reject!(WFS_ERR_INVALID_DATA);
-    }
+    catch_panic(|| {
+        if hWnd.is_null() {
+            xfs_reject!(WFS_ERR_INVALID_HWND);
+        }
+        if lpwTimerID.is_null() {
+            xfs_reject!(WFS_ERR_INVALID_POINTER);
+        }
+        if dwTimeVal == 0 {
+            xfs_reject!(WFS_ERR_INVALID_DATA);
+        }
 
-    let timer = Timer { hwnd: hWnd, context: lpContext };
-    let timer_ptr = Box::into_raw(Box::new(timer));
+        let timer = Timer { hwnd: hWnd, context: lpContext };
+        let timer_ptr = Box::into_raw(Box::new(timer));
 
-    let timer_id = match TIMERS.iter().position(|p| p.compare_exchange(ptr::null_mut(), timer_ptr, Ordering::SeqCst, Ordering::SeqCst).is_ok()) {
-        Some(index) => index + 1,
-        None => {
-            // SAFETY: the timer was allocated and not dropped yet
-            let _ = unsafe { Box::from_raw(timer_ptr) };
-            xfs_reject!(WFS_ERR_INTERNAL_ERROR)
-        }
-    };
+        let timer_id = match TIMERS.iter().position(|p| p.compare_exchange(ptr::null_mut(), timer_ptr, Ordering::SeqCst, Ordering::SeqCst).is_ok()) {
+            Some(index) => index + 1,
+            None => {
+                // SAFETY: the timer was allocated and not dropped yet
+                let _ = unsafe { Box::from_raw(timer_ptr) };
+                xfs_reject!(WFS_ERR_INTERNAL_ERROR)
+            }
+        };
 
-    unsafe {
-        if SetTimer(hWnd, timer_id, dwTimeVal, Some(timer_proc)) == 0 {
-            TIMERS[timer_id as usize - 1].store(ptr::null_mut(), Ordering::SeqCst);
-            let _ = Box::from_raw(timer_ptr);
-            xfs_reject!(WFS_ERR_INTERNAL_ERROR);
+        unsafe {
+            if SetTimer(hWnd, timer_id, dwTimeVal, Some(timer_proc)) == 0 {
+                TIMERS[timer_id as usize - 1].store(ptr::null_mut(), Ordering::SeqCst);
+                let _ = Box::from_raw(timer_ptr);
+                xfs_reject!(WFS_ERR_INTERNAL_ERROR);
+            }
+            *lpwTimerID = timer_id as u16;
         }
-        *lpwTimerID = timer_id as u16;
-    }
 
-    unsafe extern "system" fn timer_proc(hwnd: HWND, _msg: UINT, id_event: UINT_PTR, _elapsed: DWORD) {
-        let ptr = TIMERS[id_event as usize - 1].swap(ptr::null_mut(), Ordering::SeqCst);
+        unsafe extern "system" fn timer_proc(hwnd: HWND, _msg: UINT, id_event: UINT_PTR, _elapsed: DWORD) {
+            let ptr = TIMERS[id_event as usize - 1].swap(ptr::null_mut(), Ordering::SeqCst);
 
-        if !ptr.is_null() {
-            let timer = Box::from_raw(ptr);
-            PostMessageA(hwnd, WFS_TIMER_EVENT, id_event, timer.context as _);
+            if !ptr.is_null() {
+                let timer = Box::from_raw(ptr);
+                PostMessageA(hwnd, WFS_TIMER_EVENT, id_event, timer.context as _);
+            }
         }
-    }
 
-    WFS_SUCCESS
+        WFS_SUCCESS
+    })
 }
 
 #[allow(non_snake_case)]
@@ -240,7 +619,26 @@ pub extern "stdcall" fn WFMSetTimer(hWnd: HWND, lpContext: LPVOID, dwTimeVal: DW
 #[logfn(TRACE)]
 #[logfn_inputs(TRACE)]
 pub extern "stdcall" fn WFMSetTraceLevel(_hService: HSERVICE, _dwTraceLevel: DWORD) -> HRESULT {
-    WFS_SUCCESS
+    catch_panic(|| WFS_SUCCESS)
+}
+
+/// Kills the timers still set, so none of them posts WFS_TIMER_EVENT once the application shut down. The manager calls
+/// it once from WFSCleanUp, before it considers itself shut down. Buffers are left alone, the application may still
+/// hold results it has not freed.
+///
+/// This is a manager extension, not part of the XFS API.
+#[allow(non_snake_case)]
+#[no_mangle]
+#[logfn(TRACE)]
+#[logfn_inputs(TRACE)]
+pub extern "stdcall" fn CleanUp() -> HRESULT {
+    catch_panic(|| {
+        let killed = (1..=TIMERS.len()).filter(|&timer_id| kill_timer(timer_id)).count();
+        if killed > 0 {
+            warn!("Killed {killed} timers still set at clean up");
+        }
+        WFS_SUCCESS
+    })
 }
 
 #[allow(non_snake_case)]
@@ -252,10 +650,42 @@ pub extern "stdcall" fn DllMain(hinst_dll: HINSTANCE, fdw_reason: DWORD, _: LPVO
 
 #[cfg(test)]
 mod tests {
-    use std::time::Instant;
+    use std::{mem, sync::Barrier, thread, time::Instant};
+
+    use log::{Level, LevelFilter, Log, Metadata, Record};
 
     use super::*;
 
+    /// Keeps the formatted warnings and errors so tests can check the diagnostics.
+    struct CaptureLogger(Mutex<Vec<String>>);
+
+    impl Log for CaptureLogger {
+        fn enabled(&self, metadata: &Metadata) -> bool {
+            metadata.level() <= Level::Warn
+        }
+
+        fn log(&self, record: &Record) {
+            if self.enabled(record.metadata()) {
+                self.0.lock().unwrap().push(record.args().to_string());
+            }
+        }
+
+        fn flush(&self) {}
+    }
+
+    static LOGGER: CaptureLogger = CaptureLogger(Mutex::new(Vec::new()));
+
+    fn captured_logs() -> &'static Mutex<Vec<String>> {
+        // Only the first test installs it, all of them share it
+        if log::set_logger(&LOGGER).is_ok() {
+            log::set_max_level(LevelFilter::Warn);
+        }
+        &LOGGER.0
+    }
+
+    // serializes the timer tests, the exhaustion test fills every slot of the shared table
+    static TIMER_LOCK: Mutex<()> = Mutex::new(());
+
     #[test]
     fn test_allocate() {
         for _ in 0..100000 {
@@ -274,6 +704,268 @@ mod tests {
         assert_eq!(HEAP.lock().unwrap().total_bytes.load(Ordering::SeqCst), 0);
     }
 
+    #[test]
+    fn test_heap_accounting() {
+        let mut heap = Heap::new();
+        let mut buffers = Vec::new();
+
+        for round in 0..1000 {
+            for size in 0..16 {
+                let parent = heap.allocate_buffer(1 + size * round % 4096, WFS_MEM_ZEROINIT).unwrap();
+                heap.allocate_more(size + 1, parent).unwrap();
+                buffers.push(parent);
+            }
+            assert!(heap.is_consistent());
+
+            // free the older half, the rest is freed in a later round
+            let keep = buffers.split_off(buffers.len() / 2);
+            for buffer in buffers {
+                heap.deallocate(buffer).unwrap();
+            }
+            buffers = keep;
+            assert!(heap.is_consistent());
+        }
+
+        for buffer in buffers {
+            heap.deallocate(buffer).unwrap();
+        }
+        assert!(heap.is_consistent());
+        assert_eq!(heap.total_bytes.load(Ordering::SeqCst), 0);
+    }
+
+    #[test]
+    fn test_free_child_buffer() {
+        let logs = captured_logs();
+        let mut parent = ptr::null_mut();
+        let mut child = ptr::null_mut();
+
+        assert_eq!(WFMAllocateBuffer(10, WFS_MEM_ZEROINIT, &mut parent), WFS_SUCCESS);
+        assert_eq!(WFMAllocateMore(10, parent, &mut child), WFS_SUCCESS);
+        assert_eq!(WFMFreeBuffer(child), WFS_ERR_INVALID_BUFFER);
+
+        let message = format!("Attempt to free child buffer {child:?}; free the parent instead");
+        assert!(logs.lock().unwrap().iter().any(|line| *line == message));
+        assert_eq!(WFMFreeBuffer(parent), WFS_SUCCESS);
+    }
+
+    #[test]
+    fn test_allocate_more_freed_parent() {
+        let logs = captured_logs();
+
+        // a heap of its own, so no other test reuses the freed addresses in between
+        let mut heap = Heap::new();
+        let parent = heap.allocate_buffer(10, WFS_MEM_ZEROINIT).unwrap();
+        heap.deallocate(parent).unwrap();
+        assert_eq!(heap.allocate_more(10, parent), Err(WFS_ERR_INVALID_BUFFER));
+        let message = format!("Parent buffer {parent:?} was freed before WFMAllocateMore got to it, likely by another thread");
+        assert!(logs.lock().unwrap().iter().any(|line| *line == message));
+
+        let stranger = 8 as LPVOID;
+        assert_eq!(heap.allocate_more(10, stranger), Err(WFS_ERR_INVALID_BUFFER));
+        let message = format!("Parent buffer {stranger:?} was not allocated with WFMAllocateBuffer");
+        assert!(logs.lock().unwrap().iter().any(|line| *line == message));
+
+        // one thread frees the parent while the other attaches to it, the child goes either with the parent or nowhere
+        let heap = Mutex::new(heap);
+        let barrier = Barrier::new(2);
+        let (mut attached, mut rejected) = (0, 0);
+        thread::scope(|scope| {
+            let (heap, barrier) = (&heap, &barrier);
+            for _ in 0..1000 {
+                let parent = heap.lock().unwrap().allocate_buffer(10, WFS_MEM_ZEROINIT).unwrap() as usize;
+                let allocate_more = scope.spawn(move || {
+                    barrier.wait();
+                    heap.lock().unwrap().allocate_more(10, parent as LPVOID)
+                });
+                barrier.wait();
+                assert!(heap.lock().unwrap().deallocate(parent as LPVOID).is_ok());
+                match allocate_more.join().unwrap() {
+                    Ok(_) => attached += 1,
+                    Err(error) => {
+                        assert_eq!(error, WFS_ERR_INVALID_BUFFER);
+                        rejected += 1;
+                    }
+                }
+            }
+        });
+        assert_eq!(attached + rejected, 1000);
+        let heap = heap.into_inner().unwrap();
+        assert_eq!(heap.stats(), (0, 0));
+        assert!(heap.is_consistent());
+    }
+
+    #[test]
+    fn test_heap_stats() {
+        let (mut buffers, mut bytes) = (0, 0);
+        assert_eq!(WFMGetHeapStats(ptr::null_mut(), &mut bytes), WFS_ERR_INVALID_POINTER);
+        assert_eq!(WFMGetHeapStats(&mut buffers, &mut bytes), WFS_SUCCESS);
+
+        let mut heap = Heap::new();
+        let parent = heap.allocate_buffer(10, WFS_MEM_ZEROINIT).unwrap();
+        heap.allocate_more(20, parent).unwrap();
+        heap.allocate_buffer(5, WFS_MEM_ZEROINIT).unwrap();
+        assert_eq!(heap.stats(), (2, 35));
+
+        heap.deallocate(parent).unwrap();
+        assert_eq!(heap.stats(), (1, 5));
+    }
+
+    #[test]
+    fn test_tagged_heap_stats() {
+        let (mut buffers, mut bytes) = (0, 0);
+        assert_eq!(WFMGetTaggedHeapStats(5, 0, ptr::null_mut(), &mut bytes), WFS_ERR_INVALID_POINTER);
+        assert_eq!(WFMAllocateTaggedBuffer(10, WFS_MEM_ZEROINIT, 5, 302, ptr::null_mut()), WFS_ERR_INVALID_POINTER);
+        assert_eq!(WFMGetTaggedHeapStats(5, 0, &mut buffers, &mut bytes), WFS_SUCCESS);
+
+        let mut heap = Heap::new();
+        let tag = |service, command| Some(Tag { service, command });
+        let execute = heap.allocate_tagged(100, WFS_MEM_ZEROINIT, tag(5, 302)).unwrap();
+        heap.allocate_more(50, execute).unwrap();
+        let info = heap.allocate_tagged(20, WFS_MEM_ZEROINIT, tag(5, 201)).unwrap();
+        heap.allocate_tagged(7, WFS_MEM_ZEROINIT, tag(6, 302)).unwrap();
+        heap.allocate_buffer(1000, WFS_MEM_ZEROINIT).unwrap();
+
+        // attached buffers count to the tag of their parent, untagged buffers to none
+        assert_eq!(heap.tagged_stats(5, 302), (1, 150));
+        assert_eq!(heap.tagged_stats(5, 0), (2, 170));
+        assert_eq!(heap.tagged_stats(6, 0), (1, 7));
+        assert_eq!(heap.tagged_stats(7, 0), (0, 0));
+        assert_eq!(heap.stats(), (4, 1177));
+
+        heap.deallocate(execute).unwrap();
+        assert_eq!(heap.tagged_stats(5, 302), (0, 0));
+        heap.deallocate(info).unwrap();
+        assert_eq!(heap.tagged_stats(5, 0), (0, 0));
+        assert!(heap.is_consistent());
+    }
+
+    #[test]
+    fn test_free_deep() {
+        let mut heap = Heap::new();
+        let parent = heap.allocate_buffer(16, WFS_MEM_ZEROINIT).unwrap();
+        let children: Vec<_> = (1..=3).map(|size| heap.allocate_more(size, parent).unwrap()).collect();
+        assert_eq!(heap.child_count(parent), Ok(3));
+        assert_eq!(heap.child_count(children[0]), Err(WFS_ERR_INVALID_BUFFER));
+        assert_eq!(heap.stats(), (1, 22));
+
+        assert_eq!(heap.deallocate(parent), Ok(3));
+        assert_eq!(heap.stats(), (0, 0));
+        assert_eq!(heap.child_count(parent), Err(WFS_ERR_INVALID_BUFFER));
+
+        // the exports on the shared heap
+        let mut parent = ptr::null_mut();
+        let mut child = ptr::null_mut();
+        let mut count = 0;
+        assert_eq!(WFMAllocateBuffer(8, WFS_MEM_ZEROINIT, &mut parent), WFS_SUCCESS);
+        assert_eq!(WFMGetChildCount(parent, &mut count), WFS_SUCCESS);
+        assert_eq!(count, 0);
+        for _ in 0..2 {
+            assert_eq!(WFMAllocateMore(8, parent, &mut child), WFS_SUCCESS);
+        }
+        assert_eq!(WFMGetChildCount(parent, &mut count), WFS_SUCCESS);
+        assert_eq!(count, 2);
+        assert_eq!(WFMGetChildCount(parent, ptr::null_mut()), WFS_ERR_INVALID_POINTER);
+        assert_eq!(WFMFreeBufferDeep(child, &mut count), WFS_ERR_INVALID_BUFFER);
+        assert_eq!(WFMFreeBufferDeep(parent, &mut count), WFS_SUCCESS);
+        assert_eq!(count, 2);
+        assert_eq!(WFMFreeBufferDeep(parent, &mut count), WFS_ERR_INVALID_BUFFER);
+    }
+
+    #[test]
+    fn test_buffer_len() {
+        let mut heap = Heap::new();
+        let parent = heap.allocate_buffer(24, WFS_MEM_ZEROINIT).unwrap();
+        let child = heap.allocate_more(7, parent).unwrap();
+        assert_eq!(heap.buffer_len(parent), Some(24));
+        assert_eq!(heap.buffer_len(child), Some(7));
+        assert_eq!(heap.buffer_len(unsafe { (parent as *mut u8).add(1) } as LPVOID), None);
+
+        heap.deallocate(parent).unwrap();
+        assert_eq!(heap.buffer_len(parent), None);
+        assert_eq!(heap.buffer_len(child), None);
+
+        // the export on the shared heap
+        let mut buffer = ptr::null_mut();
+        let mut length = 0;
+        assert_eq!(WFMAllocateBuffer(40, WFS_MEM_ZEROINIT, &mut buffer), WFS_SUCCESS);
+        assert_eq!(WFMGetBufferLength(buffer, &mut length), WFS_SUCCESS);
+        assert_eq!(length, 40);
+        assert_eq!(WFMGetBufferLength(buffer, ptr::null_mut()), WFS_ERR_INVALID_POINTER);
+        assert_eq!(WFMFreeBuffer(buffer), WFS_SUCCESS);
+        assert_eq!(WFMGetBufferLength(buffer, &mut length), WFS_ERR_INVALID_BUFFER);
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    fn test_free_invariant() {
+        let mut heap = Heap::new();
+        let kept = heap.allocate_buffer(3, WFS_MEM_ZEROINIT).unwrap();
+        let parent = heap.allocate_buffer(16, WFS_MEM_ZEROINIT).unwrap();
+        heap.allocate_more(8, parent).unwrap();
+        heap.allocate_more(4, parent).unwrap();
+        assert_eq!(heap.stats(), (2, 31));
+
+        // the release checks run on every free, a mismatch panics
+        assert_eq!(heap.deallocate(parent), Ok(2));
+        assert_eq!(heap.stats(), (1, 3));
+        assert!(heap.is_consistent());
+        assert_eq!(heap.deallocate(kept), Ok(0));
+        assert_eq!(heap.stats(), (0, 0));
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "still referenced")]
+    fn test_free_invariant_child_referenced() {
+        let mut heap = Heap::new();
+        let parent = heap.allocate_buffer(16, WFS_MEM_ZEROINIT).unwrap();
+        let child = heap.allocate_more(8, parent).unwrap();
+        // a bookkeeping bug listing the child as a buffer of its own
+        let alias = heap.try_allocate(1, WFS_MEM_ZEROINIT).unwrap();
+        heap.allocations.insert(child as usize, alias);
+        let _ = heap.deallocate(parent);
+    }
+
+    #[test]
+    fn test_chained_buffer() {
+        let mut heap = Heap::new();
+        let parent = heap.allocate_buffer(2, WFS_MEM_ZEROINIT).unwrap();
+        let segments: Vec<LPVOID> = [b"image", b" data", b" tail"]
+            .iter()
+            .map(|bytes| {
+                let segment = heap.allocate_more(bytes.len(), parent).unwrap();
+                unsafe { ptr::copy_nonoverlapping(bytes.as_ptr(), segment as *mut u8, bytes.len()) };
+                segment
+            })
+            .collect();
+        assert_eq!(heap.chain(segments[0]).unwrap().concat(), b"image data tail");
+        assert_eq!(heap.chain(segments[1]).unwrap().concat(), b" data tail");
+        assert_eq!(heap.chain(parent).unwrap().concat(), b"\0\0image data tail");
+        assert!(heap.chain(unsafe { (parent as *mut u8).add(1) } as LPVOID).is_none());
+
+        // the export on the shared heap
+        let mut result = ptr::null_mut();
+        assert_eq!(WFMAllocateBuffer(mem::size_of::<WFSRESULT>() as ULONG, WFS_MEM_ZEROINIT, &mut result), WFS_SUCCESS);
+        let mut head = ptr::null_mut();
+        for bytes in [&b"statement "[..], b"line 1, ", b"line 2"] {
+            let mut segment = ptr::null_mut();
+            assert_eq!(WFMAllocateMore(bytes.len() as ULONG, result, &mut segment), WFS_SUCCESS);
+            unsafe { ptr::copy_nonoverlapping(bytes.as_ptr(), segment as *mut u8, bytes.len()) };
+            if head.is_null() {
+                head = segment;
+            }
+        }
+        unsafe { (*(result as LPWFSRESULT)).lpBuffer = head };
+
+        let (mut data, mut length) = (ptr::null_mut(), 0);
+        assert_eq!(WFMGetChainedBuffer(unsafe { (*(result as LPWFSRESULT)).lpBuffer }, &mut data, &mut length), WFS_SUCCESS);
+        assert_eq!(unsafe { std::slice::from_raw_parts(data as *const u8, length as usize) }, b"statement line 1, line 2");
+        assert_eq!(WFMGetChainedBuffer(head, ptr::null_mut(), &mut length), WFS_ERR_INVALID_POINTER);
+        assert_eq!(WFMFreeBuffer(data), WFS_SUCCESS);
+        assert_eq!(WFMFreeBuffer(result), WFS_SUCCESS);
+        assert_eq!(WFMGetChainedBuffer(head, &mut data, &mut length), WFS_ERR_INVALID_BUFFER);
+    }
+
     #[test]
     fn test_allocate_fail() {
         assert_eq!(WFMAllocateBuffer(20, WFS_MEM_ZEROINIT, ptr::null_mut()), WFS_ERR_INVALID_POINTER);
@@ -291,8 +983,27 @@ mod tests {
         assert_eq!(WFMAllocateMore(10, 1 as *mut _, &mut ptr::null_mut()), WFS_ERR_INVALID_BUFFER);
     }
 
+    #[test]
+    fn test_output_trace_data() {
+        let data = b"provider trace\0";
+        assert_eq!(WFMOutputTraceData(data.as_ptr() as LPSTR), WFS_SUCCESS);
+        assert_eq!(unsafe { bounded_str(data.as_ptr(), MAX_TRACE_LEN) }, (&b"provider trace"[..], true));
+        assert_eq!(WFMOutputTraceData(ptr::null_mut()), WFS_ERR_INVALID_POINTER);
+    }
+
+    #[test]
+    fn test_output_trace_data_unterminated() {
+        let data = vec![b'A'; MAX_TRACE_LEN + 100];
+        assert_eq!(WFMOutputTraceData(data.as_ptr() as LPSTR), WFS_SUCCESS);
+
+        let (prefix, terminated) = unsafe { bounded_str(data.as_ptr(), MAX_TRACE_LEN) };
+        assert!(!terminated);
+        assert_eq!(prefix.len(), MAX_TRACE_LEN);
+    }
+
     #[test]
     fn test_timer() {
+        let _lock = TIMER_LOCK.lock().unwrap_or_else(|e| e.into_inner());
         let window = SyncWindow::new(WFS_TIMER_EVENT);
         let mut value = 100;
         let mut timer_id = 0;
@@ -300,15 +1011,43 @@ mod tests {
         assert_eq!(result, WFS_SUCCESS);
         assert_ne!(timer_id, 0);
 
+        let mut timers = 0;
+        assert_eq!(WFMGetTimerCount(&mut timers), WFS_SUCCESS);
+        assert_eq!(timers, 1);
+
         let result = WFMKillTimer(timer_id);
         assert_eq!(result, WFS_SUCCESS);
 
         let result = WFMKillTimer(timer_id);
         assert_eq!(result, WFS_ERR_INVALID_TIMER);
+
+        assert_eq!(WFMGetTimerCount(&mut timers), WFS_SUCCESS);
+        assert_eq!(timers, 0);
+        assert_eq!(WFMGetTimerCount(ptr::null_mut()), WFS_ERR_INVALID_POINTER);
+    }
+
+    #[test]
+    fn test_clean_up() {
+        let _lock = TIMER_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        let window = SyncWindow::new(WFS_TIMER_EVENT);
+        let mut timer_id = 0;
+        assert_eq!(WFMSetTimer(window.handle(), ptr::null_mut(), 60_000, &mut timer_id), WFS_SUCCESS);
+
+        assert_eq!(CleanUp(), WFS_SUCCESS);
+        let mut timers = 0;
+        assert_eq!(WFMGetTimerCount(&mut timers), WFS_SUCCESS);
+        assert_eq!(timers, 0);
+        assert_eq!(WFMKillTimer(timer_id), WFS_ERR_INVALID_TIMER);
+
+        // with nothing left to kill a second clean up changes nothing
+        assert_eq!(CleanUp(), WFS_SUCCESS);
+        assert_eq!(WFMGetTimerCount(&mut timers), WFS_SUCCESS);
+        assert_eq!(timers, 0);
     }
 
     #[test]
     fn test_timer_tick() {
+        let _lock = TIMER_LOCK.lock().unwrap_or_else(|e| e.into_inner());
         let window = SyncWindow::new(WFS_TIMER_EVENT);
         let mut value = 100;
         let mut timer_id = 0;
@@ -331,4 +1070,41 @@ mod tests {
         let result = WFMKillTimer(timer_id);
         assert_eq!(result, WFS_ERR_INVALID_TIMER, "Timer must be automatically deallocated");
     }
+
+    #[test]
+    fn test_timer_exhaustion() {
+        let _lock = TIMER_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        let window = SyncWindow::new(WFS_TIMER_EVENT);
+        assert_eq!(TIMERS.len(), WORD::MAX as usize, "timer ids must stay within 1..=WORD::MAX");
+
+        // occupy every slot directly, 65535 OS timers would run into the per process USER object quota
+        for slot in TIMERS.iter() {
+            let timer = Box::into_raw(Box::new(Timer {
+                hwnd: window.handle(),
+                context: ptr::null_mut(),
+            }));
+            assert!(slot.compare_exchange(ptr::null_mut(), timer, Ordering::SeqCst, Ordering::SeqCst).is_ok());
+        }
+
+        let mut timer_id = 0;
+        let result = WFMSetTimer(window.handle(), ptr::null_mut(), 60_000, &mut timer_id);
+        assert_eq!(result, WFS_ERR_INTERNAL_ERROR);
+        assert_eq!(timer_id, 0);
+
+        for freed in [1, 12345, WORD::MAX] {
+            assert_eq!(WFMKillTimer(freed), WFS_SUCCESS);
+
+            let result = WFMSetTimer(window.handle(), ptr::null_mut(), 60_000, &mut timer_id);
+            assert_eq!(result, WFS_SUCCESS);
+            assert_eq!(timer_id, freed, "the freed slot must be reused");
+
+            let result = WFMSetTimer(window.handle(), ptr::null_mut(), 60_000, &mut 0);
+            assert_eq!(result, WFS_ERR_INTERNAL_ERROR);
+        }
+
+        for id in 1..=WORD::MAX {
+            assert_eq!(WFMKillTimer(id), WFS_SUCCESS);
+        }
+        assert!(TIMERS.iter().all(|slot| slot.load(Ordering::SeqCst).is_null()));
+    }
 }
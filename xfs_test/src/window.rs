@@ -23,12 +23,12 @@ use winapi::{
 struct Message {
     message: u32,
     // w_param: u32,
-    l_param: u32,
+    l_param: usize,
 }
 
 pub struct SyncWindow {
     hwnd: HWND,
-    receiver: Receiver<u32>,
+    receiver: Receiver<usize>,
 }
 
 struct HwndResult {
@@ -100,7 +100,7 @@ impl SyncWindow {
         }
     }
 
-    pub fn try_receive(&self) -> Result<Option<u32>, Box<dyn std::error::Error>> {
+    pub fn try_receive(&self) -> Result<Option<usize>, Box<dyn std::error::Error>> {
         match self.receiver.try_recv() {
             Ok(message) => Ok(Some(message)),
             Err(std::sync::mpsc::TryRecvError::Empty) => Ok(None),
@@ -131,7 +131,8 @@ extern "system" fn wndproc(window: HWND, message: u32, wparam: WPARAM, lparam: L
                     return 0;
                 }
                 let sender_ptr = (*createstruct).lpCreateParams;
-                SetWindowLongPtrA(window, GWLP_USERDATA, sender_ptr as i32);
+                // LONG on 32-bit and LONG_PTR on 64-bit targets, the pointer must not be truncated to 32 bits
+                SetWindowLongPtrA(window, GWLP_USERDATA, sender_ptr as _);
                 return 1;
             }
             WM_NCDESTROY => DefWindowProcA(window, message, wparam, lparam),
@@ -155,7 +156,7 @@ extern "system" fn wndproc(window: HWND, message: u32, wparam: WPARAM, lparam: L
                     .send(Message {
                         message,
                         // w_param: wparam as u32,
-                        l_param: lparam as u32,
+                        l_param: lparam as usize,
                     })
                     .unwrap();
                 1
@@ -1,5 +1,5 @@
 use winapi::{
-    shared::minwindef::{HKEY, UINT},
+    shared::minwindef::{DWORD, HKEY, UINT},
     um::winuser::WM_USER,
 };
 
@@ -18,6 +18,50 @@ pub const WFS_CFG_HKEY_USER_DEFAULT_XFS_ROOT: HKEY = 3 as HKEY;
 pub const WFS_CFG_CREATED_NEW_KEY: u32 = 0;
 pub const WFS_CFG_OPENED_EXISTING_KEY: u32 = 1;
 
+/******* Values of dwTimeOut *************************************************/
+/// The request waits until it completes. XFS.H defines it as 0, not 0xFFFFFFFF like the Win32 INFINITE, so every
+/// other value, the largest one included, is a timeout in milliseconds.
+pub const WFS_INDEFINITE_WAIT: DWORD = 0;
+
+/******* Values of dwEventClass **********************************************/
+pub const SERVICE_EVENTS: DWORD = 1;
+pub const USER_EVENTS: DWORD = 2;
+pub const SYSTEM_EVENTS: DWORD = 4;
+pub const EXECUTE_EVENTS: DWORD = 8;
+
+/******* Values of dwTraceLevel **********************************************/
+pub const WFS_TRACE_API: DWORD = 0x00000001;
+pub const WFS_TRACE_ALL_API: DWORD = 0x00000002;
+pub const WFS_TRACE_SPI: DWORD = 0x00000004;
+pub const WFS_TRACE_ALL_SPI: DWORD = 0x00000008;
+pub const WFS_TRACE_MGR: DWORD = 0x00000010;
+
+/******* Manager information categories *************************************/
+
+/* WFSGetInfo categories answered by the manager itself when hService is 0, outside every device class range */
+
+/// lpBuffer of the result is a NULL terminated array of LPSTR with the logical service names.
+pub const WFS_INF_MGR_LOGICAL_SERVICES: DWORD = 0xF001;
+
+/// lpBuffer of the result points to a [`WFSMGRSTATISTICS`](crate::WFSMGRSTATISTICS) snapshot.
+pub const WFS_INF_MGR_STATISTICS: DWORD = 0xF002;
+
+/// lpBuffer of the result is a NULL terminated array of pointers to [`WFSMGRINFLIGHT`](crate::WFSMGRINFLIGHT), one
+/// per request the providers have not completed yet, by service and request id.
+pub const WFS_INF_MGR_IN_FLIGHT: DWORD = 0xF003;
+
+/// lpBuffer of the result is a NULL terminated array of pointers to [`WFSMGRTRACELEVEL`](crate::WFSMGRTRACELEVEL), one
+/// per open service, by service handle.
+pub const WFS_INF_MGR_TRACE_LEVELS: DWORD = 0xF004;
+
+/// lpBuffer of the result is a NULL terminated array of pointers to [`WFSMGRLATENCY`](crate::WFSMGRLATENCY), one
+/// per service and command completed while the manager records latencies, by service, message and command.
+pub const WFS_INF_MGR_LATENCIES: DWORD = 0xF005;
+
+/// lpBuffer of the result points to the [`WFSMGRLASTERROR`](crate::WFSMGRLASTERROR) of the calling thread, or is NULL
+/// if the last call the thread made before this one succeeded.
+pub const WFS_INF_MGR_LAST_ERROR: DWORD = 0xF006;
+
 /****** Messages ********************************************************/
 
 /* Message-No = (WM_USER + No) */
@@ -37,3 +81,15 @@ pub const WFS_USER_EVENT: UINT = WM_USER + 22;
 pub const WFS_SYSTEM_EVENT: UINT = WM_USER + 23;
 
 pub const WFS_TIMER_EVENT: UINT = WM_USER + 100;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_indefinite_wait() {
+        // XFS.H: #define WFS_INDEFINITE_WAIT 0
+        assert_eq!(WFS_INDEFINITE_WAIT, 0);
+        assert_ne!(WFS_INDEFINITE_WAIT, winapi::um::winbase::INFINITE);
+    }
+}
@@ -0,0 +1,177 @@
+use std::{iter, mem, ptr};
+
+use lazy_static::lazy_static;
+use libloading::Symbol;
+use winapi::shared::{
+    minwindef::{LPVOID, ULONG},
+    winerror::HRESULT,
+};
+
+use crate::{WFSRESULT, WFS_ERR_OUT_OF_MEMORY, WFS_MEM_ZEROINIT, WFS_SUCCESS};
+
+lazy_static! {
+    static ref XFS_LIB: libloading::Library = unsafe { libloading::Library::new("xfs_supp.dll").unwrap() };
+    static ref WFM_ALLOCATE_BUFFER: Symbol<'static, unsafe extern "stdcall" fn(ULONG, ULONG, *mut LPVOID) -> HRESULT> = unsafe { XFS_LIB.get(b"WFMAllocateBuffer").unwrap() };
+}
+
+/// Allocates a buffer for `value` on the XFS heap and copies the value into it, e.g. to pass a command structure
+/// as lpCmdData. Returns null if the allocation fails. The buffer is released with WFMFreeBuffer.
+pub fn into_xfs_buffer<T: Copy>(value: T) -> LPVOID {
+    let mut buffer: LPVOID = ptr::null_mut();
+    // SAFETY: the allocation is at least as large as the value written into it
+    unsafe {
+        if (WFM_ALLOCATE_BUFFER)(mem::size_of::<T>() as ULONG, WFS_MEM_ZEROINIT, &mut buffer) != WFS_SUCCESS || buffer.is_null() {
+            return ptr::null_mut();
+        }
+        (buffer as *mut T).write_unaligned(value);
+    }
+    buffer
+}
+
+/// Reads the `lpBuffer` of a result as a `T`.
+///
+/// # Safety
+/// `result` must point to a WFSRESULT whose `lpBuffer` points to at least `size_of::<T>()` readable bytes
+/// holding a valid `T`. Neither needs to be aligned.
+pub unsafe fn from_xfs_result<T: Copy>(result: *const WFSRESULT) -> T {
+    let buffer = ptr::addr_of!((*result).lpBuffer).read_unaligned();
+    (buffer as *const T).read_unaligned()
+}
+
+/// Walks a null terminated array of pointers, as returned in `lpBuffer` by many WFSGetInfo categories,
+/// and yields every pointer up to the terminator. A null `base` yields nothing.
+///
+/// # Safety
+/// `base` must be null or point to a readable array of pointers ending with a null pointer. The array does not
+/// need to be aligned, as it usually lives inside a packed structure on the XFS heap.
+pub unsafe fn ptr_array(base: LPVOID) -> impl Iterator<Item = LPVOID> {
+    let mut next = base as *const LPVOID;
+    iter::from_fn(move || {
+        if next.is_null() {
+            return None;
+        }
+        let item = next.read_unaligned();
+        if item.is_null() {
+            next = ptr::null();
+            return None;
+        }
+        next = next.add(1);
+        Some(item)
+    })
+}
+
+/// Runs the two-call pattern of info queries whose provider reports WFS_ERR_OUT_OF_MEMORY when the result outgrows
+/// the size it was told to expect. `query` is called with a size hint in bytes, starting at `hint` and doubled after
+/// every WFS_ERR_OUT_OF_MEMORY, until it returns anything else or `attempts` calls were made. Providers that take no
+/// hint are simply asked again, which is often enough once the buffers of the failed attempt are freed.
+pub fn query_with_size_hint<T>(hint: usize, attempts: u32, mut query: impl FnMut(usize) -> Result<T, HRESULT>) -> Result<T, HRESULT> {
+    let mut hint = hint.max(1);
+    for _ in 1..attempts {
+        match query(hint) {
+            Err(WFS_ERR_OUT_OF_MEMORY) => hint = hint.saturating_mul(2),
+            result => return result,
+        }
+    }
+    query(hint)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::U;
+
+    lazy_static! {
+        static ref WFM_ALLOCATE_MORE: Symbol<'static, unsafe extern "stdcall" fn(ULONG, LPVOID, *mut LPVOID) -> HRESULT> = unsafe { XFS_LIB.get(b"WFMAllocateMore").unwrap() };
+        static ref WFM_FREE_BUFFER: Symbol<'static, unsafe extern "stdcall" fn(LPVOID) -> HRESULT> = unsafe { XFS_LIB.get(b"WFMFreeBuffer").unwrap() };
+    }
+
+    #[test]
+    fn test_ptr_array() {
+        const UNITS: usize = 3;
+
+        unsafe {
+            let mut array: LPVOID = ptr::null_mut();
+            assert_eq!((WFM_ALLOCATE_BUFFER)(((UNITS + 1) * mem::size_of::<LPVOID>()) as ULONG, WFS_MEM_ZEROINIT, &mut array), WFS_SUCCESS);
+
+            let mut expected = Vec::new();
+            for index in 0..UNITS {
+                let mut unit: LPVOID = ptr::null_mut();
+                assert_eq!((WFM_ALLOCATE_MORE)(mem::size_of::<u32>() as ULONG, array, &mut unit), WFS_SUCCESS);
+                (array as *mut LPVOID).add(index).write_unaligned(unit);
+                expected.push(unit);
+            }
+
+            assert_eq!(ptr_array(array).collect::<Vec<_>>(), expected);
+            assert_eq!(ptr_array(ptr::null_mut()).count(), 0);
+
+            // Stops at the first null, whatever follows it
+            (array as *mut LPVOID).add(1).write_unaligned(ptr::null_mut());
+            assert_eq!(ptr_array(array).collect::<Vec<_>>(), &expected[..1]);
+
+            assert_eq!((WFM_FREE_BUFFER)(array), WFS_SUCCESS);
+        }
+    }
+
+    #[test]
+    fn test_xfs_buffer_round_trip() {
+        #[repr(C, packed)]
+        #[derive(Clone, Copy, PartialEq, Debug)]
+        struct Command {
+            mix_number: u16,
+            amount: u32,
+            currency: [u8; 3],
+        }
+
+        let command = Command {
+            mix_number: 1,
+            amount: 12345,
+            currency: *b"EUR",
+        };
+        let buffer = into_xfs_buffer(command);
+        assert!(!buffer.is_null());
+
+        unsafe {
+            let result = WFSRESULT {
+                RequestID: 1,
+                hService: 1,
+                tsTimestamp: mem::zeroed(),
+                hResult: WFS_SUCCESS,
+                u: U { dwCommandCode: 302 },
+                lpBuffer: buffer,
+            };
+            assert_eq!(from_xfs_result::<Command>(&result), command);
+            assert_eq!(from_xfs_result::<u16>(&result), 1);
+            assert_eq!((WFM_FREE_BUFFER)(buffer), WFS_SUCCESS);
+        }
+    }
+
+    #[test]
+    fn test_query_with_size_hint() {
+        const FULL_RESULT: &[u8] = b"FULL RESULT WITH ALL THE CASH UNITS";
+
+        // a provider that fails to allocate the result until the hint covers it
+        let provider = |hints: &mut Vec<usize>, hint: usize| {
+            hints.push(hint);
+            if hint < FULL_RESULT.len() {
+                return Err(WFS_ERR_OUT_OF_MEMORY);
+            }
+            Ok(FULL_RESULT.to_vec())
+        };
+        let mut hints = Vec::new();
+        assert_eq!(query_with_size_hint(8, 4, |hint| provider(&mut hints, hint)), Ok(FULL_RESULT.to_vec()));
+        assert_eq!(hints, [8, 16, 32, 64]);
+
+        // the last error is passed on once the attempts are used up
+        let mut hints = Vec::new();
+        assert_eq!(query_with_size_hint(8, 2, |hint| provider(&mut hints, hint)), Err(WFS_ERR_OUT_OF_MEMORY));
+        assert_eq!(hints, [8, 16]);
+
+        // other errors are not retried
+        let mut calls = 0;
+        let result: Result<(), HRESULT> = query_with_size_hint(8, 4, |_| {
+            calls += 1;
+            Err(crate::WFS_ERR_HARDWARE_ERROR)
+        });
+        assert_eq!((result, calls), (Err(crate::WFS_ERR_HARDWARE_ERROR), 1));
+    }
+}
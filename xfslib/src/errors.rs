@@ -13,26 +13,26 @@ pub const WFS_ERR_CFG_KEY_NOT_EMPTY: HRESULT = -9;
 pub const WFS_ERR_CFG_NAME_TOO_LONG: HRESULT = -10;
 pub const WFS_ERR_CFG_NO_MORE_ITEMS: HRESULT = -11;
 pub const WFS_ERR_CFG_VALUE_TOO_LONG: HRESULT = -12;
-// pub const WFS_ERR_DEV_NOT_READY: HRESULT = -13;
-// pub const WFS_ERR_HARDWARE_ERROR: HRESULT = -14;
+pub const WFS_ERR_DEV_NOT_READY: HRESULT = -13;
+pub const WFS_ERR_HARDWARE_ERROR: HRESULT = -14;
 pub const WFS_ERR_INTERNAL_ERROR: HRESULT = -15;
 // pub const WFS_ERR_INVALID_ADDRESS: HRESULT = -16;
 pub const WFS_ERR_INVALID_APP_HANDLE: HRESULT = -17;
 pub const WFS_ERR_INVALID_BUFFER: HRESULT = -18;
-// pub const WFS_ERR_INVALID_CATEGORY: HRESULT = -19;
-// pub const WFS_ERR_INVALID_COMMAND: HRESULT = -20;
-// pub const WFS_ERR_INVALID_EVENT_CLASS: HRESULT = -21;
+pub const WFS_ERR_INVALID_CATEGORY: HRESULT = -19;
+pub const WFS_ERR_INVALID_COMMAND: HRESULT = -20;
+pub const WFS_ERR_INVALID_EVENT_CLASS: HRESULT = -21;
 pub const WFS_ERR_INVALID_HSERVICE: HRESULT = -22;
-// pub const WFS_ERR_INVALID_HPROVIDER: HRESULT = -23;
+pub const WFS_ERR_INVALID_HPROVIDER: HRESULT = -23;
 pub const WFS_ERR_INVALID_HWND: HRESULT = -24;
-// pub const WFS_ERR_INVALID_HWNDREG: HRESULT = -25;
+pub const WFS_ERR_INVALID_HWNDREG: HRESULT = -25;
 pub const WFS_ERR_INVALID_POINTER: HRESULT = -26;
 // pub const WFS_ERR_INVALID_REQ_ID: HRESULT = -27;
 // pub const WFS_ERR_INVALID_RESULT: HRESULT = -28;
 pub const WFS_ERR_INVALID_SERVPROV: HRESULT = -29;
 pub const WFS_ERR_INVALID_TIMER: HRESULT = -30;
 // pub const WFS_ERR_INVALID_TRACELEVEL: HRESULT = -31;
-// pub const WFS_ERR_LOCKED: HRESULT = -32;
+pub const WFS_ERR_LOCKED: HRESULT = -32;
 // pub const WFS_ERR_NO_BLOCKING_CALL: HRESULT = -33;
 // pub const WFS_ERR_NO_SERVPROV: HRESULT = -34;
 // pub const WFS_ERR_NO_SUCH_THREAD: HRESULT = -35;
@@ -44,18 +44,45 @@ pub const WFS_ERR_NOT_STARTED: HRESULT = -39;
 pub const WFS_ERR_OP_IN_PROGRESS: HRESULT = -41;
 pub const WFS_ERR_OUT_OF_MEMORY: HRESULT = -42;
 // pub const WFS_ERR_SERVICE_NOT_FOUND: HRESULT = -43;
-// pub const WFS_ERR_SPI_VER_TOO_HIGH: HRESULT = -44;
-// pub const WFS_ERR_SPI_VER_TOO_LOW: HRESULT = -45;
+pub const WFS_ERR_SPI_VER_TOO_HIGH: HRESULT = -44;
+pub const WFS_ERR_SPI_VER_TOO_LOW: HRESULT = -45;
 // pub const WFS_ERR_SRVC_VER_TOO_HIGH: HRESULT = -46;
 // pub const WFS_ERR_SRVC_VER_TOO_LOW: HRESULT = -47;
-// pub const WFS_ERR_TIMEOUT: HRESULT = -48;
+pub const WFS_ERR_TIMEOUT: HRESULT = -48;
 // pub const WFS_ERR_UNSUPP_CATEGORY: HRESULT = -49;
-// pub const WFS_ERR_UNSUPP_COMMAND: HRESULT = -50;
+pub const WFS_ERR_UNSUPP_COMMAND: HRESULT = -50;
 // pub const WFS_ERR_VERSION_ERROR_IN_SRVC: HRESULT = -51;
 pub const WFS_ERR_INVALID_DATA: HRESULT = -52;
 // pub const WFS_ERR_SOFTWARE_ERROR: HRESULT = -53;
 // pub const WFS_ERR_CONNECTION_LOST: HRESULT = -54;
 // pub const WFS_ERR_USER_ERROR: HRESULT = -55;
-// pub const WFS_ERR_UNSUPP_DATA: HRESULT = -56;
+pub const WFS_ERR_UNSUPP_DATA: HRESULT = -56;
 // pub const WFS_ERR_FRAUD_ATTEMPT: HRESULT = -57;
 // pub const WFS_ERR_SEQUENCE_ERROR: HRESULT = -58;
+
+/// Converts an XFS HRESULT into a Rust result, so `?` can be used instead of comparing against WFS_SUCCESS.
+pub trait HResultExt {
+    fn ok(self) -> Result<(), HRESULT>;
+}
+
+impl HResultExt for HRESULT {
+    fn ok(self) -> Result<(), HRESULT> {
+        match self {
+            WFS_SUCCESS => Ok(()),
+            error => Err(error),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_hresult_ok() {
+        assert_eq!(WFS_SUCCESS.ok(), Ok(()));
+        assert_eq!(WFS_ERR_INTERNAL_ERROR.ok(), Err(WFS_ERR_INTERNAL_ERROR));
+        assert_eq!(WFS_ERR_INVALID_HSERVICE.ok(), Err(WFS_ERR_INVALID_HSERVICE));
+        assert_eq!(WFS_ERR_CFG_NO_MORE_ITEMS.ok(), Err(WFS_ERR_CFG_NO_MORE_ITEMS));
+    }
+}
@@ -0,0 +1,488 @@
+//! Frames exchanged between `xfs_isolate.dll`, which stands in for an isolated provider inside the application, and
+//! the `xfs_host.exe` surrogate process hosting the real provider.
+//!
+//! A frame is a DWORD length followed by a DWORD tag and the fields of the frame, each one a little endian DWORD or a
+//! byte string prefixed with its DWORD length. Requests and replies travel on two one way [`Pipe`]s, as a synchronous
+//! pipe handle serializes its reads and writes and a pending read would otherwise block every reply.
+
+use std::{
+    ffi::CString,
+    io::{self, Read, Write},
+    ptr,
+};
+
+use winapi::{
+    shared::{
+        minwindef::{DWORD, WORD},
+        winerror::{ERROR_BROKEN_PIPE, ERROR_PIPE_CONNECTED},
+    },
+    um::{
+        fileapi::{CreateFileA, ReadFile, WriteFile, OPEN_EXISTING},
+        handleapi::{CloseHandle, INVALID_HANDLE_VALUE},
+        namedpipeapi::{ConnectNamedPipe, CreateNamedPipeA},
+        winbase::{FILE_FLAG_FIRST_PIPE_INSTANCE, PIPE_ACCESS_INBOUND, PIPE_ACCESS_OUTBOUND, PIPE_READMODE_BYTE, PIPE_REJECT_REMOTE_CLIENTS, PIPE_TYPE_BYTE, PIPE_WAIT},
+        winnt::{GENERIC_READ, GENERIC_WRITE, HANDLE, HRESULT},
+    },
+};
+
+use crate::{HSERVICE, REQUESTID};
+
+/// Largest frame accepted, anything longer is taken for a corrupted stream.
+pub const MAX_FRAME_LEN: usize = 64 * 1024;
+
+/// Buffer size of the pipes.
+const PIPE_BUFFER_SIZE: DWORD = 4096;
+
+/// SPI call sent to the surrogate.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Request {
+    Open {
+        service: HSERVICE,
+        request_id: REQUESTID,
+        logical_name: Vec<u8>,
+        app_id: Vec<u8>,
+        trace_level: DWORD,
+        timeout: DWORD,
+        spi_versions: DWORD,
+        srvc_versions: DWORD,
+    },
+    Close {
+        request_id: REQUESTID,
+    },
+    Execute {
+        request_id: REQUESTID,
+        command: DWORD,
+        timeout: DWORD,
+    },
+    GetInfo {
+        request_id: REQUESTID,
+        category: DWORD,
+        timeout: DWORD,
+    },
+    Lock {
+        request_id: REQUESTID,
+        timeout: DWORD,
+    },
+    Unlock {
+        request_id: REQUESTID,
+    },
+    /// Cancels one request, or all of them for request id 0. Not answered.
+    Cancel {
+        request_id: REQUESTID,
+    },
+}
+
+/// Answer of the surrogate.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Reply {
+    /// The SPI function returned, a completion follows only if it returned WFS_SUCCESS.
+    Returned { request_id: REQUESTID, h_result: HRESULT },
+    /// WFPOpen returned, along with the versions it wrote as `[wVersion, wLowVersion, wHighVersion]`.
+    Opened {
+        request_id: REQUESTID,
+        h_result: HRESULT,
+        spi_version: [WORD; 3],
+        srvc_version: [WORD; 3],
+    },
+    /// The provider posted the completion `message` of the request.
+    Completed { request_id: REQUESTID, message: u32, command: DWORD, h_result: HRESULT },
+}
+
+impl Reply {
+    /// Request the reply belongs to.
+    pub fn request_id(&self) -> REQUESTID {
+        match self {
+            Reply::Returned { request_id, .. } | Reply::Opened { request_id, .. } | Reply::Completed { request_id, .. } => *request_id,
+        }
+    }
+
+    /// Status the SPI function or the completion reported.
+    pub fn h_result(&self) -> HRESULT {
+        match self {
+            Reply::Returned { h_result, .. } | Reply::Opened { h_result, .. } | Reply::Completed { h_result, .. } => *h_result,
+        }
+    }
+}
+
+/// Message that can be sent as a frame.
+pub trait Frame: Sized {
+    fn encode(&self, encoder: &mut Encoder);
+    fn decode(decoder: &mut Decoder) -> io::Result<Self>;
+}
+
+/// Builds the body of a frame.
+#[derive(Default)]
+pub struct Encoder(Vec<u8>);
+
+impl Encoder {
+    pub fn dword(&mut self, value: u32) -> &mut Self {
+        self.0.extend_from_slice(&value.to_le_bytes());
+        self
+    }
+
+    pub fn bytes(&mut self, value: &[u8]) -> &mut Self {
+        self.dword(value.len() as u32);
+        self.0.extend_from_slice(value);
+        self
+    }
+}
+
+/// Reads the fields of a frame body, failing with UnexpectedEof when the body ends early.
+pub struct Decoder<'a>(&'a [u8]);
+
+impl Decoder<'_> {
+    pub fn dword(&mut self) -> io::Result<u32> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    pub fn bytes(&mut self) -> io::Result<Vec<u8>> {
+        let len = self.dword()? as usize;
+        Ok(self.take(len)?.to_vec())
+    }
+
+    fn take(&mut self, len: usize) -> io::Result<&[u8]> {
+        if self.0.len() < len {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "truncated frame"));
+        }
+        let (value, rest) = self.0.split_at(len);
+        self.0 = rest;
+        Ok(value)
+    }
+}
+
+const OPEN: u32 = 1;
+const CLOSE: u32 = 2;
+const EXECUTE: u32 = 3;
+const GET_INFO: u32 = 4;
+const LOCK: u32 = 5;
+const UNLOCK: u32 = 6;
+const CANCEL: u32 = 7;
+
+const RETURNED: u32 = 101;
+const OPENED: u32 = 102;
+const COMPLETED: u32 = 103;
+
+fn unknown_tag(tag: u32) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("unknown frame tag {tag}"))
+}
+
+impl Frame for Request {
+    fn encode(&self, encoder: &mut Encoder) {
+        match self {
+            Request::Open {
+                service,
+                request_id,
+                logical_name,
+                app_id,
+                trace_level,
+                timeout,
+                spi_versions,
+                srvc_versions,
+            } => encoder
+                .dword(OPEN)
+                .dword(*service as u32)
+                .dword(*request_id)
+                .bytes(logical_name)
+                .bytes(app_id)
+                .dword(*trace_level)
+                .dword(*timeout)
+                .dword(*spi_versions)
+                .dword(*srvc_versions),
+            Request::Close { request_id } => encoder.dword(CLOSE).dword(*request_id),
+            Request::Execute { request_id, command, timeout } => encoder.dword(EXECUTE).dword(*request_id).dword(*command).dword(*timeout),
+            Request::GetInfo { request_id, category, timeout } => encoder.dword(GET_INFO).dword(*request_id).dword(*category).dword(*timeout),
+            Request::Lock { request_id, timeout } => encoder.dword(LOCK).dword(*request_id).dword(*timeout),
+            Request::Unlock { request_id } => encoder.dword(UNLOCK).dword(*request_id),
+            Request::Cancel { request_id } => encoder.dword(CANCEL).dword(*request_id),
+        };
+    }
+
+    fn decode(decoder: &mut Decoder) -> io::Result<Self> {
+        Ok(match decoder.dword()? {
+            OPEN => Request::Open {
+                service: decoder.dword()? as HSERVICE,
+                request_id: decoder.dword()?,
+                logical_name: decoder.bytes()?,
+                app_id: decoder.bytes()?,
+                trace_level: decoder.dword()?,
+                timeout: decoder.dword()?,
+                spi_versions: decoder.dword()?,
+                srvc_versions: decoder.dword()?,
+            },
+            CLOSE => Request::Close { request_id: decoder.dword()? },
+            EXECUTE => Request::Execute {
+                request_id: decoder.dword()?,
+                command: decoder.dword()?,
+                timeout: decoder.dword()?,
+            },
+            GET_INFO => Request::GetInfo {
+                request_id: decoder.dword()?,
+                category: decoder.dword()?,
+                timeout: decoder.dword()?,
+            },
+            LOCK => Request::Lock {
+                request_id: decoder.dword()?,
+                timeout: decoder.dword()?,
+            },
+            UNLOCK => Request::Unlock { request_id: decoder.dword()? },
+            CANCEL => Request::Cancel { request_id: decoder.dword()? },
+            tag => return Err(unknown_tag(tag)),
+        })
+    }
+}
+
+impl Frame for Reply {
+    fn encode(&self, encoder: &mut Encoder) {
+        match self {
+            Reply::Returned { request_id, h_result } => encoder.dword(RETURNED).dword(*request_id).dword(*h_result as u32),
+            Reply::Opened {
+                request_id,
+                h_result,
+                spi_version,
+                srvc_version,
+            } => {
+                encoder.dword(OPENED).dword(*request_id).dword(*h_result as u32);
+                for version in spi_version.iter().chain(srvc_version) {
+                    encoder.dword(*version as u32);
+                }
+                encoder
+            }
+            Reply::Completed {
+                request_id,
+                message,
+                command,
+                h_result,
+            } => encoder.dword(COMPLETED).dword(*request_id).dword(*message).dword(*command).dword(*h_result as u32),
+        };
+    }
+
+    fn decode(decoder: &mut Decoder) -> io::Result<Self> {
+        Ok(match decoder.dword()? {
+            RETURNED => Reply::Returned {
+                request_id: decoder.dword()?,
+                h_result: decoder.dword()? as HRESULT,
+            },
+            OPENED => {
+                let request_id = decoder.dword()?;
+                let h_result = decoder.dword()? as HRESULT;
+                let mut versions = [0; 6];
+                for version in versions.iter_mut() {
+                    *version = decoder.dword()? as WORD;
+                }
+                Reply::Opened {
+                    request_id,
+                    h_result,
+                    spi_version: [versions[0], versions[1], versions[2]],
+                    srvc_version: [versions[3], versions[4], versions[5]],
+                }
+            }
+            COMPLETED => Reply::Completed {
+                request_id: decoder.dword()?,
+                message: decoder.dword()?,
+                command: decoder.dword()?,
+                h_result: decoder.dword()? as HRESULT,
+            },
+            tag => return Err(unknown_tag(tag)),
+        })
+    }
+}
+
+/// Writes one frame in a single write.
+pub fn send<F: Frame>(writer: &mut impl Write, frame: &F) -> io::Result<()> {
+    let mut encoder = Encoder::default();
+    frame.encode(&mut encoder);
+    let mut buffer = (encoder.0.len() as u32).to_le_bytes().to_vec();
+    buffer.extend_from_slice(&encoder.0);
+    writer.write_all(&buffer)?;
+    writer.flush()
+}
+
+/// Reads one frame, a stream closed between two frames ends with UnexpectedEof as well.
+pub fn receive<F: Frame>(reader: &mut impl Read) -> io::Result<F> {
+    let mut len = [0; 4];
+    reader.read_exact(&mut len)?;
+    let len = u32::from_le_bytes(len) as usize;
+    if len > MAX_FRAME_LEN {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, format!("frame of {len} bytes")));
+    }
+    let mut body = vec![0; len];
+    reader.read_exact(&mut body)?;
+    F::decode(&mut Decoder(&body))
+}
+
+/// Names of the request and reply pipes of one surrogate.
+pub struct PipeNames {
+    pub requests: String,
+    pub replies: String,
+}
+
+impl PipeNames {
+    pub fn new(base: &str) -> Self {
+        PipeNames {
+            requests: format!(r"\\.\pipe\{base}.requests"),
+            replies: format!(r"\\.\pipe\{base}.replies"),
+        }
+    }
+}
+
+/// One end of a one way named pipe, closed on drop.
+pub struct Pipe(HANDLE);
+
+// SAFETY: the handle is owned by the pipe and only used through it
+unsafe impl Send for Pipe {}
+
+impl Pipe {
+    /// Creates the server end of a local pipe, `inbound` for a pipe the server reads from.
+    pub fn create(name: &str, inbound: bool) -> io::Result<Self> {
+        let name = CString::new(name)?;
+        let access = if inbound { PIPE_ACCESS_INBOUND } else { PIPE_ACCESS_OUTBOUND };
+        let mode = PIPE_TYPE_BYTE | PIPE_READMODE_BYTE | PIPE_WAIT | PIPE_REJECT_REMOTE_CLIENTS;
+        // SAFETY: the name is a valid null terminated string
+        let handle = unsafe { CreateNamedPipeA(name.as_ptr(), access | FILE_FLAG_FIRST_PIPE_INSTANCE, mode, 1, PIPE_BUFFER_SIZE, PIPE_BUFFER_SIZE, 0, ptr::null_mut()) };
+        if handle == INVALID_HANDLE_VALUE {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Pipe(handle))
+    }
+
+    /// Waits until the client has opened the pipe.
+    pub fn accept(&self) -> io::Result<()> {
+        // SAFETY: the handle is a pipe created by create
+        if unsafe { ConnectNamedPipe(self.0, ptr::null_mut()) } == 0 {
+            let error = io::Error::last_os_error();
+            // The client was quicker than us
+            if error.raw_os_error() != Some(ERROR_PIPE_CONNECTED as i32) {
+                return Err(error);
+            }
+        }
+        Ok(())
+    }
+
+    /// Opens the client end of a pipe, `read` for a pipe the server writes to.
+    pub fn open(name: &str, read: bool) -> io::Result<Self> {
+        let name = CString::new(name)?;
+        let access = if read { GENERIC_READ } else { GENERIC_WRITE };
+        // SAFETY: the name is a valid null terminated string
+        let handle = unsafe { CreateFileA(name.as_ptr(), access, 0, ptr::null_mut(), OPEN_EXISTING, 0, ptr::null_mut()) };
+        if handle == INVALID_HANDLE_VALUE {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Pipe(handle))
+    }
+}
+
+impl Read for Pipe {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut read = 0;
+        // SAFETY: the buffer length is passed along
+        if unsafe { ReadFile(self.0, buf.as_mut_ptr() as _, buf.len() as DWORD, &mut read, ptr::null_mut()) } == 0 {
+            let error = io::Error::last_os_error();
+            // The other end closed the pipe
+            if error.raw_os_error() == Some(ERROR_BROKEN_PIPE as i32) {
+                return Ok(0);
+            }
+            return Err(error);
+        }
+        Ok(read as usize)
+    }
+}
+
+impl Write for Pipe {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut written = 0;
+        // SAFETY: the buffer length is passed along
+        if unsafe { WriteFile(self.0, buf.as_ptr() as _, buf.len() as DWORD, &mut written, ptr::null_mut()) } == 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(written as usize)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Drop for Pipe {
+    fn drop(&mut self) {
+        // SAFETY: the handle is owned by the pipe and closed exactly once
+        unsafe { CloseHandle(self.0) };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+    use crate::WFS_ERR_HARDWARE_ERROR;
+
+    #[test]
+    fn test_frame_round_trip() {
+        let requests = [
+            Request::Open {
+                service: 3,
+                request_id: 1,
+                logical_name: b"xfs_isolated".to_vec(),
+                app_id: Vec::new(),
+                trace_level: 0,
+                timeout: 1000,
+                spi_versions: 0x1E0003,
+                srvc_versions: 0x1E0003,
+            },
+            Request::Execute {
+                request_id: 2,
+                command: 302,
+                timeout: 0,
+            },
+            Request::Cancel { request_id: 0 },
+        ];
+        let replies = [
+            Reply::Returned {
+                request_id: 2,
+                h_result: WFS_ERR_HARDWARE_ERROR,
+            },
+            Reply::Opened {
+                request_id: 1,
+                h_result: 0,
+                spi_version: [0x1E03, 0x0003, 0x1E03],
+                srvc_version: [0x0003, 0x0003, 0x1E03],
+            },
+            Reply::Completed {
+                request_id: 2,
+                message: 7,
+                command: 302,
+                h_result: 0,
+            },
+        ];
+
+        let mut stream = Vec::new();
+        requests.iter().for_each(|request| send(&mut stream, request).unwrap());
+        replies.iter().for_each(|reply| send(&mut stream, reply).unwrap());
+
+        let mut stream = Cursor::new(stream);
+        for request in &requests {
+            assert_eq!(&receive::<Request>(&mut stream).unwrap(), request);
+        }
+        for reply in &replies {
+            assert_eq!(&receive::<Reply>(&mut stream).unwrap(), reply);
+        }
+        assert_eq!(receive::<Reply>(&mut stream).unwrap_err().kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn test_malformed_frames() {
+        let mut stream = Vec::new();
+        send(&mut stream, &Request::Close { request_id: 5 }).unwrap();
+
+        // a frame cut short by a dying peer
+        let truncated = &stream[..stream.len() - 1];
+        assert_eq!(receive::<Request>(&mut Cursor::new(truncated)).unwrap_err().kind(), io::ErrorKind::UnexpectedEof);
+
+        // a request read as a reply
+        assert_eq!(receive::<Reply>(&mut Cursor::new(&stream)).unwrap_err().kind(), io::ErrorKind::InvalidData);
+
+        // a length no frame has
+        let oversized = ((MAX_FRAME_LEN + 1) as u32).to_le_bytes();
+        assert_eq!(receive::<Request>(&mut Cursor::new(&oversized)).unwrap_err().kind(), io::ErrorKind::InvalidData);
+    }
+}
@@ -1,17 +1,25 @@
 use winapi::ctypes::c_char;
 use winapi::shared::minwindef::{DWORD, LPVOID, WORD};
 use winapi::shared::ntdef::{ULONG, USHORT};
+use winapi::shared::windef::HWND;
 use winapi::um::minwinbase::SYSTEMTIME;
-use winapi::um::winnt::{HANDLE, HRESULT};
+use winapi::um::winnt::{HANDLE, HRESULT, LPSTR};
 
 pub use constants::*;
 pub use errors::*;
+pub use trace::*;
 pub use util::*;
 pub use version::*;
 pub use window::*;
 
 mod constants;
+pub mod conv;
 mod errors;
+pub mod ipc;
+pub mod registry;
+#[cfg(feature = "sandbox")]
+pub mod sandbox;
+mod trace;
 mod util;
 mod version;
 mod window;
@@ -32,11 +40,12 @@ macro_rules! xfs_unwrap {
 }
 
 /// Rejects with specific error and logs error.
+/// Rejecting with WFS_SUCCESS is a bug in the caller and logged as such, see [`rejected`].
 #[macro_export]
 macro_rules! xfs_reject {
     ($l:expr) => {{
         error!(stringify!($l));
-        return $l;
+        return $crate::rejected($l);
     }};
 }
 
@@ -77,3 +86,84 @@ pub struct WFSRESULT {
     pub u: U,
     pub lpBuffer: LPVOID,
 }
+
+/// Runtime statistics of the manager, returned for [`WFS_INF_MGR_STATISTICS`].
+/// The heap figures are taken before the result carrying them is allocated.
+#[allow(non_snake_case)]
+#[repr(C, packed)]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct WFSMGRSTATISTICS {
+    /// Services opened successfully and not closed yet.
+    pub dwOpenServices: DWORD,
+    /// Buffers allocated with WFMAllocateBuffer and not freed yet.
+    pub dwHeapBuffers: DWORD,
+    /// Bytes held by those buffers, including the ones attached with WFMAllocateMore.
+    pub dwHeapBytes: DWORD,
+    /// Timers set with WFMSetTimer that have neither fired nor been killed.
+    pub dwTimers: DWORD,
+    /// Threads with a blocking call in progress.
+    pub dwBlockedThreads: DWORD,
+    /// Logical services whose provider is cached, dropped again when the XFS configuration changes.
+    pub dwResolvedServices: DWORD,
+}
+
+/// Request a provider has not completed yet, returned for [`WFS_INF_MGR_IN_FLIGHT`].
+#[allow(non_snake_case)]
+#[repr(C, packed)]
+#[derive(Clone, Copy, Debug)]
+pub struct WFSMGRINFLIGHT {
+    pub hService: HSERVICE,
+    pub RequestID: REQUESTID,
+    /// Completion message the request ends with, WFS_EXECUTE_COMPLETE and so on.
+    pub dwMessage: DWORD,
+    /// Command or category of the request, 0 for the ones that have none.
+    pub dwCommand: DWORD,
+    /// Window the application gets the completion on.
+    pub hWnd: HWND,
+    /// Milliseconds since the request was handed to the provider.
+    pub dwElapsed: DWORD,
+}
+
+/// Trace level of an open service, returned for [`WFS_INF_MGR_TRACE_LEVELS`].
+#[allow(non_snake_case)]
+#[repr(C, packed)]
+#[derive(Clone, Copy, Debug)]
+pub struct WFSMGRTRACELEVEL {
+    pub hService: HSERVICE,
+    /// Logical service name the service was opened with.
+    pub lpszLogicalName: LPSTR,
+    /// Trace level in effect, including the operator's floor, as WFMGetTraceLevel returns it.
+    pub dwTraceLevel: DWORD,
+}
+
+/// Completion times of one command of a service, returned for [`WFS_INF_MGR_LATENCIES`].
+/// All times are in milliseconds, from handing the request to the provider to its completion.
+#[allow(non_snake_case)]
+#[repr(C, packed)]
+#[derive(Clone, Copy, Debug)]
+pub struct WFSMGRLATENCY {
+    pub hService: HSERVICE,
+    /// Completion message of the requests, WFS_EXECUTE_COMPLETE and so on.
+    pub dwMessage: DWORD,
+    /// Command or category of the requests, 0 for the ones that have none.
+    pub dwCommand: DWORD,
+    /// Requests completed.
+    pub dwCount: DWORD,
+    pub dwMinimum: DWORD,
+    pub dwAverage: DWORD,
+    pub dwMaximum: DWORD,
+}
+
+/// Last call of a thread that failed, returned for [`WFS_INF_MGR_LAST_ERROR`].
+#[allow(non_snake_case)]
+#[repr(C, packed)]
+#[derive(Clone, Copy)]
+pub struct WFSMGRLASTERROR {
+    pub hResult: HRESULT,
+    /// Exported function the application called, WFSExecute and so on.
+    pub lpszOperation: LPSTR,
+    /// Service the call was made on, 0 for the ones that have none.
+    pub hService: HSERVICE,
+    /// UTC time the call returned.
+    pub tsTimestamp: SYSTEMTIME,
+}
@@ -0,0 +1,305 @@
+//! Keys of the XFS configuration that close themselves.
+//!
+//! A [`RegKey`] is opened through the WFM configuration functions and closed with WFMCloseKey when it is dropped,
+//! so early returns no longer leak keys. The functions are passed in as a [`ConfigApi`], as the configuration DLL is
+//! loaded by the crate using the key.
+
+use std::ffi::CString;
+
+use log::error;
+use winapi::shared::minwindef::{DWORD, HKEY, LPDWORD, MAX_PATH, PFILETIME, PHKEY};
+use winapi::um::winnt::{HRESULT, LPSTR};
+
+use crate::{HResultExt, WFS_CFG_OPENED_EXISTING_KEY, WFS_ERR_CFG_NO_MORE_ITEMS, WFS_ERR_INVALID_DATA, WFS_SUCCESS};
+
+/// The WFM configuration functions a [`RegKey`] calls, usually the exports of `xfs_conf.dll`.
+#[derive(Clone, Copy)]
+pub struct ConfigApi {
+    pub open_key: unsafe extern "stdcall" fn(HKEY, LPSTR, PHKEY) -> HRESULT,
+    pub create_key: unsafe extern "stdcall" fn(HKEY, LPSTR, PHKEY, LPDWORD) -> HRESULT,
+    pub create_volatile_key: unsafe extern "stdcall" fn(HKEY, LPSTR, PHKEY, LPDWORD) -> HRESULT,
+    pub close_key: unsafe extern "stdcall" fn(HKEY) -> HRESULT,
+    pub query_value: unsafe extern "stdcall" fn(HKEY, LPSTR, LPSTR, LPDWORD) -> HRESULT,
+    pub enum_key: unsafe extern "stdcall" fn(HKEY, DWORD, LPSTR, LPDWORD, PFILETIME) -> HRESULT,
+    pub enum_value: unsafe extern "stdcall" fn(HKEY, DWORD, LPSTR, LPDWORD, LPSTR, LPDWORD) -> HRESULT,
+    pub set_value: unsafe extern "stdcall" fn(HKEY, LPSTR, LPSTR, DWORD) -> HRESULT,
+}
+
+/// Lifetime of a key created by [`RegKey::create`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum KeyLifetime {
+    /// Stored with the rest of the configuration.
+    #[default]
+    Persistent,
+    /// Kept in memory only and gone after a reboot, for transient session state.
+    Volatile,
+}
+
+/// Whether [`RegKey::create`] created the key or found it in place.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Disposition {
+    CreatedNew,
+    OpenedExisting,
+}
+
+/// Open configuration key, closed on drop.
+pub struct RegKey<'a> {
+    api: &'a ConfigApi,
+    key: HKEY,
+}
+
+impl<'a> RegKey<'a> {
+    /// Opens `path` below one of the XFS configuration roots.
+    pub fn open(api: &'a ConfigApi, root: HKEY, path: &str) -> Result<Self, HRESULT> {
+        let path = CString::new(path).map_err(|_| WFS_ERR_INVALID_DATA)?;
+        let mut key = std::ptr::null_mut();
+        // SAFETY: the path is a valid null terminated string
+        unsafe { (api.open_key)(root, path.as_ptr() as LPSTR, &mut key) }.ok()?;
+        Ok(RegKey { api, key })
+    }
+
+    /// Opens `path` below one of the XFS configuration roots, creating it with `lifetime` if it does not exist.
+    /// A key that exists already is opened as it is, whatever its lifetime.
+    pub fn create(api: &'a ConfigApi, root: HKEY, path: &str, lifetime: KeyLifetime) -> Result<(Self, Disposition), HRESULT> {
+        let path = CString::new(path).map_err(|_| WFS_ERR_INVALID_DATA)?;
+        let create_key = match lifetime {
+            KeyLifetime::Persistent => api.create_key,
+            KeyLifetime::Volatile => api.create_volatile_key,
+        };
+        let mut key = std::ptr::null_mut();
+        let mut disposition = 0;
+        // SAFETY: the path is a valid null terminated string
+        unsafe { create_key(root, path.as_ptr() as LPSTR, &mut key, &mut disposition) }.ok()?;
+        let disposition = match disposition {
+            WFS_CFG_OPENED_EXISTING_KEY => Disposition::OpenedExisting,
+            _ => Disposition::CreatedNew,
+        };
+        Ok((RegKey { api, key }, disposition))
+    }
+
+    /// Raw handle of the key, for registry functions the configuration API does not wrap.
+    pub fn handle(&self) -> HKEY {
+        self.key
+    }
+
+    /// Reads a string value of the key.
+    pub fn query_value(&self, name: &str) -> Result<String, HRESULT> {
+        let name = CString::new(name).map_err(|_| WFS_ERR_INVALID_DATA)?;
+        let mut value = [0u8; MAX_PATH];
+        let mut value_len = MAX_PATH as DWORD;
+        // SAFETY: the key is open and the buffer length is passed along
+        unsafe { (self.api.query_value)(self.key, name.as_ptr() as LPSTR, value.as_mut_ptr() as LPSTR, &mut value_len) }.ok()?;
+        Ok(String::from_utf8_lossy(&value[..value_len as usize]).into_owned())
+    }
+
+    /// Returns the names of the subkeys.
+    pub fn enum_keys(&self) -> Result<Vec<String>, HRESULT> {
+        let mut names = Vec::new();
+        for index in 0.. {
+            let mut name = [0u8; MAX_PATH];
+            let mut name_len = MAX_PATH as DWORD;
+            // SAFETY: the key is open and the buffer length is passed along
+            match unsafe { (self.api.enum_key)(self.key, index, name.as_mut_ptr() as LPSTR, &mut name_len, std::ptr::null_mut()) } {
+                WFS_SUCCESS => names.push(String::from_utf8_lossy(&name[..name_len as usize]).into_owned()),
+                WFS_ERR_CFG_NO_MORE_ITEMS => break,
+                error => return Err(error),
+            }
+        }
+        Ok(names)
+    }
+
+    /// Returns the names and data of the values, as strings.
+    pub fn enum_values(&self) -> Result<Vec<(String, String)>, HRESULT> {
+        let mut values = Vec::new();
+        for index in 0.. {
+            let mut name = [0u8; MAX_PATH];
+            let mut name_len = MAX_PATH as DWORD;
+            let mut data = [0u8; 4096];
+            let mut data_len = data.len() as DWORD;
+            // SAFETY: the key is open and the buffer lengths are passed along
+            match unsafe { (self.api.enum_value)(self.key, index, name.as_mut_ptr() as LPSTR, &mut name_len, data.as_mut_ptr() as LPSTR, &mut data_len) } {
+                WFS_SUCCESS => {
+                    // WFMEnumValue reports the length without the terminator, which wraps for empty data
+                    let data = String::from_utf8_lossy(&data[..(data_len as usize).min(data.len())]);
+                    values.push((String::from_utf8_lossy(&name[..name_len as usize]).into_owned(), data.trim_end_matches('\0').to_string()));
+                }
+                WFS_ERR_CFG_NO_MORE_ITEMS => break,
+                error => return Err(error),
+            }
+        }
+        Ok(values)
+    }
+
+    /// Writes a string value of the key.
+    pub fn set_value(&self, name: &str, value: &str) -> Result<(), HRESULT> {
+        let name = CString::new(name).map_err(|_| WFS_ERR_INVALID_DATA)?;
+        let value = CString::new(value).map_err(|_| WFS_ERR_INVALID_DATA)?;
+        // SAFETY: the key is open, name and value are valid null terminated strings
+        unsafe { (self.api.set_value)(self.key, name.as_ptr() as LPSTR, value.as_ptr() as LPSTR, value.as_bytes().len() as DWORD) }.ok()
+    }
+}
+
+impl Drop for RegKey<'_> {
+    fn drop(&mut self) {
+        // SAFETY: the key was opened by open_key and is closed exactly once
+        let result = unsafe { (self.api.close_key)(self.key) };
+        if result != WFS_SUCCESS {
+            error!("WFMCloseKey failed: {result}");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        ffi::CStr,
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Mutex,
+        },
+    };
+
+    use super::*;
+    use crate::{WFS_CFG_CREATED_NEW_KEY, WFS_ERR_CFG_INVALID_NAME, WFS_ERR_CFG_INVALID_SUBKEY};
+
+    const KEY: HKEY = 0x1234 as HKEY;
+
+    /// Handle of every key the test backend created or opened through create_key.
+    const CREATED_KEY: HKEY = 0x5678 as HKEY;
+
+    // counts the keys the test backend closed, created keys aside
+    static CLOSED: AtomicUsize = AtomicUsize::new(0);
+
+    // holds the paths of the keys the test backend created and whether each of them is volatile
+    static CREATED: Mutex<Vec<(String, bool)>> = Mutex::new(Vec::new());
+
+    unsafe extern "stdcall" fn open_key(_root: HKEY, path: LPSTR, key: PHKEY) -> HRESULT {
+        if CStr::from_ptr(path).to_bytes() != b"LOGICAL_SERVICES" {
+            return WFS_ERR_CFG_INVALID_SUBKEY;
+        }
+        key.write(KEY);
+        WFS_SUCCESS
+    }
+
+    unsafe fn create(path: LPSTR, key: PHKEY, disposition: LPDWORD, volatile: bool) -> HRESULT {
+        let path = CStr::from_ptr(path).to_string_lossy().into_owned();
+        let mut created = CREATED.lock().unwrap();
+        if created.iter().any(|(created, _)| *created == path) {
+            disposition.write(WFS_CFG_OPENED_EXISTING_KEY);
+        } else {
+            created.push((path, volatile));
+            disposition.write(WFS_CFG_CREATED_NEW_KEY);
+        }
+        key.write(CREATED_KEY);
+        WFS_SUCCESS
+    }
+
+    unsafe extern "stdcall" fn create_key(_root: HKEY, path: LPSTR, key: PHKEY, disposition: LPDWORD) -> HRESULT {
+        create(path, key, disposition, false)
+    }
+
+    unsafe extern "stdcall" fn create_volatile_key(_root: HKEY, path: LPSTR, key: PHKEY, disposition: LPDWORD) -> HRESULT {
+        create(path, key, disposition, true)
+    }
+
+    /// Drops the volatile keys, as a reboot would.
+    fn reboot() {
+        CREATED.lock().unwrap().retain(|(_, volatile)| !volatile);
+    }
+
+    unsafe extern "stdcall" fn close_key(key: HKEY) -> HRESULT {
+        if key != CREATED_KEY {
+            assert_eq!(key, KEY);
+            CLOSED.fetch_add(1, Ordering::SeqCst);
+        }
+        WFS_SUCCESS
+    }
+
+    unsafe extern "stdcall" fn query_value(_key: HKEY, name: LPSTR, value: LPSTR, value_len: LPDWORD) -> HRESULT {
+        if CStr::from_ptr(name).to_bytes() != b"provider" {
+            return WFS_ERR_CFG_INVALID_NAME;
+        }
+        std::ptr::copy_nonoverlapping(b"xfs_mock".as_ptr(), value as *mut u8, 8);
+        value_len.write(8);
+        WFS_SUCCESS
+    }
+
+    unsafe extern "stdcall" fn enum_key(_key: HKEY, index: DWORD, name: LPSTR, name_len: LPDWORD, _last_write: PFILETIME) -> HRESULT {
+        if index > 0 {
+            return WFS_ERR_CFG_NO_MORE_ITEMS;
+        }
+        std::ptr::copy_nonoverlapping(b"xfs_mock".as_ptr(), name as *mut u8, 8);
+        name_len.write(8);
+        WFS_SUCCESS
+    }
+
+    unsafe extern "stdcall" fn enum_value(_key: HKEY, index: DWORD, name: LPSTR, name_len: LPDWORD, data: LPSTR, data_len: LPDWORD) -> HRESULT {
+        let (value_name, value_data): (&[u8], &[u8]) = match index {
+            0 => (b"provider", b"xfs_mock\0"),
+            1 => (b"empty", b"\0"),
+            _ => return WFS_ERR_CFG_NO_MORE_ITEMS,
+        };
+        std::ptr::copy_nonoverlapping(value_name.as_ptr(), name as *mut u8, value_name.len());
+        name_len.write(value_name.len() as DWORD);
+        std::ptr::copy_nonoverlapping(value_data.as_ptr(), data as *mut u8, value_data.len());
+        // like WFMEnumValue, without the terminator
+        data_len.write(value_data.len() as DWORD - 1);
+        WFS_SUCCESS
+    }
+
+    unsafe extern "stdcall" fn set_value(_key: HKEY, _name: LPSTR, value: LPSTR, len: DWORD) -> HRESULT {
+        assert_eq!(CStr::from_ptr(value).to_bytes().len(), len as usize);
+        WFS_SUCCESS
+    }
+
+    const API: ConfigApi = ConfigApi {
+        open_key,
+        create_key,
+        create_volatile_key,
+        close_key,
+        query_value,
+        enum_key,
+        enum_value,
+        set_value,
+    };
+
+    #[test]
+    fn test_reg_key_closes() {
+        let closed = CLOSED.load(Ordering::SeqCst);
+        {
+            let key = RegKey::open(&API, std::ptr::null_mut(), "LOGICAL_SERVICES").unwrap();
+            assert_eq!(key.query_value("provider"), Ok("xfs_mock".to_string()));
+            assert_eq!(key.enum_keys(), Ok(vec!["xfs_mock".to_string()]));
+            assert_eq!(key.enum_values(), Ok(vec![("provider".to_string(), "xfs_mock".to_string()), ("empty".to_string(), String::new())]));
+            assert_eq!(key.set_value("provider", "other"), Ok(()));
+        }
+        assert_eq!(CLOSED.load(Ordering::SeqCst), closed + 1);
+
+        // the error path closes the key as well
+        {
+            let key = RegKey::open(&API, std::ptr::null_mut(), "LOGICAL_SERVICES").unwrap();
+            assert_eq!(key.query_value("dllname"), Err(WFS_ERR_CFG_INVALID_NAME));
+        }
+        assert_eq!(CLOSED.load(Ordering::SeqCst), closed + 2);
+
+        // a key that was never opened is not closed
+        assert_eq!(RegKey::open(&API, std::ptr::null_mut(), "SERVICE_PROVIDERS").err(), Some(WFS_ERR_CFG_INVALID_SUBKEY));
+        assert_eq!(CLOSED.load(Ordering::SeqCst), closed + 2);
+    }
+
+    #[test]
+    fn test_create_key_lifetime() {
+        let create = |path, lifetime| RegKey::create(&API, std::ptr::null_mut(), path, lifetime).map(|(_, disposition)| disposition);
+
+        assert_eq!(create("SESSION", KeyLifetime::Volatile), Ok(Disposition::CreatedNew));
+        assert_eq!(create("SESSION", KeyLifetime::Volatile), Ok(Disposition::OpenedExisting));
+        // an existing key is opened whatever lifetime was asked for
+        assert_eq!(create("SESSION", KeyLifetime::Persistent), Ok(Disposition::OpenedExisting));
+        assert_eq!(create("CONFIG", KeyLifetime::default()), Ok(Disposition::CreatedNew));
+
+        // volatile keys do not survive a reboot, persistent ones do
+        reboot();
+        assert_eq!(create("SESSION", KeyLifetime::Volatile), Ok(Disposition::CreatedNew));
+        assert_eq!(create("CONFIG", KeyLifetime::Persistent), Ok(Disposition::OpenedExisting));
+    }
+}
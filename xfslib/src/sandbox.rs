@@ -0,0 +1,132 @@
+//! Private copy of the XFS configuration roots for tests, built with the `sandbox` feature.
+//!
+//! A [`Sandbox`] points XFS_REGISTRY_ROOT at a key below HKEY_CURRENT_USER, so `xfs_conf.dll` and everything reading
+//! the configuration through it see the logical services and providers the test writes there instead of the machine's.
+//! This needs no admin rights. XFS_REGISTRY_ROOT is process wide, so there is one sandbox at a time per test binary.
+
+use std::{
+    ffi::{CString, OsString},
+    ptr,
+    sync::{Mutex, MutexGuard},
+};
+
+use winapi::{
+    shared::{
+        minwindef::{DWORD, HKEY},
+        winerror::ERROR_SUCCESS,
+    },
+    um::{
+        winnt::{KEY_ALL_ACCESS, REG_OPTION_NON_VOLATILE, REG_SZ},
+        winreg::{RegCloseKey, RegCreateKeyExA, RegDeleteTreeA, RegSetValueExA, HKEY_CURRENT_USER},
+    },
+};
+
+/// Moves the XFS configuration roots of `xfs_conf.dll` below another key.
+const REGISTRY_ROOT_ENV: &str = "XFS_REGISTRY_ROOT";
+
+/// Key of the logical services below the sandbox.
+pub const LOGICAL_SERVICES: &str = ".DEFAULT\\XFS\\LOGICAL_SERVICES";
+
+/// Key of the service providers below the sandbox.
+pub const SERVICE_PROVIDERS: &str = "SOFTWARE\\XFS\\SERVICE_PROVIDERS";
+
+// held by the sandbox in use, the next one waits for it to be dropped
+static SERIAL: Mutex<()> = Mutex::new(());
+
+/// Empty XFS configuration below `HKEY_CURRENT_USER\Software\xfsrs_<name>_test`, removed again on drop along with
+/// the XFS_REGISTRY_ROOT override, which gets its previous value back.
+pub struct Sandbox {
+    base: String,
+    previous: Option<OsString>,
+    _serial: MutexGuard<'static, ()>,
+}
+
+impl Sandbox {
+    /// Creates the sandbox and points XFS_REGISTRY_ROOT at it, once the sandbox of any other test is dropped.
+    pub fn new(name: &str) -> Self {
+        let serial = SERIAL.lock().unwrap_or_else(|error| error.into_inner());
+        let base = format!("Software\\xfsrs_{name}_test");
+        // left behind by a test run that was killed
+        delete_tree(&base);
+        let previous = std::env::var_os(REGISTRY_ROOT_ENV);
+        std::env::set_var(REGISTRY_ROOT_ENV, format!("HKCU\\{base}"));
+        Sandbox { base, previous, _serial: serial }
+    }
+
+    /// Gets the path below HKEY_CURRENT_USER of a key of the sandbox, for registry functions the sandbox does not wrap.
+    pub fn path(&self, path: &str) -> String {
+        format!("{}\\{path}", self.base)
+    }
+
+    /// Maps the logical service to the provider, and the provider to its DLL unless `dll_name` is None.
+    pub fn map_service(&self, service: &str, provider: &str, dll_name: Option<&str>) {
+        self.set_value(&format!("{LOGICAL_SERVICES}\\{service}"), "provider", provider);
+        match dll_name {
+            Some(dll_name) => self.set_provider_value(provider, "dllname", dll_name),
+            None => self.create_key(&format!("{SERVICE_PROVIDERS}\\{provider}")),
+        }
+    }
+
+    /// Writes a string value of the provider's key, e.g. one of its policies.
+    pub fn set_provider_value(&self, provider: &str, name: &str, value: &str) {
+        self.set_value(&format!("{SERVICE_PROVIDERS}\\{provider}"), name, value);
+    }
+
+    /// Writes a string value of a key of the sandbox, creating the key if needed.
+    pub fn set_value(&self, path: &str, name: &str, value: &str) {
+        let value = CString::new(value).unwrap();
+        self.set_raw_value(path, name, REG_SZ, value.as_bytes_with_nul());
+    }
+
+    /// Writes a value of any type of a key of the sandbox, creating the key if needed.
+    pub fn set_raw_value(&self, path: &str, name: &str, value_type: DWORD, bytes: &[u8]) {
+        let name = CString::new(name).unwrap();
+        let key = self.open_created(path);
+        unsafe {
+            let result = RegSetValueExA(key, name.as_ptr(), 0, value_type, bytes.as_ptr(), bytes.len() as DWORD);
+            RegCloseKey(key);
+            assert_eq!(result as u32, ERROR_SUCCESS);
+        }
+    }
+
+    /// Creates a key of the sandbox without any values.
+    pub fn create_key(&self, path: &str) {
+        let key = self.open_created(path);
+        unsafe { RegCloseKey(key) };
+    }
+
+    fn open_created(&self, path: &str) -> HKEY {
+        let path = CString::new(self.path(path)).unwrap();
+        let mut key: HKEY = ptr::null_mut();
+        let result = unsafe {
+            RegCreateKeyExA(
+                HKEY_CURRENT_USER,
+                path.as_ptr(),
+                0,
+                ptr::null_mut(),
+                REG_OPTION_NON_VOLATILE,
+                KEY_ALL_ACCESS,
+                ptr::null_mut(),
+                &mut key,
+                ptr::null_mut(),
+            )
+        };
+        assert_eq!(result as u32, ERROR_SUCCESS);
+        key
+    }
+}
+
+impl Drop for Sandbox {
+    fn drop(&mut self) {
+        delete_tree(&self.base);
+        match &self.previous {
+            Some(previous) => std::env::set_var(REGISTRY_ROOT_ENV, previous),
+            None => std::env::remove_var(REGISTRY_ROOT_ENV),
+        }
+    }
+}
+
+fn delete_tree(path: &str) {
+    let path = CString::new(path).unwrap();
+    unsafe { RegDeleteTreeA(HKEY_CURRENT_USER, path.as_ptr()) };
+}
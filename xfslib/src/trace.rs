@@ -0,0 +1,215 @@
+use std::{
+    collections::hash_map::DefaultHasher,
+    ffi::CStr,
+    fmt,
+    hash::{Hash, Hasher},
+    ops::{BitAnd, BitOr, BitOrAssign},
+};
+
+use winapi::{ctypes::c_void, shared::minwindef::DWORD, um::winnt::CHAR};
+
+use crate::{WFS_TRACE_ALL_API, WFS_TRACE_ALL_SPI, WFS_TRACE_API, WFS_TRACE_MGR, WFS_TRACE_SPI};
+
+/// When set, application buffers are traced with their contents. Meant for the lab: on a production ATM the buffers
+/// carry card and track data, so by default only their length and a hash are traced.
+pub const XFS_TRACE_BUFFERS_ENV: &str = "XFS_TRACE_BUFFERS";
+
+/// Set of WFS_TRACE_* categories passed as dwTraceLevel.
+/// Bits without a named category are kept as is, providers are free to define their own.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub struct TraceLevel(DWORD);
+
+impl TraceLevel {
+    pub const NONE: Self = Self(0);
+    pub const API: Self = Self(WFS_TRACE_API);
+    pub const ALL_API: Self = Self(WFS_TRACE_ALL_API);
+    pub const SPI: Self = Self(WFS_TRACE_SPI);
+    pub const ALL_SPI: Self = Self(WFS_TRACE_ALL_SPI);
+    pub const MGR: Self = Self(WFS_TRACE_MGR);
+
+    pub const fn from_bits(bits: DWORD) -> Self {
+        Self(bits)
+    }
+
+    pub const fn bits(self) -> DWORD {
+        self.0
+    }
+
+    /// Returns true if every category of `other` is set.
+    pub const fn contains(self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    /// Returns true if any category of `other` is set.
+    pub const fn intersects(self, other: Self) -> bool {
+        self.0 & other.0 != 0
+    }
+
+    pub const fn is_empty(self) -> bool {
+        self.0 == 0
+    }
+}
+
+impl BitOr for TraceLevel {
+    type Output = Self;
+
+    fn bitor(self, other: Self) -> Self {
+        Self(self.0 | other.0)
+    }
+}
+
+impl BitOrAssign for TraceLevel {
+    fn bitor_assign(&mut self, other: Self) {
+        self.0 |= other.0;
+    }
+}
+
+impl BitAnd for TraceLevel {
+    type Output = Self;
+
+    fn bitand(self, other: Self) -> Self {
+        Self(self.0 & other.0)
+    }
+}
+
+impl From<DWORD> for TraceLevel {
+    fn from(bits: DWORD) -> Self {
+        Self(bits)
+    }
+}
+
+impl From<TraceLevel> for DWORD {
+    fn from(level: TraceLevel) -> Self {
+        level.0
+    }
+}
+
+/// Application buffer as it appears in the trace, see [`XFS_TRACE_BUFFERS_ENV`].
+pub struct Redacted<'a>(pub &'a [u8]);
+
+impl<'a> Redacted<'a> {
+    /// Null terminated string supplied by the application, a null pointer traces as an empty buffer.
+    ///
+    /// # Safety
+    ///
+    /// `ptr` must be null or point to a null terminated string that outlives the returned value.
+    pub unsafe fn c_str(ptr: *const CHAR) -> Self {
+        if ptr.is_null() {
+            return Redacted(&[]);
+        }
+        Redacted(CStr::from_ptr(ptr).to_bytes())
+    }
+
+    /// Formats the buffer with its contents if `show` is set, otherwise as its length and a hash.
+    pub fn fmt_with(&self, show: bool) -> String {
+        if show {
+            return format!("{:?}", String::from_utf8_lossy(self.0));
+        }
+        // the hash tells equal buffers apart across trace lines without revealing them
+        let mut hasher = DefaultHasher::new();
+        self.0.hash(&mut hasher);
+        format!("<{} bytes, hash {:016x}>", self.0.len(), hasher.finish())
+    }
+}
+
+impl fmt::Display for Redacted<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.fmt_with(std::env::var_os(XFS_TRACE_BUFFERS_ENV).is_some()))
+    }
+}
+
+/// Command data or query details as they appear in the trace, see [`XFS_TRACE_BUFFERS_ENV`]. Only the provider knows
+/// their layout, so they are traced like a [`Redacted`] buffer when the size they were allocated with is known, e.g.
+/// from WFMGetBufferLength, and by address otherwise.
+pub struct RedactedData {
+    ptr: *const c_void,
+    len: Option<usize>,
+}
+
+impl RedactedData {
+    /// # Safety
+    ///
+    /// `ptr` must be null or, if `len` is given, point to `len` readable bytes that outlive the returned value.
+    pub unsafe fn new(ptr: *const c_void, len: Option<usize>) -> Self {
+        RedactedData { ptr, len }
+    }
+
+    /// Formats the data like [`Redacted::fmt_with`] if its length is known.
+    pub fn fmt_with(&self, show: bool) -> String {
+        if self.ptr.is_null() {
+            return "NULL".to_string();
+        }
+        match self.len {
+            // SAFETY: the pointer is valid for len bytes, see new
+            Some(len) => Redacted(unsafe { std::slice::from_raw_parts(self.ptr as *const u8, len) }).fmt_with(show),
+            None => format!("<{:?}, length unknown>", self.ptr),
+        }
+    }
+}
+
+impl fmt::Display for RedactedData {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.fmt_with(std::env::var_os(XFS_TRACE_BUFFERS_ENV).is_some()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_categories() {
+        let level = TraceLevel::API | TraceLevel::SPI;
+        assert!(level.contains(TraceLevel::API));
+        assert!(level.contains(TraceLevel::API | TraceLevel::SPI));
+        assert!(!level.contains(TraceLevel::API | TraceLevel::MGR));
+        assert!(level.intersects(TraceLevel::SPI | TraceLevel::MGR));
+        assert!(!level.intersects(TraceLevel::ALL_API | TraceLevel::ALL_SPI));
+        assert_eq!(level & TraceLevel::SPI, TraceLevel::SPI);
+        assert!((level & TraceLevel::MGR).is_empty());
+        assert!(TraceLevel::default().is_empty());
+
+        let mut level = TraceLevel::NONE;
+        level |= TraceLevel::MGR;
+        assert_eq!(level, TraceLevel::MGR);
+    }
+
+    #[test]
+    fn test_dword_round_trip() {
+        for bits in [0, WFS_TRACE_API, WFS_TRACE_ALL_API | WFS_TRACE_ALL_SPI, WFS_TRACE_MGR, 0x8000_0010] {
+            assert_eq!(DWORD::from(TraceLevel::from(bits)), bits);
+            assert_eq!(TraceLevel::from_bits(bits).bits(), bits);
+        }
+        assert_eq!((TraceLevel::API | TraceLevel::ALL_API | TraceLevel::SPI | TraceLevel::ALL_SPI | TraceLevel::MGR).bits(), 0x1f);
+    }
+
+    #[test]
+    fn test_redacted() {
+        let track = b"4000123412341234=2512\0";
+        let redacted = unsafe { Redacted::c_str(track.as_ptr() as *const CHAR) }.fmt_with(false);
+        assert!(!redacted.contains("4000123412341234"));
+        assert!(redacted.starts_with("<21 bytes, hash "));
+        assert_eq!(redacted, Redacted(&track[..21]).fmt_with(false));
+        assert_eq!(unsafe { Redacted::c_str(std::ptr::null()) }.fmt_with(false).split(',').next(), Some("<0 bytes"));
+
+        assert_eq!(unsafe { Redacted::c_str(track.as_ptr() as *const CHAR) }.fmt_with(true), "\"4000123412341234=2512\"");
+    }
+
+    #[test]
+    fn test_redacted_data() {
+        let track = b"4000123412341234=2512";
+        let data = unsafe { RedactedData::new(track.as_ptr() as *const c_void, Some(track.len())) };
+        assert_eq!(data.fmt_with(false), Redacted(&track[..]).fmt_with(false));
+        assert!(data.fmt_with(false).starts_with("<21 bytes, hash "));
+        assert_eq!(data.fmt_with(true), "\"4000123412341234=2512\"");
+
+        // without a length nothing is read, whatever the mode
+        let data = unsafe { RedactedData::new(track.as_ptr() as *const c_void, None) };
+        assert!(data.fmt_with(false).ends_with(", length unknown>"));
+        assert_eq!(data.fmt_with(true), data.fmt_with(false));
+
+        let data = unsafe { RedactedData::new(std::ptr::null(), Some(21)) };
+        assert_eq!(data.fmt_with(false), "NULL");
+        assert_eq!(data.fmt_with(true), "NULL");
+    }
+}
@@ -1,37 +1,241 @@
-use std::path::Path;
+use std::{
+    ffi::CString,
+    mem,
+    panic::{self, AssertUnwindSafe},
+    path::Path,
+    sync::{
+        atomic::{AtomicU32, Ordering},
+        Once,
+    },
+};
 
-use log::{trace, LevelFilter};
+use log::{error, trace, warn, LevelFilter, Log, Metadata, Record};
 use log4rs::{
-    append::file::FileAppender,
+    append::rolling_file::{
+        policy::compound::{roll::fixed_window::FixedWindowRoller, trigger::size::SizeTrigger, CompoundPolicy},
+        RollingFileAppender,
+    },
     config::{Appender, Root},
     encode::pattern::PatternEncoder,
     Config,
 };
 use winapi::{
-    shared::minwindef::{DWORD, HINSTANCE, HMODULE, MAX_PATH},
-    um::{libloaderapi::GetModuleFileNameA, winnt::DLL_PROCESS_ATTACH},
+    shared::{
+        basetsd::UINT_PTR,
+        minwindef::{DWORD, HINSTANCE, HMODULE, MAX_PATH},
+    },
+    um::{
+        debugapi::OutputDebugStringA,
+        libloaderapi::GetModuleFileNameA,
+        winbase::{IsBadReadPtr, IsBadWritePtr},
+        winnt::{DLL_PROCESS_ATTACH, HRESULT},
+    },
 };
 
+use crate::{WFS_ERR_INTERNAL_ERROR, WFS_SUCCESS};
+
+/// When set, traces are mirrored to `OutputDebugStringA` so they can be watched live in DebugView.
+pub const XFS_DEBUG_OUTPUT_ENV: &str = "XFS_DEBUG_OUTPUT";
+
+/// Size in bytes at which the trace file is rolled over, 10 MB by default.
+pub const XFS_LOG_MAX_SIZE_ENV: &str = "XFS_LOG_MAX_SIZE";
+
+/// Number of rolled over trace files kept next to the current one as `<name>.log.1` and up, 5 by default.
+pub const XFS_LOG_FILES_ENV: &str = "XFS_LOG_FILES";
+
+/// MDC key the id of the exported call is traced under, see [`catch_panic`].
+pub const CALL_ID_KEY: &str = "call";
+
+const DEFAULT_LOG_MAX_SIZE: u64 = 10 * 1024 * 1024;
+const DEFAULT_LOG_FILES: u32 = 5;
+
 pub fn module_init(dll: HINSTANCE, fdw_reason: DWORD) {
     if fdw_reason != DLL_PROCESS_ATTACH {
         return;
     }
 
     let filename = unsafe { get_module_name(dll) };
-    let logfile = FileAppender::builder()
-        .encoder(Box::new(PatternEncoder::new("{d(%Y-%m-%d %H:%M:%S)} {l} {L} - {m}\n")))
-        .build(format!("$ENV{{Public}}\\{filename}.log"))
-        .unwrap();
-    let config = Config::builder()
-        .appender(Appender::builder().build("logfile", Box::new(logfile)))
-        .build(Root::builder().appender("logfile").build(LevelFilter::Trace))
-        .unwrap();
-
-    log4rs::init_config(config).unwrap();
+    let config = log_config(&format!("$ENV{{Public}}\\{filename}.log"), LogRotation::from_env(), debug_output_enabled());
+
+    init_logger(config);
+    install_panic_hook();
     let pid = std::process::id();
     trace!("DLL attached: {filename}, process id: {pid}");
 }
 
+/// Installs the configuration as the global logger and returns true, or returns false if a logger is already set.
+///
+/// Attaching a second time within the same logger scope, e.g. a DLL loaded again or a host that set up its own
+/// logger, must not panic in DllMain, so the existing logger is kept.
+pub fn init_logger(config: Config) -> bool {
+    match log4rs::init_config(config) {
+        Ok(_) => true,
+        Err(error) => {
+            trace!("Keeping the existing logger: {error}");
+            false
+        }
+    }
+}
+
+/// Logs panics with their location to the trace file before the default hook runs, so a panic that takes the
+/// process down still leaves a trace. Installed once per module.
+pub fn install_panic_hook() {
+    static INSTALL: Once = Once::new();
+    INSTALL.call_once(|| {
+        let previous = panic::take_hook();
+        panic::set_hook(Box::new(move |info| {
+            error!("{info}");
+            flush_logs();
+            previous(info);
+        }));
+    });
+}
+
+/// Hands every record the appenders still hold to the operating system, called where the process may go down next.
+///
+/// The trace file appender writes each record through as it is logged, so this only matters for appenders added
+/// later that buffer. A record that reached the operating system survives the process, not a power loss.
+pub fn flush_logs() {
+    log::logger().flush();
+}
+
+/// Last call id handed out by [`catch_panic`] in this module.
+static LAST_CALL_ID: AtomicU32 = AtomicU32::new(0);
+
+/// Runs the body of an exported function and turns a panic into WFS_ERR_INTERNAL_ERROR.
+/// Unwinding out of an `extern "stdcall"` function is undefined behavior, so every exported function returning
+/// an HRESULT runs its body through this.
+///
+/// The body is traced under a fresh call id, so the lines of concurrent calls can be told apart. A call made from
+/// within another one, like WFSOpen calling WFSAsyncOpen, keeps the id of the outer call.
+pub fn catch_panic(body: impl FnOnce() -> HRESULT) -> HRESULT {
+    let _call = CallScope::enter(call_id().unwrap_or_else(|| LAST_CALL_ID.fetch_add(1, Ordering::SeqCst) + 1));
+    panic::catch_unwind(AssertUnwindSafe(body)).unwrap_or(WFS_ERR_INTERNAL_ERROR)
+}
+
+/// Id of the exported call the thread is tracing for, if any.
+pub fn call_id() -> Option<u32> {
+    log_mdc::get(CALL_ID_KEY, |id| id.and_then(|id| id.parse().ok()))
+}
+
+/// Traces the thread under a call id until dropped, then under the one before. Work done on another thread on
+/// behalf of a call, like relaying its completion, enters the id of that call.
+pub struct CallScope(log_mdc::InsertGuard);
+
+impl CallScope {
+    pub fn enter(id: u32) -> Self {
+        CallScope(log_mdc::insert_scoped(CALL_ID_KEY, id.to_string()))
+    }
+}
+
+/// Passes the HRESULT of an [`xfs_reject!`](crate::xfs_reject) through. A success code ending up in an error
+/// return means the caller's error mapping is wrong, which would otherwise go unnoticed, so it is warned about.
+pub fn rejected(h_result: HRESULT) -> HRESULT {
+    if h_result == WFS_SUCCESS {
+        warn!("Rejected with WFS_SUCCESS, an error branch returns success");
+    }
+    h_result
+}
+
+fn debug_output_enabled() -> bool {
+    std::env::var_os(XFS_DEBUG_OUTPUT_ENV).is_some()
+}
+
+/// Limits of the trace file, an ATM runs for months and an unbounded trace eventually fills the disk.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct LogRotation {
+    max_size: u64,
+    files: u32,
+}
+
+impl LogRotation {
+    /// Reads the limits from [`XFS_LOG_MAX_SIZE_ENV`] and [`XFS_LOG_FILES_ENV`].
+    fn from_env() -> Self {
+        Self::parse(std::env::var(XFS_LOG_MAX_SIZE_ENV).ok().as_deref(), std::env::var(XFS_LOG_FILES_ENV).ok().as_deref())
+    }
+
+    /// Parses the limits, missing or unparsable values keep the default.
+    fn parse(max_size: Option<&str>, files: Option<&str>) -> Self {
+        fn value<T: std::str::FromStr>(value: Option<&str>, default: T) -> T {
+            value.and_then(|value| value.trim().parse().ok()).unwrap_or(default)
+        }
+
+        LogRotation {
+            max_size: value(max_size, DEFAULT_LOG_MAX_SIZE),
+            files: value(files, DEFAULT_LOG_FILES),
+        }
+    }
+}
+
+impl Default for LogRotation {
+    fn default() -> Self {
+        LogRotation {
+            max_size: DEFAULT_LOG_MAX_SIZE,
+            files: DEFAULT_LOG_FILES,
+        }
+    }
+}
+
+/// Builds the trace file appender, rolled over to `<path>.1` once it exceeds the maximum size.
+fn log_file(path: &str, rotation: LogRotation) -> RollingFileAppender {
+    let roller = FixedWindowRoller::builder().base(1).build(&format!("{path}.{{}}"), rotation.files).unwrap();
+    let policy = CompoundPolicy::new(Box::new(SizeTrigger::new(rotation.max_size)), Box::new(roller));
+    RollingFileAppender::builder()
+        .encoder(Box::new(PatternEncoder::new(&format!("{{d(%Y-%m-%d %H:%M:%S)}} {{l}} {{L}} [{{X({CALL_ID_KEY})(-)}}] - {{m}}\n"))))
+        .build(path, Box::new(policy))
+        .unwrap()
+}
+
+/// Builds the logger configuration. The file appender is always present, the debug string appender only on request.
+fn log_config(path: &str, rotation: LogRotation, debug_output: bool) -> Config {
+    let logfile = log_file(path, rotation);
+    let mut config = Config::builder().appender(Appender::builder().build("logfile", Box::new(logfile)));
+    let mut root = Root::builder().appender("logfile");
+
+    if debug_output {
+        config = config.appender(Appender::builder().build("debugstring", Box::new(DebugStringAppender)));
+        root = root.appender("debugstring");
+    }
+
+    config.build(root.build(LevelFilter::Trace)).unwrap()
+}
+
+/// Mirrors log records to the attached debugger or DebugView.
+#[derive(Debug)]
+struct DebugStringAppender;
+
+impl Log for DebugStringAppender {
+    fn enabled(&self, _: &Metadata) -> bool {
+        true
+    }
+
+    fn log(&self, record: &Record) {
+        let call = call_id().map_or_else(|| "-".to_string(), |id| id.to_string());
+        if let Ok(line) = CString::new(format!("{} {} [{call}] - {}\n", record.level(), record.line().unwrap_or(0), record.args())) {
+            // SAFETY: the line is a valid null terminated string
+            unsafe { OutputDebugStringA(line.as_ptr()) };
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+/// Best-effort check that an application supplied pointer can be written to.
+///
+/// IsBadWritePtr only reflects the page protection at the time of the call, is racy against other threads
+/// changing the mapping and may swallow guard page exceptions, so it only turns the common bad pointer bugs
+/// into an error instead of an access violation. It is not a guarantee.
+pub fn is_writable<T>(ptr: *mut T) -> bool {
+    // SAFETY: IsBadWritePtr probes the memory under an exception handler
+    !ptr.is_null() && unsafe { IsBadWritePtr(ptr as *mut _, mem::size_of::<T>() as UINT_PTR) } == 0
+}
+
+/// Best-effort check that an application supplied pointer can be read from. See [`is_writable`] for the limitations.
+pub fn is_readable<T>(ptr: *const T) -> bool {
+    // SAFETY: IsBadReadPtr probes the memory under an exception handler
+    !ptr.is_null() && unsafe { IsBadReadPtr(ptr as *const _, mem::size_of::<T>() as UINT_PTR) } == 0
+}
+
 unsafe fn get_module_name(module: HMODULE) -> String {
     let mut i8slice = [0i8; MAX_PATH];
     let len = GetModuleFileNameA(module, i8slice.as_mut_ptr(), MAX_PATH as u32) as usize;
@@ -43,3 +247,150 @@ unsafe fn get_module_name(module: HMODULE) -> String {
     let dir = std::str::from_utf8(&u8slice[..len]).unwrap_or("");
     Path::new(dir).file_name().unwrap_or_default().to_owned().into_string().unwrap_or_default()
 }
+
+#[cfg(test)]
+mod tests {
+    use std::ptr;
+
+    use log4rs::append::Append;
+
+    use super::*;
+
+    fn logfile() -> String {
+        std::env::temp_dir().join("xfslib_test.log").to_string_lossy().into_owned()
+    }
+
+    #[test]
+    fn test_pointer_probe() {
+        let mut value = 0u32;
+        assert!(is_writable(&mut value));
+        assert!(is_readable(&value));
+        assert!(!is_writable(ptr::null_mut::<u32>()));
+        assert!(!is_writable(1 as *mut u32));
+        assert!(!is_readable(1 as *const u32));
+        assert!(!is_writable("read only".as_ptr() as *mut u8));
+    }
+
+    #[test]
+    fn test_init_logger_twice() {
+        init_logger(log_config(&logfile(), LogRotation::default(), false));
+        assert!(!init_logger(log_config(&logfile(), LogRotation::default(), false)));
+    }
+
+    #[test]
+    fn test_catch_panic() {
+        init_logger(log_config(&logfile(), LogRotation::default(), false));
+        install_panic_hook();
+
+        assert_eq!(catch_panic(|| panic!("test_catch_panic marker")), WFS_ERR_INTERNAL_ERROR);
+        assert_eq!(catch_panic(|| crate::WFS_SUCCESS), crate::WFS_SUCCESS);
+
+        let log = std::fs::read_to_string(logfile()).unwrap();
+        assert!(log.contains("test_catch_panic marker"));
+        assert!(log.contains(file!()));
+    }
+
+    #[test]
+    fn test_flush_logs() {
+        init_logger(log_config(&logfile(), LogRotation::default(), false));
+        trace!("test_flush_logs marker");
+        flush_logs();
+
+        let log = std::fs::read_to_string(logfile()).unwrap();
+        assert!(log.contains("test_flush_logs marker"));
+    }
+
+    #[test]
+    fn test_call_id() {
+        assert_eq!(call_id(), None);
+        let mut ids = Vec::new();
+        for _ in 0..2 {
+            catch_panic(|| {
+                let outer = call_id().unwrap();
+                // a nested call is part of the outer one
+                catch_panic(|| {
+                    assert_eq!(call_id(), Some(outer));
+                    crate::WFS_SUCCESS
+                });
+                ids.push(outer);
+                crate::WFS_SUCCESS
+            });
+            assert_eq!(call_id(), None);
+        }
+        assert_ne!(ids[0], ids[1]);
+
+        // work done on behalf of the call enters its id
+        let scope = CallScope::enter(ids[0]);
+        assert_eq!(call_id(), Some(ids[0]));
+        drop(scope);
+        assert_eq!(call_id(), None);
+    }
+
+    #[test]
+    fn test_reject_success() {
+        fn reject(h_result: HRESULT) -> HRESULT {
+            crate::xfs_reject!(h_result)
+        }
+
+        // the log file is shared by every run and every test of this binary, so only warnings written
+        // since this test started count
+        fn warnings() -> usize {
+            flush_logs();
+            std::fs::read_to_string(logfile()).unwrap_or_default().matches("Rejected with WFS_SUCCESS").count()
+        }
+
+        init_logger(log_config(&logfile(), LogRotation::default(), false));
+        let before = warnings();
+        assert_eq!(reject(crate::WFS_ERR_INVALID_POINTER), crate::WFS_ERR_INVALID_POINTER);
+        assert_eq!(warnings(), before);
+
+        assert_eq!(reject(WFS_SUCCESS), WFS_SUCCESS);
+        assert_eq!(warnings(), before + 1);
+    }
+
+    #[test]
+    fn test_log_config_default() {
+        let config = log_config(&logfile(), LogRotation::default(), false);
+        assert_eq!(config.appenders().len(), 1);
+        assert_eq!(config.root().appenders(), &["logfile".to_string()]);
+    }
+
+    #[test]
+    fn test_log_config_debug_output() {
+        let config = log_config(&logfile(), LogRotation::default(), true);
+        assert_eq!(config.appenders().len(), 2);
+        assert_eq!(config.root().appenders(), &["logfile".to_string(), "debugstring".to_string()]);
+    }
+
+    #[test]
+    fn test_log_rotation_parse() {
+        assert_eq!(LogRotation::parse(None, None), LogRotation::default());
+        assert_eq!(
+            LogRotation::parse(Some(" 1048576 "), Some("ten")),
+            LogRotation {
+                max_size: 1048576,
+                files: DEFAULT_LOG_FILES
+            }
+        );
+    }
+
+    #[test]
+    fn test_log_file_rolls_over() {
+        let path = std::env::temp_dir().join("xfslib_rotation_test.log").to_string_lossy().into_owned();
+        let archive = |index: u32| format!("{path}.{index}");
+        for file in [path.clone(), archive(1), archive(2), archive(3)] {
+            let _ = std::fs::remove_file(file);
+        }
+
+        let appender = log_file(&path, LogRotation { max_size: 64, files: 2 });
+        for _ in 0..10 {
+            appender.append(&Record::builder().args(format_args!("a trace line long enough to roll")).build()).unwrap();
+        }
+
+        assert!(Path::new(&path).exists());
+        assert!(Path::new(&archive(1)).exists());
+        assert!(Path::new(&archive(2)).exists());
+        assert!(!Path::new(&archive(3)).exists());
+        assert!(std::fs::metadata(&path).unwrap().len() <= 128);
+    }
+}
@@ -1,6 +1,8 @@
 use winapi::shared::minwindef::{BYTE, DWORD, WORD};
 
-#[derive(PartialEq, PartialOrd, Debug)]
+/// XFS version number. Packed into a WORD as the XFS API specifies it, with the major version in the low-order byte
+/// and the minor version in the high-order byte, so 3.30 is 0x1E03.
+#[derive(Clone, Copy, PartialEq, PartialOrd, Debug)]
 pub struct Version {
     pub major: BYTE,
     pub minor: BYTE,
@@ -14,7 +16,7 @@ impl Version {
         }
     }
 
-    pub fn new_explicit(major: BYTE, minor: BYTE) -> Self {
+    pub const fn new_explicit(major: BYTE, minor: BYTE) -> Self {
         Self { major, minor }
     }
 
@@ -23,7 +25,9 @@ impl Version {
     }
 }
 
-#[derive(Debug)]
+/// Range of XFS versions. Packed into a DWORD as dwVersionsRequired of WFSStartUp and WFSOpen, with the lowest
+/// version in the high-order word and the highest version in the low-order word.
+#[derive(Clone, Copy, PartialEq, Debug)]
 pub struct VersionRange {
     pub start: Version,
     pub end: Version,
@@ -37,10 +41,17 @@ impl VersionRange {
         }
     }
 
-    pub fn new_explicit(start: Version, end: Version) -> Self {
+    pub const fn new_explicit(start: Version, end: Version) -> Self {
         Self { start, end }
     }
 
+    /// Returns the versions both ranges contain, or None if they have none in common.
+    pub fn intersect(&self, other: &VersionRange) -> Option<VersionRange> {
+        let start = if self.start > other.start { self.start } else { other.start };
+        let end = if self.end < other.end { self.end } else { other.end };
+        (start <= end).then_some(VersionRange { start, end })
+    }
+
     pub fn value(&self) -> DWORD {
         ((self.start.value() as DWORD) << 16) | self.end.value() as DWORD
     }
@@ -58,6 +69,19 @@ mod tests {
         assert_eq!(version.value(), 0x0102);
     }
 
+    #[test]
+    fn test_spec_layout() {
+        // 3.30 as providers report it in WFSVERSION.wVersion
+        let version = Version::new(0x1E03);
+        assert_eq!((version.major, version.minor), (3, 30));
+        assert_eq!(Version::new_explicit(3, 30).value(), 0x1E03);
+
+        // dwVersionsRequired for 3.00 to 3.30
+        let range = VersionRange::new_explicit(Version::new_explicit(3, 0), Version::new_explicit(3, 30));
+        assert_eq!(range.value(), 0x0003_1E03);
+        assert_eq!(VersionRange::new(0x0003_1E03), range);
+    }
+
     #[test]
     fn test_range() {
         let range = VersionRange::new(0x01020304);
@@ -67,4 +91,16 @@ mod tests {
         assert_eq!(range.end.major, 4);
         assert_eq!(range.value(), 0x01020304);
     }
+
+    #[test]
+    fn test_intersect() {
+        let range = |start: (u8, u8), end: (u8, u8)| VersionRange::new_explicit(Version::new_explicit(start.0, start.1), Version::new_explicit(end.0, end.1));
+
+        assert_eq!(range((2, 0), (3, 30)).intersect(&range((2, 0), (2, 30))), Some(range((2, 0), (2, 30))));
+        assert_eq!(range((3, 0), (3, 30)).intersect(&range((2, 10), (3, 10))), Some(range((3, 0), (3, 10))));
+        assert_eq!(range((3, 0), (3, 30)).intersect(&range((3, 30), (4, 0))), Some(range((3, 30), (3, 30))));
+        assert_eq!(range((3, 0), (3, 30)).intersect(&range((2, 0), (2, 30))), None);
+        // minor versions compare below the next major version
+        assert_eq!(range((2, 99), (2, 99)).intersect(&range((3, 0), (3, 0))), None);
+    }
 }
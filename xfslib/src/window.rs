@@ -1,10 +1,17 @@
 use std::{
     ffi::CString,
+    panic::{self, AssertUnwindSafe},
     ptr,
-    sync::mpsc::{Receiver, Sender},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        mpsc::{Receiver, Sender, TrySendError},
+        Arc,
+    },
     thread,
 };
 
+use log::{error, warn};
+
 use winapi::{
     ctypes::c_void,
     shared::{
@@ -23,12 +30,16 @@ use winapi::{
 struct Message {
     message: u32,
     // w_param: u32,
-    l_param: u32,
+    l_param: usize,
 }
 
+/// Number of completions a [`SyncWindow`] buffers before it starts dropping new ones.
+pub const DEFAULT_QUEUE_CAPACITY: usize = 1024;
+
 pub struct SyncWindow {
     hwnd: HWND,
-    receiver: Receiver<u32>,
+    receiver: Receiver<usize>,
+    dropped: Arc<AtomicUsize>,
 }
 
 struct HwndResult {
@@ -39,8 +50,16 @@ unsafe impl Send for HwndResult {}
 
 impl SyncWindow {
     pub fn new(message: u32) -> Self {
+        Self::with_capacity(message, DEFAULT_QUEUE_CAPACITY)
+    }
+
+    /// Creates a window that buffers at most `capacity` matching messages.
+    /// Messages arriving while the queue is full are dropped with a warning, so a flooding provider cannot exhaust memory.
+    pub fn with_capacity(message: u32, capacity: usize) -> Self {
         let (sender, receiver) = std::sync::mpsc::channel::<Message>();
-        let (sender_res, receiver_res) = std::sync::mpsc::channel();
+        let (sender_res, receiver_res) = std::sync::mpsc::sync_channel(capacity);
+        let dropped = Arc::new(AtomicUsize::new(0));
+        let dropped_counter = dropped.clone();
         let (sender_hwnd, receiver_hwnd) = std::sync::mpsc::channel();
 
         thread::spawn(move || unsafe {
@@ -87,8 +106,16 @@ impl SyncWindow {
                 Ok(message) => message,
                 Err(_) => break,
             };
-            if received.message == message {
-                sender_res.send(received.l_param).unwrap();
+            if received.message != message {
+                continue;
+            }
+            match sender_res.try_send(received.l_param) {
+                Ok(()) => {}
+                Err(TrySendError::Full(l_param)) => {
+                    let dropped = dropped_counter.fetch_add(1, Ordering::SeqCst) + 1;
+                    warn!("SyncWindow queue full ({capacity}), dropping message {message} with lParam {l_param:#x}, {dropped} dropped so far");
+                }
+                Err(TrySendError::Disconnected(_)) => break,
             }
         });
 
@@ -97,10 +124,11 @@ impl SyncWindow {
         Self {
             hwnd: hwnd.hwnd,
             receiver: receiver_res,
+            dropped,
         }
     }
 
-    pub fn try_receive(&self) -> Result<Option<u32>, Box<dyn std::error::Error>> {
+    pub fn try_receive(&self) -> Result<Option<usize>, Box<dyn std::error::Error>> {
         match self.receiver.try_recv() {
             Ok(message) => Ok(Some(message)),
             Err(std::sync::mpsc::TryRecvError::Empty) => Ok(None),
@@ -111,6 +139,11 @@ impl SyncWindow {
     pub fn handle(&self) -> HWND {
         self.hwnd
     }
+
+    /// Number of messages dropped because the queue was full.
+    pub fn dropped(&self) -> usize {
+        self.dropped.load(Ordering::SeqCst)
+    }
 }
 
 impl Drop for SyncWindow {
@@ -121,8 +154,12 @@ impl Drop for SyncWindow {
     }
 }
 
+/// Window procedure of the [`SyncWindow`], forwarding messages to the channel in GWLP_USERDATA.
+///
+/// Runs as a Windows callback, so nothing may unwind out of it: a receiver that is gone is ignored, as is a
+/// missing sender, and any panic is caught.
 extern "system" fn wndproc(window: HWND, message: u32, wparam: WPARAM, lparam: LPARAM) -> LRESULT {
-    unsafe {
+    panic::catch_unwind(AssertUnwindSafe(|| unsafe {
         match message as u32 {
             WM_GETMINMAXINFO => DefWindowProcA(window, message, wparam, lparam),
             WM_NCCREATE => {
@@ -131,15 +168,18 @@ extern "system" fn wndproc(window: HWND, message: u32, wparam: WPARAM, lparam: L
                     return 0;
                 }
                 let sender_ptr = (*createstruct).lpCreateParams;
-                SetWindowLongPtrA(window, GWLP_USERDATA, sender_ptr as i32);
-                return 1;
+                // LONG on 32-bit and LONG_PTR on 64-bit targets, the pointer must not be truncated to 32 bits
+                SetWindowLongPtrA(window, GWLP_USERDATA, sender_ptr as _);
+                1
             }
             WM_NCDESTROY => DefWindowProcA(window, message, wparam, lparam),
             WM_NCCALCSIZE => DefWindowProcA(window, message, wparam, lparam),
             WM_CREATE => DefWindowProcA(window, message, wparam, lparam),
             WM_DESTROY => {
-                let ptr = GetWindowLongPtrA(window, GWLP_USERDATA) as *mut Sender<Message>;
-                drop(Box::from_raw(ptr));
+                let ptr = SetWindowLongPtrA(window, GWLP_USERDATA, 0) as *mut Sender<Message>;
+                if !ptr.is_null() {
+                    drop(Box::from_raw(ptr));
+                }
                 PostQuitMessage(0);
                 0
             }
@@ -150,16 +190,114 @@ extern "system" fn wndproc(window: HWND, message: u32, wparam: WPARAM, lparam: L
             SPI_GETDOCKMOVING => 0,
             _ => {
                 let ptr = GetWindowLongPtrA(window, GWLP_USERDATA) as *mut Sender<Message>;
-                let sender = &*ptr;
-                sender
-                    .send(Message {
-                        message,
-                        // w_param: wparam as u32,
-                        l_param: lparam as u32,
-                    })
-                    .unwrap();
+                if ptr.is_null() {
+                    return DefWindowProcA(window, message, wparam, lparam);
+                }
+                let sent = (*ptr).send(Message {
+                    message,
+                    // w_param: wparam as u32,
+                    l_param: lparam as usize,
+                });
+                if sent.is_err() {
+                    warn!("SyncWindow receiver is gone, dropping message {message} with lParam {lparam:#x}");
+                }
                 1
             }
         }
+    }))
+    .unwrap_or_else(|_| {
+        error!("SyncWindow panicked handling message {message}");
+        0
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use winapi::um::winuser::{IsWindow, SendMessageA, WM_USER};
+
+    use super::*;
+
+    #[test]
+    fn test_bounded_queue() {
+        const MESSAGE: u32 = WM_USER + 1;
+        const CAPACITY: usize = 4;
+        const FLOOD: usize = 100;
+
+        let window = SyncWindow::with_capacity(MESSAGE, CAPACITY);
+        for l_param in 0..FLOOD {
+            assert_ne!(unsafe { PostMessageA(window.handle(), MESSAGE, 0, l_param as LPARAM) }, 0);
+        }
+
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while window.dropped() < FLOOD - CAPACITY && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(window.dropped(), FLOOD - CAPACITY);
+
+        let mut received = Vec::new();
+        while let Some(l_param) = window.try_receive().unwrap() {
+            received.push(l_param);
+        }
+        assert_eq!(received, (0..CAPACITY).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_receiver_gone() {
+        const MESSAGE: u32 = WM_USER + 2;
+
+        let mut window = SyncWindow::new(MESSAGE);
+        drop(std::mem::replace(&mut window.receiver, std::sync::mpsc::sync_channel(1).1));
+
+        // the first message finds the result receiver gone and stops the forwarding thread, which drops the channel
+        assert_ne!(unsafe { PostMessageA(window.handle(), MESSAGE, 0, 1) }, 0);
+        thread::sleep(Duration::from_millis(100));
+
+        // the window procedure now fails to send, which must neither panic nor take the window down
+        assert_eq!(unsafe { SendMessageA(window.handle(), MESSAGE, 0, 2) }, 1);
+        assert_ne!(unsafe { IsWindow(window.handle()) }, 0);
+    }
+
+    #[test]
+    fn test_round_trip() {
+        const MESSAGE: u32 = WM_USER + 3;
+
+        // the sender pointer is read back from GWLP_USERDATA and results travel in lParam, neither may be truncated
+        // to 32 bits on 64-bit targets
+        const L_PARAM: usize = usize::MAX - 1;
+        let window = SyncWindow::new(MESSAGE);
+        assert_ne!(unsafe { PostMessageA(window.handle(), MESSAGE, 0, L_PARAM as LPARAM) }, 0);
+
+        let deadline = Instant::now() + Duration::from_secs(5);
+        let l_param = loop {
+            if let Some(l_param) = window.try_receive().unwrap() {
+                break l_param;
+            }
+            assert!(Instant::now() < deadline, "message not received");
+            thread::sleep(Duration::from_millis(1));
+        };
+        assert_eq!(l_param, L_PARAM);
+    }
+
+    #[test]
+    fn test_heap_pointer() {
+        const MESSAGE: u32 = WM_USER + 4;
+
+        // results are heap pointers, on 64-bit targets usually above the 32-bit range
+        let value = Box::new(0x0123_4567_89AB_CDEFu64);
+        let window = SyncWindow::new(MESSAGE);
+        assert_ne!(unsafe { PostMessageA(window.handle(), MESSAGE, 0, &*value as *const u64 as LPARAM) }, 0);
+
+        let deadline = Instant::now() + Duration::from_secs(5);
+        let pointer = loop {
+            if let Some(l_param) = window.try_receive().unwrap() {
+                break l_param as *const u64;
+            }
+            assert!(Instant::now() < deadline, "message not received");
+            thread::sleep(Duration::from_millis(1));
+        };
+        assert_eq!(pointer, &*value as *const u64);
+        assert_eq!(unsafe { *pointer }, 0x0123_4567_89AB_CDEF);
     }
 }